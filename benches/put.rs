@@ -0,0 +1,35 @@
+//! Put path benchmarks.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rumdb::{prelude::*, DbOptions, RumDb};
+
+fn put(c: &mut Criterion) {
+    let mut group = c.benchmark_group("put");
+    group.throughput(Throughput::Elements(1));
+
+    for (name, max_log_file_size) in [("no_rotation", 1024 * 1024 * 1024), ("rotation", 64 * 1024)]
+    {
+        let dir = tempdir::TempDir::new("rumdb-bench").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(max_log_file_size)
+            .keydir_snapshot(false);
+        let mut db = RumDb::open(dir.path(), opts).unwrap();
+        let mut i = 0u64;
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    i += 1;
+                    ((i % 10_000).to_le_bytes().to_vec(), vec![0; 100])
+                },
+                |(k, v)| db.put(k, v).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, put);
+criterion_main!(benches);
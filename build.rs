@@ -0,0 +1,14 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Protobuf files are compiled in Rust, so building doesn't need `protoc`.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto");
+
+        let file_descriptors = protox::compile(["proto/rumdb.proto"], ["proto"])?;
+        tonic_build::configure().compile_fds(file_descriptors)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,19 @@
+use rumdb::prelude::*;
+
+fn main() {
+    let db = Database::open("/tmp/database.rumdb/").unwrap();
+
+    db.put(b"user:1".to_vec(), b"alice".to_vec()).unwrap();
+    db.put(b"user:2".to_vec(), b"bob".to_vec()).unwrap();
+
+    for (k, v) in db.scan(b"user:").unwrap() {
+        println!(
+            "{} => {}",
+            String::from_utf8_lossy(&k),
+            String::from_utf8_lossy(&v)
+        );
+    }
+
+    db.remove(b"user:1").unwrap();
+    assert_eq!(db.get(b"user:1").unwrap(), None);
+}
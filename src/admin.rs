@@ -0,0 +1,181 @@
+//! HTTP admin endpoints, enabled by the `admin` feature.
+//!
+//! `AdminService` lets operators inspect and manage a `Database` embedded in a running
+//! application:
+//!
+//! - `GET /health`: `ok`, or 503 if the database can't be accessed.
+//! - `GET /stats`: storage statistics as JSON, see `DiskStorageStats`.
+//! - `POST /compact`: compacts the database.
+//! - `GET /keys?prefix=&limit=`: JSON array of up to `limit` keys starting with `prefix`,
+//!   non-printable bytes escaped.
+//!
+//! Storage operations block, so they run on the blocking thread pool of tokio.
+
+use std::io;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+
+use crate::{errors::StorageError, storage::DiskStorageStats, Database};
+
+/// Number of keys returned by `/keys` without `limit`.
+const DEFAULT_KEYS_LIMIT: usize = 1000;
+
+type AdminResult<T> = Result<T, (StatusCode, String)>;
+
+/// Admin endpoints over a database.
+#[derive(Debug, Clone)]
+pub struct AdminService {
+    db: Database,
+}
+
+impl AdminService {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    /// Wraps the service into a router, to be served or nested into the router of the
+    /// application.
+    pub fn into_router(self) -> Router {
+        Router::new()
+            .route("/health", get(health))
+            .route("/stats", get(stats))
+            .route("/compact", post(compact))
+            .route("/keys", get(keys))
+            .with_state(self)
+    }
+
+    /// Serves the endpoints on the `listener` until the server fails.
+    pub async fn serve(self, listener: TcpListener) -> Result<(), io::Error> {
+        axum::serve(listener, self.into_router()).await
+    }
+
+    /// Runs the storage operation `f` on the blocking thread pool.
+    async fn run<T, F>(&self, f: F) -> AdminResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, StorageError> + Send + 'static,
+    {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || f(&db))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .map_err(|e| {
+                let status = match e {
+                    StorageError::ReadOnly => StatusCode::CONFLICT,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+
+                (status, e.to_string())
+            })
+    }
+}
+
+async fn health(State(service): State<AdminService>) -> AdminResult<&'static str> {
+    service
+        .run(|db| db.read().map(|_| ()))
+        .await
+        .map_err(|(_, e)| (StatusCode::SERVICE_UNAVAILABLE, e))?;
+
+    Ok("ok")
+}
+
+async fn stats(State(service): State<AdminService>) -> AdminResult<Json<DiskStorageStats>> {
+    service.run(|db| db.storage_stats()).await.map(Json)
+}
+
+async fn compact(State(service): State<AdminService>) -> AdminResult<StatusCode> {
+    service.run(|db| db.compact()).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct KeysQuery {
+    #[serde(default)]
+    prefix: String,
+    limit: Option<usize>,
+}
+
+async fn keys(
+    State(service): State<AdminService>,
+    Query(query): Query<KeysQuery>,
+) -> AdminResult<Json<Vec<String>>> {
+    let limit = query.limit.unwrap_or(DEFAULT_KEYS_LIMIT);
+    let keys = service
+        .run(move |db| db.scan_keys(query.prefix.as_bytes(), limit))
+        .await?;
+
+    Ok(Json(
+        keys.iter().map(|k| k.escape_ascii().to_string()).collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Sends an HTTP/1.1 request to the server at `addr`, returning the response status and
+    /// body.
+    async fn request(addr: std::net::SocketAddr, method: &str, target: &str) -> (u16, String) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{method} {target} HTTP/1.1\r\nHost: rumdb\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), body.to_string())
+    }
+
+    #[tokio::test]
+    async fn admin_service_should_serve_endpoints() {
+        let dir = tempdir::TempDir::new("admin-test").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        db.put(b"user:1".to_vec(), b"alice".to_vec()).unwrap();
+        db.put(b"user:\n".to_vec(), b"bob".to_vec()).unwrap();
+        db.put(b"group:1".to_vec(), b"admins".to_vec()).unwrap();
+        db.put(b"group:1".to_vec(), b"staff".to_vec()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(AdminService::new(db.clone()).serve(listener));
+
+        assert_eq!(request(addr, "GET", "/health").await, (200, "ok".into()));
+
+        let (status, body) = request(addr, "GET", "/stats").await;
+        assert_eq!(status, 200);
+        assert!(body.starts_with("{\"keys\":3,"));
+
+        assert_eq!(
+            request(addr, "GET", "/keys?prefix=group").await,
+            (200, r#"["group:1"]"#.into())
+        );
+        assert_eq!(
+            request(addr, "GET", "/keys?prefix=user%3A%0A").await,
+            (200, r#"["user:\\n"]"#.into())
+        );
+
+        let (status, body) = request(addr, "GET", "/keys?limit=2").await;
+        assert_eq!(status, 200);
+        assert_eq!(body.matches("\",\"").count(), 1);
+
+        assert_eq!(request(addr, "GET", "/compact").await.0, 405);
+        assert_eq!(request(addr, "POST", "/compact").await.0, 204);
+        assert_eq!(db.get(b"group:1").unwrap(), Some(b"staff".to_vec()));
+    }
+}
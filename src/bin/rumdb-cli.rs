@@ -0,0 +1,510 @@
+//! Command line tool for inspecting and editing a RumDB database.
+//!
+//! ```text
+//! rumdb-cli <PATH> [--keyspace <NAME>] <get|put|del|keys|export|stats|compact|verify|reindex|dump>
+//! ```
+
+use std::{
+    error::Error,
+    io::{self, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use rumdb::{
+    log_reader::{EntryKind, LogReader},
+    prelude::*,
+};
+
+/// Number of key bytes shown by `dump`.
+const KEY_PREVIEW_SIZE: usize = 32;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "rumdb-cli",
+    version,
+    about = "Inspects and edits a RumDB database"
+)]
+struct Cli {
+    /// Database directory.
+    path: PathBuf,
+
+    /// Keyspace to operate on instead of the database itself.
+    #[arg(short, long)]
+    keyspace: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Prints the value of a key.
+    Get { key: String },
+    /// Sets the value of a key.
+    Put { key: String, value: String },
+    /// Removes a key.
+    Del { key: String },
+    /// Lists keys, sorted.
+    Keys {
+        /// Lists only keys starting with the prefix.
+        #[arg(short, long, default_value = "")]
+        prefix: String,
+    },
+    /// Streams key-value pairs, in no particular order.
+    Export {
+        /// Output format.
+        #[arg(short, long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        /// Exports only pairs whose key starts with the prefix.
+        #[arg(short, long, default_value = "")]
+        prefix: String,
+        /// How keys and values are decoded.
+        #[arg(short, long, value_enum, default_value_t = Encoding::Utf8)]
+        encoding: Encoding,
+    },
+    /// Prints storage statistics.
+    Stats,
+    /// Rewrites live entries of sealed log files and removes dead log files.
+    Compact {
+        /// Compacts all log files, the active one included.
+        #[arg(long, conflicts_with = "segments")]
+        all: bool,
+        /// Compacts only the log files with these ids.
+        segments: Vec<u32>,
+    },
+    /// Verifies log file checksums and keys, reporting inconsistencies.
+    Verify,
+    /// Rebuilds the keydir from log files, rewriting their footers, and verifies it.
+    Reindex,
+    /// Prints raw entries of a log file, superseded ones included.
+    Dump {
+        /// Log file id or path relative to the database directory.
+        segment: String,
+    },
+}
+
+/// Output format of `export`.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    /// One JSON object per line, with `key` and `value` strings.
+    Json,
+    /// CSV with a `key,value` header.
+    Csv,
+}
+
+/// Decoding of keys and values by `export`.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Encoding {
+    /// UTF-8, invalid sequences replaced.
+    Utf8,
+    /// Lowercase hex.
+    Hex,
+}
+
+impl Encoding {
+    fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Hex => bytes.iter().map(|b| format!("{b:02x}")).collect(),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli, &mut io::stdout().lock()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            let mut message = e.to_string();
+            let mut source = e.source();
+
+            while let Some(e) = source {
+                message = format!("{message}: {e}");
+                source = e.source();
+            }
+
+            eprintln!("error: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the command, writing its output to `out`. Returns whether the command succeeded.
+fn run(cli: Cli, out: &mut impl Write) -> Result<bool, Box<dyn Error>> {
+    if !cli.path.is_dir() {
+        return Err(format!("no database at {}", cli.path.display()).into());
+    }
+
+    // Log files are read without opening the database, which may be in use.
+    if let Command::Dump { segment } = &cli.command {
+        let mut path = cli.path.clone();
+
+        if let Some(name) = &cli.keyspace {
+            path = path.join("keyspaces").join(name);
+        }
+
+        path = match segment.parse::<u32>() {
+            Ok(file_id) => path.join(format!("{file_id}.rumdb.log")),
+            Err(_) => path.join(segment),
+        };
+
+        return dump(LogReader::open(path)?, out);
+    }
+
+    let mut db = RumDb::open_default(&cli.path)?;
+
+    let storage = match &cli.keyspace {
+        Some(name) => {
+            if db.get_keyspace(name).is_none() && !matches!(cli.command, Command::Put { .. }) {
+                return Err(format!("unknown keyspace: {name}").into());
+            }
+
+            db.keyspace(name)?
+        }
+        None => &mut db,
+    };
+
+    match cli.command {
+        Command::Get { key } => match storage.get(key.as_bytes())? {
+            Some(value) => writeln!(out, "{}", value.escape_ascii())?,
+            None => return Err(format!("key not found: {key}").into()),
+        },
+        Command::Put { key, value } => storage.put(key.into_bytes(), value.into_bytes())?,
+        Command::Del { key } => storage.remove(key.as_bytes())?,
+        Command::Keys { prefix } => {
+            let mut keys = storage
+                .scan_prefix(prefix.as_bytes())
+                .map(|kv| kv.map(|(k, _)| k))
+                .collect::<Result<Vec<_>, _>>()?;
+            keys.sort();
+
+            for key in keys {
+                writeln!(out, "{}", key.escape_ascii())?;
+            }
+        }
+        Command::Export {
+            format,
+            prefix,
+            encoding,
+        } => {
+            if let Format::Csv = format {
+                writeln!(out, "key,value")?;
+            }
+
+            for kv in storage.scan_prefix(prefix.as_bytes()) {
+                let (key, value) = kv?;
+                let (key, value) = (encoding.decode(&key), encoding.decode(&value));
+
+                match format {
+                    Format::Json => writeln!(
+                        out,
+                        "{{\"key\":{},\"value\":{}}}",
+                        json_string(&key),
+                        json_string(&value)
+                    )?,
+                    Format::Csv => writeln!(out, "{},{}", csv_field(&key), csv_field(&value))?,
+                }
+            }
+        }
+        Command::Stats => {
+            writeln!(out, "{}", storage.storage_stats())?;
+
+            let mut keyspaces: Vec<_> = storage.keyspace_names().collect();
+            keyspaces.sort();
+
+            if !keyspaces.is_empty() {
+                writeln!(out, "keyspaces: {}", keyspaces.join(", "))?;
+            }
+        }
+        Command::Compact { all, ref segments } => {
+            let before = storage.storage_stats().log_files;
+
+            match (all, segments.is_empty()) {
+                (true, _) => storage.compact_all()?,
+                (false, true) => storage.compact()?,
+                (false, false) => storage.compact_segments(segments)?,
+            }
+
+            let after = storage.storage_stats().log_files;
+
+            writeln!(out, "log files: {before} -> {after}")?;
+        }
+        Command::Verify | Command::Reindex => {
+            let report = match cli.command {
+                Command::Reindex => storage.rebuild_index()?,
+                _ => storage.verify()?,
+            };
+
+            for inconsistency in report.inconsistencies.iter() {
+                writeln!(out, "{inconsistency}")?;
+            }
+
+            writeln!(out, "{report}")?;
+
+            return Ok(report.is_ok());
+        }
+        Command::Dump { .. } => unreachable!("log files are dumped without opening the database"),
+    }
+
+    Ok(true)
+}
+
+/// Quotes the string as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Quotes the string as a CSV field if it holds separators, quotes or line breaks.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Prints entries of the log file. Returns whether all checksums match.
+fn dump(reader: LogReader, out: &mut impl Write) -> Result<bool, Box<dyn Error>> {
+    write!(
+        out,
+        "format version: {}, size: {} bytes",
+        reader.format_version(),
+        reader.size()
+    )?;
+
+    if let Some(created_at) = reader.created_at() {
+        let created_at = i64::try_from(created_at)
+            .ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map_or_else(|| created_at.to_string(), |ts| ts.to_rfc3339());
+
+        write!(out, ", created at: {created_at}")?;
+    }
+
+    if let Some(stats) = reader.stats() {
+        write!(
+            out,
+            ", sealed: {} entries, {} live",
+            stats.entries, stats.live_entries
+        )?;
+    }
+
+    writeln!(out)?;
+
+    let mut corrupt = 0;
+
+    for entry in reader {
+        let entry = entry?;
+
+        let timestamp = i64::try_from(entry.timestamp)
+            .ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map_or_else(|| entry.timestamp.to_string(), |ts| ts.to_rfc3339());
+
+        let kind = match entry.kind {
+            EntryKind::Put => "put",
+            EntryKind::Tombstone => "tombstone",
+            EntryKind::MergeOperand => "merge",
+        };
+
+        let mut key = entry.key[..entry.key.len().min(KEY_PREVIEW_SIZE)]
+            .escape_ascii()
+            .to_string();
+
+        if entry.key.len() > KEY_PREVIEW_SIZE {
+            key.push_str("...");
+        }
+
+        let checksum = match entry.checksum_valid {
+            Some(true) => "ok",
+            Some(false) => {
+                corrupt += 1;
+                "mismatch"
+            }
+            None => "-",
+        };
+
+        writeln!(
+            out,
+            "{}\t{timestamp}\t{kind}\t{key}\tvalue: {} bytes\tchecksum: {checksum}",
+            entry.offset, entry.value_size
+        )?;
+    }
+
+    Ok(corrupt == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_cli(path: &std::path::Path, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let cli = Cli::try_parse_from(
+            ["rumdb-cli", path.to_str().unwrap()]
+                .iter()
+                .chain(args.iter()),
+        )?;
+
+        let mut out = Vec::new();
+        assert!(run(cli, &mut out)?);
+
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn decode_hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn cli_should_export_round_trippable_pairs() {
+        let dir = tempdir::TempDir::new("rumdb-cli-test").unwrap();
+        let path = dir.path();
+
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (b"plain".to_vec(), b"value".to_vec()),
+            (b"a,\"quoted\"\nkey".to_vec(), b"with,comma".to_vec()),
+            (vec![0, 255, 128], vec![]),
+            (b"empty".to_vec(), vec![0xc3, 0x28]),
+        ];
+        pairs.extend((0..100u8).map(|i| (vec![b'k', i], vec![i; i as usize])));
+
+        {
+            let mut db = RumDb::open_default(path).unwrap();
+
+            for (key, value) in pairs.iter() {
+                db.put(key.clone(), value.clone()).unwrap();
+            }
+        }
+
+        pairs.sort();
+
+        let json = run_cli(path, &["export", "-e", "hex"]).unwrap();
+        let mut exported: Vec<_> = json
+            .lines()
+            .map(|line| {
+                let fields = line
+                    .strip_prefix("{\"key\":\"")
+                    .and_then(|line| line.strip_suffix("\"}"))
+                    .unwrap();
+                let (key, value) = fields.split_once("\",\"value\":\"").unwrap();
+
+                (decode_hex(key), decode_hex(value))
+            })
+            .collect();
+        exported.sort();
+        assert_eq!(exported, pairs);
+
+        let csv = run_cli(path, &["export", "-f", "csv", "-e", "hex"]).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("key,value"));
+
+        let mut exported: Vec<_> = lines
+            .map(|line| {
+                let (key, value) = line.split_once(',').unwrap();
+                (decode_hex(key), decode_hex(value))
+            })
+            .collect();
+        exported.sort();
+        assert_eq!(exported, pairs);
+    }
+
+    #[test]
+    fn cli_should_run_commands() {
+        let dir = tempdir::TempDir::new("rumdb-cli-test").unwrap();
+        let path = dir.path();
+
+        assert!(run_cli(&path.join("missing"), &["stats"]).is_err());
+
+        run_cli(path, &["put", "hello", "world"]).unwrap();
+        run_cli(path, &["put", "help", "me"]).unwrap();
+        run_cli(path, &["put", "removed", "value"]).unwrap();
+        run_cli(path, &["del", "removed"]).unwrap();
+        run_cli(path, &["-k", "users", "put", "alice", "admin"]).unwrap();
+
+        assert_eq!(run_cli(path, &["get", "hello"]).unwrap(), "world\n");
+        assert!(run_cli(path, &["get", "removed"]).is_err());
+        assert_eq!(run_cli(path, &["keys"]).unwrap(), "hello\nhelp\n");
+        assert_eq!(run_cli(path, &["keys", "-p", "hell"]).unwrap(), "hello\n");
+        assert_eq!(
+            run_cli(path, &["-k", "users", "get", "alice"]).unwrap(),
+            "admin\n"
+        );
+        assert!(run_cli(path, &["-k", "missing", "keys"]).is_err());
+
+        let stats = run_cli(path, &["stats"]).unwrap();
+        assert!(stats.starts_with("keys: 2, "));
+        assert!(stats.ends_with("keyspaces: users\n"));
+
+        let dump = run_cli(path, &["dump", "0"]).unwrap();
+        let lines: Vec<_> = dump.lines().collect();
+        assert!(lines[0].starts_with("format version: 6, "));
+        assert!(lines[0].contains(", created at: "));
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].starts_with("14\t"));
+        assert!(lines[1].contains("\tput\thello\tvalue: 5 bytes\tchecksum: ok"));
+        assert!(lines[4].contains("\ttombstone\tremoved\t"));
+        assert!(run_cli(path, &["dump", "1"]).is_err());
+        assert_eq!(
+            run_cli(path, &["-k", "users", "dump", "0.rumdb.log"])
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+
+        assert_eq!(
+            run_cli(path, &["export", "-p", "hell"]).unwrap(),
+            "{\"key\":\"hello\",\"value\":\"world\"}\n"
+        );
+        assert_eq!(
+            run_cli(path, &["export", "-f", "csv", "-e", "hex", "-p", "help"]).unwrap(),
+            "key,value\n68656c70,6d65\n"
+        );
+        assert_eq!(json_string("a\"\\\n\u{1}"), r#""a\"\\\n\u0001""#);
+        assert_eq!(csv_field("a,\"b\""), r#""a,""b""""#);
+
+        assert_eq!(
+            run_cli(path, &["reindex"]).unwrap(),
+            "log files: 1, entries: 4, keys: 2, inconsistencies: 0\n"
+        );
+
+        run_cli(path, &["compact"]).unwrap();
+        assert_eq!(
+            run_cli(path, &["verify"]).unwrap(),
+            "log files: 1, entries: 4, keys: 2, inconsistencies: 0\n"
+        );
+
+        assert!(run_cli(path, &["compact", "7"]).is_err());
+        assert_eq!(
+            run_cli(path, &["compact", "--all"]).unwrap(),
+            "log files: 1 -> 1\n"
+        );
+        assert_eq!(
+            run_cli(path, &["verify"]).unwrap(),
+            "log files: 1, entries: 2, keys: 2, inconsistencies: 0\n"
+        );
+    }
+}
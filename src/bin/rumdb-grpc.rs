@@ -0,0 +1,44 @@
+//! gRPC server over a RumDB database.
+//!
+//! ```text
+//! rumdb-grpc <PATH> [--listen <ADDR>]
+//! ```
+
+use std::{error::Error, net::SocketAddr, path::PathBuf};
+
+use clap::Parser;
+use rumdb::{grpc::RumDbService, prelude::*};
+use tonic::transport::Server;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "rumdb-grpc",
+    version,
+    about = "Serves a RumDB database over gRPC"
+)]
+struct Args {
+    /// Database directory.
+    path: PathBuf,
+
+    /// Address to listen on.
+    #[arg(short, long, default_value = "127.0.0.1:50051")]
+    listen: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let db = Database::open(&args.path)?;
+
+    eprintln!("serving {} on {}", args.path.display(), args.listen);
+
+    Server::builder()
+        .add_service(RumDbService::new(db).into_server())
+        .serve_with_shutdown(args.listen, async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,468 @@
+//! Server speaking a subset of the Redis protocol (RESP) over a RumDB database.
+//!
+//! ```text
+//! rumdb-server <PATH> [--listen <ADDR>]
+//! ```
+//!
+//! Supported commands: PING, ECHO, GET, SET, DEL, EXISTS, SCAN, TTL and QUIT. Keys never
+//! expire, so TTL only tells whether a key exists.
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    thread,
+};
+
+use clap::Parser;
+use rumdb::prelude::*;
+
+/// Number of keys returned by SCAN without COUNT.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Maximum number of arguments of a command.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// Maximum size of a bulk string argument in bytes.
+const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "rumdb-server",
+    version,
+    about = "Serves a RumDB database over the Redis protocol"
+)]
+struct Args {
+    /// Database directory.
+    path: PathBuf,
+
+    /// Address to listen on.
+    #[arg(short, long, default_value = "127.0.0.1:6379")]
+    listen: String,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let db = Database::open(&args.path)?;
+    let listener = TcpListener::bind(&args.listen)?;
+
+    eprintln!(
+        "serving {} on {}",
+        args.path.display(),
+        listener.local_addr()?
+    );
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("error: failed to accept connection: {e}");
+                continue;
+            }
+        };
+
+        let db = db.clone();
+
+        thread::spawn(move || {
+            if let Err(e) = serve(&db, stream) {
+                eprintln!("error: connection failed: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Executes commands of the connection until the client quits or disconnects.
+fn serve(db: &Database, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Reply::Error(format!("ERR Protocol error: {e}")).write_to(&mut writer)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+
+        execute(db, &args).write_to(&mut writer)?;
+
+        // Pipelined commands are answered together.
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Reply to a command.
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Self::Simple(s) => write!(out, "+{s}\r\n"),
+            Self::Error(e) => write!(out, "-{e}\r\n"),
+            Self::Integer(n) => write!(out, ":{n}\r\n"),
+            Self::Bulk(None) => write!(out, "$-1\r\n"),
+            Self::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Self::Array(replies) => {
+                write!(out, "*{}\r\n", replies.len())?;
+
+                for reply in replies {
+                    reply.write_to(out)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<StorageError> for Reply {
+    fn from(e: StorageError) -> Self {
+        Self::Error(format!("ERR {e}"))
+    }
+}
+
+/// Reads a command, either a RESP array of bulk strings or an inline command.
+/// Returns `None` once the client has disconnected.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+
+    let Some(len) = line.strip_prefix(b"*") else {
+        return Ok(Some(
+            line.split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    };
+
+    let len = parse_len(len, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(len);
+
+    for _ in 0..len {
+        let line = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+
+        let Some(size) = line.strip_prefix(b"$") else {
+            return Err(invalid_data("expected bulk string"));
+        };
+
+        let mut arg = vec![0; parse_len(size, MAX_BULK_SIZE)? + 2];
+        reader.read_exact(&mut arg)?;
+
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid_data("expected CRLF after bulk string"));
+        }
+
+        arg.truncate(arg.len() - 2);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+/// Reads a line without the trailing CRLF. Returns `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+
+    if line.pop() != Some(b'\n') {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+fn parse_len(s: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| invalid_data("invalid length"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn execute(db: &Database, args: &[Vec<u8>]) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+
+    let arity_ok = match name.as_str() {
+        "PING" => args.len() <= 1,
+        "ECHO" | "GET" | "TTL" => args.len() == 1,
+        "SET" => args.len() == 2,
+        "DEL" | "EXISTS" => !args.is_empty(),
+        "SCAN" => !args.is_empty() && args.len() % 2 == 1,
+        "QUIT" => true,
+        _ => return Reply::Error(format!("ERR unknown command '{name}'")),
+    };
+
+    if !arity_ok {
+        // Options of SET, e.g. expiration, are not supported either.
+        return Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ));
+    }
+
+    let res = match name.as_str() {
+        "PING" => Ok(match args.first() {
+            Some(message) => Reply::Bulk(Some(message.clone())),
+            None => Reply::Simple("PONG"),
+        }),
+        "ECHO" => Ok(Reply::Bulk(Some(args[0].clone()))),
+        "GET" => db.get(&args[0]).map(Reply::Bulk),
+        "SET" => db
+            .put(args[0].clone(), args[1].clone())
+            .map(|_| Reply::Simple("OK")),
+        "DEL" => count_existing(db, args).and_then(|(count, existing)| {
+            for k in existing {
+                db.remove(k)?;
+            }
+
+            Ok(Reply::Integer(count))
+        }),
+        "EXISTS" => count_existing(db, args).map(|(count, _)| Reply::Integer(count)),
+        "TTL" => db
+            .get(&args[0])
+            .map(|v| Reply::Integer(if v.is_some() { -1 } else { -2 })),
+        "SCAN" => return scan(db, args),
+        "QUIT" => Ok(Reply::Simple("OK")),
+        _ => unreachable!("unknown commands are rejected above"),
+    };
+
+    res.unwrap_or_else(Reply::from)
+}
+
+/// Counts the existing keys, repeated keys counted every time they occur as Redis does.
+fn count_existing<'a>(
+    db: &Database,
+    keys: &'a [Vec<u8>],
+) -> Result<(i64, Vec<&'a [u8]>), StorageError> {
+    let mut existing = Vec::new();
+    let mut count = 0;
+
+    for k in keys {
+        if db.get(k)?.is_some() {
+            existing.push(k.as_slice());
+            count += 1;
+        }
+    }
+
+    Ok((count, existing))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`. The cursor is the number of keys, in
+/// ascending order, already returned, so keys added or removed between calls may be
+/// skipped or returned twice, as Redis allows.
+fn scan(db: &Database, args: &[Vec<u8>]) -> Reply {
+    let Some(cursor) = std::str::from_utf8(&args[0])
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+    else {
+        return Reply::Error("ERR invalid cursor".to_string());
+    };
+
+    let mut pattern: &[u8] = b"*";
+    let mut count = DEFAULT_SCAN_COUNT;
+
+    for option in args[1..].chunks(2) {
+        match String::from_utf8_lossy(&option[0])
+            .to_ascii_uppercase()
+            .as_str()
+        {
+            "MATCH" => pattern = &option[1],
+            "COUNT" => match std::str::from_utf8(&option[1])
+                .ok()
+                .and_then(|s| s.parse().ok())
+            {
+                Some(n) if n > 0 => count = n,
+                _ => return Reply::Error("ERR value is out of range".to_string()),
+            },
+            _ => return Reply::Error("ERR syntax error".to_string()),
+        }
+    }
+
+    // Only the literal prefix of the pattern narrows the scan down.
+    let prefix_len = pattern
+        .iter()
+        .position(|c| b"*?[\\".contains(c))
+        .unwrap_or(pattern.len());
+
+    let mut keys: Vec<_> = match db.scan(&pattern[..prefix_len]) {
+        Ok(pairs) => pairs.into_iter().map(|(k, _)| k).collect(),
+        Err(e) => return e.into(),
+    };
+    keys.sort();
+
+    let end = (cursor + count).min(keys.len());
+    let next_cursor = if end == keys.len() { 0 } else { end };
+
+    let matching = keys
+        .get(cursor..end)
+        .unwrap_or_default()
+        .iter()
+        .filter(|k| glob_match(pattern, k))
+        .map(|k| Reply::Bulk(Some(k.clone())))
+        .collect();
+
+    Reply::Array(vec![
+        Reply::Bulk(Some(next_cursor.to_string().into_bytes())),
+        Reply::Array(matching),
+    ])
+}
+
+/// Matches the `key` against a Redis glob pattern: `*`, `?`, `[...]` classes and `\` escapes.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|i| glob_match(rest, &key[i..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'[', rest)) => {
+            let Some(end) = rest.iter().position(|&c| c == b']') else {
+                return key.first() == Some(&b'[') && glob_match(rest, &key[1..]);
+            };
+
+            let Some((&c, key_rest)) = key.split_first() else {
+                return false;
+            };
+
+            let (negated, class) = match rest[..end].split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, &rest[..end]),
+            };
+
+            let mut matched = false;
+            let mut i = 0;
+
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= (class[i]..=class[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+
+            matched != negated && glob_match(&rest[end + 1..], key_rest)
+        }
+        Some((b'\\', [escaped, rest @ ..])) => {
+            key.first() == Some(escaped) && glob_match(rest, &key[1..])
+        }
+        Some((c, rest)) => key.first() == Some(c) && glob_match(rest, &key[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(db: &Database, input: &[u8]) -> Vec<u8> {
+        let mut reader = input;
+        let mut out = Vec::new();
+
+        while let Some(args) = read_command(&mut reader).unwrap() {
+            execute(db, &args).write_to(&mut out).unwrap();
+        }
+
+        out
+    }
+
+    #[test]
+    fn server_should_execute_commands() {
+        let dir = tempdir::TempDir::new("rumdb-server-test").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        assert_eq!(run(&db, b"PING\r\n"), b"+PONG\r\n");
+        assert_eq!(
+            run(&db, b"*3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nworld\r\n"),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&db, b"*2\r\n$3\r\nget\r\n$5\r\nhello\r\n"),
+            b"$5\r\nworld\r\n"
+        );
+        assert_eq!(run(&db, b"GET missing\r\n"), b"$-1\r\n");
+        assert_eq!(run(&db, b"EXISTS hello missing hello\r\n"), b":2\r\n");
+        assert_eq!(run(&db, b"TTL hello\r\nTTL missing\r\n"), b":-1\r\n:-2\r\n");
+        assert_eq!(
+            run(&db, b"SET hello world EX 10\r\n"),
+            b"-ERR wrong number of arguments for 'set' command\r\n"
+        );
+        assert_eq!(
+            run(&db, b"FLUSHALL\r\n"),
+            b"-ERR unknown command 'FLUSHALL'\r\n"
+        );
+
+        for k in ["user:1", "user:2", "user:3", "group:1"] {
+            run(&db, format!("SET {k} value\r\n").as_bytes());
+        }
+
+        assert_eq!(
+            run(&db, b"SCAN 0 MATCH user:* COUNT 2\r\n"),
+            b"*2\r\n$1\r\n2\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n"
+        );
+        assert_eq!(
+            run(&db, b"SCAN 2 MATCH user:* COUNT 2\r\n"),
+            b"*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:3\r\n"
+        );
+        assert_eq!(
+            run(&db, b"SCAN 0 MATCH *:1\r\n"),
+            b"*2\r\n$1\r\n0\r\n*2\r\n$7\r\ngroup:1\r\n$6\r\nuser:1\r\n"
+        );
+
+        assert_eq!(run(&db, b"DEL hello user:1 missing\r\n"), b":2\r\n");
+        assert_eq!(run(&db, b"GET hello\r\n"), b"$-1\r\n");
+
+        let mut reader: &[u8] = b"*1\r\n$4\r\nPINGX\r\n";
+        assert!(read_command(&mut reader).is_err());
+    }
+
+    #[test]
+    fn glob_should_match_redis_patterns() {
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+        assert!(!glob_match(b"hello", b"hello!"));
+    }
+}
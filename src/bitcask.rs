@@ -0,0 +1,517 @@
+//! Bitcask data and hint files, as written by Riak's bitcask.
+//!
+//! A bitcask directory holds data files named `<id>.bitcask.data`, each a sequence of
+//! entries made of a crc32, a timestamp, the key and value sizes, the key and the value. The
+//! crc32 covers everything after it. Removals are entries whose value is a tombstone.
+//!
+//! A data file may come with a hint file `<id>.bitcask.hint`, holding for each key the
+//! timestamp, key size, total size, a tombstone bit and the offset of its latest entry in
+//! the data file, followed by the key. A final record with an empty key holds a crc32 of the
+//! hint file in place of the total size. Integers are big-endian.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    errors::{FormatError, StorageError},
+    vfs::{OpenMode, Vfs, VfsAppender, VfsFile, VfsReader},
+};
+
+/// Size of a data file entry header: crc32, timestamp, key size and value size.
+const DATA_HEADER_SIZE: usize = 14;
+
+/// Size of a hint file entry header: timestamp, key size, total size and offset.
+const HINT_HEADER_SIZE: usize = 18;
+
+/// Offset of the hint file record holding the crc32, without the tombstone bit.
+const HINT_CRC_OFFSET: u64 = u64::MAX >> 1;
+
+/// Tombstone bit of hint file offsets.
+const HINT_TOMBSTONE: u64 = 1 << 63;
+
+/// Size data files are rolled over at, the default maximum of bitcask.
+pub(crate) const MAX_DATA_FILE_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Tombstone values of the successive bitcask versions. Later ones are followed by the id of
+/// a data file.
+const TOMBSTONE: &[u8] = b"bitcask_tombstone";
+const TOMBSTONES_WITH_FILE_ID: [&[u8]; 2] = [b"bitcask_tombstone1", b"bitcask_tombstone2"];
+
+fn is_tombstone(value: &[u8]) -> bool {
+    value == TOMBSTONE
+        || TOMBSTONES_WITH_FILE_ID
+            .iter()
+            .any(|tombstone| value.len() == tombstone.len() + 4 && value.starts_with(tombstone))
+}
+
+fn data_file_name(file_id: u32) -> String {
+    format!("{file_id}.bitcask.data")
+}
+
+fn hint_file_name(file_id: u32) -> String {
+    format!("{file_id}.bitcask.hint")
+}
+
+/// Latest entry of a key in a bitcask directory.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    file_id: u32,
+    offset: u64,
+    timestamp: u32,
+    value_pos: u64,
+    value_size: u32,
+    tombstone: bool,
+}
+
+impl Location {
+    /// Entries are ordered as by bitcask: by timestamp, then by position.
+    fn is_newer_than(&self, other: &Self) -> bool {
+        (self.timestamp, self.file_id, self.offset)
+            >= (other.timestamp, other.file_id, other.offset)
+    }
+}
+
+/// Reader of the live key-value pairs of a bitcask directory.
+pub(crate) struct BitcaskReader {
+    files: BTreeMap<u32, Arc<dyn VfsFile>>,
+    keydir: HashMap<Vec<u8>, Location>,
+}
+
+impl BitcaskReader {
+    /// Reads the keys of the bitcask directory `dir`, from hint files where they are valid
+    /// and from data files otherwise.
+    pub fn open(vfs: &dyn Vfs, dir: &Path) -> Result<Self, StorageError> {
+        let names = vfs.list(dir)?;
+        let mut reader = Self {
+            files: BTreeMap::new(),
+            keydir: HashMap::new(),
+        };
+
+        let file_ids: Vec<u32> = names
+            .iter()
+            .filter_map(|name| name.strip_suffix(".bitcask.data")?.parse().ok())
+            .collect();
+
+        for file_id in file_ids {
+            let file = vfs.open(&dir.join(data_file_name(file_id)), OpenMode::ReadOnly)?;
+            reader.files.insert(file_id, file);
+
+            let hint_path = dir.join(hint_file_name(file_id));
+            let hinted =
+                vfs.exists(&hint_path) && reader.read_hint_file(vfs, &hint_path, file_id)?;
+
+            if !hinted {
+                reader.read_data_file(file_id)?;
+            }
+        }
+
+        Ok(reader)
+    }
+
+    /// Iterates live key-value pairs, in no particular order, with their timestamps.
+    pub fn pairs(
+        &self,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>, u32), StorageError>> + '_ {
+        self.keydir
+            .iter()
+            .filter(|(_, location)| !location.tombstone)
+            .map(|(key, location)| {
+                let mut value = vec![0; location.value_size as usize];
+                self.files[&location.file_id].read_exact_at(&mut value, location.value_pos)?;
+
+                Ok((key.clone(), value, location.timestamp))
+            })
+    }
+
+    fn insert(&mut self, key: Vec<u8>, location: Location) {
+        match self.keydir.get(&key) {
+            Some(existing) if !location.is_newer_than(existing) => (),
+            _ => {
+                self.keydir.insert(key, location);
+            }
+        }
+    }
+
+    /// Reads the keys of a data file from its hint file. Returns false, reading nothing, if
+    /// the hint file is corrupted.
+    fn read_hint_file(
+        &mut self,
+        vfs: &dyn Vfs,
+        path: &Path,
+        file_id: u32,
+    ) -> Result<bool, StorageError> {
+        let file = vfs.open(path, OpenMode::ReadOnly)?;
+        let mut buf = vec![0; file.len()? as usize];
+        file.read_exact_at(&mut buf, 0)?;
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+
+        loop {
+            let Some(header) = buf.get(pos..pos + HINT_HEADER_SIZE) else {
+                return Ok(false);
+            };
+            let timestamp = u32::from_be_bytes(header[0..4].try_into().unwrap());
+            let key_size = u16::from_be_bytes(header[4..6].try_into().unwrap()) as usize;
+            let total_size = u32::from_be_bytes(header[6..10].try_into().unwrap());
+            let offset = u64::from_be_bytes(header[10..18].try_into().unwrap());
+
+            if key_size == 0 && offset & !HINT_TOMBSTONE == HINT_CRC_OFFSET {
+                if crc32fast::hash(&buf[..pos]) != total_size || pos + HINT_HEADER_SIZE != buf.len()
+                {
+                    return Ok(false);
+                }
+
+                break;
+            }
+
+            let key_pos = pos + HINT_HEADER_SIZE;
+            let Some(key) = buf.get(key_pos..key_pos + key_size) else {
+                return Ok(false);
+            };
+            let Some(value_size) = total_size.checked_sub((DATA_HEADER_SIZE + key_size) as u32)
+            else {
+                return Ok(false);
+            };
+
+            let offset_in_file = offset & !HINT_TOMBSTONE;
+            entries.push((
+                key.to_vec(),
+                Location {
+                    file_id,
+                    offset: offset_in_file,
+                    timestamp,
+                    value_pos: offset_in_file + (DATA_HEADER_SIZE + key_size) as u64,
+                    value_size,
+                    tombstone: offset & HINT_TOMBSTONE != 0,
+                },
+            ));
+
+            pos = key_pos + key_size;
+        }
+
+        for (key, mut location) in entries {
+            // Hint files written before the tombstone bit existed list tombstones as values.
+            if !location.tombstone && Self::may_be_tombstone(location.value_size) {
+                let mut value = vec![0; location.value_size as usize];
+                self.files[&file_id].read_exact_at(&mut value, location.value_pos)?;
+                location.tombstone = is_tombstone(&value);
+            }
+
+            self.insert(key, location);
+        }
+
+        Ok(true)
+    }
+
+    fn may_be_tombstone(value_size: u32) -> bool {
+        value_size as usize == TOMBSTONE.len()
+            || TOMBSTONES_WITH_FILE_ID
+                .iter()
+                .any(|tombstone| value_size as usize == tombstone.len() + 4)
+    }
+
+    /// Reads the keys of a data file by scanning its entries. A torn entry at the end, left
+    /// by a crash, ends the scan.
+    fn read_data_file(&mut self, file_id: u32) -> Result<(), StorageError> {
+        let mut reader = BufReader::new(VfsReader::new(self.files[&file_id].clone(), 0));
+        let mut offset = 0;
+        let mut header = [0; DATA_HEADER_SIZE];
+
+        loop {
+            match reader.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+
+            let crc = u32::from_be_bytes(header[0..4].try_into().unwrap());
+            let timestamp = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            let key_size = u16::from_be_bytes(header[8..10].try_into().unwrap()) as usize;
+            let value_size = u32::from_be_bytes(header[10..14].try_into().unwrap());
+
+            let mut key_value = vec![0; key_size + value_size as usize];
+            match reader.read_exact(&mut key_value) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&header[4..]);
+            hasher.update(&key_value);
+
+            if hasher.finalize() != crc {
+                return Err(FormatError::ChecksumMismatch.into());
+            }
+
+            let tombstone = is_tombstone(&key_value[key_size..]);
+            key_value.truncate(key_size);
+
+            self.insert(
+                key_value,
+                Location {
+                    file_id,
+                    offset,
+                    timestamp,
+                    value_pos: offset + (DATA_HEADER_SIZE + key_size) as u64,
+                    value_size,
+                    tombstone,
+                },
+            );
+
+            offset += (DATA_HEADER_SIZE + key_size) as u64 + value_size as u64;
+        }
+    }
+}
+
+/// Writer of bitcask data files along with their hint files, numbered from 1.
+pub(crate) struct BitcaskWriter<'a> {
+    vfs: &'a dyn Vfs,
+    dir: PathBuf,
+    file_id: u32,
+    current: Option<BitcaskFiles>,
+}
+
+/// Data and hint file being written.
+struct BitcaskFiles {
+    data: BufWriter<VfsAppender>,
+    hint: BufWriter<VfsAppender>,
+    hint_hasher: crc32fast::Hasher,
+    size: u64,
+}
+
+impl<'a> BitcaskWriter<'a> {
+    pub fn new(vfs: &'a dyn Vfs, dir: &Path) -> Self {
+        Self {
+            vfs,
+            dir: dir.to_path_buf(),
+            file_id: 0,
+            current: None,
+        }
+    }
+
+    /// Appends a key-value pair. Keys must be at most `u16::MAX` bytes and values at most
+    /// `u32::MAX` bytes.
+    pub fn pair(&mut self, key: &[u8], value: &[u8], timestamp: u32) -> Result<(), io::Error> {
+        let entry_size = (DATA_HEADER_SIZE + key.len() + value.len()) as u64;
+
+        if self
+            .current
+            .as_ref()
+            .is_none_or(|files| files.size > 0 && files.size + entry_size > MAX_DATA_FILE_SIZE)
+        {
+            self.roll()?;
+        }
+
+        let files = self.current.as_mut().unwrap();
+
+        let mut header = [0; DATA_HEADER_SIZE];
+        header[4..8].copy_from_slice(&timestamp.to_be_bytes());
+        header[8..10].copy_from_slice(&(key.len() as u16).to_be_bytes());
+        header[10..14].copy_from_slice(&(value.len() as u32).to_be_bytes());
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(key);
+        hasher.update(value);
+        header[0..4].copy_from_slice(&hasher.finalize().to_be_bytes());
+
+        files.data.write_all(&header)?;
+        files.data.write_all(key)?;
+        files.data.write_all(value)?;
+
+        let mut hint = [0; HINT_HEADER_SIZE];
+        hint[0..4].copy_from_slice(&timestamp.to_be_bytes());
+        hint[4..6].copy_from_slice(&(key.len() as u16).to_be_bytes());
+        hint[6..10].copy_from_slice(&(entry_size as u32).to_be_bytes());
+        hint[10..18].copy_from_slice(&files.size.to_be_bytes());
+        files.write_hint(&hint)?;
+        files.write_hint(key)?;
+
+        files.size += entry_size;
+
+        Ok(())
+    }
+
+    /// Completes the files being written and syncs the directory.
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.complete()?;
+        self.vfs.sync_dir(&self.dir)
+    }
+
+    /// Completes the files being written and starts the next ones.
+    fn roll(&mut self) -> Result<(), io::Error> {
+        self.complete()?;
+        self.file_id += 1;
+
+        let open = |name: String| -> io::Result<BufWriter<VfsAppender>> {
+            let file = self.vfs.open(&self.dir.join(name), OpenMode::CreateNew)?;
+            Ok(BufWriter::new(VfsAppender(file)))
+        };
+
+        self.current = Some(BitcaskFiles {
+            data: open(data_file_name(self.file_id))?,
+            hint: open(hint_file_name(self.file_id))?,
+            hint_hasher: crc32fast::Hasher::new(),
+            size: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Closes the hint file with its crc32 and syncs the files being written.
+    fn complete(&mut self) -> Result<(), io::Error> {
+        let Some(mut files) = self.current.take() else {
+            return Ok(());
+        };
+
+        let crc = files.hint_hasher.clone().finalize();
+        let mut record = [0; HINT_HEADER_SIZE];
+        record[6..10].copy_from_slice(&crc.to_be_bytes());
+        record[10..18].copy_from_slice(&HINT_CRC_OFFSET.to_be_bytes());
+        files.hint.write_all(&record)?;
+
+        for mut writer in [files.data, files.hint] {
+            writer.flush()?;
+            writer.get_ref().0.sync()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BitcaskFiles {
+    fn write_hint(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.hint_hasher.update(buf);
+        self.hint.write_all(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vfs::MemoryVfs;
+
+    use super::*;
+
+    /// Appends a data file entry as written by bitcask.
+    fn append_entry(file: &dyn VfsFile, key: &[u8], value: &[u8], timestamp: u32) {
+        let mut body = timestamp.to_be_bytes().to_vec();
+        body.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        body.extend_from_slice(key);
+        body.extend_from_slice(value);
+
+        file.append_all(&crc32fast::hash(&body).to_be_bytes())
+            .unwrap();
+        file.append_all(&body).unwrap();
+    }
+
+    fn sorted_pairs(reader: &BitcaskReader) -> Vec<(Vec<u8>, Vec<u8>, u32)> {
+        let mut pairs: Vec<_> = reader.pairs().map(Result::unwrap).collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn bitcask_reader_should_read_data_files() {
+        let vfs = MemoryVfs::default();
+        let dir = Path::new("/bitcask");
+        vfs.create_dir_all(dir).unwrap();
+
+        let first = vfs
+            .open(&dir.join("1.bitcask.data"), OpenMode::CreateNew)
+            .unwrap();
+        append_entry(&*first, b"a", b"1", 10);
+        append_entry(&*first, b"b", b"2", 10);
+        append_entry(&*first, b"c", b"3", 10);
+
+        let second = vfs
+            .open(&dir.join("2.bitcask.data"), OpenMode::CreateNew)
+            .unwrap();
+        append_entry(&*second, b"a", b"11", 20);
+        append_entry(&*second, b"b", b"bitcask_tombstone", 20);
+        append_entry(&*second, b"c", b"bitcask_tombstone2\0\0\0\x01", 20);
+        // Older than the entry of the first data file, as left by a merge.
+        append_entry(&*second, b"d", b"4", 5);
+        // Torn by a crash.
+        second.append_all(&[0; 10]).unwrap();
+
+        let reader = BitcaskReader::open(&vfs, dir).unwrap();
+        assert_eq!(
+            sorted_pairs(&reader),
+            vec![
+                (b"a".to_vec(), b"11".to_vec(), 20),
+                (b"d".to_vec(), b"4".to_vec(), 5)
+            ]
+        );
+
+        second.write_all_at(b"X", 20).unwrap();
+        assert!(matches!(
+            BitcaskReader::open(&vfs, dir),
+            Err(StorageError::FormatError(FormatError::ChecksumMismatch))
+        ));
+    }
+
+    #[test]
+    fn bitcask_writer_should_write_files_read_through_hints() {
+        let vfs = MemoryVfs::default();
+        let dir = Path::new("/bitcask");
+        vfs.create_dir_all(dir).unwrap();
+
+        let mut writer = BitcaskWriter::new(&vfs, dir);
+        writer.pair(b"a", b"1", 10).unwrap();
+        writer.pair(b"b", b"bitcask_tombstone", 20).unwrap();
+        writer.pair(b"c", b"3", 30).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            vfs.list(dir).unwrap(),
+            vec!["1.bitcask.data", "1.bitcask.hint"]
+        );
+
+        let expected = vec![
+            (b"a".to_vec(), b"1".to_vec(), 10),
+            (b"c".to_vec(), b"3".to_vec(), 30),
+        ];
+        assert_eq!(
+            sorted_pairs(&BitcaskReader::open(&vfs, dir).unwrap()),
+            expected
+        );
+
+        // Keys are read from the data file if the hint file is corrupted.
+        let hint = vfs
+            .open(&dir.join("1.bitcask.hint"), OpenMode::Existing)
+            .unwrap();
+        hint.write_all_at(b"X", HINT_HEADER_SIZE as u64).unwrap();
+
+        let reader = BitcaskReader::open(&vfs, dir).unwrap();
+        assert_eq!(sorted_pairs(&reader), expected);
+
+        // Hint files mark tombstones.
+        hint.set_len(0).unwrap();
+        let mut buf = Vec::new();
+        for (key, offset) in [(b"a", 0), (b"c", 16 | HINT_TOMBSTONE)] {
+            buf.extend_from_slice(&40u32.to_be_bytes());
+            buf.extend_from_slice(&1u16.to_be_bytes());
+            buf.extend_from_slice(&16u32.to_be_bytes());
+            buf.extend_from_slice(&offset.to_be_bytes());
+            buf.extend_from_slice(key);
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&[0; 6]);
+        buf.extend_from_slice(&crc.to_be_bytes());
+        buf.extend_from_slice(&(HINT_CRC_OFFSET | HINT_TOMBSTONE).to_be_bytes());
+        hint.append_all(&buf).unwrap();
+
+        let reader = BitcaskReader::open(&vfs, dir).unwrap();
+        assert_eq!(
+            sorted_pairs(&reader),
+            vec![(b"a".to_vec(), b"1".to_vec(), 40)]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Change data capture.
+//!
+//! Subscribers receive a `ChangeEvent` after every put, removal or merge of a key they
+//! are interested in. Previous values are only read while someone is subscribed to the key.
+//! A `Watch` waits for changes of a single key.
+
+use std::{
+    sync::mpsc::{self, Receiver, RecvError, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+/// Change of the value of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: Vec<u8>,
+    /// Value before the change, `None` if the key didn't exist.
+    pub old: Option<Vec<u8>>,
+    /// Value after the change, `None` if the key has been removed.
+    pub new: Option<Vec<u8>>,
+    /// Timestamp of the entry written by the change.
+    pub timestamp: u64,
+}
+
+/// Watch over changes of a single key.
+#[derive(Debug)]
+pub struct Watch {
+    key: Vec<u8>,
+    receiver: Receiver<ChangeEvent>,
+}
+
+impl Watch {
+    pub(crate) fn new(key: &[u8], subscribers: &mut Subscribers) -> Self {
+        Self {
+            key: key.to_vec(),
+            receiver: subscribers.subscribe(key),
+        }
+    }
+
+    /// Watched key.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Blocks until the key changes or is removed. Changes made since the previous call
+    /// are returned first, oldest first. Fails once the storage has been closed.
+    pub fn wait(&self) -> Result<ChangeEvent, RecvError> {
+        loop {
+            let event = self.receiver.recv()?;
+
+            if event.key == self.key {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Like `wait`, but gives up after the `timeout`.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<ChangeEvent, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let event = self
+                .receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+
+            if event.key == self.key {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+/// Change subscribers of a storage.
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    subscribers: Vec<Subscriber>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    prefix: Vec<u8>,
+    sender: Sender<ChangeEvent>,
+}
+
+impl Subscribers {
+    /// Subscribes to changes of keys starting with the `prefix`.
+    pub fn subscribe(&mut self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+
+        self.subscribers.push(Subscriber {
+            prefix: prefix.to_vec(),
+            sender,
+        });
+
+        receiver
+    }
+
+    /// Whether any subscriber is interested in changes of the key.
+    pub fn is_interested(&self, k: &[u8]) -> bool {
+        self.subscribers
+            .iter()
+            .any(|subscriber| k.starts_with(&subscriber.prefix))
+    }
+
+    /// Sends the event to interested subscribers, dropping those that have hung up.
+    pub fn publish(&mut self, event: ChangeEvent) {
+        self.subscribers.retain(|subscriber| {
+            !event.key.starts_with(&subscriber.prefix)
+                || subscriber.sender.send(event.clone()).is_ok()
+        });
+    }
+}
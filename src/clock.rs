@@ -0,0 +1,119 @@
+//! Timestamp sources.
+//!
+//! Entries are stamped, and their expiration checked, with timestamps of the `Clock` set in
+//! `DbOptions`. Timestamps are `u64`, seconds since the Unix epoch for the wall clocks. Log
+//! files of format versions before 6 store them as `u32`. Log file creation times and history
+//! retention always use the system clock.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use chrono::Utc;
+
+/// Source of entry timestamps.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Current timestamp.
+    fn now(&self) -> u64;
+}
+
+/// Wall clock, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        Utc::now().timestamp().max(0) as u64
+    }
+}
+
+/// Wall clock which never goes backwards, e.g. when the system time is adjusted.
+#[derive(Debug, Default)]
+pub struct MonotonicClock {
+    last: AtomicU64,
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> u64 {
+        let now = SystemClock.now();
+
+        self.last.fetch_max(now, Ordering::Relaxed).max(now)
+    }
+}
+
+/// Logical clock incrementing on every call, so every timestamp is unique.
+#[derive(Debug, Default)]
+pub struct LogicalClock {
+    next: AtomicU64,
+}
+
+impl LogicalClock {
+    /// Creates a clock whose first timestamp is `start`.
+    pub fn starting_at(start: u64) -> Self {
+        Self {
+            next: AtomicU64::new(start),
+        }
+    }
+}
+
+impl Clock for LogicalClock {
+    fn now(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Clock returning a set timestamp, for tests.
+#[derive(Debug, Default)]
+pub struct FixedClock {
+    now: AtomicU64,
+}
+
+impl FixedClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    /// Sets the timestamp returned from now on.
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::Relaxed);
+    }
+
+    /// Moves the timestamp `secs` forward.
+    pub fn advance(&self, secs: u64) {
+        self.now.fetch_add(secs, Ordering::Relaxed);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clocks_should_tick() {
+        assert!(SystemClock.now() > 1_600_000_000);
+
+        let clock = MonotonicClock::default();
+        assert!(clock.now() > 1_600_000_000);
+        clock.last.store(u64::MAX, Ordering::Relaxed);
+        assert_eq!(clock.now(), u64::MAX);
+
+        let clock = LogicalClock::starting_at(5);
+        assert_eq!((clock.now(), clock.now(), clock.now()), (5, 6, 7));
+
+        let clock = FixedClock::new(42);
+        assert_eq!(clock.now(), 42);
+        clock.advance(8);
+        assert_eq!(clock.now(), 50);
+        clock.set(1);
+        assert_eq!(clock.now(), 1);
+    }
+}
@@ -0,0 +1,1328 @@
+//! High-level database facade.
+//!
+//! `Database` bundles `RumDb` with sensible defaults behind a cheaply cloneable,
+//! thread-safe handle. Use `DiskStorage` directly to pick a keydir and options explicitly.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Write},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    changes::{ChangeEvent, Watch},
+    errors::{CompareAndSwapError, StorageError, TxnConflict},
+    group_commit::GroupCommit,
+    storage::{
+        Compaction, CompactionRecord, DiskStorageStats, KeyValue, PrefixSnapshot, PutOptions,
+        ReadOptions, Storage, TxnRead, ValueEntry, VerifyReport, Version, WriteBatch,
+    },
+    DbOptions, RumDb,
+};
+
+/// How often the background maintenance runs.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Thread-safe database handle.
+///
+/// Handles are cheap to clone and share the same underlying storage. The storage is closed
+/// when the last handle is dropped.
+#[derive(Debug, Clone)]
+pub struct Database {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    db: RwLock<RumDb>,
+
+    /// Stops the maintenance thread once dropped.
+    _maintenance: Sender<()>,
+
+    /// Syncs writes with `DbOptions::sync_writes`.
+    group_commit: Option<Arc<GroupCommit>>,
+
+    /// Whether to repair missing log files, see `DbOptions::repair_missing_segments`.
+    repair_missing_segments: bool,
+
+    /// Expired keys removed per maintenance run, see `DbOptions::ttl_sweep_limit`.
+    ttl_sweep_limit: usize,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(group_commit) = &self.group_commit {
+            group_commit.stop();
+        }
+    }
+}
+
+impl Database {
+    /// Opens or creates a new database at the `path` directory with default options.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::open_with(path, DbOptions::default())
+    }
+
+    /// Opens or creates a new database at the `path` directory.
+    pub fn open_with(path: impl AsRef<Path>, opts: DbOptions) -> Result<Self, StorageError> {
+        // Writes are synced by the commit thread rather than one by one by the storage.
+        let group_commit = opts.sync_writes.then(Arc::<GroupCommit>::default);
+        let repair_missing_segments = opts.repair_missing_segments;
+        let ttl_sweep_limit = opts.ttl_sweep_limit;
+        let db = RumDb::open(path, opts.sync_writes(false))?;
+        let (maintenance, stop) = mpsc::channel();
+
+        let shared = Arc::new(Shared {
+            db: RwLock::new(db),
+            _maintenance: maintenance,
+            group_commit: group_commit.clone(),
+            repair_missing_segments,
+            ttl_sweep_limit,
+        });
+
+        if let Some(group_commit) = group_commit {
+            let weak = Arc::downgrade(&shared);
+
+            thread::Builder::new()
+                .name("rumdb-commit".to_string())
+                .spawn(move || {
+                    group_commit.run(|| match weak.upgrade() {
+                        Some(shared) => Self { shared }.sync_unlocked(),
+                        None => Ok(()),
+                    })
+                })?;
+        }
+
+        let weak = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name("rumdb-maintenance".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(MAINTENANCE_INTERVAL) {
+                    Self::maintain(&weak);
+                }
+            })?;
+
+        Ok(Self { shared })
+    }
+
+    /// Get a value from the database.
+    pub fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.read()?.get(k)
+    }
+
+    /// Get a value from the database along with its timestamp and location.
+    pub fn get_entry(&self, k: &[u8]) -> Result<Option<ValueEntry>, StorageError> {
+        self.read()?.get_entry(k)
+    }
+
+    /// Get a value from the database with per-call options.
+    pub fn get_opt(&self, k: &[u8], opts: ReadOptions) -> Result<Option<Vec<u8>>, StorageError> {
+        self.read()?.get_opt(k, opts)
+    }
+
+    /// Get a value as `Bytes`, without copying it where possible.
+    /// See `DiskStorage::get_bytes`.
+    #[cfg(feature = "bytes")]
+    pub fn get_bytes(&self, k: &[u8]) -> Result<Option<bytes::Bytes>, StorageError> {
+        self.read()?.get_bytes(k)
+    }
+
+    /// Get values of all the `keys`, returned in the same order.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        self.read()?.get_many(keys)
+    }
+
+    /// Returns the current and retained previous versions of the key, newest first.
+    pub fn get_versions(&self, k: &[u8]) -> Result<Vec<Version>, StorageError> {
+        self.read()?.get_versions(k)
+    }
+
+    /// Returns the value the key had at the `timestamp`, as far as versions are retained.
+    pub fn get_at(&self, k: &[u8], timestamp: u64) -> Result<Option<Vec<u8>>, StorageError> {
+        self.read()?.get_at(k, timestamp)
+    }
+
+    /// Put a value into the database.
+    pub fn put(&self, k: Vec<u8>, v: Vec<u8>) -> Result<(), StorageError> {
+        self.write_synced(|db| db.put(k, v))
+    }
+
+    /// Put a value into the database with per-call options. An explicit `sync` bypasses
+    /// the group commit of `DbOptions::sync_writes`.
+    pub fn put_opt(&self, k: Vec<u8>, v: Vec<u8>, opts: PutOptions) -> Result<(), StorageError> {
+        match opts.sync {
+            Some(_) => self.write()?.put_opt(k, v, opts),
+            None => self.write_synced(|db| db.put_opt(k, v, opts)),
+        }
+    }
+
+    /// Put a value into the database, returning the previous one.
+    pub fn insert(&self, k: Vec<u8>, v: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError> {
+        self.write_synced(|db| db.insert(k, v))
+    }
+
+    /// Put a value into the database only if the key doesn't exist, atomically. Returns
+    /// whether the value was put.
+    pub fn put_if_absent(&self, k: Vec<u8>, v: Vec<u8>) -> Result<bool, StorageError> {
+        self.write_synced(|db| db.put_if_absent(k, v))
+    }
+
+    /// Put a value of `len` bytes read from the `reader` into the database.
+    pub fn put_from_reader(
+        &self,
+        k: Vec<u8>,
+        reader: impl Read,
+        len: u64,
+    ) -> Result<(), StorageError> {
+        self.write_synced(|db| db.put_from_reader(k, reader, len))
+    }
+
+    /// Append a merge operand for the key.
+    pub fn merge(&self, k: Vec<u8>, operand: Vec<u8>) -> Result<(), StorageError> {
+        self.write_synced(|db| db.merge(k, operand))
+    }
+
+    /// Remove a value from the database.
+    pub fn remove(&self, k: &[u8]) -> Result<(), StorageError> {
+        self.write_synced(|db| db.remove(k))
+    }
+
+    /// Add `delta` to the counter stored under the key, atomically. Returns the new value.
+    pub fn increment(&self, k: &[u8], delta: i64) -> Result<u64, StorageError> {
+        self.write_synced(|db| db.increment(k, delta))
+    }
+
+    /// Remove a value from the database, returning it.
+    pub fn take(&self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.write_synced(|db| db.take(k))
+    }
+
+    /// Write the puts and removals of the batch atomically.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        self.write_synced(|db| db.write_batch(batch))
+    }
+
+    /// Write the puts and removals queued in the returned pipeline with a single write on
+    /// flush, without the atomicity of `write_batch`.
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline {
+            db: self.clone(),
+            batch: WriteBatch::default(),
+        }
+    }
+
+    /// Begin an optimistic transaction. Its writes are committed atomically, unless a key it
+    /// read changed in the meantime.
+    pub fn begin(&self) -> Txn {
+        Txn {
+            db: self.clone(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Atomically replace the value of the key with `new` if the current value is `expected`.
+    /// On mismatch the actual value is returned.
+    pub fn compare_and_swap(
+        &self,
+        k: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Result<(), CompareAndSwapError>, StorageError> {
+        self.write_synced(|db| db.compare_and_swap(k, expected, new))
+    }
+
+    /// Replace the value of the key with the result of `f` applied to the current value,
+    /// as a single locked operation. Returning `None` from `f` removes the key.
+    pub fn update<F>(&self, k: &[u8], f: F) -> Result<Option<Vec<u8>>, StorageError>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        self.write_synced(|db| db.update(k, f))
+    }
+
+    /// Returns all key-value pairs whose key starts with `prefix`.
+    pub fn scan(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, StorageError> {
+        self.read()?.scan_prefix(prefix).collect()
+    }
+
+    /// Returns an iterator over the key-value pairs whose key starts with `prefix` at the
+    /// time of the call. Values are read as the iterator advances, without holding the lock
+    /// in between, through the entries the keys pointed to at the time. Writes and
+    /// compactions meanwhile are not seen and never make the iterator skip or repeat keys.
+    pub fn scan_iter(&self, prefix: &[u8]) -> Result<ScanIter, StorageError> {
+        Ok(ScanIter {
+            db: self.clone(),
+            snapshot: self.read()?.snapshot_prefix(prefix)?,
+        })
+    }
+
+    /// Returns the key-value pairs whose key starts with `prefix` which the `filter`
+    /// accepts. See `DiskStorage::scan_filtered`.
+    pub fn scan_filtered(
+        &self,
+        prefix: &[u8],
+        filter: impl FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<Vec<KeyValue>, StorageError> {
+        self.read()?.scan_filtered(prefix, filter).collect()
+    }
+
+    /// Like `scan_filtered`, but values of keys the `key_filter` rejects aren't read.
+    pub fn scan_filtered_by_key(
+        &self,
+        prefix: &[u8],
+        key_filter: impl FnMut(&[u8]) -> bool,
+        filter: impl FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<Vec<KeyValue>, StorageError> {
+        self.read()?
+            .scan_filtered_by_key(prefix, key_filter, filter)
+            .collect()
+    }
+
+    /// Calls `f` with every key-value pair, reading log files sequentially, while holding
+    /// the read lock. See `DiskStorage::for_each_in_disk_order`.
+    pub fn for_each_in_disk_order(&self, f: impl FnMut(&[u8], &[u8])) -> Result<(), StorageError> {
+        self.read()?.for_each_in_disk_order(f)
+    }
+
+    /// Returns up to `limit` keys starting with `prefix`, without reading their values.
+    pub fn scan_keys(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>, StorageError> {
+        Ok(self.read()?.scan_prefix_keys(prefix).take(limit).collect())
+    }
+
+    /// Returns all keys written at or after the `timestamp`, in seconds since the epoch.
+    pub fn modified_since(&self, timestamp: u64) -> Result<Vec<Vec<u8>>, StorageError> {
+        Ok(self.read()?.modified_since(timestamp).collect())
+    }
+
+    /// Remove all keys starting with `prefix`, returning the number of removed keys.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> Result<usize, StorageError> {
+        self.write_synced(|db| db.delete_prefix(prefix))
+    }
+
+    /// Remove all keys, keyspaces included.
+    pub fn clear(&self) -> Result<(), StorageError> {
+        self.write()?.clear()
+    }
+
+    /// Writes buffered entries to the log files. The background maintenance flushes and
+    /// syncs them every second.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.write()?.flush()
+    }
+
+    /// Syncs the database and snapshots its keydir, then drops this handle. The next open
+    /// loads the snapshot instead of scanning log files, unless other handles write to the
+    /// database meanwhile. See `DiskStorage::snapshot_keydir`.
+    pub fn close(self) -> Result<(), StorageError> {
+        self.write()?.snapshot_keydir()
+    }
+
+    /// Rewrites live entries of sealed log files and removes the dead log files.
+    ///
+    /// The database stays available while the compaction is throttled, see
+    /// `DbOptions::compaction_rate_limit`.
+    pub fn compact(&self) -> Result<(), StorageError> {
+        self.run_compactions(|db| db.start_compaction().map(Some))
+    }
+
+    /// Rewrites live entries of the log files and removes them. Keyspaces are not included.
+    /// See `DiskStorage::compact_segments`.
+    pub fn compact_segments(&self, file_ids: &[u32]) -> Result<(), StorageError> {
+        self.run_compaction(None, |db| db.start_segments_compaction(file_ids).map(Some))
+    }
+
+    /// Compacts all log files, the active one included. See `DiskStorage::compact_all`.
+    pub fn compact_all(&self) -> Result<(), StorageError> {
+        self.run_compactions(|db| db.start_full_compaction().map(Some))
+    }
+
+    /// Returns the storage statistics.
+    pub fn storage_stats(&self) -> Result<DiskStorageStats, StorageError> {
+        Ok(self.read()?.storage_stats())
+    }
+
+    /// Returns the latest compactions and merges, oldest first. See
+    /// `DiskStorage::compaction_history`.
+    pub fn compaction_history(&self) -> Result<Vec<CompactionRecord>, StorageError> {
+        Ok(self.read()?.compaction_history().cloned().collect())
+    }
+
+    /// Returns a snapshot of the operation metrics. See `DiskStorage::metrics`.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Result<crate::metrics::MetricsSnapshot, StorageError> {
+        Ok(self.read()?.metrics())
+    }
+
+    /// Verifies log file checksums and keys. See `DiskStorage::verify`.
+    pub fn verify(&self) -> Result<VerifyReport, StorageError> {
+        self.write()?.verify()
+    }
+
+    /// Creates a copy of the database at the `path` directory which can be opened on its
+    /// own. See `DiskStorage::checkpoint`.
+    pub fn checkpoint(&self, path: impl AsRef<Path>) -> Result<(), StorageError> {
+        self.write()?.checkpoint(path)
+    }
+
+    /// Drops log files which have disappeared, removing the keys in them. See
+    /// `DiskStorage::repair_missing_segments`.
+    pub fn repair_missing_segments(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        self.write()?.repair_missing_segments()
+    }
+
+    /// Removes up to `limit` expired keys. Returns the number of removed keys.
+    /// See `DiskStorage::sweep_expired`.
+    pub fn sweep_expired(&self, limit: usize) -> Result<usize, StorageError> {
+        self.write_synced(|db| db.sweep_expired(limit))
+    }
+
+    /// Rebuilds the keydir from log files, rewriting their footers. See
+    /// `DiskStorage::rebuild_index`.
+    pub fn rebuild_index(&self) -> Result<VerifyReport, StorageError> {
+        self.write()?.rebuild_index()
+    }
+
+    /// Writes all live key-value pairs to the `writer` in the portable dump format.
+    /// See `DiskStorage::export`.
+    pub fn export(&self, writer: impl Write) -> Result<u64, StorageError> {
+        self.read()?.export(writer)
+    }
+
+    /// Subscribes to changes of all keys. See `DiskStorage::subscribe_prefix`.
+    pub fn subscribe(&self) -> Result<Receiver<ChangeEvent>, StorageError> {
+        Ok(self.write()?.subscribe())
+    }
+
+    /// Subscribes to changes of keys starting with the `prefix`.
+    /// See `DiskStorage::subscribe_prefix`.
+    pub fn subscribe_prefix(&self, prefix: &[u8]) -> Result<Receiver<ChangeEvent>, StorageError> {
+        Ok(self.write()?.subscribe_prefix(prefix))
+    }
+
+    /// Watches the key for changes. See `Watch`.
+    pub fn watch(&self, k: &[u8]) -> Result<Watch, StorageError> {
+        Ok(self.write()?.watch(k))
+    }
+
+    /// Returns a handle to the keyspace with the `name`, creating it if it doesn't exist.
+    pub fn keyspace(&self, name: &str) -> Result<Keyspace, StorageError> {
+        self.write()?.keyspace(name)?;
+
+        Ok(Keyspace {
+            db: self.clone(),
+            name: name.to_string(),
+        })
+    }
+
+    /// Runs the compactions `start` starts on the storage and on every keyspace step by
+    /// step, releasing the lock in between steps.
+    fn run_compactions(
+        &self,
+        start: impl Fn(&mut RumDb) -> Result<Option<Compaction>, StorageError>,
+    ) -> Result<(), StorageError> {
+        let names: Vec<_> = self.read()?.keyspace_names().map(String::from).collect();
+        self.run_compaction(None, &start)?;
+
+        for name in names {
+            self.run_compaction(Some(&name), &start)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the compaction `start` starts on the storage or the `keyspace`, sleeping without
+    /// the lock whenever it is throttled.
+    fn run_compaction(
+        &self,
+        keyspace: Option<&str>,
+        start: impl Fn(&mut RumDb) -> Result<Option<Compaction>, StorageError>,
+    ) -> Result<(), StorageError> {
+        let Some(mut compaction) = start(storage_of(&mut *self.write()?, keyspace)?)? else {
+            return Ok(());
+        };
+
+        loop {
+            let step = storage_of(&mut *self.write()?, keyspace)?.compaction_step(&mut compaction);
+
+            match step? {
+                Some(delay) => thread::sleep(delay),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    pub(crate) fn read(&self) -> Result<RwLockReadGuard<'_, RumDb>, StorageError> {
+        self.shared.db.read().or(Err(StorageError::LockPoisoned))
+    }
+
+    pub(crate) fn write(&self) -> Result<RwLockWriteGuard<'_, RumDb>, StorageError> {
+        self.shared.db.write().or(Err(StorageError::LockPoisoned))
+    }
+
+    /// Syncs the log files. Buffered entries are flushed under the write lock, but the log
+    /// files are synced once it's released, so reads and writes don't wait for the disk.
+    fn sync_unlocked(&self) -> Result<(), StorageError> {
+        let files = self.write()?.flush_for_sync()?;
+
+        for file in files {
+            file.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the write operation `f` and, with `DbOptions::sync_writes`, waits for the commit
+    /// thread to sync its entries.
+    fn write_synced<T>(
+        &self,
+        f: impl FnOnce(&mut RumDb) -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        let Some(group_commit) = &self.shared.group_commit else {
+            return f(&mut *self.write()?);
+        };
+
+        let (res, ticket) = {
+            let mut db = self.write()?;
+            let res = f(&mut db)?;
+
+            (res, group_commit.enqueue())
+        };
+
+        group_commit.wait(ticket)?;
+
+        Ok(res)
+    }
+
+    fn maintain(shared: &Weak<Shared>) {
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+
+        let repair_missing_segments = shared.repair_missing_segments;
+        let ttl_sweep_limit = shared.ttl_sweep_limit;
+        let db = Self { shared };
+
+        if let Err(e) = db.sync_unlocked() {
+            log::warn!("⚠️  Background sync failed: {e}");
+        }
+
+        // The write lock is only taken for mutations, and released in between them.
+        match db.write() {
+            Ok(mut db) => {
+                if let Err(e) = db.gc_rotated_logs() {
+                    log::warn!("⚠️  Failed to remove dead log files: {e}");
+                }
+
+                if let Err(e) = db.reclaim_logs() {
+                    log::warn!("⚠️  Failed to remove retired log files: {e}");
+                }
+            }
+            Err(_) => {
+                log::warn!("⚠️  Background maintenance skipped: database lock poisoned");
+                return;
+            }
+        }
+
+        if repair_missing_segments && db.read().is_ok_and(|db| !db.missing_log_files().is_empty()) {
+            if let Err(e) = db.write().and_then(|mut db| db.repair_missing_segments()) {
+                log::warn!("⚠️  Failed to repair missing log files: {e}");
+            }
+        }
+
+        if ttl_sweep_limit > 0 {
+            // Expired keys are looked up under the read lock, which doesn't block reads.
+            let res = db
+                .read()
+                .map(|db| db.expired_keys(ttl_sweep_limit))
+                .and_then(|expired| {
+                    if expired.is_empty() {
+                        return Ok(0);
+                    }
+
+                    db.write()?.remove_expired(expired)
+                });
+
+            match res {
+                Ok(_) | Err(StorageError::ReadOnly) => (),
+                Err(e) => log::warn!("⚠️  Failed to sweep expired keys: {e}"),
+            }
+        }
+
+        // Merges are throttled like compactions, so the lock is released in between steps.
+        if let Err(e) = db.run_compactions(RumDb::start_merge) {
+            log::warn!("⚠️  Background merge failed: {e}");
+        }
+    }
+}
+
+/// The storage or its `keyspace`.
+fn storage_of<'a>(
+    db: &'a mut RumDb,
+    keyspace: Option<&str>,
+) -> Result<&'a mut RumDb, StorageError> {
+    match keyspace {
+        Some(name) => db.keyspace(name),
+        None => Ok(db),
+    }
+}
+
+/// Optimistic transaction of a `Database`, created by `Database::begin`.
+///
+/// Reads go to the database, writes are buffered until `Txn::commit`. Dropping the
+/// transaction discards its writes.
+#[derive(Debug)]
+pub struct Txn {
+    db: Database,
+
+    /// Read keys as they were read, to detect changes on commit.
+    reads: HashMap<Vec<u8>, TxnRead>,
+
+    /// Buffered values, `None` for removals.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Txn {
+    /// Get a value, seeing the writes of the transaction.
+    pub fn get(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        if let Some(v) = self.writes.get(k) {
+            return Ok(v.clone());
+        }
+
+        let (read, v) = self.db.read()?.get_for_txn(k)?;
+        self.reads.entry(k.to_vec()).or_insert(read);
+
+        Ok(v)
+    }
+
+    /// Put a value on commit.
+    pub fn put(&mut self, k: Vec<u8>, v: Vec<u8>) {
+        self.writes.insert(k, Some(v));
+    }
+
+    /// Remove a value on commit.
+    pub fn remove(&mut self, k: &[u8]) {
+        self.writes.insert(k.to_vec(), None);
+    }
+
+    /// Write the buffered writes as an atomic batch if none of the read keys changed since
+    /// they were read. On conflict nothing is written and the changed key is returned.
+    pub fn commit(self) -> Result<Result<(), TxnConflict>, StorageError> {
+        let mut batch = WriteBatch::default();
+
+        for (k, v) in self.writes {
+            match v {
+                Some(v) => batch.put(k, v),
+                None => batch.remove(&k),
+            }
+        }
+
+        let reads = self.reads;
+        self.db.write_synced(|db| db.commit_txn(&reads, batch))
+    }
+}
+
+/// Iterator over the key-value pairs of a `Database` as of its creation, see
+/// `Database::scan_iter`. Pairs are yielded in the keydir iteration order.
+#[derive(Debug)]
+pub struct ScanIter {
+    db: Database,
+    snapshot: PrefixSnapshot,
+}
+
+impl Iterator for ScanIter {
+    type Item = Result<KeyValue, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.db.read() {
+            Ok(db) => self.snapshot.next_pair(&db),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.snapshot.len(), Some(self.snapshot.len()))
+    }
+}
+
+impl ScanIter {
+    /// Yields the pairs left in ascending key order instead. Keys are sorted in memory,
+    /// values are still read as the iterator advances.
+    pub fn sorted(mut self) -> Self {
+        self.snapshot.sort();
+        self
+    }
+}
+
+/// Puts and removals queued to be written at once, see `Database::pipeline`.
+///
+/// Entries are written as independent entries, so after a crash any of them may be lost,
+/// as with separate writes. Queued operations are discarded when the pipeline is dropped.
+#[derive(Debug)]
+pub struct Pipeline {
+    db: Database,
+    batch: WriteBatch,
+}
+
+impl Pipeline {
+    /// Queue a put of the value.
+    pub fn put(&mut self, k: Vec<u8>, v: Vec<u8>) {
+        self.batch.put(k, v);
+    }
+
+    /// Queue a removal of the key.
+    pub fn remove(&mut self, k: &[u8]) {
+        self.batch.remove(k);
+    }
+
+    /// Number of queued operations.
+    pub fn len(&self) -> usize {
+        self.batch.len()
+    }
+
+    /// Whether no operations are queued.
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    /// Write the queued operations with a single write, emptying the pipeline.
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        let batch = std::mem::take(&mut self.batch);
+        self.db.write_synced(|db| db.write_pipelined(batch))
+    }
+}
+
+/// Handle to a named keyspace of a `Database`.
+///
+/// Keys of a keyspace are isolated from the other keyspaces and the database itself.
+#[derive(Debug, Clone)]
+pub struct Keyspace {
+    db: Database,
+    name: String,
+}
+
+impl Keyspace {
+    /// Keyspace name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get a value from the keyspace.
+    pub fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.db
+            .read()?
+            .get_keyspace(&self.name)
+            .ok_or_else(|| StorageError::UnknownKeyspace(self.name.clone()))?
+            .get(k)
+    }
+
+    /// Put a value into the keyspace.
+    pub fn put(&self, k: Vec<u8>, v: Vec<u8>) -> Result<(), StorageError> {
+        self.db
+            .write_synced(|db| db.keyspace(&self.name)?.put(k, v))
+    }
+
+    /// Put a value into the keyspace, returning the previous one.
+    pub fn insert(&self, k: Vec<u8>, v: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError> {
+        self.db
+            .write_synced(|db| db.keyspace(&self.name)?.insert(k, v))
+    }
+
+    /// Put a value into the keyspace only if the key doesn't exist, atomically. Returns
+    /// whether the value was put.
+    pub fn put_if_absent(&self, k: Vec<u8>, v: Vec<u8>) -> Result<bool, StorageError> {
+        self.db
+            .write_synced(|db| db.keyspace(&self.name)?.put_if_absent(k, v))
+    }
+
+    /// Append a merge operand for the key of the keyspace.
+    pub fn merge(&self, k: Vec<u8>, operand: Vec<u8>) -> Result<(), StorageError> {
+        self.db
+            .write_synced(|db| db.keyspace(&self.name)?.merge(k, operand))
+    }
+
+    /// Remove a value from the keyspace.
+    pub fn remove(&self, k: &[u8]) -> Result<(), StorageError> {
+        self.db
+            .write_synced(|db| db.keyspace(&self.name)?.remove(k))
+    }
+
+    /// Add `delta` to the counter stored under the key of the keyspace, atomically. Returns
+    /// the new value.
+    pub fn increment(&self, k: &[u8], delta: i64) -> Result<u64, StorageError> {
+        self.db
+            .write_synced(|db| db.keyspace(&self.name)?.increment(k, delta))
+    }
+
+    /// Remove a value from the keyspace, returning it.
+    pub fn take(&self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.db.write_synced(|db| db.keyspace(&self.name)?.take(k))
+    }
+
+    /// Remove all keys of the keyspace starting with `prefix`, returning the number of
+    /// removed keys.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> Result<usize, StorageError> {
+        self.db
+            .write_synced(|db| db.keyspace(&self.name)?.delete_prefix(prefix))
+    }
+
+    /// Returns all key-value pairs of the keyspace whose key starts with `prefix`.
+    pub fn scan(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, StorageError> {
+        self.db
+            .read()?
+            .get_keyspace(&self.name)
+            .ok_or_else(|| StorageError::UnknownKeyspace(self.name.clone()))?
+            .scan_prefix(prefix)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_should_get_put_remove_scan() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        db.put(b"user:1".to_vec(), b"alice".to_vec()).unwrap();
+        db.put(b"user:2".to_vec(), b"bob".to_vec()).unwrap();
+        db.put(b"group:1".to_vec(), b"admins".to_vec()).unwrap();
+
+        assert_eq!(db.get(b"user:1").unwrap(), Some(b"alice".to_vec()));
+
+        db.remove(b"user:1").unwrap();
+        assert_eq!(db.get(b"user:1").unwrap(), None);
+
+        assert_eq!(
+            db.scan(b"user:").unwrap(),
+            vec![(b"user:2".to_vec(), b"bob".to_vec())]
+        );
+
+        db.close().unwrap();
+        assert!(dir.path().join(crate::snapshot::SNAPSHOT_FILE).exists());
+
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get(b"user:2").unwrap(), Some(b"bob".to_vec()));
+    }
+
+    #[test]
+    fn database_should_be_shared_between_threads() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        let handles: Vec<_> = (0..4u8)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || db.put(vec![i], vec![i]).unwrap())
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for i in 0..4u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[test]
+    fn database_should_group_commit_synced_writes() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open_with(dir.path(), DbOptions::default().sync_writes(true)).unwrap();
+
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for j in 0..25u8 {
+                        db.put(vec![i, j], vec![j]).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Writers waiting for a sync are committed together by the next one.
+        let group_commit = db.shared.group_commit.clone().unwrap();
+        assert!(group_commit.commits() < 200);
+
+        db.keyspace("users")
+            .unwrap()
+            .put(b"1".to_vec(), b"alice".to_vec())
+            .unwrap();
+        drop(db);
+
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get(&[7, 24]).unwrap(), Some(vec![24]));
+        assert_eq!(
+            db.keyspace("users").unwrap().get(b"1").unwrap(),
+            Some(b"alice".to_vec())
+        );
+    }
+
+    #[test]
+    fn database_should_compare_and_swap_concurrently() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        db.put(b"counter".to_vec(), 0u64.to_le_bytes().to_vec())
+            .unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        let mut current = db.get(b"counter").unwrap();
+
+                        loop {
+                            let value =
+                                u64::from_le_bytes(current.as_deref().unwrap().try_into().unwrap());
+                            let new = (value + 1).to_le_bytes().to_vec();
+
+                            match db
+                                .compare_and_swap(b"counter", current.as_deref(), Some(new))
+                                .unwrap()
+                            {
+                                Ok(()) => break,
+                                Err(e) => current = e.current,
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(100u64.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn database_should_update_concurrently() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        db.update(b"counter", |old| {
+                            let value =
+                                old.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+                            Some((value + 1).to_le_bytes().to_vec())
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(100u64.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn database_should_iterate_over_snapshot_during_writes() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let opts =
+            DbOptions::default()
+                .max_log_file_size(1024)
+                .merge_operator(|_, existing, operand| {
+                    let mut value = existing.unwrap_or_default().to_vec();
+                    value.extend_from_slice(operand);
+                    Some(value)
+                });
+        let db = Database::open_with(dir.path(), opts).unwrap();
+
+        for i in 0..100u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+        }
+        db.merge(vec![100], b"a".to_vec()).unwrap();
+
+        let mut iter = db.scan_iter(&[]).unwrap();
+        let mut pairs: Vec<_> = iter.by_ref().take(10).map(Result::unwrap).collect();
+        assert_eq!(iter.size_hint(), (91, Some(91)));
+
+        // Overwrite, remove and add keys, compacting the log files the iterator reads.
+        for i in 0..100u8 {
+            db.put(vec![i], vec![0; 30]).unwrap();
+        }
+        for i in 0..50u8 {
+            db.remove(&[i]).unwrap();
+        }
+        db.merge(vec![100], b"b".to_vec()).unwrap();
+        db.put(vec![200], vec![200]).unwrap();
+        db.compact().unwrap();
+
+        pairs.extend(iter.map(Result::unwrap));
+        pairs.sort();
+
+        let mut expected: Vec<_> = (0..100u8).map(|i| (vec![i], vec![i; 20])).collect();
+        expected.push((vec![100], b"a".to_vec()));
+        assert_eq!(pairs, expected);
+
+        assert_eq!(db.scan_iter(&[]).unwrap().count(), 52);
+    }
+
+    #[test]
+    fn database_should_iterate_over_snapshot_during_clear() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(1024);
+        let db = Database::open_with(dir.path(), opts).unwrap();
+
+        for i in 0..100u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+        }
+
+        let mut iter = db.scan_iter(&[]).unwrap();
+        let mut pairs: Vec<_> = iter.by_ref().take(10).map(Result::unwrap).collect();
+
+        db.clear().unwrap();
+        db.put(vec![200], vec![200]).unwrap();
+
+        // Cleared log files stay in place while the iterator may read them.
+        assert!(dir.path().join("0.rumdb.log").exists());
+        assert!(dir.path().join("CLEARED").exists());
+
+        pairs.extend(iter.map(Result::unwrap));
+        pairs.sort();
+
+        let expected: Vec<_> = (0..100u8).map(|i| (vec![i], vec![i; 20])).collect();
+        assert_eq!(pairs, expected);
+
+        db.write().unwrap().reclaim_logs().unwrap();
+        assert!(!dir.path().join("0.rumdb.log").exists());
+        assert!(!dir.path().join("CLEARED").exists());
+        assert_eq!(db.scan(&[]).unwrap(), vec![(vec![200], vec![200])]);
+    }
+
+    #[test]
+    fn database_should_flush_pipeline() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        db.put(b"stale".to_vec(), b"value".to_vec()).unwrap();
+
+        let mut pipeline = db.pipeline();
+        for i in 0..100u8 {
+            pipeline.put(vec![i], vec![i; 10]);
+        }
+        pipeline.remove(b"stale");
+        assert_eq!(pipeline.len(), 101);
+        assert_eq!(db.get(&[0]).unwrap(), None);
+
+        pipeline.flush().unwrap();
+        assert!(pipeline.is_empty());
+        assert_eq!(db.get(&[99]).unwrap(), Some(vec![99; 10]));
+        assert_eq!(db.get(b"stale").unwrap(), None);
+
+        pipeline.put(b"dropped".to_vec(), b"value".to_vec());
+        drop(pipeline);
+        assert_eq!(db.get(b"dropped").unwrap(), None);
+    }
+
+    #[test]
+    fn database_should_commit_transactions() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        db.put(b"alice".to_vec(), 100u64.to_be_bytes().to_vec())
+            .unwrap();
+        db.put(b"bob".to_vec(), 100u64.to_be_bytes().to_vec())
+            .unwrap();
+
+        let balance = |txn: &mut Txn, k: &[u8]| {
+            u64::from_be_bytes(txn.get(k).unwrap().unwrap().try_into().unwrap())
+        };
+
+        let mut txn = db.begin();
+        txn.put(b"carol".to_vec(), b"new".to_vec());
+        assert_eq!(txn.get(b"carol").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(balance(&mut txn, b"alice"), 100);
+
+        db.put(b"alice".to_vec(), 50u64.to_be_bytes().to_vec())
+            .unwrap();
+        assert_eq!(
+            txn.commit().unwrap(),
+            Err(TxnConflict {
+                key: b"alice".to_vec()
+            })
+        );
+        assert_eq!(db.get(b"carol").unwrap(), None);
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let (from, to): (&[u8], &[u8]) = if i % 2 == 0 {
+                        (b"alice", b"bob")
+                    } else {
+                        (b"bob", b"alice")
+                    };
+
+                    for _ in 0..25 {
+                        loop {
+                            let mut txn = db.begin();
+                            let from_balance = balance(&mut txn, from);
+                            let to_balance = balance(&mut txn, to);
+                            txn.put(from.to_vec(), (from_balance - 1).to_be_bytes().to_vec());
+                            txn.put(to.to_vec(), (to_balance + 1).to_be_bytes().to_vec());
+
+                            if txn.commit().unwrap().is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut txn = db.begin();
+        assert_eq!(balance(&mut txn, b"alice") + balance(&mut txn, b"bob"), 150);
+    }
+
+    #[test]
+    fn database_should_commit_transactions_across_compaction() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+
+        // Compactions move the read keys without changing them.
+        let mut txn = db.begin();
+        assert_eq!(txn.get(b"a").unwrap(), Some(b"1".to_vec()));
+        txn.put(b"c".to_vec(), b"3".to_vec());
+        db.compact_all().unwrap();
+        assert_eq!(db.compaction_history().unwrap()[0].entries_rewritten, 2);
+        assert_eq!(txn.commit().unwrap(), Ok(()));
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        // Overwrites are still conflicts, even with values of the same size moved meanwhile.
+        let mut txn = db.begin();
+        assert_eq!(txn.get(b"b").unwrap(), Some(b"2".to_vec()));
+        txn.put(b"c".to_vec(), b"4".to_vec());
+        db.put(b"b".to_vec(), b"5".to_vec()).unwrap();
+        db.compact_all().unwrap();
+        assert_eq!(
+            txn.commit().unwrap(),
+            Err(TxnConflict { key: b"b".to_vec() })
+        );
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn database_should_put_if_absent_concurrently() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        let handles: Vec<_> = (0..4u8)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    (0..25u8)
+                        .filter(|id| db.put_if_absent(vec![*id], vec![i]).unwrap())
+                        .count()
+                })
+            })
+            .collect();
+
+        let reserved: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(reserved, 25);
+    }
+
+    #[test]
+    fn database_should_increment_concurrently() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        db.increment(b"counter", 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(db.increment(b"counter", 0).unwrap(), 100);
+    }
+
+    #[test]
+    fn database_should_isolate_keyspaces() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        let users = db.keyspace("users").unwrap();
+        assert_eq!(users.name(), "users");
+
+        users.put(b"1".to_vec(), b"alice".to_vec()).unwrap();
+        db.put(b"1".to_vec(), b"default".to_vec()).unwrap();
+
+        assert_eq!(users.get(b"1").unwrap(), Some(b"alice".to_vec()));
+        assert_eq!(db.get(b"1").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(
+            users.scan(b"").unwrap(),
+            vec![(b"1".to_vec(), b"alice".to_vec())]
+        );
+
+        users.remove(b"1").unwrap();
+        assert_eq!(users.get(b"1").unwrap(), None);
+        assert_eq!(db.get(b"1").unwrap(), Some(b"default".to_vec()));
+        // Keyspaces are compacted in turn, leaving no dead entries behind.
+        db.compact_all().unwrap();
+        assert_eq!(users.get(b"1").unwrap(), None);
+        assert_eq!(db.get(b"1").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(db.storage_stats().unwrap().log_files, 1);
+        assert_eq!(db.compaction_history().unwrap()[0].entries_rewritten, 1);
+
+        // Handles of keyspaces which no longer exist fail rather than panic.
+        let missing = Keyspace {
+            db: db.clone(),
+            name: "missing".to_string(),
+        };
+        assert!(matches!(
+            missing.get(b"1"),
+            Err(StorageError::UnknownKeyspace(name)) if name == "missing"
+        ));
+        assert!(matches!(
+            missing.scan(b""),
+            Err(StorageError::UnknownKeyspace(_))
+        ));
+    }
+
+    #[test]
+    fn database_should_watch_key() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        let watch = db.watch(b"config").unwrap();
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                db.put(b"other".to_vec(), b"value".to_vec()).unwrap();
+                db.put(b"config".to_vec(), b"v1".to_vec()).unwrap();
+                db.remove(b"config").unwrap();
+            })
+        };
+
+        let event = watch.wait().unwrap();
+        assert_eq!(event.key, b"config");
+        assert_eq!(event.new, Some(b"v1".to_vec()));
+        assert_eq!(watch.wait().unwrap().new, None);
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn database_should_release_lock_on_drop() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+
+        {
+            let db = Database::open(dir.path()).unwrap();
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+        }
+
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn database_should_sweep_expired_keys_in_background() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let opts = DbOptions::default().ttl_sweep_limit(100);
+        let db = Database::open_with(dir.path(), opts).unwrap();
+        let expiring = PutOptions {
+            ttl: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+
+        for i in 0..10u8 {
+            db.put_opt(vec![i], vec![i], expiring).unwrap();
+        }
+        db.put(b"forever".to_vec(), b"value".to_vec()).unwrap();
+
+        // Expired keys are removed without being read.
+        let start = std::time::Instant::now();
+        while db.storage_stats().unwrap().keys > 1 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(db.storage_stats().unwrap().expired_swept, 10);
+        assert_eq!(db.sweep_expired(100).unwrap(), 0);
+        assert_eq!(db.get(b"forever").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn database_should_serve_reads_while_compaction_is_throttled() {
+        let dir = tempdir::TempDir::new("database-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(64 * 1024)
+            .compaction_rate_limit(500_000);
+        let db = Database::open_with(dir.path(), opts).unwrap();
+
+        for i in 0..200u8 {
+            db.put(vec![i], vec![i; 1000]).unwrap();
+        }
+
+        let compaction = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let start = std::time::Instant::now();
+                db.compact().unwrap();
+                start.elapsed()
+            })
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        let start = std::time::Instant::now();
+        assert_eq!(db.get(&[7]).unwrap(), Some(vec![7; 1000]));
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        assert!(compaction.join().unwrap() >= Duration::from_millis(300));
+
+        let stats = db.storage_stats().unwrap();
+        assert_eq!(stats.compaction_rate_limit, Some(500_000));
+        assert!(stats.compaction_throughput > 0 && stats.compaction_throughput <= 500_000);
+        assert!(stats
+            .to_string()
+            .contains("(limit: 500000 bytes/s), last compaction: "));
+        assert_eq!(db.compaction_history().unwrap().len(), 1);
+        assert_eq!(db.get(&[199]).unwrap(), Some(vec![199; 1000]));
+    }
+}
@@ -0,0 +1,275 @@
+//! Portable dump format.
+//!
+//! A dump holds live key-value pairs independently of the log format, so it can be imported
+//! by any version of RumDB on any machine. It starts with magic bytes and a version, followed
+//! by tagged pair and keyspace records. Pairs after a keyspace record belong to that keyspace.
+//! A crc32 of everything before it closes the dump. Integers are little-endian. Pair
+//! timestamps are `u64` since version 2, `u32` before.
+
+use std::io::{self, Read, Write};
+
+use crate::errors::FormatError;
+
+const DUMP_MAGIC: &[u8; 8] = b"RUMDBDMP";
+const DUMP_VERSION: u8 = 2;
+
+const TAG_END: u8 = 0;
+const TAG_PAIR: u8 = 1;
+const TAG_KEYSPACE: u8 = 2;
+
+/// Dump record.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Record {
+    /// Key-value pair with the timestamp of its last update.
+    Pair {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        timestamp: u64,
+    },
+    /// Start of the pairs of the named keyspace.
+    Keyspace(String),
+}
+
+/// Writes dump records.
+pub(crate) struct DumpWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Writes the dump header.
+    pub fn new(inner: W) -> Result<Self, io::Error> {
+        let mut writer = Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        };
+
+        writer.write(DUMP_MAGIC)?;
+        writer.write(&[DUMP_VERSION])?;
+
+        Ok(writer)
+    }
+
+    /// Appends a key-value pair.
+    pub fn pair(&mut self, key: &[u8], value: &[u8], timestamp: u64) -> Result<(), io::Error> {
+        self.write(&[TAG_PAIR])?;
+        self.write(&(key.len() as u32).to_le_bytes())?;
+        self.write(key)?;
+        self.write(&(value.len() as u64).to_le_bytes())?;
+        self.write(value)?;
+        self.write(&timestamp.to_le_bytes())
+    }
+
+    /// Starts the pairs of the keyspace with the `name`.
+    pub fn keyspace(&mut self, name: &str) -> Result<(), io::Error> {
+        self.write(&[TAG_KEYSPACE])?;
+        self.write(&(name.len() as u32).to_le_bytes())?;
+        self.write(name.as_bytes())
+    }
+
+    /// Completes the dump and flushes the underlying writer.
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.write(&[TAG_END])?;
+
+        let crc = self.hasher.finalize();
+        self.inner.write_all(&crc.to_le_bytes())?;
+
+        self.inner.flush()
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.hasher.update(buf);
+        self.inner.write_all(buf)
+    }
+}
+
+/// Reads dump records. The checksum is verified once the last record has been read.
+pub(crate) struct DumpReader<R: Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    version: u8,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Reads the dump header.
+    pub fn new(inner: R) -> Result<Self, FormatError> {
+        let mut reader = Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            version: DUMP_VERSION,
+        };
+
+        if &reader.read_array::<8>()? != DUMP_MAGIC {
+            return Err(FormatError::DeserializeError);
+        }
+
+        reader.version = match reader.read_array::<1>()?[0] {
+            version @ 1..=DUMP_VERSION => version,
+            version => return Err(FormatError::UnsupportedVersion(version)),
+        };
+
+        Ok(reader)
+    }
+
+    /// Reads the next record. Returns `None` after the last record if the checksum matches.
+    pub fn next_record(&mut self) -> Result<Option<Record>, FormatError> {
+        match self.read_array::<1>()?[0] {
+            TAG_PAIR => {
+                let key_size = u32::from_le_bytes(self.read_array()?);
+                let key = self.read_vec(key_size.into())?;
+                let value_size = u64::from_le_bytes(self.read_array()?);
+                let value = self.read_vec(value_size)?;
+                let timestamp = match self.version {
+                    1 => u32::from_le_bytes(self.read_array()?).into(),
+                    _ => u64::from_le_bytes(self.read_array()?),
+                };
+
+                Ok(Some(Record::Pair {
+                    key,
+                    value,
+                    timestamp,
+                }))
+            }
+            TAG_KEYSPACE => {
+                let name_size = u32::from_le_bytes(self.read_array()?);
+                let name = String::from_utf8(self.read_vec(name_size.into())?)
+                    .or(Err(FormatError::DeserializeError))?;
+
+                Ok(Some(Record::Keyspace(name)))
+            }
+            TAG_END => {
+                let crc = self.hasher.clone().finalize();
+                let mut expected = [0; 4];
+                self.inner
+                    .read_exact(&mut expected)
+                    .or(Err(FormatError::DeserializeError))?;
+
+                if crc != u32::from_le_bytes(expected) {
+                    return Err(FormatError::ChecksumMismatch);
+                }
+
+                Ok(None)
+            }
+            _ => Err(FormatError::DeserializeError),
+        }
+    }
+
+    fn read_vec(&mut self, len: u64) -> Result<Vec<u8>, FormatError> {
+        // The length is untrusted until the checksum is verified, so the buffer grows
+        // with the bytes actually read instead of being allocated upfront.
+        let mut buf = Vec::new();
+        (&mut self.inner)
+            .take(len)
+            .read_to_end(&mut buf)
+            .or(Err(FormatError::DeserializeError))?;
+
+        if buf.len() as u64 != len {
+            return Err(FormatError::DeserializeError);
+        }
+
+        self.hasher.update(&buf);
+
+        Ok(buf)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], FormatError> {
+        let mut buf = [0; N];
+        self.inner
+            .read_exact(&mut buf)
+            .or(Err(FormatError::DeserializeError))?;
+        self.hasher.update(&buf);
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_should_roundtrip_records() {
+        let mut buf = Vec::new();
+
+        let mut writer = DumpWriter::new(&mut buf).unwrap();
+        writer.pair(b"hello", b"world", 42).unwrap();
+        writer.keyspace("users").unwrap();
+        writer.pair(b"alice", b"", u64::MAX).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = DumpReader::new(buf.as_slice()).unwrap();
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(Record::Pair {
+                key: b"hello".to_vec(),
+                value: b"world".to_vec(),
+                timestamp: 42,
+            })
+        );
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(Record::Keyspace("users".to_string()))
+        );
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(Record::Pair {
+                key: b"alice".to_vec(),
+                value: Vec::new(),
+                timestamp: u64::MAX,
+            })
+        );
+        assert_eq!(reader.next_record().unwrap(), None);
+
+        // Corrupted value and truncated dump.
+        let mut corrupted = buf.clone();
+        corrupted[27] ^= 0xff;
+
+        let mut reader = DumpReader::new(corrupted.as_slice()).unwrap();
+        let last = loop {
+            match reader.next_record() {
+                Ok(Some(_)) => (),
+                last => break last,
+            }
+        };
+        assert!(matches!(last, Err(FormatError::ChecksumMismatch)));
+
+        let mut reader = DumpReader::new(&buf[..buf.len() - 6]).unwrap();
+        assert!(reader.next_record().is_ok());
+        assert!(reader.next_record().is_ok());
+        assert!(reader.next_record().is_err());
+
+        assert!(DumpReader::new(&b"RUMDBKDS\x01"[..]).is_err());
+    }
+
+    #[test]
+    fn dump_should_read_version_1() {
+        let mut buf = DUMP_MAGIC.to_vec();
+        buf.push(1);
+        buf.push(TAG_PAIR);
+        buf.extend_from_slice(&5u32.to_le_bytes());
+        buf.extend_from_slice(b"hello");
+        buf.extend_from_slice(&5u64.to_le_bytes());
+        buf.extend_from_slice(b"world");
+        buf.extend_from_slice(&42u32.to_le_bytes());
+        buf.push(TAG_END);
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        let mut reader = DumpReader::new(buf.as_slice()).unwrap();
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(Record::Pair {
+                key: b"hello".to_vec(),
+                value: b"world".to_vec(),
+                timestamp: 42,
+            })
+        );
+        assert_eq!(reader.next_record().unwrap(), None);
+
+        buf[DUMP_MAGIC.len()] = DUMP_VERSION + 1;
+        assert!(matches!(
+            DumpReader::new(buf.as_slice()),
+            Err(FormatError::UnsupportedVersion(3))
+        ));
+    }
+}
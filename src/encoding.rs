@@ -0,0 +1,29 @@
+//! Integer encodings.
+//!
+//! Integers are encoded big-endian, so encoded keys sort like the integers in ordered
+//! keydirs. Counters updated by `DiskStorage::increment` use the same encoding.
+
+/// Encodes the integer as 8 bytes.
+pub fn encode_u64(n: u64) -> [u8; 8] {
+    n.to_be_bytes()
+}
+
+/// Decodes an integer encoded by `encode_u64`, `None` if `bytes` are not 8 bytes long.
+pub fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_should_preserve_order() {
+        let mut keys: Vec<_> = [300, 1, u64::MAX, 0, 256].map(encode_u64).to_vec();
+        keys.sort();
+
+        let decoded: Vec<_> = keys.iter().map(|k| decode_u64(k).unwrap()).collect();
+        assert_eq!(decoded, [0, 1, 256, 300, u64::MAX]);
+        assert_eq!(decode_u64(b"short"), None);
+    }
+}
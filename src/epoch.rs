@@ -0,0 +1,162 @@
+//! Epoch-based reclamation of log files.
+//!
+//! Readers pin the current epoch while they look up keydir entries and read through them.
+//! GC and clears retire log files instead of deleting them right away, advancing the epoch. A log file
+//! retired at an epoch is only deleted once no reader pinned at that epoch or earlier is
+//! left, as such readers may still hold keydir entries pointing into it. Retired log files
+//! stay readable until then.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::vfs::VfsFile;
+
+/// Epochs pinned by readers and log files retired by GC or clears.
+#[derive(Default)]
+pub(crate) struct Epochs {
+    state: Mutex<EpochState>,
+}
+
+#[derive(Default)]
+struct EpochState {
+    epoch: u64,
+    /// Number of readers pinned at each epoch.
+    pinned: BTreeMap<u64, usize>,
+    /// Retired log files along with the epoch they were retired at.
+    retired: BTreeMap<u32, (u64, Arc<dyn VfsFile>)>,
+}
+
+impl Epochs {
+    /// Pins the current epoch until the guard is dropped.
+    pub fn pin(self: &Arc<Self>) -> EpochGuard {
+        let mut state = self.state();
+        let epoch = state.epoch;
+        *state.pinned.entry(epoch).or_default() += 1;
+
+        EpochGuard {
+            epochs: self.clone(),
+            epoch,
+        }
+    }
+
+    /// Retires the log file, deferring its deletion until no reader may reference it.
+    pub fn retire(&self, file_id: u32, file: Arc<dyn VfsFile>) {
+        let mut state = self.state();
+        let epoch = state.epoch;
+        state.retired.insert(file_id, (epoch, file));
+        state.epoch += 1;
+    }
+
+    /// Handle of a retired log file which hasn't been reclaimed yet.
+    pub fn retired(&self, file_id: u32) -> Option<Arc<dyn VfsFile>> {
+        self.state()
+            .retired
+            .get(&file_id)
+            .map(|(_, file)| file.clone())
+    }
+
+    /// Whether a log file with an id lower than `file_id` is retired and not reclaimed yet.
+    pub fn retired_before(&self, file_id: u32) -> bool {
+        self.state().retired.range(..file_id).next().is_some()
+    }
+
+    /// Ids of retired log files no reader may reference anymore, oldest first. They are no
+    /// longer readable once returned.
+    pub fn reclaim(&self) -> Vec<u32> {
+        let mut state = self.state();
+        let oldest_pinned = state.pinned.keys().next().copied().unwrap_or(u64::MAX);
+
+        let reclaimable: Vec<_> = state
+            .retired
+            .iter()
+            .filter(|(_, (epoch, _))| *epoch < oldest_pinned)
+            .map(|(file_id, _)| *file_id)
+            .collect();
+
+        for file_id in &reclaimable {
+            state.retired.remove(file_id);
+        }
+
+        reclaimable
+    }
+
+    fn state(&self) -> MutexGuard<'_, EpochState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for Epochs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state();
+
+        f.debug_struct("Epochs")
+            .field("epoch", &state.epoch)
+            .field("pinned", &state.pinned)
+            .field("retired", &state.retired.keys())
+            .finish()
+    }
+}
+
+/// Epoch pinned by a reader, unpinned once dropped.
+#[derive(Debug)]
+pub(crate) struct EpochGuard {
+    epochs: Arc<Epochs>,
+    epoch: u64,
+}
+
+impl Drop for EpochGuard {
+    fn drop(&mut self) {
+        let mut state = self.epochs.state();
+
+        if let Some(count) = state.pinned.get_mut(&self.epoch) {
+            *count -= 1;
+
+            if *count == 0 {
+                state.pinned.remove(&self.epoch);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vfs::{MemoryVfs, OpenMode, Vfs};
+
+    use super::*;
+
+    #[test]
+    fn epochs_should_reclaim_files_once_unpinned() {
+        let vfs = MemoryVfs::default();
+        let file = vfs
+            .open(std::path::Path::new("/0.rumdb.log"), OpenMode::CreateNew)
+            .unwrap();
+        let epochs = Arc::new(Epochs::default());
+
+        let early = epochs.pin();
+        epochs.retire(0, file.clone());
+        let late = epochs.pin();
+        epochs.retire(1, file.clone());
+
+        // The early reader may reference both files, the late one only the second.
+        assert!(epochs.reclaim().is_empty());
+        assert!(epochs.retired(0).is_some());
+
+        drop(early);
+        assert_eq!(epochs.reclaim(), vec![0]);
+        assert!(epochs.retired(0).is_none());
+        assert!(epochs.retired(1).is_some());
+        assert!(!epochs.retired_before(1));
+        assert!(epochs.retired_before(2));
+
+        drop(late);
+        assert_eq!(epochs.reclaim(), vec![1]);
+
+        // Readers pinned after a file has been retired don't hold it back.
+        epochs.retire(2, file);
+        let _reader = epochs.pin();
+        assert_eq!(epochs.reclaim(), vec![2]);
+    }
+}
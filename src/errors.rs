@@ -1,6 +1,6 @@
 //! Errors module.
 
-use std::io;
+use std::{io, path::PathBuf};
 
 use thiserror::Error;
 
@@ -8,16 +8,125 @@ use thiserror::Error;
 pub enum FormatError {
     #[error("invalid bytes, cannot deserialize entry")]
     DeserializeError,
+
+    #[error("unsupported format version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("incomplete entry at {0}")]
+    TornEntry(u64),
 }
 
 #[derive(Debug, Error)]
 pub enum StorageError {
-    #[error("invalid path")]
-    IoError(#[from] io::Error),
+    #[error("I/O error: {0}")]
+    IoError(#[source] io::Error),
+
+    #[error("disk is full: {0}")]
+    DiskFull(#[source] io::Error),
+
+    #[error("invalid log format")]
+    FormatError(#[from] FormatError),
 
     #[error("db is already locked")]
     AlreadyLocked,
 
     #[error("unknown log file: {0}.rumdb.log")]
     UnknownLogFile(u32),
+
+    #[error("missing log file: {0}.rumdb.log")]
+    MissingLogFile(u32),
+
+    #[error("merge operator is not set")]
+    MergeOperatorNotSet,
+
+    #[error("invalid keyspace name: {0}")]
+    InvalidKeyspaceName(String),
+
+    #[error("unknown keyspace: {0}")]
+    UnknownKeyspace(String),
+
+    #[error("directory is not empty: {0}")]
+    DirectoryNotEmpty(PathBuf),
+
+    #[error("storage is read-only")]
+    ReadOnly,
+
+    #[error("corrupted entry in {file_id}.rumdb.log at {offset}")]
+    Corrupted { file_id: u32, offset: u64 },
+
+    #[error("checksum mismatch in {file_id}.rumdb.log at {offset}")]
+    ChecksumMismatch { file_id: u32, offset: u64 },
+
+    #[error("unsupported format version {version} of {file_id}.rumdb.log")]
+    UnsupportedVersion { file_id: u32, version: u8 },
+
+    #[error("database lock poisoned by a panicked thread")]
+    LockPoisoned,
+
+    #[error("replication failed: {0}")]
+    Replication(String),
+
+    #[error("value is not a counter: {0} bytes")]
+    NotACounter(usize),
+
+    #[error("counter overflow")]
+    CounterOverflow,
+
+    #[error("key of {size} bytes exceeds the maximum of {max} bytes")]
+    KeyTooLarge { size: usize, max: usize },
+
+    #[error("value of {size} bytes exceeds the maximum of {max} bytes")]
+    ValueTooLarge { size: u64, max: u64 },
+
+    #[error("writes stalled until compaction catches up: {0}")]
+    WriteStalled(String),
+
+    #[error("database size of {size} bytes would exceed the quota of {max} bytes")]
+    QuotaExceeded { size: u64, max: u64 },
+
+    #[error("database has {found} shards, opened with {expected}")]
+    ShardCountMismatch { expected: usize, found: usize },
+
+    #[error("not a database: {0}")]
+    NotADatabase(PathBuf),
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::StorageFull => Self::DiskFull(e),
+            _ => Self::IoError(e),
+        }
+    }
+}
+
+impl StorageError {
+    /// Adds the id of the log file the error occurred in to a format version error.
+    pub(crate) fn in_log_file(self, file_id: u32) -> Self {
+        match self {
+            Self::FormatError(FormatError::UnsupportedVersion(version)) => {
+                Self::UnsupportedVersion { file_id, version }
+            }
+            e => e,
+        }
+    }
+}
+
+/// Compare-and-swap mismatch.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("compare and swap mismatch")]
+pub struct CompareAndSwapError {
+    /// Actual value of the key.
+    pub current: Option<Vec<u8>>,
+}
+
+/// Transaction conflict: a key read by the transaction changed before its commit.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("transaction conflict")]
+pub struct TxnConflict {
+    /// First key found changed.
+    pub key: Vec<u8>,
 }
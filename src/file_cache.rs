@@ -0,0 +1,212 @@
+//! Cache of open file handles.
+//!
+//! A `CachedFile` opens its file on first access and keeps the handle in a `FileCache`, which
+//! closes the least recently used handles once more than its capacity are open. Handles still
+//! in use, e.g. by a log reader, stay open until released.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::vfs::{FileMap, OpenMode, Vfs, VfsFile};
+
+/// Least recently used cache of file handles.
+pub(crate) struct FileCache {
+    vfs: Arc<dyn Vfs>,
+    capacity: usize,
+    /// Whether files are opened with `Vfs::open_direct`.
+    direct: bool,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Open handles along with the tick they were last used at.
+    handles: HashMap<PathBuf, (Arc<dyn VfsFile>, u64)>,
+    tick: u64,
+}
+
+impl FileCache {
+    /// Creates a cache keeping at most `capacity` handles open, at least one.
+    pub fn new(vfs: Arc<dyn Vfs>, capacity: usize, direct: bool) -> Arc<Self> {
+        Arc::new(Self {
+            vfs,
+            capacity: capacity.max(1),
+            direct,
+            state: Mutex::default(),
+        })
+    }
+
+    /// Returns a file opened with the `mode` through the cache on demand.
+    pub fn file(self: &Arc<Self>, path: PathBuf, mode: OpenMode) -> Arc<CachedFile> {
+        Arc::new(CachedFile {
+            path,
+            mode,
+            cache: self.clone(),
+        })
+    }
+
+    /// Number of open handles.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.state().handles.len()
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn handle(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>> {
+        let mut state = self.state();
+        state.tick += 1;
+        let tick = state.tick;
+
+        if let Some((handle, used)) = state.handles.get_mut(path) {
+            *used = tick;
+            return Ok(handle.clone());
+        }
+
+        if state.handles.len() >= self.capacity {
+            let lru = state
+                .handles
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(path, _)| path.clone());
+
+            if let Some(lru) = lru {
+                state.handles.remove(&lru);
+            }
+        }
+
+        let handle = if self.direct {
+            self.vfs.open_direct(path, mode)?
+        } else {
+            self.vfs.open(path, mode)?
+        };
+        state
+            .handles
+            .insert(path.to_path_buf(), (handle.clone(), tick));
+
+        Ok(handle)
+    }
+
+    /// Closes the handle of the file at `path`, e.g. before removing the file.
+    pub fn evict(&self, path: &Path) {
+        self.state().handles.remove(path);
+    }
+}
+
+impl fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCache")
+            .field("capacity", &self.capacity)
+            .field("open", &self.state().handles.len())
+            .finish()
+    }
+}
+
+/// File opened through a `FileCache` on demand.
+#[derive(Debug)]
+pub(crate) struct CachedFile {
+    path: PathBuf,
+    mode: OpenMode,
+    cache: Arc<FileCache>,
+}
+
+impl CachedFile {
+    fn handle(&self) -> io::Result<Arc<dyn VfsFile>> {
+        self.cache.handle(&self.path, self.mode)
+    }
+}
+
+impl VfsFile for CachedFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.handle()?.read_at(buf, pos)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        self.handle()?.write_all_at(buf, pos)
+    }
+
+    fn read_batch_at(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        self.handle()?.read_batch_at(reads)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        self.handle()?.append(buf)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.handle()?.set_len(len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.handle()?.len()
+    }
+
+    fn mapped(&self) -> Option<FileMap> {
+        self.handle().ok()?.mapped()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.handle()?.sync()
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        self.handle()?.try_lock()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.handle()?.unlock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vfs::MemoryVfs;
+
+    use super::*;
+
+    #[test]
+    fn file_cache_should_close_least_recently_used_handles() {
+        let vfs = Arc::new(MemoryVfs::default());
+        let dir = Path::new("/files");
+        vfs.create_dir_all(dir).unwrap();
+
+        let cache = FileCache::new(vfs.clone(), 2, false);
+        let files: Vec<_> = (0..3)
+            .map(|i| {
+                let path = dir.join(i.to_string());
+                vfs.open(&path, OpenMode::CreateNew)
+                    .unwrap()
+                    .append_all(&[i])
+                    .unwrap();
+
+                cache.file(path, OpenMode::ReadOnly)
+            })
+            .collect();
+
+        let read = |file: &CachedFile| {
+            let mut buf = [0];
+            file.read_exact_at(&mut buf, 0).unwrap();
+            buf[0]
+        };
+
+        assert_eq!(read(&files[0]), 0);
+        assert_eq!(read(&files[1]), 1);
+        assert_eq!(read(&files[0]), 0);
+        assert_eq!(read(&files[2]), 2);
+        assert_eq!(cache.len(), 2);
+
+        // The handle of the second file has been closed, it is reopened.
+        assert!(!cache.state().handles.contains_key(&dir.join("1")));
+        assert_eq!(read(&files[1]), 1);
+        assert_eq!(cache.len(), 2);
+
+        cache.evict(&dir.join("1"));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,321 @@
+//! Segment footers.
+//!
+//! A footer is appended to a log file when it is sealed. It starts with magic bytes, followed
+//! by an index of every entry in the log file and a fixed-size trailer. The trailer holds
+//! summary stats of the log file, the offset the footer starts at, a crc32 of the index and
+//! a crc32 of the trailer itself, and ends with the magic bytes again, so readers find the
+//! footer from the end of the file. Integers are little-endian. Timestamps are `u64` in log
+//! files of format version 6 and later, `u32` before.
+
+use std::io;
+
+use crate::{
+    errors::FormatError,
+    format::FormatVersion,
+    log_reader::{EntryKind, LogEntry},
+    vfs::VfsFile,
+};
+
+/// Magic bytes a footer starts and ends with.
+pub(crate) const FOOTER_MAGIC: &[u8; 8] = b"RUMDBFTR";
+
+/// Size of timestamps in footers of log files of the `version` format.
+fn timestamp_size(version: FormatVersion) -> usize {
+    match version.has_wide_timestamps() {
+        true => 8,
+        false => 4,
+    }
+}
+
+/// Size of the trailer closing a footer.
+fn trailer_size(version: FormatVersion) -> usize {
+    40 + 2 * timestamp_size(version)
+}
+
+/// Size of an index entry without the key.
+fn index_entry_size(version: FormatVersion) -> usize {
+    21 + 2 * timestamp_size(version)
+}
+
+/// Summary stats of a sealed log file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentStats {
+    /// Number of entries in the log file.
+    pub entries: u64,
+    /// Number of live entries when the log file was sealed.
+    pub live_entries: u64,
+    /// Earliest entry timestamp, 0 if there are no entries.
+    pub min_timestamp: u64,
+    /// Latest entry timestamp, 0 if there are no entries.
+    pub max_timestamp: u64,
+}
+
+/// Entry of a footer index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub key: Vec<u8>,
+    pub kind: EntryKind,
+    pub timestamp: u64,
+    pub expires_at: u64,
+    pub value_pos: u64,
+    pub value_size: u64,
+}
+
+impl From<LogEntry> for IndexEntry {
+    fn from(entry: LogEntry) -> Self {
+        Self {
+            key: entry.key,
+            kind: entry.kind,
+            timestamp: entry.timestamp,
+            expires_at: entry.expires_at,
+            value_pos: entry.value_pos,
+            value_size: entry.value_size,
+        }
+    }
+}
+
+/// Footer trailer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Trailer {
+    /// Offset the footer starts at, which is where entries end.
+    pub footer_pos: u64,
+    pub stats: SegmentStats,
+    index_crc: u32,
+    version: FormatVersion,
+}
+
+/// Encodes a footer with the `index` of a log file of the `version` format whose entries
+/// end at `footer_pos`.
+pub(crate) fn encode(
+    index: &[IndexEntry],
+    live_entries: u64,
+    footer_pos: u64,
+    version: FormatVersion,
+) -> Vec<u8> {
+    let mut buf = FOOTER_MAGIC.to_vec();
+    let put_timestamp = |buf: &mut Vec<u8>, timestamp: u64| match timestamp_size(version) {
+        8 => buf.extend_from_slice(&timestamp.to_le_bytes()),
+        _ => buf.extend_from_slice(&(timestamp as u32).to_le_bytes()),
+    };
+
+    for entry in index {
+        let kind = match entry.kind {
+            EntryKind::Put => 0,
+            EntryKind::Tombstone => 1,
+            EntryKind::MergeOperand => 2,
+        };
+
+        buf.push(kind);
+        put_timestamp(&mut buf, entry.timestamp);
+        put_timestamp(&mut buf, entry.expires_at);
+        buf.extend_from_slice(&entry.value_pos.to_le_bytes());
+        buf.extend_from_slice(&entry.value_size.to_le_bytes());
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.key);
+    }
+
+    let stats = SegmentStats {
+        entries: index.len() as u64,
+        live_entries,
+        min_timestamp: index.iter().map(|entry| entry.timestamp).min().unwrap_or(0),
+        max_timestamp: index.iter().map(|entry| entry.timestamp).max().unwrap_or(0),
+    };
+    let index_crc = crc32fast::hash(&buf[FOOTER_MAGIC.len()..]);
+    let trailer_pos = buf.len();
+
+    buf.extend_from_slice(&footer_pos.to_le_bytes());
+    buf.extend_from_slice(&stats.entries.to_le_bytes());
+    buf.extend_from_slice(&stats.live_entries.to_le_bytes());
+    put_timestamp(&mut buf, stats.min_timestamp);
+    put_timestamp(&mut buf, stats.max_timestamp);
+    buf.extend_from_slice(&index_crc.to_le_bytes());
+
+    let trailer_crc = crc32fast::hash(&buf[trailer_pos..]);
+    buf.extend_from_slice(&trailer_crc.to_le_bytes());
+    buf.extend_from_slice(FOOTER_MAGIC);
+
+    buf
+}
+
+impl Trailer {
+    /// Reads the trailer at the end of the `file` of `size` bytes and of the `version` format,
+    /// whose entries start at `entries_pos`. Returns `None` if the file has no valid footer.
+    pub fn read(
+        file: &dyn VfsFile,
+        size: u64,
+        entries_pos: u64,
+        version: FormatVersion,
+    ) -> Result<Option<Self>, io::Error> {
+        let trailer_size = trailer_size(version);
+
+        if size < entries_pos + (FOOTER_MAGIC.len() + trailer_size) as u64 {
+            return Ok(None);
+        }
+
+        let mut buf = vec![0; trailer_size];
+        file.read_exact_at(&mut buf, size - trailer_size as u64)?;
+
+        let t = timestamp_size(version);
+        let u32_at = |pos: usize| u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap());
+        let u64_at = |pos: usize| u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap());
+        let timestamp_at = |pos: usize| match t {
+            8 => u64_at(pos),
+            _ => u32_at(pos).into(),
+        };
+
+        let crc_pos = 28 + 2 * t;
+
+        if &buf[crc_pos + 4..] != FOOTER_MAGIC
+            || crc32fast::hash(&buf[..crc_pos]) != u32_at(crc_pos)
+        {
+            return Ok(None);
+        }
+
+        let trailer = Self {
+            footer_pos: u64_at(0),
+            stats: SegmentStats {
+                entries: u64_at(8),
+                live_entries: u64_at(16),
+                min_timestamp: timestamp_at(24),
+                max_timestamp: timestamp_at(24 + t),
+            },
+            index_crc: u32_at(24 + 2 * t),
+            version,
+        };
+
+        let valid_pos = trailer.footer_pos >= entries_pos
+            && trailer.footer_pos + (FOOTER_MAGIC.len() + trailer_size) as u64 <= size;
+
+        Ok(valid_pos.then_some(trailer))
+    }
+
+    /// Reads the index of the footer at the end of the `file` of `size` bytes.
+    pub fn read_index(
+        &self,
+        file: &dyn VfsFile,
+        size: u64,
+    ) -> Result<Vec<IndexEntry>, FormatError> {
+        let index_pos = self.footer_pos + FOOTER_MAGIC.len() as u64;
+        let trailer_size = trailer_size(self.version) as u64;
+        let mut buf = vec![0; (size - trailer_size - index_pos) as usize];
+        file.read_exact_at(&mut buf, index_pos)
+            .or(Err(FormatError::DeserializeError))?;
+
+        if crc32fast::hash(&buf) != self.index_crc {
+            return Err(FormatError::ChecksumMismatch);
+        }
+
+        let mut index = Vec::with_capacity(self.stats.entries as usize);
+        let mut pos = 0;
+        let t = timestamp_size(self.version);
+        let entry_size = index_entry_size(self.version);
+
+        while pos < buf.len() {
+            let fields = buf
+                .get(pos..pos + entry_size)
+                .ok_or(FormatError::DeserializeError)?;
+            let u32_at = |pos: usize| u32::from_le_bytes(fields[pos..pos + 4].try_into().unwrap());
+            let u64_at = |pos: usize| u64::from_le_bytes(fields[pos..pos + 8].try_into().unwrap());
+            let timestamp_at = |pos: usize| match t {
+                8 => u64_at(pos),
+                _ => u32_at(pos).into(),
+            };
+
+            let kind = match fields[0] {
+                0 => EntryKind::Put,
+                1 => EntryKind::Tombstone,
+                2 => EntryKind::MergeOperand,
+                _ => return Err(FormatError::DeserializeError),
+            };
+
+            let key_pos = pos + entry_size;
+            let key = buf
+                .get(key_pos..key_pos + u32_at(17 + 2 * t) as usize)
+                .ok_or(FormatError::DeserializeError)?;
+
+            index.push(IndexEntry {
+                key: key.to_vec(),
+                kind,
+                timestamp: timestamp_at(1),
+                expires_at: timestamp_at(1 + t),
+                value_pos: u64_at(1 + 2 * t),
+                value_size: u64_at(9 + 2 * t),
+            });
+
+            pos = key_pos + key.len();
+        }
+
+        if index.len() as u64 != self.stats.entries {
+            return Err(FormatError::DeserializeError);
+        }
+
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vfs::{OpenMode, StdVfs, Vfs};
+
+    use super::*;
+
+    #[test]
+    fn footer_should_roundtrip_index() {
+        for (version, timestamp) in [
+            (FormatVersion::V4, 20),
+            (FormatVersion::V6, u64::from(u32::MAX) + 20),
+        ] {
+            let dir = tempdir::TempDir::new("footer-test").unwrap();
+            let file = StdVfs
+                .open(&dir.path().join("0.rumdb.log"), OpenMode::CreateNew)
+                .unwrap();
+
+            let index = vec![
+                IndexEntry {
+                    key: b"hello".to_vec(),
+                    kind: EntryKind::Put,
+                    timestamp,
+                    expires_at: timestamp + 10,
+                    value_pos: 40,
+                    value_size: 5,
+                },
+                IndexEntry {
+                    key: b"hello".to_vec(),
+                    kind: EntryKind::Tombstone,
+                    timestamp: 10,
+                    expires_at: 0,
+                    value_pos: 80,
+                    value_size: 0,
+                },
+            ];
+
+            file.append_all(&[0; 100]).unwrap();
+            let footer = encode(&index, 1, 100, version);
+            file.append_all(&footer).unwrap();
+            let size = file.len().unwrap();
+
+            let trailer = Trailer::read(&*file, size, 10, version).unwrap().unwrap();
+            assert_eq!(trailer.footer_pos, 100);
+            assert_eq!(
+                trailer.stats,
+                SegmentStats {
+                    entries: 2,
+                    live_entries: 1,
+                    min_timestamp: 10,
+                    max_timestamp: timestamp,
+                }
+            );
+            assert_eq!(trailer.read_index(&*file, size).unwrap(), index);
+
+            assert_eq!(Trailer::read(&*file, size - 1, 10, version).unwrap(), None);
+            assert_eq!(Trailer::read(&*file, size, 101, version).unwrap(), None);
+
+            let key_pos = 100 + FOOTER_MAGIC.len() + index_entry_size(version);
+            file.write_all_at(b"J", key_pos as u64).unwrap();
+            assert!(matches!(
+                trailer.read_index(&*file, size),
+                Err(FormatError::ChecksumMismatch)
+            ));
+        }
+    }
+}
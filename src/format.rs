@@ -1,127 +1,632 @@
 //! Module provides serialization/deserialization ops.
+//!
+//! Log files written by rumdb 0.2 (format version 1) have no segment header and use a fixed
+//! 12-byte entry header with 32-bit sizes. Newer log files start with a segment header which
+//! holds the format version of all entries in the file. Format version 3 adds the expiration
+//! time of the entry to the header, format version 4 the creation time of the log file to
+//! the segment header. Format version 5 encodes entry headers compactly, with varint sizes
+//! and timestamps relative to the creation time of the log file. Format versions 6 and 7
+//! widen timestamps, expiration times and the creation time of the log file to `u64`, with
+//! fixed-size and compact entry headers respectively. Log files of older versions are still
+//! read, their timestamps are widened as they are decoded.
 
-use chrono::Utc;
+use crate::{
+    clock::{Clock, SystemClock},
+    errors::FormatError,
+};
 
-use crate::errors::FormatError;
+/// Magic bytes every versioned log file starts with.
+pub(crate) const MAGIC: &[u8; 5] = b"RUMDB";
 
-pub(crate) const HEADER_SIZE: usize = 12;
+/// Size of the segment header prefix: magic bytes followed by the format version.
+pub(crate) const SEGMENT_HEADER_SIZE: usize = MAGIC.len() + 1;
+
+/// Maximum segment header size among all format versions.
+pub(crate) const MAX_SEGMENT_HEADER_SIZE: usize = SEGMENT_HEADER_SIZE + 8;
+
+/// Maximum entry header size among all format versions.
+pub(crate) const MAX_HEADER_SIZE: usize = 40;
+
+/// Size of the chunks large values are processed in.
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Entry flag marking a removed key.
+pub(crate) const FLAG_TOMBSTONE: u8 = 0b0000_0001;
+
+/// Entry flag marking a merge operand.
+pub(crate) const FLAG_MERGE: u8 = 0b0000_0010;
+
+/// Entry flag marking an entry followed by more entries of the same atomic batch.
+pub(crate) const FLAG_BATCH: u8 = 0b0000_0100;
+
+/// Entry flag marking a copy of a live entry rewritten by a compaction.
+pub(crate) const FLAG_REWRITE: u8 = 0b0000_1000;
+
+/// Log file format version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FormatVersion {
+    /// Legacy format: timestamp, key size and value size as `u32`.
+    /// Entries with an empty value are tombstones.
+    V1 = 1,
+    /// Checksum, timestamp, flags, `u32` key size and `u64` value size.
+    V2 = 2,
+    /// Version 2 followed by the `u32` expiration time, 0 if the entry never expires.
+    V3 = 3,
+    /// Version 3 with the `u32` creation time of the log file in the segment header.
+    V4 = 4,
+    /// Version 4 with compact entry headers: checksum, flags, the timestamp relative to
+    /// the creation time of the log file, key size, value size and expiration time as varints.
+    V5 = 5,
+    /// Version 4 with the `u64` timestamp and expiration time, and the `u64` creation time
+    /// of the log file in the segment header.
+    V6 = 6,
+    /// Version 5 with the `u64` creation time of the log file in the segment header.
+    V7 = 7,
+}
+
+impl FormatVersion {
+    /// Format version new log files are written in.
+    pub const CURRENT: Self = Self::V6;
+
+    /// Size of the entry header in this format version, the maximum size for compact
+    /// entry headers.
+    pub fn header_size(self) -> usize {
+        match self {
+            Self::V1 => 12,
+            Self::V2 => 21,
+            Self::V3 | Self::V4 => 25,
+            Self::V5 | Self::V7 => MAX_HEADER_SIZE,
+            Self::V6 => 33,
+        }
+    }
+
+    /// Whether entry headers are encoded compactly in this format version.
+    pub fn is_compact(self) -> bool {
+        matches!(self, Self::V5 | Self::V7)
+    }
+
+    /// Whether this format version stores timestamps as `u64`, in entry headers, segment
+    /// headers and footers alike.
+    pub fn has_wide_timestamps(self) -> bool {
+        matches!(self, Self::V6 | Self::V7)
+    }
+
+    /// Size of the segment header in this format version.
+    pub fn segment_header_size(self) -> usize {
+        match self {
+            Self::V1 => 0,
+            Self::V2 | Self::V3 => SEGMENT_HEADER_SIZE,
+            Self::V4 | Self::V5 => SEGMENT_HEADER_SIZE + 4,
+            Self::V6 | Self::V7 => MAX_SEGMENT_HEADER_SIZE,
+        }
+    }
+
+    /// Encodes a segment header for this format version of a log file created at
+    /// `created_at`, saturated in versions storing it as `u32`. Only the first
+    /// `self.segment_header_size()` bytes are meaningful.
+    pub fn segment_header(self, created_at: u64) -> [u8; MAX_SEGMENT_HEADER_SIZE] {
+        let mut buf = [0; MAX_SEGMENT_HEADER_SIZE];
+
+        buf[..MAGIC.len()].copy_from_slice(MAGIC);
+        buf[MAGIC.len()] = self as u8;
+
+        match self {
+            Self::V4 | Self::V5 => {
+                let created_at = u32::try_from(created_at).unwrap_or(u32::MAX);
+                buf[SEGMENT_HEADER_SIZE..SEGMENT_HEADER_SIZE + 4]
+                    .copy_from_slice(&created_at.to_le_bytes());
+            }
+            Self::V6 | Self::V7 => {
+                buf[SEGMENT_HEADER_SIZE..].copy_from_slice(&created_at.to_le_bytes())
+            }
+            _ => (),
+        }
+
+        buf
+    }
+
+    /// Creation time of the log file stored in its `segment_header`, if this format
+    /// version stores one.
+    pub fn created_at(self, segment_header: &[u8]) -> Option<u64> {
+        let created_at = segment_header.get(SEGMENT_HEADER_SIZE..self.segment_header_size())?;
+
+        match self {
+            Self::V4 | Self::V5 => Some(u32::from_le_bytes(created_at.try_into().unwrap()).into()),
+            Self::V6 | Self::V7 => Some(u64::from_le_bytes(created_at.try_into().unwrap())),
+            _ => None,
+        }
+    }
+
+    /// Detects the format version from the beginning of a log file.
+    ///
+    /// Files without magic bytes are legacy version 1 files.
+    pub fn detect(prefix: &[u8]) -> Result<Self, FormatError> {
+        if prefix.len() < SEGMENT_HEADER_SIZE || !prefix.starts_with(MAGIC) {
+            return Ok(Self::V1);
+        }
+
+        Self::from_u8(prefix[MAGIC.len()])
+    }
+
+    /// Format version stored as the `version` byte by a segment header.
+    pub fn from_u8(version: u8) -> Result<Self, FormatError> {
+        match version {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            3 => Ok(Self::V3),
+            4 => Ok(Self::V4),
+            5 => Ok(Self::V5),
+            6 => Ok(Self::V6),
+            7 => Ok(Self::V7),
+            version => Err(FormatError::UnsupportedVersion(version)),
+        }
+    }
+}
+
+/// Layout of the entry headers of a log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub version: FormatVersion,
+    /// Timestamp compact entry headers store timestamps relative to, the creation time of
+    /// the log file.
+    pub base: u64,
+}
+
+impl Layout {
+    /// Creates a new `Layout`.
+    pub fn new(version: FormatVersion, base: u64) -> Self {
+        Self { version, base }
+    }
+}
+
+impl From<FormatVersion> for Layout {
+    fn from(version: FormatVersion) -> Self {
+        Self::new(version, 0)
+    }
+}
 
 /// DB entry Header. It contains the following entry metadata:
+///     - checksum
 ///     - timestamp
+///     - flags
 ///     - key size
 ///     - value size
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub(crate) struct Header([u8; HEADER_SIZE]);
+///     - expiration time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    crc: u32,
+    timestamp: u64,
+    flags: u8,
+    key_size: u32,
+    value_size: u64,
+    expires_at: u64,
+}
 
 impl Header {
     /// Creates a new `Header`.
-    pub fn new(timestamp: u32, key_size: u32, value_size: u32) -> Self {
-        let mut buf = [0; 12];
+    pub fn new(timestamp: u64, key_size: u32, value_size: u64) -> Self {
+        Self {
+            crc: 0,
+            timestamp,
+            flags: 0,
+            key_size,
+            value_size,
+            expires_at: 0,
+        }
+    }
+
+    /// Creates a new tombstone `Header`.
+    pub fn tombstone(timestamp: u64, key_size: u32) -> Self {
+        Self {
+            flags: FLAG_TOMBSTONE,
+            ..Self::new(timestamp, key_size, 0)
+        }
+    }
+
+    /// Creates a new merge operand `Header`.
+    pub fn merge_operand(timestamp: u64, key_size: u32, value_size: u64) -> Self {
+        Self {
+            flags: FLAG_MERGE,
+            ..Self::new(timestamp, key_size, value_size)
+        }
+    }
 
-        buf[..4].copy_from_slice(&timestamp.to_le_bytes());
-        buf[4..8].copy_from_slice(&key_size.to_le_bytes());
-        buf[8..].copy_from_slice(&value_size.to_le_bytes());
+    /// Sets the time the entry expires at.
+    pub fn expiring(self, expires_at: u64) -> Self {
+        Self { expires_at, ..self }
+    }
 
-        Self(buf)
+    /// Entry checksum.
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    /// Sets the entry checksum.
+    pub fn set_crc(&mut self, crc: u32) {
+        self.crc = crc;
     }
 
     /// Entry timestamp.
-    pub fn timestamp(&self) -> u32 {
-        u32::from_le_bytes(self.0[..4].try_into().unwrap())
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
     }
 
     /// Entry key size.
     pub fn key_size(&self) -> usize {
-        u32::from_le_bytes(self.0[4..8].try_into().unwrap()) as usize
+        self.key_size as usize
     }
 
     /// Entry value size.
-    pub fn value_size(&self) -> usize {
-        u32::from_le_bytes(self.0[8..].try_into().unwrap()) as usize
+    pub fn value_size(&self) -> u64 {
+        self.value_size
     }
 
-    /// Returns a slice to the underlying header byte representation.
-    pub fn as_slice(&self) -> &[u8] {
-        &self.0
+    /// Time the entry expires at, 0 if it never expires.
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
     }
-}
 
-impl From<(u32, u32, u32)> for Header {
-    fn from(entry_tuple: (u32, u32, u32)) -> Self {
-        Self::new(entry_tuple.0, entry_tuple.1, entry_tuple.2)
+    /// Whether the entry marks a removed key.
+    pub fn is_tombstone(&self) -> bool {
+        self.flags & FLAG_TOMBSTONE != 0
     }
-}
 
-impl From<[u8; HEADER_SIZE]> for Header {
-    fn from(value: [u8; HEADER_SIZE]) -> Self {
-        Self(value)
+    /// Whether the entry value is a merge operand.
+    pub fn is_merge_operand(&self) -> bool {
+        self.flags & FLAG_MERGE != 0
     }
-}
 
-impl TryFrom<&[u8]> for Header {
-    type Error = FormatError;
+    /// Whether more entries of the same atomic batch follow the entry.
+    pub fn is_batched(&self) -> bool {
+        self.flags & FLAG_BATCH != 0
+    }
+
+    /// Whether the entry is a copy rewritten by a compaction.
+    pub fn is_rewrite(&self) -> bool {
+        self.flags & FLAG_REWRITE != 0
+    }
+
+    /// Size of the header in the `layout`.
+    pub fn size(&self, layout: Layout) -> usize {
+        match layout.version {
+            version if version.is_compact() => self.encode_compact(layout.base).1,
+            version => version.header_size(),
+        }
+    }
+
+    /// Size of the whole entry in the `layout`.
+    pub fn entry_size(&self, layout: Layout) -> u64 {
+        // Saturates on corrupt value sizes, which are caught by bounds checks.
+        ((self.size(layout) + self.key_size()) as u64).saturating_add(self.value_size)
+    }
+
+    /// Returns a checksum hasher fed with the header fields covered by the checksum in the
+    /// `version` format. Key and value are expected to be fed next. Headers of versions 5
+    /// to 7 are checksummed in the version 4 layout, or the version 6 one if timestamps
+    /// don't fit it, so the checksum doesn't depend on the log file the entry is written to.
+    pub fn hasher(&self, version: FormatVersion) -> crc32fast::Hasher {
+        let version = match version {
+            FormatVersion::V5 | FormatVersion::V6 | FormatVersion::V7
+                if self.fits_u32_timestamps() =>
+            {
+                FormatVersion::V4
+            }
+            FormatVersion::V5 | FormatVersion::V7 => FormatVersion::V6,
+            version => version,
+        };
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.encode(version.into())[4..version.header_size()]);
+        hasher
+    }
+
+    /// Whether the timestamp and expiration time fit format versions storing them as `u32`.
+    fn fits_u32_timestamps(&self) -> bool {
+        u32::try_from(self.timestamp).is_ok() && u32::try_from(self.expires_at).is_ok()
+    }
+
+    /// Encodes the header in the `layout`. Timestamps are truncated in format versions
+    /// storing them as `u32`, which new entries are never written in.
+    /// Only the first `self.size(layout)` bytes of the result are meaningful.
+    pub fn encode(&self, layout: Layout) -> [u8; MAX_HEADER_SIZE] {
+        let mut buf = [0; MAX_HEADER_SIZE];
+
+        match layout.version {
+            FormatVersion::V1 => {
+                buf[..4].copy_from_slice(&(self.timestamp as u32).to_le_bytes());
+                buf[4..8].copy_from_slice(&self.key_size.to_le_bytes());
+                buf[8..12].copy_from_slice(&(self.value_size as u32).to_le_bytes());
+            }
+            FormatVersion::V2 | FormatVersion::V3 | FormatVersion::V4 => {
+                buf[..4].copy_from_slice(&self.crc.to_le_bytes());
+                buf[4..8].copy_from_slice(&(self.timestamp as u32).to_le_bytes());
+                buf[8] = self.flags;
+                buf[9..13].copy_from_slice(&self.key_size.to_le_bytes());
+                buf[13..21].copy_from_slice(&self.value_size.to_le_bytes());
+
+                if layout.version != FormatVersion::V2 {
+                    buf[21..25].copy_from_slice(&(self.expires_at as u32).to_le_bytes());
+                }
+            }
+            FormatVersion::V5 | FormatVersion::V7 => buf = self.encode_compact(layout.base).0,
+            FormatVersion::V6 => {
+                buf[..4].copy_from_slice(&self.crc.to_le_bytes());
+                buf[4..12].copy_from_slice(&self.timestamp.to_le_bytes());
+                buf[12] = self.flags;
+                buf[13..17].copy_from_slice(&self.key_size.to_le_bytes());
+                buf[17..25].copy_from_slice(&self.value_size.to_le_bytes());
+                buf[25..33].copy_from_slice(&self.expires_at.to_le_bytes());
+            }
+        }
+
+        buf
+    }
+
+    /// Encodes the header compactly with timestamps relative to the `base`, returning
+    /// the encoded size.
+    fn encode_compact(&self, base: u64) -> ([u8; MAX_HEADER_SIZE], usize) {
+        let mut buf = [0; MAX_HEADER_SIZE];
+
+        buf[..4].copy_from_slice(&self.crc.to_le_bytes());
+        buf[4] = self.flags;
+
+        // Wraps for timestamps past `i64::MAX`, as decoding does.
+        let delta = (self.timestamp as i64).wrapping_sub(base as i64);
+        let mut pos = 5;
+
+        for value in [
+            zigzag(delta),
+            self.key_size.into(),
+            self.value_size,
+            self.expires_at,
+        ] {
+            pos += encode_varint(value, &mut buf[pos..]);
+        }
+
+        (buf, pos)
+    }
+
+    /// Decodes a header in the `layout` from the beginning of the `buf`.
+    pub fn decode(buf: &[u8], layout: Layout) -> Result<Self, FormatError> {
+        let version = layout.version;
+
+        if !version.is_compact() && buf.len() < version.header_size() {
+            return Err(FormatError::DeserializeError);
+        }
+
+        let u32_at = |pos: usize| u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap());
+        let u64_at = |pos: usize| u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap());
+
+        let header = match version {
+            FormatVersion::V1 => {
+                let value_size = u32_at(8) as u64;
+                let flags = if value_size == 0 { FLAG_TOMBSTONE } else { 0 };
+
+                Self {
+                    crc: 0,
+                    timestamp: u32_at(0).into(),
+                    flags,
+                    key_size: u32_at(4),
+                    value_size,
+                    expires_at: 0,
+                }
+            }
+            FormatVersion::V2 | FormatVersion::V3 | FormatVersion::V4 => Self {
+                crc: u32_at(0),
+                timestamp: u32_at(4).into(),
+                flags: buf[8],
+                key_size: u32_at(9),
+                value_size: u64_at(13),
+                expires_at: match version {
+                    FormatVersion::V2 => 0,
+                    _ => u32_at(21).into(),
+                },
+            },
+            FormatVersion::V5 | FormatVersion::V7 => Self::decode_compact(buf, layout.base)?,
+            FormatVersion::V6 => Self {
+                crc: u32_at(0),
+                timestamp: u64_at(4),
+                flags: buf[12],
+                key_size: u32_at(13),
+                value_size: u64_at(17),
+                expires_at: u64_at(25),
+            },
+        };
+
+        Ok(header)
+    }
 
-    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.len() != 12 {
+    /// Decodes a compact header with timestamps relative to the `base`.
+    fn decode_compact(buf: &[u8], base: u64) -> Result<Self, FormatError> {
+        if buf.len() < 5 {
             return Err(FormatError::DeserializeError);
         }
 
-        let mut buf = [0; 12];
+        let mut pos = 5;
+        let mut next = || {
+            let (value, size) = decode_varint(&buf[pos..]).ok_or(FormatError::DeserializeError)?;
+            pos += size;
+
+            Ok::<_, FormatError>(value)
+        };
 
-        buf.copy_from_slice(value);
+        let timestamp = (base as i64).wrapping_add(unzigzag(next()?));
+        let key_size = next()?;
+        let value_size = next()?;
+        let expires_at = next()?;
 
-        Ok(Self(buf))
+        Ok(Self {
+            crc: u32::from_le_bytes(buf[..4].try_into().unwrap()),
+            timestamp: timestamp as u64,
+            flags: buf[4],
+            key_size: key_size.try_into().or(Err(FormatError::DeserializeError))?,
+            value_size,
+            expires_at,
+        })
     }
 }
 
-impl From<Header> for [u8; HEADER_SIZE] {
-    fn from(header: Header) -> Self {
-        header.0
+/// Writes the varint of the `value` to the `buf`, returning its size.
+fn encode_varint(mut value: u64, buf: &mut [u8]) -> usize {
+    let mut pos = 0;
+
+    while value >= 0x80 {
+        buf[pos] = value as u8 | 0x80;
+        value >>= 7;
+        pos += 1;
+    }
+
+    buf[pos] = value as u8;
+    pos + 1
+}
+
+/// Reads a varint from the beginning of the `buf`, returning it with its size.
+/// Returns `None` if the `buf` ends within the varint or it overflows `u64`.
+fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+
+    for (i, &byte) in buf.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f).checked_shl(7 * i as u32)?;
+
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
     }
+
+    None
+}
+
+/// Maps signed values to unsigned ones, so small negative values have short varints.
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
 }
 
 /// Entry disk representation.
 #[derive(Debug, Clone)]
-pub(crate) struct DiskEntry {
+pub(crate) struct DiskEntry<'a> {
     pub header: Header,
-    pub key: Vec<u8>,
-    pub value: Vec<u8>,
+    pub key: &'a [u8],
+    pub value: &'a [u8],
 }
 
-impl DiskEntry {
+impl<'a> DiskEntry<'a> {
     /// Creates a new `DiskEntry`.
-    pub fn new(key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Self {
-        let timestamp: u32 = Utc::now().timestamp().try_into().unwrap();
-        let key_size = key.as_ref().len() as u32;
-        let value_size = value.as_ref().len() as u32;
+    pub fn new(key: &'a [u8], value: &'a [u8]) -> Self {
+        let header = Header::new(Self::now(), key.len() as u32, value.len() as u64);
+
+        Self::with_header(header, key, value)
+    }
+
+    /// Creates a new tombstone `DiskEntry`.
+    pub fn tombstone(key: &'a [u8]) -> Self {
+        let header = Header::tombstone(Self::now(), key.len() as u32);
 
-        let header = Header::new(timestamp, key_size, value_size);
-        let key = key.as_ref().to_vec();
-        let value = value.as_ref().to_vec();
+        Self::with_header(header, key, &[])
+    }
+
+    /// Creates a new merge operand `DiskEntry`.
+    pub fn merge_operand(key: &'a [u8], operand: &'a [u8]) -> Self {
+        let header = Header::merge_operand(Self::now(), key.len() as u32, operand.len() as u64);
+
+        Self::with_header(header, key, operand)
+    }
+
+    /// Replaces the entry timestamp, the current time by default.
+    pub fn at(self, timestamp: u64) -> Self {
+        let header = Header {
+            timestamp,
+            ..self.header
+        };
+
+        Self::with_header(header, self.key, self.value)
+    }
+
+    /// Marks the entry as followed by more entries of the same atomic batch.
+    pub fn batched(self) -> Self {
+        let header = Header {
+            flags: self.header.flags | FLAG_BATCH,
+            ..self.header
+        };
+
+        Self::with_header(header, self.key, self.value)
+    }
+
+    /// Sets the time the entry expires at, never by default.
+    pub fn expiring(self, expires_at: u64) -> Self {
+        let header = Header {
+            expires_at,
+            ..self.header
+        };
+
+        Self::with_header(header, self.key, self.value)
+    }
+
+    /// Marks the entry as a copy of a live entry rewritten by a compaction.
+    pub fn rewritten(self) -> Self {
+        let header = Header {
+            flags: self.header.flags | FLAG_REWRITE,
+            ..self.header
+        };
+
+        Self::with_header(header, self.key, self.value)
+    }
+
+    fn with_header(mut header: Header, key: &'a [u8], value: &'a [u8]) -> Self {
+        let mut hasher = header.hasher(FormatVersion::CURRENT);
+        hasher.update(key);
+        hasher.update(value);
+        header.crc = hasher.finalize();
 
         Self { header, key, value }
     }
+
+    /// Current timestamp of the system clock.
+    pub fn now() -> u64 {
+        SystemClock.now()
+    }
 }
 
 /// Keydir in-memory entry.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct KeydirEntry {
     pub file_id: u32,
-    pub value_size: usize,
+    pub value_size: u64,
     pub value_pos: u64,
-    pub timestamp: u32,
+    pub timestamp: u64,
+    /// Time the value expires at, 0 if it never expires.
+    pub expires_at: u64,
 }
 
 impl KeydirEntry {
     /// Creates a new `DiskEntry`.
-    pub fn new(file_id: u32, value_size: usize, value_pos: u64, timestamp: u32) -> Self {
+    pub fn new(file_id: u32, value_size: u64, value_pos: u64, timestamp: u64) -> Self {
         Self {
             file_id,
             value_size,
             value_pos,
             timestamp,
+            expires_at: 0,
         }
     }
+
+    /// Sets the time the value expires at.
+    pub fn expiring(self, expires_at: u64) -> Self {
+        Self { expires_at, ..self }
+    }
+
+    /// Whether the value has expired by the system clock.
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(DiskEntry::now())
+    }
+
+    /// Whether the value has expired at the `now` timestamp.
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
 }
 
 #[cfg(test)]
@@ -129,9 +634,10 @@ mod tests {
     use super::*;
     use rand::Rng;
 
-    fn header_test(header: Header) {
-        let data: [u8; HEADER_SIZE] = header.clone().into();
-        let deserialized_header = Header::from(data);
+    fn header_test(header: Header, version: FormatVersion) {
+        let data = header.encode(version.into());
+        let size = header.size(version.into());
+        let deserialized_header = Header::decode(&data[..size], version.into()).unwrap();
 
         assert_eq!(header, deserialized_header);
     }
@@ -139,27 +645,158 @@ mod tests {
     fn random_header() -> Header {
         let mut rng = rand::thread_rng();
 
-        Header::new(rng.gen(), rng.gen(), rng.gen())
+        Header::new(rng.gen::<u32>().into(), rng.gen(), rng.gen())
+    }
+
+    fn random_wide_header() -> Header {
+        let mut rng = rand::thread_rng();
+
+        Header::new(rng.gen(), rng.gen(), rng.gen()).expiring(rng.gen())
     }
 
     #[test]
     fn it_should_serialize_header() {
         let tests = [
             Header::new(10, 10, 10),
-            Header::new(0, 0, 0),
             Header::new(10000, 10000, 10000),
+            Header::tombstone(0, 0),
         ];
 
         for test in tests {
-            header_test(test);
+            header_test(test, FormatVersion::V1);
+            header_test(test, FormatVersion::V2);
+            header_test(test, FormatVersion::V3);
         }
+
+        header_test(Header::merge_operand(10, 10, 10), FormatVersion::V2);
+
+        header_test(Header::new(0, 0, u64::MAX), FormatVersion::V2);
+
+        let header = Header {
+            expires_at: 42,
+            ..Header::new(10, 10, 10)
+        };
+        header_test(header, FormatVersion::V3);
+        header_test(header, FormatVersion::V4);
+        header_test(header, FormatVersion::V6);
+
+        let header = Header::new(u64::from(u32::MAX) + 10, 10, 10).expiring(u64::MAX);
+        header_test(header, FormatVersion::V5);
+        header_test(header, FormatVersion::V6);
+        assert_eq!(header.size(FormatVersion::V6.into()), 33);
+    }
+
+    #[test]
+    fn it_should_checksum_headers_in_older_layouts() {
+        let checksum = |header: Header, version: FormatVersion| {
+            let mut hasher = header.hasher(version);
+            hasher.update(b"key");
+            hasher.finalize()
+        };
+
+        let header = Header::new(1000, 3, 5).expiring(2000);
+        assert_eq!(
+            checksum(header, FormatVersion::V6),
+            checksum(header, FormatVersion::V4)
+        );
+
+        let header = Header::new(u64::from(u32::MAX) + 1, 3, 5);
+        assert_eq!(
+            checksum(header, FormatVersion::V5),
+            checksum(header, FormatVersion::V6)
+        );
+        assert_ne!(
+            checksum(header, FormatVersion::V6),
+            checksum(header, FormatVersion::V4)
+        );
     }
 
     #[test]
     fn it_should_serialize_header_random() {
         for _ in 0..100 {
-            header_test(random_header())
+            header_test(random_header(), FormatVersion::V2);
+            header_test(random_header(), FormatVersion::V3);
+            header_test(random_header(), FormatVersion::V4);
+            header_test(random_header(), FormatVersion::V5);
+            header_test(random_wide_header(), FormatVersion::V5);
+            header_test(random_wide_header(), FormatVersion::V6);
+        }
+    }
+
+    #[test]
+    fn it_should_serialize_compact_header() {
+        let header = Header::new(1000, 3, 5).expiring(2000);
+
+        for base in [0, 990, 1000, 1010, u32::MAX.into(), u64::MAX] {
+            let layout = Layout::new(FormatVersion::V7, base);
+            let data = header.encode(layout);
+            let size = header.size(layout);
+
+            assert_eq!(Header::decode(&data[..size], layout).unwrap(), header);
+            assert!(Header::decode(&data[..size - 1], layout).is_err());
+        }
+
+        let layout = Layout::new(FormatVersion::V5, 990);
+        assert_eq!(header.size(layout), 10);
+        assert_eq!(header.entry_size(layout), 18);
+
+        let mut hasher = header.hasher(FormatVersion::V5);
+        hasher.update(b"key");
+        let mut expected = header.hasher(FormatVersion::V4);
+        expected.update(b"key");
+        assert_eq!(hasher.finalize(), expected.finalize());
+    }
+
+    #[test]
+    fn it_should_encode_varints() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = [0; 10];
+            let len = encode_varint(value, &mut buf);
+            assert_eq!(decode_varint(&buf[..len]), Some((value, len)));
+            assert_eq!(decode_varint(&buf[..len - 1]), None);
+        }
+
+        for value in [0, 1, -1, i64::MIN, i64::MAX] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+
+    #[test]
+    fn it_should_detect_format_version() {
+        assert_eq!(
+            FormatVersion::detect(&FormatVersion::V2.segment_header(42)).unwrap(),
+            FormatVersion::V2
+        );
+        assert_eq!(
+            FormatVersion::detect(&FormatVersion::V3.segment_header(42)).unwrap(),
+            FormatVersion::V3
+        );
+
+        let segment_header = FormatVersion::V4.segment_header(42);
+        assert_eq!(
+            FormatVersion::detect(&segment_header).unwrap(),
+            FormatVersion::V4
+        );
+        assert_eq!(FormatVersion::V4.created_at(&segment_header), Some(42));
+        assert_eq!(FormatVersion::V3.created_at(&segment_header), None);
+
+        let created_at = u64::from(u32::MAX) + 42;
+        for version in [FormatVersion::V6, FormatVersion::V7] {
+            let segment_header = version.segment_header(created_at);
+            assert_eq!(FormatVersion::detect(&segment_header).unwrap(), version);
+            assert_eq!(version.created_at(&segment_header), Some(created_at));
         }
+        assert_eq!(
+            FormatVersion::V5.created_at(&FormatVersion::V5.segment_header(created_at)),
+            Some(u32::MAX.into())
+        );
+        assert_eq!(
+            FormatVersion::detect(&Header::new(1, 2, 3).encode(FormatVersion::V1.into())[..12])
+                .unwrap(),
+            FormatVersion::V1
+        );
+        assert_eq!(FormatVersion::detect(&[]).unwrap(), FormatVersion::V1);
+        assert!(FormatVersion::detect(b"RUMDB\xff").is_err());
     }
 
     #[test]
@@ -168,5 +805,47 @@ mod tests {
 
         assert_eq!(entry.header.key_size(), 5);
         assert_eq!(entry.header.value_size(), 5);
+        assert!(!entry.header.is_tombstone());
+
+        let mut hasher = entry.header.hasher(FormatVersion::CURRENT);
+        hasher.update(b"hello");
+        hasher.update(b"world");
+        assert_eq!(entry.header.crc(), hasher.finalize());
+
+        let entry = DiskEntry::tombstone(b"hello");
+        assert!(entry.header.is_tombstone());
+
+        let entry = DiskEntry::merge_operand(b"hello", b"+1");
+        assert!(entry.header.is_merge_operand());
+        assert!(!entry.header.is_tombstone());
+
+        let entry = entry.at(42);
+        assert_eq!(entry.header.timestamp(), 42);
+        assert!(entry.header.is_merge_operand());
+
+        let mut hasher = entry.header.hasher(FormatVersion::CURRENT);
+        hasher.update(b"hello");
+        hasher.update(b"+1");
+        assert_eq!(entry.header.crc(), hasher.finalize());
+
+        let entry = DiskEntry::new(b"hello", b"world").expiring(42);
+        assert_eq!(entry.header.expires_at(), 42);
+        assert!(!entry.header.is_batched());
+
+        let entry = DiskEntry::tombstone(b"hello").batched();
+        assert!(entry.header.is_batched());
+        assert!(entry.header.is_tombstone());
+
+        let mut hasher = entry.header.hasher(FormatVersion::CURRENT);
+        hasher.update(b"hello");
+        assert_eq!(entry.header.crc(), hasher.finalize());
+    }
+
+    #[test]
+    fn keydir_entry_should_expire() {
+        let entry = KeydirEntry::new(0, 0, 0, 0);
+        assert!(!entry.is_expired());
+        assert!(entry.expiring(1).is_expired());
+        assert!(!entry.expiring(DiskEntry::now() + 60).is_expired());
     }
 }
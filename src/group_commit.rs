@@ -0,0 +1,191 @@
+//! Group commit of synchronous writes.
+//!
+//! Writers enqueue a ticket once their entries are written and wait for it to be synced.
+//! A commit thread syncs all the writes enqueued since the previous sync at once, so
+//! concurrent writers share a single fsync.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::errors::StorageError;
+
+/// Queue of writes waiting to be synced.
+#[derive(Debug, Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<CommitState>,
+    cond: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    /// Ticket of the last enqueued write.
+    written: u64,
+    /// Ticket of the last synced write.
+    synced: u64,
+    /// Failed syncs by the last ticket they covered.
+    failures: BTreeMap<u64, Failure>,
+    /// Number of syncs performed.
+    commits: u64,
+    stopped: bool,
+}
+
+/// Sync failure reported to every write it covered.
+#[derive(Debug)]
+struct Failure {
+    /// First ticket covered by the sync.
+    from: u64,
+    /// Number of writers yet to be notified.
+    waiters: u64,
+    kind: io::ErrorKind,
+    message: String,
+}
+
+impl GroupCommit {
+    fn state(&self) -> MutexGuard<'_, CommitState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Registers a written entry, returning the ticket to wait for. Called while holding the
+    /// storage write lock, so a sync started later covers the entry.
+    pub fn enqueue(&self) -> u64 {
+        let mut state = self.state();
+        state.written += 1;
+        self.cond.notify_all();
+
+        state.written
+    }
+
+    /// Blocks until the write with the `ticket` has been synced.
+    pub fn wait(&self, ticket: u64) -> Result<(), StorageError> {
+        let mut state = self.state();
+
+        while state.synced < ticket {
+            state = self
+                .cond
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+
+        let Some((&to, failure)) = state
+            .failures
+            .range_mut(ticket..)
+            .next()
+            .filter(|(_, failure)| failure.from <= ticket)
+        else {
+            return Ok(());
+        };
+
+        let error = io::Error::new(failure.kind, failure.message.clone());
+        failure.waiters -= 1;
+
+        if failure.waiters == 0 {
+            state.failures.remove(&to);
+        }
+
+        Err(error.into())
+    }
+
+    /// Syncs the enqueued writes with `sync` until stopped, all the writes enqueued during
+    /// a sync at once by the next one.
+    pub fn run(&self, mut sync: impl FnMut() -> Result<(), StorageError>) {
+        let mut state = self.state();
+
+        loop {
+            while state.written == state.synced && !state.stopped {
+                state = self
+                    .cond
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+
+            if state.written == state.synced {
+                return;
+            }
+
+            let (from, to) = (state.synced + 1, state.written);
+            drop(state);
+
+            let res = sync();
+
+            state = self.state();
+            state.synced = to;
+            state.commits += 1;
+
+            if let Err(e) = res {
+                let kind = match &e {
+                    StorageError::IoError(e) | StorageError::DiskFull(e) => e.kind(),
+                    _ => io::ErrorKind::Other,
+                };
+
+                state.failures.insert(
+                    to,
+                    Failure {
+                        from,
+                        waiters: to - from + 1,
+                        kind,
+                        message: e.to_string(),
+                    },
+                );
+            }
+
+            self.cond.notify_all();
+        }
+    }
+
+    /// Makes `run` return once the enqueued writes are synced.
+    pub fn stop(&self) {
+        self.state().stopped = true;
+        self.cond.notify_all();
+    }
+
+    /// Number of syncs performed.
+    #[cfg(test)]
+    pub fn commits(&self) -> u64 {
+        self.state().commits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn group_commit_should_report_failed_syncs_to_covered_writes() {
+        let commit = Arc::new(GroupCommit::default());
+
+        let first = commit.enqueue();
+        let second = commit.enqueue();
+
+        let runner = {
+            let commit = commit.clone();
+            let mut syncs = 0;
+
+            thread::spawn(move || {
+                commit.run(|| {
+                    syncs += 1;
+
+                    match syncs {
+                        1 => Err(io::Error::other("disk is gone").into()),
+                        _ => Ok(()),
+                    }
+                })
+            })
+        };
+
+        assert!(commit.wait(first).is_err());
+        assert!(commit.wait(second).is_err());
+        assert!(commit.state().failures.is_empty());
+
+        let third = commit.enqueue();
+        assert!(commit.wait(third).is_ok());
+
+        commit.stop();
+        runner.join().unwrap();
+        assert_eq!(commit.commits(), 2);
+    }
+}
@@ -0,0 +1,230 @@
+//! gRPC service, enabled by the `grpc` feature.
+//!
+//! `RumDbService` serves the `rumdb.v1.RumDb` service defined in `proto/rumdb.proto` over
+//! a `Database`. Storage operations block, so they run on the blocking thread pool of tokio.
+//! Scans stream pairs through a bounded channel as they are read, so neither the server nor
+//! a slow client holds the whole result in memory.
+
+use std::pin::Pin;
+
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status};
+
+use crate::{errors::StorageError, Database};
+
+use proto::{
+    rum_db_server::{RumDb, RumDbServer},
+    DeleteRequest, DeleteResponse, GetRequest, GetResponse, KeyValue, PutRequest, PutResponse,
+    ScanRequest, StatsRequest, StatsResponse,
+};
+
+/// Messages and service stubs generated from `proto/rumdb.proto`.
+pub mod proto {
+    tonic::include_proto!("rumdb.v1");
+}
+
+/// Key-value pairs a scan reads ahead of the client.
+const SCAN_BUFFER_SIZE: usize = 256;
+
+/// `rumdb.v1.RumDb` service over a database.
+#[derive(Debug, Clone)]
+pub struct RumDbService {
+    db: Database,
+}
+
+impl RumDbService {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    /// Wraps the service into a server to be added to a `tonic::transport::Server`.
+    pub fn into_server(self) -> RumDbServer<Self> {
+        RumDbServer::new(self)
+    }
+
+    /// Runs the storage operation `f` on the blocking thread pool.
+    async fn run<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, StorageError> + Send + 'static,
+    {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || f(&db))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+type ScanStream = Pin<Box<dyn Stream<Item = Result<KeyValue, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl RumDb for RumDbService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        let value = self.run(move |db| db.get(&key)).await?;
+
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let PutRequest { key, value } = request.into_inner();
+        self.run(move |db| db.put(key, value)).await?;
+
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let key = request.into_inner().key;
+        self.run(move |db| db.remove(&key)).await?;
+
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    type ScanStream = ScanStream;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let prefix = request.into_inner().prefix;
+        let iter = self.run(move |db| db.scan_iter(&prefix)).await?.sorted();
+        let (tx, rx) = mpsc::channel(SCAN_BUFFER_SIZE);
+
+        // Reading stops once the client is gone and the receiver is dropped.
+        tokio::task::spawn_blocking(move || {
+            for pair in iter {
+                let pair = pair
+                    .map(|(key, value)| KeyValue { key, value })
+                    .map_err(|e| Status::internal(e.to_string()));
+                let failed = pair.is_err();
+
+                if tx.blocking_send(pair).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let stats = self.run(|db| db.storage_stats()).await?;
+
+        Ok(Response::new(StatsResponse {
+            keys: stats.keys as u64,
+            keydir_memory: stats.keydir_memory as u64,
+            log_files: stats.log_files as u64,
+            disk_usage: stats.disk_usage,
+            live_bytes: stats.live_bytes,
+            dead_bytes: stats.dead_bytes,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn grpc_service_should_serve_requests() {
+        let dir = tempdir::TempDir::new("grpc-test").unwrap();
+        let service = RumDbService::new(Database::open(dir.path()).unwrap());
+
+        for (key, value) in [
+            ("user:2", "bob"),
+            ("user:1", "alice"),
+            ("group:1", "admins"),
+        ] {
+            service
+                .put(Request::new(PutRequest {
+                    key: key.into(),
+                    value: value.into(),
+                }))
+                .await
+                .unwrap();
+        }
+
+        let get = |key: &str| {
+            service.get(Request::new(GetRequest {
+                key: key.as_bytes().to_vec(),
+            }))
+        };
+
+        assert_eq!(
+            get("user:1").await.unwrap().into_inner().value,
+            Some(b"alice".to_vec())
+        );
+
+        service
+            .delete(Request::new(DeleteRequest {
+                key: b"user:1".to_vec(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(get("user:1").await.unwrap().into_inner().value, None);
+
+        let pairs: Vec<_> = service
+            .scan(Request::new(ScanRequest {
+                prefix: b"user:".to_vec(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|kv| kv.unwrap().key)
+            .collect()
+            .await;
+        assert_eq!(pairs, [b"user:2".to_vec()]);
+
+        let stats = service
+            .stats(Request::new(StatsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(stats.keys, 2);
+        assert!(stats.dead_bytes > 0);
+    }
+
+    #[tokio::test]
+    async fn grpc_service_should_stream_scans_in_key_order() {
+        let dir = tempdir::TempDir::new("grpc-test").unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let service = RumDbService::new(db.clone());
+        let count = SCAN_BUFFER_SIZE as u32 * 4;
+
+        for i in (0..count).rev() {
+            db.put(i.to_be_bytes().to_vec(), b"old".to_vec()).unwrap();
+        }
+
+        let scan = || service.scan(Request::new(ScanRequest { prefix: Vec::new() }));
+
+        let mut stream = scan().await.unwrap().into_inner();
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.key, 0u32.to_be_bytes());
+
+        // Pairs written after the scan started aren't seen.
+        for i in 0..count {
+            db.put(i.to_be_bytes().to_vec(), b"new".to_vec()).unwrap();
+        }
+
+        let pairs: Vec<_> = stream.map(|kv| kv.unwrap()).collect().await;
+        assert_eq!(pairs.len(), count as usize - 1);
+        assert!(pairs.windows(2).all(|pair| pair[0].key < pair[1].key));
+        assert!(pairs.iter().all(|kv| kv.value == b"old"));
+
+        // Dropping a stream part way through stops the scan.
+        let mut stream = scan().await.unwrap().into_inner();
+        assert_eq!(stream.next().await.unwrap().unwrap().value, b"new");
+        drop(stream);
+    }
+}
@@ -3,61 +3,721 @@
 //! Keydir is an in-memory structure that maps all keys to their
 //! corresponding locations on disk.
 
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::BuildHasher,
+    mem,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        PoisonError, RwLock,
+    },
+};
 
-use crate::format::KeydirEntry;
+pub use crate::format::KeydirEntry;
+use crate::DbOptions;
 
 pub trait Keydir {
-    /// Returns a reference to the corresponding entry.
-    fn get(&self, k: &[u8]) -> Option<&KeydirEntry>;
+    /// Returns the corresponding entry.
+    fn get(&self, k: &[u8]) -> Option<KeydirEntry>;
 
     /// Puts a key and entry into the Keydir.
     fn put(&mut self, k: Vec<u8>, v: KeydirEntry);
 
-    /// Removes an entry from the Keydir.
-    fn remove(&mut self, k: &[u8]);
+    /// Removes an entry from the Keydir, returning it if the key existed.
+    fn remove(&mut self, k: &[u8]) -> Option<KeydirEntry>;
+
+    /// Number of keys in the Keydir.
+    fn len(&self) -> usize;
+
+    /// Whether the Keydir is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate number of bytes the Keydir takes in memory: key bytes plus per-entry
+    /// overhead. Allocator overhead and spare capacity are not accounted for.
+    fn approximate_memory_usage(&self) -> usize;
+
+    /// Returns an iterator over all keys and their entries.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + '_>;
+
+    /// Returns an iterator over all keys starting with `prefix` and their entries.
+    fn iter_prefix<'a>(
+        &'a self,
+        prefix: &'a [u8],
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + 'a> {
+        Box::new(self.iter().filter(move |(k, _)| k.starts_with(prefix)))
+    }
+}
+
+/// Keydir keeping keys in lexicographic order.
+pub trait OrderedKeydir: Keydir {
+    /// Returns an iterator over keys within the bounds and their entries, in ascending order.
+    fn range<'a>(
+        &'a self,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + 'a>;
+
+    /// Returns an iterator over keys within the bounds and their entries, in descending order.
+    fn range_rev<'a>(
+        &'a self,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + 'a>;
 }
 
-pub trait KeydirDefault: Default {}
+pub trait KeydirDefault: Default {
+    /// Creates an empty keydir configured with the database options.
+    fn with_options(_opts: &DbOptions) -> Self {
+        Self::default()
+    }
+}
 
 /// Keydir represented as a hashmap.
+///
+/// Keys are hashed with SipHash by default. A faster hasher can be plugged in through the `S`
+/// parameter, e.g. `AHashmapKeydir` or `FxHashmapKeydir` with the `ahash` or `fxhash`
+/// feature enabled.
+#[derive(Default)]
+pub struct HashmapKeydir<S = RandomState> {
+    mapping: HashMap<Vec<u8>, KeydirEntry, S>,
+    key_bytes: usize,
+}
+
+/// Hashmap keydir using aHash.
+#[cfg(feature = "ahash")]
+pub type AHashmapKeydir = HashmapKeydir<ahash::RandomState>;
+
+/// Hashmap keydir using FxHash.
+#[cfg(feature = "fxhash")]
+pub type FxHashmapKeydir = HashmapKeydir<rustc_hash::FxBuildHasher>;
+
+impl<S> HashmapKeydir<S> {
+    /// Creates an empty keydir which uses `hasher` to hash keys.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self {
+            mapping: HashMap::with_hasher(hasher),
+            key_bytes: 0,
+        }
+    }
+}
+
+impl<S> fmt::Debug for HashmapKeydir<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HashmapKeydir")
+            .field("mapping", &self.mapping)
+            .field("key_bytes", &self.key_bytes)
+            .finish()
+    }
+}
+
+/// Memory taken by a hashmap keydir entry besides the key bytes.
+const HASHMAP_ENTRY_OVERHEAD: usize = mem::size_of::<(Vec<u8>, KeydirEntry)>();
+
+impl<S: BuildHasher> Keydir for HashmapKeydir<S> {
+    fn get(&self, key: &[u8]) -> Option<KeydirEntry> {
+        self.mapping.get(key).copied()
+    }
+
+    fn put(&mut self, k: Vec<u8>, v: KeydirEntry) {
+        let key_size = k.len();
+
+        if self.mapping.insert(k, v).is_none() {
+            self.key_bytes += key_size;
+        }
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Option<KeydirEntry> {
+        let (k, v) = self.mapping.remove_entry(k)?;
+        self.key_bytes -= k.len();
+
+        Some(v)
+    }
+
+    fn len(&self) -> usize {
+        self.mapping.len()
+    }
+
+    fn approximate_memory_usage(&self) -> usize {
+        self.key_bytes + self.mapping.len() * HASHMAP_ENTRY_OVERHEAD
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + '_> {
+        Box::new(self.mapping.iter().map(|(k, v)| (k.clone(), *v)))
+    }
+}
+
+impl<S: BuildHasher + Default> KeydirDefault for HashmapKeydir<S> {}
+
+type Shard = RwLock<HashMap<Vec<u8>, KeydirEntry>>;
+
+/// Default number of `ShardedKeydir` shards.
+pub const DEFAULT_KEYDIR_SHARDS: usize = 16;
+
+/// Keydir partitioned across several hashmaps, each protected by its own lock.
+///
+/// Besides the `Keydir` trait, it provides `&self` methods which can be used from several
+/// threads at once, contending only on the shard of the key.
+#[derive(Debug)]
+pub struct ShardedKeydir {
+    shards: Box<[Shard]>,
+    hasher: RandomState,
+    key_bytes: AtomicUsize,
+}
+
+impl ShardedKeydir {
+    /// Creates an empty keydir with `shards` shards.
+    pub fn with_shards(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+            key_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of shards.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Puts a key and entry into the keydir, locking only the shard of the key.
+    pub fn insert(&self, k: Vec<u8>, v: KeydirEntry) {
+        let key_size = k.len();
+        let mut shard = self
+            .shard(&k)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if shard.insert(k, v).is_none() {
+            self.key_bytes.fetch_add(key_size, Ordering::Relaxed);
+        }
+    }
+
+    /// Removes an entry from the keydir, locking only the shard of the key. Returns the
+    /// entry if the key existed.
+    pub fn delete(&self, k: &[u8]) -> Option<KeydirEntry> {
+        let (k, v) = self
+            .shard(k)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove_entry(k)?;
+
+        self.key_bytes.fetch_sub(k.len(), Ordering::Relaxed);
+
+        Some(v)
+    }
+
+    fn shard(&self, k: &[u8]) -> &Shard {
+        let shard = self.hasher.hash_one(k) as usize % self.shards.len();
+
+        &self.shards[shard]
+    }
+}
+
+impl Default for ShardedKeydir {
+    fn default() -> Self {
+        Self::with_shards(DEFAULT_KEYDIR_SHARDS)
+    }
+}
+
+impl Keydir for ShardedKeydir {
+    fn get(&self, k: &[u8]) -> Option<KeydirEntry> {
+        self.shard(k)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(k)
+            .copied()
+    }
+
+    fn put(&mut self, k: Vec<u8>, v: KeydirEntry) {
+        self.insert(k, v);
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Option<KeydirEntry> {
+        self.delete(k)
+    }
+
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner).len())
+            .sum()
+    }
+
+    fn approximate_memory_usage(&self) -> usize {
+        self.key_bytes.load(Ordering::Relaxed) + self.len() * HASHMAP_ENTRY_OVERHEAD
+    }
+
+    /// Iterates shard by shard. Each shard is locked only while its entries are copied.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + '_> {
+        Box::new(self.shards.iter().flat_map(|shard| {
+            shard
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect::<Vec<_>>()
+        }))
+    }
+}
+
+impl KeydirDefault for ShardedKeydir {
+    fn with_options(opts: &DbOptions) -> Self {
+        Self::with_shards(opts.keydir_shards)
+    }
+}
+
+/// Keydir represented as a radix tree.
+///
+/// Keys sharing a prefix share the tree nodes storing it, which makes the keydir compact for
+/// large sets of keys with long common prefixes. Keys are kept in lexicographic order, so
+/// the keydir supports ordered iteration, range and efficient prefix scans.
+#[derive(Default, Debug)]
+pub struct RadixKeydir {
+    root: RadixNode,
+    len: usize,
+}
+
+/// Radix tree node. Children are sorted by the first byte of their prefix, which is unique
+/// among siblings.
 #[derive(Default, Debug)]
-pub struct HashmapKeydir {
-    mapping: HashMap<Vec<u8>, KeydirEntry>,
+struct RadixNode {
+    prefix: Vec<u8>,
+    entry: Option<KeydirEntry>,
+    children: Vec<RadixNode>,
 }
 
-impl Keydir for HashmapKeydir {
-    fn get(&self, key: &[u8]) -> Option<&KeydirEntry> {
-        self.mapping.get(key)
+impl RadixNode {
+    fn child(&self, byte: u8) -> Result<usize, usize> {
+        self.children
+            .binary_search_by_key(&byte, |child| child.prefix[0])
+    }
+
+    /// Puts the entry under the `key`, relative to this node.
+    fn put(&mut self, key: &[u8], entry: KeydirEntry) -> Option<KeydirEntry> {
+        if key.is_empty() {
+            return self.entry.replace(entry);
+        }
+
+        let i = match self.child(key[0]) {
+            Ok(i) => i,
+            Err(i) => {
+                self.children.insert(
+                    i,
+                    RadixNode {
+                        prefix: key.to_vec(),
+                        entry: Some(entry),
+                        children: Vec::new(),
+                    },
+                );
+
+                return None;
+            }
+        };
+
+        let child = &mut self.children[i];
+        let common = child
+            .prefix
+            .iter()
+            .zip(key)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        if common < child.prefix.len() {
+            let mut tail = std::mem::take(child);
+            let head = tail.prefix.drain(..common).collect();
+
+            *child = RadixNode {
+                prefix: head,
+                entry: None,
+                children: vec![tail],
+            };
+        }
+
+        child.put(&key[common..], entry)
+    }
+
+    /// Removes the entry under the `key`, relative to this node.
+    fn remove(&mut self, key: &[u8]) -> Option<KeydirEntry> {
+        if key.is_empty() {
+            return self.entry.take();
+        }
+
+        let i = self.child(key[0]).ok()?;
+        let child = &mut self.children[i];
+
+        if !key.starts_with(&child.prefix) {
+            return None;
+        }
+
+        let prefix_len = child.prefix.len();
+        let removed = child.remove(&key[prefix_len..]);
+
+        if child.entry.is_none() {
+            match child.children.len() {
+                0 => {
+                    self.children.remove(i);
+                }
+                1 => {
+                    let grandchild = child.children.pop().unwrap();
+                    child.prefix.extend_from_slice(&grandchild.prefix);
+                    child.entry = grandchild.entry;
+                    child.children = grandchild.children;
+                }
+                _ => (),
+            }
+        }
+
+        removed
+    }
+
+    fn get(&self, key: &[u8]) -> Option<KeydirEntry> {
+        if key.is_empty() {
+            return self.entry;
+        }
+
+        let child = &self.children[self.child(key[0]).ok()?];
+
+        key.strip_prefix(child.prefix.as_slice())
+            .and_then(|key| child.get(key))
+    }
+}
+
+/// Radix tree iterator over keys within bounds.
+struct RadixIter<'a> {
+    /// Nodes to visit along with the length of their parent key.
+    /// Reverse iteration revisits a node after its children, the flag marks such a visit.
+    stack: Vec<(&'a RadixNode, usize, bool)>,
+    key: Vec<u8>,
+    start: Bound<&'a [u8]>,
+    end: Bound<&'a [u8]>,
+    reverse: bool,
+}
+
+impl<'a> RadixIter<'a> {
+    fn new(
+        root: &'a RadixNode,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+        reverse: bool,
+    ) -> Self {
+        Self {
+            stack: vec![(root, 0, false)],
+            key: Vec::new(),
+            start,
+            end,
+            reverse,
+        }
+    }
+
+    /// Whether all keys of the subtree at the current key are before the start bound.
+    fn subtree_before_start(&self) -> bool {
+        match self.start {
+            Bound::Unbounded => false,
+            Bound::Included(start) | Bound::Excluded(start) => {
+                self.key.as_slice() < start && !start.starts_with(&self.key)
+            }
+        }
+    }
+
+    /// Whether all keys of the subtree at the current key are after the end bound.
+    fn subtree_after_end(&self) -> bool {
+        match self.end {
+            Bound::Unbounded => false,
+            Bound::Included(end) => self.key.as_slice() > end,
+            Bound::Excluded(end) => self.key.as_slice() >= end,
+        }
+    }
+
+    fn within_bounds(&self) -> bool {
+        let key = self.key.as_slice();
+
+        let after_start = match self.start {
+            Bound::Unbounded => true,
+            Bound::Included(start) => key >= start,
+            Bound::Excluded(start) => key > start,
+        };
+
+        after_start && !self.subtree_after_end()
+    }
+}
+
+impl Iterator for RadixIter<'_> {
+    type Item = (Vec<u8>, KeydirEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, depth, visited)) = self.stack.pop() {
+            self.key.truncate(depth);
+            self.key.extend_from_slice(&node.prefix);
+
+            let depth = self.key.len();
+
+            if !self.reverse {
+                if self.subtree_before_start() {
+                    continue;
+                }
+
+                if self.subtree_after_end() {
+                    self.stack.clear();
+                    return None;
+                }
+
+                self.stack.extend(
+                    node.children
+                        .iter()
+                        .rev()
+                        .map(|child| (child, depth, false)),
+                );
+            } else if !visited {
+                if self.subtree_after_end() {
+                    continue;
+                }
+
+                if self.subtree_before_start() {
+                    self.stack.clear();
+                    return None;
+                }
+
+                self.stack.push((node, depth - node.prefix.len(), true));
+                self.stack
+                    .extend(node.children.iter().map(|child| (child, depth, false)));
+
+                continue;
+            }
+
+            if let Some(entry) = node.entry {
+                if self.within_bounds() {
+                    return Some((self.key.clone(), entry));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Keydir for RadixKeydir {
+    fn get(&self, k: &[u8]) -> Option<KeydirEntry> {
+        self.root.get(k)
     }
 
     fn put(&mut self, k: Vec<u8>, v: KeydirEntry) {
-        self.mapping.insert(k, v);
+        if self.root.put(&k, v).is_none() {
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Option<KeydirEntry> {
+        let removed = self.root.remove(k)?;
+        self.len -= 1;
+
+        Some(removed)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Walks the whole tree, accounting for prefix bytes and node overhead.
+    fn approximate_memory_usage(&self) -> usize {
+        let mut usage = 0;
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            usage += node.prefix.len() + mem::size_of::<RadixNode>();
+            stack.extend(&node.children);
+        }
+
+        usage
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + '_> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    fn iter_prefix<'a>(
+        &'a self,
+        prefix: &'a [u8],
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + 'a> {
+        Box::new(
+            self.range(Bound::Included(prefix), Bound::Unbounded)
+                .take_while(move |(k, _)| k.starts_with(prefix)),
+        )
+    }
+}
+
+impl OrderedKeydir for RadixKeydir {
+    fn range<'a>(
+        &'a self,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + 'a> {
+        Box::new(RadixIter::new(&self.root, start, end, false))
     }
 
-    fn remove(&mut self, k: &[u8]) {
-        self.mapping.remove(k);
+    fn range_rev<'a>(
+        &'a self,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, KeydirEntry)> + 'a> {
+        Box::new(RadixIter::new(&self.root, start, end, true))
     }
 }
 
-impl KeydirDefault for HashmapKeydir {}
+impl KeydirDefault for RadixKeydir {}
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::hash_map::DefaultHasher, hash::BuildHasherDefault, sync::Arc, thread};
+
+    use rand::Rng;
+
     use super::*;
 
     fn test_keydir(mut keydir: impl Keydir) {
         assert_eq!(keydir.get(b"hello"), None);
+        assert!(keydir.is_empty());
 
+        let empty_usage = keydir.approximate_memory_usage();
         let entry = KeydirEntry::new(0, 1, 2, 3);
 
-        keydir.put(b"hello".to_vec(), entry.clone());
+        keydir.put(b"hello".to_vec(), entry);
 
-        assert_eq!(keydir.get(b"hello"), Some(&entry));
+        assert_eq!(keydir.get(b"hello"), Some(entry));
+        assert_eq!(keydir.len(), 1);
+
+        let usage = keydir.approximate_memory_usage();
+        assert!(usage >= empty_usage + b"hello".len());
+
+        keydir.put(b"hello".to_vec(), entry);
+        assert_eq!(keydir.len(), 1);
+        assert_eq!(keydir.approximate_memory_usage(), usage);
+
+        let entries: Vec<_> = keydir.iter().collect();
+        assert_eq!(entries, vec![(b"hello".to_vec(), entry)]);
+
+        assert_eq!(keydir.remove(b"hello"), Some(entry));
+        assert_eq!(keydir.remove(b"hello"), None);
+
+        assert_eq!(keydir.get(b"hello"), None);
+        assert!(keydir.is_empty());
+        assert_eq!(keydir.approximate_memory_usage(), empty_usage);
     }
 
     #[test]
     fn hashmap_keydir_should_implement_keydir() {
-        test_keydir(HashmapKeydir::default());
+        test_keydir(<HashmapKeydir>::default());
+        test_keydir(HashmapKeydir::with_hasher(BuildHasherDefault::<
+            DefaultHasher,
+        >::default()));
+
+        #[cfg(feature = "ahash")]
+        test_keydir(AHashmapKeydir::default());
+
+        #[cfg(feature = "fxhash")]
+        test_keydir(FxHashmapKeydir::default());
+    }
+
+    #[test]
+    fn radix_keydir_should_implement_keydir() {
+        test_keydir(RadixKeydir::default());
+    }
+
+    #[test]
+    fn radix_keydir_should_keep_keys_ordered() {
+        let mut rng = rand::thread_rng();
+        let mut keydir = RadixKeydir::default();
+        let mut expected = std::collections::BTreeMap::new();
+
+        for i in 0..2000u64 {
+            let len = rng.gen_range(0..6);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a'..b'e')).collect();
+            let entry = KeydirEntry::new(0, 0, i, 0);
+
+            if rng.gen_bool(0.3) {
+                keydir.remove(&key);
+                expected.remove(&key);
+            } else {
+                keydir.put(key.clone(), entry);
+                expected.insert(key, entry);
+            }
+        }
+
+        assert_eq!(keydir.len(), expected.len());
+        assert_eq!(
+            keydir.iter().collect::<Vec<_>>(),
+            expected.clone().into_iter().collect::<Vec<_>>()
+        );
+
+        for (k, v) in expected.iter() {
+            assert_eq!(keydir.get(k), Some(*v));
+        }
+
+        let bounds = [
+            (Bound::Included(&b"b"[..]), Bound::Excluded(&b"cc"[..])),
+            (Bound::Excluded(b"b"), Bound::Included(b"cc")),
+            (Bound::Unbounded, Bound::Included(b"")),
+            (Bound::Included(b"abc"), Bound::Unbounded),
+            (Bound::Excluded(b"dddd"), Bound::Unbounded),
+        ];
+
+        for (start, end) in bounds {
+            let range: Vec<_> = expected
+                .range::<[u8], _>((start, end))
+                .map(|(k, v)| (k.clone(), *v))
+                .collect();
+
+            assert_eq!(keydir.range(start, end).collect::<Vec<_>>(), range);
+
+            let range_rev: Vec<_> = range.into_iter().rev().collect();
+            assert_eq!(keydir.range_rev(start, end).collect::<Vec<_>>(), range_rev);
+        }
+
+        let prefix: Vec<_> = expected
+            .iter()
+            .filter(|(k, _)| k.starts_with(b"ab"))
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        assert_eq!(keydir.iter_prefix(b"ab").collect::<Vec<_>>(), prefix);
+    }
+
+    #[test]
+    fn sharded_keydir_should_implement_keydir() {
+        test_keydir(ShardedKeydir::default());
+        test_keydir(ShardedKeydir::with_shards(1));
+    }
+
+    #[test]
+    fn sharded_keydir_should_be_shared_between_threads() {
+        let keydir = Arc::new(ShardedKeydir::with_shards(4));
+        assert_eq!(keydir.shards(), 4);
+
+        let handles: Vec<_> = (0..4u32)
+            .map(|t| {
+                let keydir = keydir.clone();
+                thread::spawn(move || {
+                    for i in 0..100u32 {
+                        let k = (t * 100 + i).to_le_bytes().to_vec();
+                        keydir.insert(k, KeydirEntry::new(t, 0, i as u64, 0));
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(keydir.iter().count(), 400);
+        assert_eq!(
+            keydir.get(&250u32.to_le_bytes()),
+            Some(KeydirEntry::new(2, 0, 50, 0))
+        );
+
+        keydir.delete(&250u32.to_le_bytes());
+        assert_eq!(keydir.get(&250u32.to_le_bytes()), None);
     }
 }
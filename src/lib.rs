@@ -1,26 +1,221 @@
-#![feature(map_first_last)]
+use std::{num::NonZeroUsize, sync::Arc, thread, time::Duration};
 
+use clock::{Clock, SystemClock};
 use keydir::HashmapKeydir;
+use observer::StorageObserver;
 use storage::DiskStorage;
+use vfs::{StdVfs, Vfs};
 
+#[cfg(feature = "admin")]
+pub mod admin;
+mod bitcask;
+pub mod changes;
+pub mod clock;
+mod database;
+mod dump;
+pub mod encoding;
+mod epoch;
 pub mod errors;
+mod file_cache;
+mod footer;
 mod format;
-mod keydir;
+mod group_commit;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod keydir;
+pub mod log_reader;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod observer;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+pub mod replication;
+mod sharded;
+mod snapshot;
 pub mod storage;
+mod value_cache;
+pub mod vfs;
+
+pub use database::{Database, Keyspace, Pipeline, ScanIter, Txn};
+pub use sharded::ShardedDb;
+pub use storage::{destroy, destroy_with_vfs, exists, exists_with_vfs};
+
+/// Commonly used types.
+pub mod prelude {
+    pub use crate::{
+        errors::StorageError,
+        storage::{PutOptions, ReadOptions, Storage, WriteBatch},
+        Database, DbOptions, Keyspace, RumDb, ShardedDb,
+    };
+}
 
 pub type RumDb = DiskStorage<HashmapKeydir>;
 
+/// Merge operator folding a merge operand into the existing value of the key.
+/// Returning `None` removes the key.
+pub type MergeOperator = fn(key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>>;
+
+/// How corrupted log files are handled on open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Opening fails on a corrupted entry. Only an incomplete trailing entry of the active
+    /// log file, left by a crash, is truncated.
+    #[default]
+    Strict,
+    /// Corrupted entries are skipped, scanning resumes at the next entry with a matching
+    /// checksum. Checksums of all log files are verified and the keydir snapshot is ignored.
+    SkipCorrupted,
+}
+
+/// How writes are stalled once the storage is past a threshold set by
+/// `DbOptions::stall_fragmentation` or `DbOptions::stall_log_files`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WriteStallMode {
+    /// Writes fail with `StorageError::WriteStalled`.
+    #[default]
+    Reject,
+    /// Writes are delayed by the duration before proceeding.
+    Delay(Duration),
+}
+
 /// Database options.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DbOptions {
     /// Maximum log file size in bytes.
     max_log_file_size: usize,
+
+    /// Merge operator used to fold merge operands.
+    merge_operator: Option<MergeOperator>,
+
+    /// Number of shards of a sharded keydir.
+    keydir_shards: usize,
+
+    /// Whether to snapshot the keydir on close to speed up the next open.
+    keydir_snapshot: bool,
+
+    /// Number of threads sealed log files are ingested on when building the keydir.
+    keydir_build_threads: usize,
+
+    /// Size of the active log file write buffer in bytes. Buffered entries are lost on
+    /// a crash unless flushed. Zero writes entries through.
+    write_buffer_size: usize,
+
+    /// Filesystem the storage files are accessed through.
+    vfs: Arc<dyn Vfs>,
+
+    /// How corrupted log files are handled on open.
+    recovery_mode: RecoveryMode,
+
+    /// Receiver of storage events.
+    observer: Option<Arc<dyn StorageObserver>>,
+
+    /// How long log files without live entries are kept for point-in-time reads.
+    history_retention: Duration,
+
+    /// How long log files removed by GC are kept in the trash directory.
+    trash_retention: Duration,
+
+    /// Maximum number of sealed log files kept open.
+    max_open_files: usize,
+
+    /// Capacity of the value cache in bytes.
+    value_cache_size: usize,
+
+    /// Whether log files are opened with `Vfs::open_direct`.
+    direct_io: bool,
+
+    /// Whether writes are synced before returning.
+    sync_writes: bool,
+
+    /// Whether sealed log files are memory-mapped.
+    #[cfg(feature = "mmap")]
+    mmap_reads: bool,
+
+    /// Number of previous versions retained for every key.
+    history_versions: usize,
+
+    /// Timestamp the storage has been opened at by `DiskStorage::open_at`.
+    open_at: Option<u64>,
+
+    /// Maximum key size in bytes.
+    max_key_size: usize,
+
+    /// Maximum value size in bytes.
+    max_value_size: u64,
+
+    /// Whether new log files use varint-encoded entry headers.
+    compact_headers: bool,
+
+    /// Source of entry timestamps.
+    clock: Arc<dyn Clock>,
+
+    /// Share of dead bytes past which writes are stalled.
+    stall_fragmentation: Option<f64>,
+
+    /// Number of log files past which writes are stalled.
+    stall_log_files: Option<usize>,
+
+    /// How writes are stalled.
+    write_stall_mode: WriteStallMode,
+
+    /// Maximum size of the log files in bytes.
+    max_db_size: Option<u64>,
+
+    /// Share of dead bytes past which sealed log files are merged in the background.
+    merge_fragmentation: Option<f64>,
+
+    /// Bytes of live entries merges may rewrite per `merge_interval`.
+    merge_budget: u64,
+
+    /// Interval the merge budget is granted for.
+    merge_interval: Duration,
+
+    /// Maximum compaction throughput in bytes per second.
+    compaction_rate_limit: Option<u64>,
+
+    /// Whether `Database` repairs missing log files found by reads.
+    repair_missing_segments: bool,
+
+    /// Maximum number of expired keys `Database` removes per maintenance run.
+    ttl_sweep_limit: usize,
 }
 
 impl Default for DbOptions {
     fn default() -> Self {
         Self {
             max_log_file_size: 100 * 1024 * 1024, // 100 MB
+            merge_operator: None,
+            keydir_shards: keydir::DEFAULT_KEYDIR_SHARDS,
+            keydir_snapshot: true,
+            keydir_build_threads: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            write_buffer_size: 0,
+            vfs: Arc::new(StdVfs),
+            recovery_mode: RecoveryMode::Strict,
+            observer: None,
+            history_retention: Duration::ZERO,
+            trash_retention: Duration::ZERO,
+            history_versions: 0,
+            max_open_files: 1000,
+            value_cache_size: 0,
+            direct_io: false,
+            sync_writes: false,
+            #[cfg(feature = "mmap")]
+            mmap_reads: false,
+            open_at: None,
+            max_key_size: u32::MAX as usize,
+            max_value_size: u64::MAX,
+            compact_headers: false,
+            clock: Arc::new(SystemClock),
+            stall_fragmentation: None,
+            stall_log_files: None,
+            write_stall_mode: WriteStallMode::Reject,
+            max_db_size: None,
+            merge_fragmentation: None,
+            merge_budget: u64::MAX,
+            merge_interval: Duration::from_secs(60),
+            compaction_rate_limit: None,
+            repair_missing_segments: false,
+            ttl_sweep_limit: 0,
         }
     }
 }
@@ -30,4 +225,218 @@ impl DbOptions {
         self.max_log_file_size = value;
         self
     }
+
+    pub fn merge_operator(mut self, value: MergeOperator) -> Self {
+        self.merge_operator = Some(value);
+        self
+    }
+
+    pub fn keydir_shards(mut self, value: usize) -> Self {
+        self.keydir_shards = value;
+        self
+    }
+
+    pub fn keydir_snapshot(mut self, value: bool) -> Self {
+        self.keydir_snapshot = value;
+        self
+    }
+
+    pub fn keydir_build_threads(mut self, value: usize) -> Self {
+        self.keydir_build_threads = value;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, value: usize) -> Self {
+        self.write_buffer_size = value;
+        self
+    }
+
+    pub fn vfs(mut self, value: Arc<dyn Vfs>) -> Self {
+        self.vfs = value;
+        self
+    }
+
+    pub fn recovery_mode(mut self, value: RecoveryMode) -> Self {
+        self.recovery_mode = value;
+        self
+    }
+
+    pub fn observer(mut self, value: Arc<dyn StorageObserver>) -> Self {
+        self.observer = Some(value);
+        self
+    }
+
+    /// Keeps log files for the `value` after their last live entry has been overwritten or
+    /// compacted, so `DiskStorage::open_at` can read them. Disabled by default.
+    pub fn history_retention(mut self, value: Duration) -> Self {
+        self.history_retention = value;
+        self
+    }
+
+    /// Moves log files removed by GC into the `trash` subdirectory of the storage instead of
+    /// deleting them, and purges them once they have been there for the `value`, so data
+    /// lost to a liveness accounting bug can still be recovered meanwhile. Disabled by
+    /// default, in which case a leftover trash directory is left as is.
+    pub fn trash_retention(mut self, value: Duration) -> Self {
+        self.trash_retention = value;
+        self
+    }
+
+    /// Keeps at most `value` sealed log files of the storage and of every keyspace open,
+    /// closing the least recently used ones and reopening them on demand. 1000 by default.
+    pub fn max_open_files(mut self, value: usize) -> Self {
+        self.max_open_files = value;
+        self
+    }
+
+    /// Caches up to `value` bytes of recently read values in memory, consulted by `get`
+    /// before reading log files. Disabled by default.
+    pub fn value_cache_size(mut self, value: usize) -> Self {
+        self.value_cache_size = value;
+        self
+    }
+
+    /// Syncs log files before puts, removals and merges return, so acknowledged writes survive
+    /// a power loss. `Database` syncs the writes of concurrent writers together with a single
+    /// fsync. Disabled by default, writes are synced every second by `Database`.
+    pub fn sync_writes(mut self, value: bool) -> Self {
+        self.sync_writes = value;
+        self
+    }
+
+    /// Opens log files with `O_DIRECT` on Linux, bypassing the page cache, for applications
+    /// managing their own cache. Writes are aligned to blocks, so a small write buffer, see
+    /// `write_buffer_size`, is recommended. Disabled by default.
+    pub fn direct_io(mut self, value: bool) -> Self {
+        self.direct_io = value;
+        self
+    }
+
+    /// Reads sealed log files through memory maps instead of a syscall per read. The active
+    /// log file is still read with `pread`. Log files must not be modified by other processes.
+    /// Applies to `StdVfs` only. Disabled by default.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_reads(mut self, value: bool) -> Self {
+        self.mmap_reads = value;
+        self
+    }
+
+    /// Retains up to `value` previous versions of every key for `DiskStorage::get_versions`
+    /// and `DiskStorage::get_at`, keeping the log files holding them. Versions overwritten
+    /// within the same second and versions of merged keys are not retained. The keydir is
+    /// rebuilt from log files on open, without a snapshot. Disabled by default.
+    pub fn history_versions(mut self, value: usize) -> Self {
+        self.history_versions = value;
+        self
+    }
+
+    /// Rejects writes of keys longer than `value` bytes, capped by the 4 GiB the log format
+    /// can store.
+    pub fn max_key_size(mut self, value: usize) -> Self {
+        self.max_key_size = value.min(u32::MAX as usize);
+        self
+    }
+
+    /// Rejects writes of values longer than `value` bytes. Unlimited by default.
+    pub fn max_value_size(mut self, value: u64) -> Self {
+        self.max_value_size = value;
+        self
+    }
+
+    /// Writes new log files with varint-encoded entry headers. Saves up to 16 bytes per
+    /// entry; older releases can't read such files.
+    pub fn compact_headers(mut self, value: bool) -> Self {
+        self.compact_headers = value;
+        self
+    }
+
+    /// Stamps entries and checks their expiration with the `value` clock instead of the
+    /// system clock.
+    pub fn clock(mut self, value: Arc<dyn Clock>) -> Self {
+        self.clock = value;
+        self
+    }
+
+    /// Stalls puts and merges once dead bytes take more than `value` of the disk usage, from
+    /// 0 to 1, until `DiskStorage::compact` catches up. Checked when log files are sealed
+    /// or removed. Disabled by default.
+    pub fn stall_fragmentation(mut self, value: f64) -> Self {
+        self.stall_fragmentation = Some(value);
+        self
+    }
+
+    /// Stalls puts and merges once the storage has more than `value` log files, until
+    /// `DiskStorage::compact` catches up. Disabled by default.
+    pub fn stall_log_files(mut self, value: usize) -> Self {
+        self.stall_log_files = Some(value);
+        self
+    }
+
+    /// How writes are stalled, rejected by default.
+    pub fn write_stall_mode(mut self, value: WriteStallMode) -> Self {
+        self.write_stall_mode = value;
+        self
+    }
+
+    /// Rejects puts and merges which would take the log files past `value` bytes, compacting
+    /// first if that reclaims enough space. Keyspaces have quotas of their own. Unlimited by
+    /// default.
+    pub fn max_db_size(mut self, value: u64) -> Self {
+        self.max_db_size = Some(value);
+        self
+    }
+
+    /// Merges sealed log files with dead bytes taking at least `value` of their size, from 0
+    /// to 1, most fragmented first, see `DiskStorage::merge_fragmented`. `Database` merges
+    /// them in the background. Disabled by default.
+    pub fn merge_fragmentation(mut self, value: f64) -> Self {
+        self.merge_fragmentation = Some(value);
+        self
+    }
+
+    /// Limits merges to rewriting `value` bytes of live entries per `merge_interval`, which
+    /// bounds their write amplification. Unlimited by default.
+    pub fn merge_budget(mut self, value: u64) -> Self {
+        self.merge_budget = value;
+        self
+    }
+
+    /// Interval the `merge_budget` is granted for, a minute by default.
+    pub fn merge_interval(mut self, value: Duration) -> Self {
+        self.merge_interval = value;
+        self
+    }
+
+    /// Throttles compactions and merges to write at most `value` bytes per second, so they
+    /// don't starve reads and writes of disk bandwidth. `Database` releases the storage lock
+    /// while compactions are throttled. Unlimited by default.
+    pub fn compaction_rate_limit(mut self, value: u64) -> Self {
+        self.compaction_rate_limit = Some(value);
+        self
+    }
+
+    /// Makes `Database` repair log files found missing by reads in the background, see
+    /// `DiskStorage::repair_missing_segments`. Disabled by default, so reads of keys in
+    /// missing log files fail with `StorageError::MissingLogFile` until repaired by hand.
+    pub fn repair_missing_segments(mut self, value: bool) -> Self {
+        self.repair_missing_segments = value;
+        self
+    }
+
+    /// Makes `Database` remove up to `value` expired keys about every second in the
+    /// background, see `DiskStorage::sweep_expired`, so expired entries are reclaimed even
+    /// if the keys are never read or written again. Disabled by default, with 0.
+    pub fn ttl_sweep_limit(mut self, value: usize) -> Self {
+        self.ttl_sweep_limit = value;
+        self
+    }
+
+    /// Format version new log files are written in.
+    pub(crate) fn format_version(&self) -> format::FormatVersion {
+        if self.compact_headers {
+            format::FormatVersion::V7
+        } else {
+            format::FormatVersion::CURRENT
+        }
+    }
 }
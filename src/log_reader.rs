@@ -0,0 +1,409 @@
+//! Raw log file reader.
+//!
+//! `LogReader` iterates entries of a single log file as they are stored, superseded entries
+//! and tombstones included, which is useful for debugging corruption and fragmentation.
+//! The footer of a sealed log file is not iterated, its stats are returned by `stats`.
+
+use std::{
+    io::{self, Read},
+    path::Path,
+    sync::Arc,
+};
+
+pub use crate::footer::SegmentStats;
+
+use crate::{
+    errors::{FormatError, StorageError},
+    footer::{IndexEntry, Trailer, FOOTER_MAGIC},
+    format::{FormatVersion, Header, Layout, CHUNK_SIZE, MAX_HEADER_SIZE, MAX_SEGMENT_HEADER_SIZE},
+    vfs::{OpenMode, StdVfs, Vfs, VfsFile, VfsReader},
+};
+
+/// Kind of a log entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Value of a key.
+    Put,
+    /// Removal of a key.
+    Tombstone,
+    /// Merge operand of a key.
+    MergeOperand,
+}
+
+/// Log entry as stored in a log file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// Offset of the entry in the log file.
+    pub offset: u64,
+    /// Size of the whole entry in bytes.
+    pub size: u64,
+    pub timestamp: u64,
+    /// Time the entry expires at, 0 if it never expires.
+    pub expires_at: u64,
+    pub kind: EntryKind,
+    /// Whether more entries of the same atomic batch follow.
+    pub batched: bool,
+    /// Whether the entry is a copy rewritten by a compaction.
+    pub rewritten: bool,
+    pub key: Vec<u8>,
+    /// Offset of the value in the log file.
+    pub value_pos: u64,
+    pub value_size: u64,
+    /// Whether the entry checksum matches. `None` if checksums are not verified or the
+    /// entry has no checksum, as in format version 1.
+    pub checksum_valid: Option<bool>,
+}
+
+/// Iterator over the entries of a log file.
+///
+/// An incomplete trailing entry yields `FormatError::TornEntry` and ends the iteration.
+/// `skip_to_valid_entry` resumes it past a corrupted entry.
+#[derive(Debug)]
+pub struct LogReader {
+    file: Arc<dyn VfsFile>,
+    version: FormatVersion,
+    created_at: Option<u64>,
+    footer: Option<Trailer>,
+    size: u64,
+    pos: u64,
+    verify_checksums: bool,
+}
+
+impl LogReader {
+    /// Opens the log file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::open_with_vfs(&StdVfs, path)
+    }
+
+    /// Opens the log file at `path` of the `vfs`.
+    pub fn open_with_vfs(vfs: &dyn Vfs, path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::new(vfs.open(path.as_ref(), OpenMode::ReadOnly)?)
+    }
+
+    pub(crate) fn new(file: Arc<dyn VfsFile>) -> Result<Self, StorageError> {
+        let size = file.len()?;
+
+        let mut segment_header = [0; MAX_SEGMENT_HEADER_SIZE];
+        let segment_header = &mut segment_header[..MAX_SEGMENT_HEADER_SIZE.min(size as usize)];
+        file.read_exact_at(segment_header, 0)?;
+
+        let version = FormatVersion::detect(segment_header)?;
+
+        if segment_header.len() < version.segment_header_size() {
+            return Err(FormatError::DeserializeError.into());
+        }
+
+        let entries_pos = version.segment_header_size() as u64;
+
+        // Footers are written since format version 4.
+        let footer = match version {
+            FormatVersion::V1 | FormatVersion::V2 | FormatVersion::V3 => None,
+            _ => Trailer::read(&*file, size, entries_pos, version)?,
+        };
+
+        Ok(Self {
+            file,
+            version,
+            created_at: version.created_at(segment_header),
+            footer,
+            size: footer.map_or(size, |footer| footer.footer_pos),
+            pos: entries_pos,
+            verify_checksums: true,
+        })
+    }
+
+    /// Whether to verify entry checksums, which requires reading every value.
+    /// Enabled by default.
+    pub fn verify_checksums(mut self, value: bool) -> Self {
+        self.verify_checksums = value;
+        self
+    }
+
+    /// Format version of the log file.
+    pub fn format_version(&self) -> u8 {
+        self.version as u8
+    }
+
+    pub(crate) fn version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// Layout of the entry headers of the log file.
+    pub(crate) fn layout(&self) -> Layout {
+        Layout::new(self.version, self.created_at.unwrap_or_default())
+    }
+
+    /// Time the log file was created at, `None` before format version 4.
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    /// Size of the log file entries in bytes, the footer excluded.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Stats stored in the footer of a sealed log file, `None` if the log file has no footer.
+    pub fn stats(&self) -> Option<SegmentStats> {
+        self.footer.map(|footer| footer.stats)
+    }
+
+    /// Whether the log file has been sealed with a footer.
+    pub(crate) fn has_footer(&self) -> bool {
+        self.footer.is_some()
+    }
+
+    /// Reads the footer index of every entry of the log file, if it has a footer.
+    pub(crate) fn read_index(&self) -> Result<Option<Vec<IndexEntry>>, StorageError> {
+        let Some(footer) = self.footer else {
+            return Ok(None);
+        };
+
+        Ok(Some(footer.read_index(&*self.file, self.file.len()?)?))
+    }
+
+    /// Moves to the entry at `pos`. Positions inside the segment header move to the first entry.
+    pub fn seek(&mut self, pos: u64) {
+        self.pos = pos.max(self.version.segment_header_size() as u64);
+    }
+
+    /// Reads the value of the `entry`.
+    pub fn read_value(&self, entry: &LogEntry) -> Result<Vec<u8>, StorageError> {
+        let mut value = vec![0; entry.value_size as usize];
+        self.file.read_exact_at(&mut value, entry.value_pos)?;
+
+        Ok(value)
+    }
+
+    /// Moves to the first entry at or after `pos` whose checksum matches, returning its
+    /// offset. Returns `None` if there is no such entry, which is always the case for
+    /// format version 1 log files, having no checksums.
+    pub fn skip_to_valid_entry(&mut self, pos: u64) -> Result<Option<u64>, StorageError> {
+        if self.version == FormatVersion::V1 {
+            return Ok(None);
+        }
+
+        for pos in pos..self.size {
+            match self.entry_at(pos, true) {
+                Ok(entry) if entry.checksum_valid == Some(true) => {
+                    self.pos = pos;
+                    return Ok(Some(pos));
+                }
+                Ok(_) | Err(StorageError::FormatError(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn entry_at(&self, pos: u64, verify_checksum: bool) -> Result<LogEntry, StorageError> {
+        let layout = self.layout();
+
+        // Compact headers are read up to their maximum size.
+        let max_header_size = self.version.header_size();
+        let read_size = max_header_size.min(self.size.saturating_sub(pos) as usize);
+
+        let mut buf = [0; MAX_HEADER_SIZE];
+        self.file.read_exact_at(&mut buf[..read_size], pos)?;
+
+        // A crash while sealing the log file leaves an incomplete footer.
+        if buf[..read_size].starts_with(FOOTER_MAGIC) {
+            return Err(FormatError::TornEntry(pos).into());
+        }
+
+        let header = match Header::decode(&buf[..read_size], layout) {
+            Ok(header) => header,
+            Err(_) if read_size < max_header_size => {
+                return Err(FormatError::TornEntry(pos).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let header_size = header.size(layout);
+        let size = header.entry_size(layout);
+
+        if size > self.size.saturating_sub(pos) {
+            return Err(FormatError::TornEntry(pos).into());
+        }
+
+        let mut key = vec![0; header.key_size()];
+        self.file
+            .read_exact_at(&mut key, pos + header_size as u64)?;
+
+        let value_pos = pos + (header_size + header.key_size()) as u64;
+        let value_size = header.value_size();
+
+        let checksum_valid = if verify_checksum && self.version != FormatVersion::V1 {
+            let mut hasher = header.hasher(self.version);
+            hasher.update(&key);
+            hash_chunks(
+                &mut VfsReader::new(&*self.file, value_pos),
+                value_size,
+                &mut hasher,
+            )?;
+
+            Some(hasher.finalize() == header.crc())
+        } else {
+            None
+        };
+
+        let kind = if header.is_merge_operand() {
+            EntryKind::MergeOperand
+        } else if header.is_tombstone() {
+            EntryKind::Tombstone
+        } else {
+            EntryKind::Put
+        };
+
+        Ok(LogEntry {
+            offset: pos,
+            size,
+            timestamp: header.timestamp(),
+            expires_at: header.expires_at(),
+            kind,
+            batched: header.is_batched(),
+            rewritten: header.is_rewrite(),
+            key,
+            value_pos,
+            value_size,
+            checksum_valid,
+        })
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<LogEntry, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.size {
+            return None;
+        }
+
+        let entry = self.entry_at(self.pos, self.verify_checksums);
+
+        match &entry {
+            Ok(entry) => self.pos += entry.size,
+            Err(_) => self.pos = self.size,
+        }
+
+        Some(entry)
+    }
+}
+
+/// Feeds the next `len` bytes of the `reader` into the `hasher`, chunk by chunk.
+fn hash_chunks(
+    reader: &mut impl Read,
+    len: u64,
+    hasher: &mut crc32fast::Hasher,
+) -> Result<(), io::Error> {
+    let mut chunk = vec![0; CHUNK_SIZE.min(len as usize)];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let chunk_size = chunk.len().min(remaining as usize);
+
+        reader.read_exact(&mut chunk[..chunk_size])?;
+        hasher.update(&chunk[..chunk_size]);
+
+        remaining -= chunk_size as u64;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use crate::{prelude::*, DbOptions};
+
+    use super::*;
+
+    #[test]
+    fn log_reader_should_read_entries() {
+        let dir = tempdir::TempDir::new("log-reader-test").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+
+        {
+            let mut db = RumDb::open(
+                dir.path(),
+                DbOptions::default().merge_operator(|_, _, operand| Some(operand.to_vec())),
+            )
+            .unwrap();
+
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+            db.merge(b"hello".to_vec(), b"!".to_vec()).unwrap();
+            db.remove(b"hello").unwrap();
+        }
+
+        let reader = LogReader::open(&log_path).unwrap();
+        assert_eq!(reader.format_version(), FormatVersion::CURRENT as u8);
+
+        let entries: Vec<_> = LogReader::open(&log_path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let kinds: Vec<_> = entries.iter().map(|entry| entry.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                EntryKind::Put,
+                EntryKind::MergeOperand,
+                EntryKind::Tombstone
+            ]
+        );
+        assert_eq!(
+            entries[0].offset,
+            FormatVersion::CURRENT.segment_header_size() as u64
+        );
+        assert!(reader.created_at().is_some());
+        assert_eq!(entries[1].offset, entries[0].offset + entries[0].size);
+        assert!(entries.iter().all(|entry| entry.key == b"hello"));
+        assert!(entries
+            .iter()
+            .all(|entry| entry.checksum_valid == Some(true)));
+        assert_eq!(reader.read_value(&entries[0]).unwrap(), b"world");
+
+        // Corrupted value and torn trailing entry.
+        let log = OpenOptions::new().write(true).open(&log_path).unwrap();
+        log.set_len(reader.size() - 1).unwrap();
+        std::os::unix::prelude::FileExt::write_at(&log, b"W", entries[0].value_pos).unwrap();
+
+        let entries: Vec<_> = LogReader::open(&log_path).unwrap().collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].as_ref().unwrap().checksum_valid, Some(false));
+        assert!(matches!(
+            entries[2],
+            Err(StorageError::FormatError(FormatError::TornEntry(_)))
+        ));
+
+        let mut reader = LogReader::open(&log_path).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(
+            reader
+                .skip_to_valid_entry(entries[0].as_ref().unwrap().offset + 1)
+                .unwrap(),
+            Some(entries[1].as_ref().unwrap().offset)
+        );
+        assert_eq!(
+            reader.next().unwrap().unwrap().kind,
+            EntryKind::MergeOperand
+        );
+        assert_eq!(
+            reader
+                .skip_to_valid_entry(entries[1].as_ref().unwrap().offset + 1)
+                .unwrap(),
+            None
+        );
+
+        let entry = LogReader::open(&log_path)
+            .unwrap()
+            .verify_checksums(false)
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.checksum_valid, None);
+    }
+}
@@ -0,0 +1,143 @@
+//! Operation metrics, enabled by the `metrics` feature.
+//!
+//! Storage operations are counted and timed in process, see `DiskStorage::metrics`, and
+//! reported through the `metrics` facade, so an installed recorder such as a Prometheus
+//! exporter picks them up as `rumdb_operations_total` and `rumdb_operation_duration_seconds`
+//! labeled by `operation`. With the `opentelemetry` feature they are exported through
+//! OpenTelemetry as well, see `otel`.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Instrumented storage operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    Put,
+    Remove,
+    Compact,
+}
+
+impl Operation {
+    const ALL: [Self; 4] = [Self::Get, Self::Put, Self::Remove, Self::Compact];
+
+    /// Value of the `operation` label.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Get => "get",
+            Self::Put => "put",
+            Self::Remove => "remove",
+            Self::Compact => "compact",
+        }
+    }
+}
+
+/// Statistics of an operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationStats {
+    /// Number of completed operations.
+    pub count: u64,
+    /// Total time spent in the operations.
+    pub total_time: Duration,
+    /// Longest operation time.
+    pub max_time: Duration,
+}
+
+impl OperationStats {
+    /// Mean operation time.
+    pub fn mean_time(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.total_time.as_nanos() / count as u128) as u64),
+        }
+    }
+}
+
+/// Snapshot of the operation metrics of a storage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub get: OperationStats,
+    pub put: OperationStats,
+    pub remove: OperationStats,
+    pub compact: OperationStats,
+}
+
+/// Operation counters, updated without locking so reads can be timed through `&self`.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    operations: [OperationCounters; Operation::ALL.len()],
+    #[cfg(feature = "opentelemetry")]
+    otel: crate::otel::Instruments,
+}
+
+#[derive(Debug, Default)]
+struct OperationCounters {
+    count: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl Metrics {
+    /// Starts timing the operation. It is recorded when the returned timer is dropped.
+    pub fn start(self: &Arc<Self>, operation: Operation) -> Timer {
+        Timer {
+            metrics: self.clone(),
+            operation,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let stats = |operation: Operation| {
+            let counters = &self.operations[operation as usize];
+
+            OperationStats {
+                count: counters.count.load(Ordering::Relaxed),
+                total_time: Duration::from_nanos(counters.total_nanos.load(Ordering::Relaxed)),
+                max_time: Duration::from_nanos(counters.max_nanos.load(Ordering::Relaxed)),
+            }
+        };
+
+        MetricsSnapshot {
+            get: stats(Operation::Get),
+            put: stats(Operation::Put),
+            remove: stats(Operation::Remove),
+            compact: stats(Operation::Compact),
+        }
+    }
+
+    fn record(&self, operation: Operation, elapsed: Duration) {
+        let counters = &self.operations[operation as usize];
+        let nanos = elapsed.as_nanos() as u64;
+
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        counters.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+
+        ::metrics::counter!("rumdb_operations_total", "operation" => operation.name()).increment(1);
+        ::metrics::histogram!("rumdb_operation_duration_seconds", "operation" => operation.name())
+            .record(elapsed.as_secs_f64());
+
+        #[cfg(feature = "opentelemetry")]
+        self.otel.record(operation, elapsed);
+    }
+}
+
+/// Times an operation until dropped. Owns a handle to the metrics, so the timed operation
+/// may borrow the storage mutably.
+pub(crate) struct Timer {
+    metrics: Arc<Metrics>,
+    operation: Operation,
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.metrics.record(self.operation, self.start.elapsed());
+    }
+}
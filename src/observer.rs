@@ -0,0 +1,39 @@
+//! Storage event hooks.
+//!
+//! A `StorageObserver` set in `DbOptions` is notified of storage maintenance events, e.g. to
+//! emit telemetry or back up log files once they are sealed. Keyspaces share the observer of
+//! the storage, so every callback gets the directory of the storage the event happened in.
+
+use std::{fmt, path::Path};
+
+use crate::storage::{OpenProgress, RecoveryReport};
+
+/// Receiver of storage events. All callbacks do nothing by default.
+///
+/// Callbacks run synchronously on the thread performing the operation, holding the storage,
+/// so they should return quickly and must not access the storage.
+pub trait StorageObserver: fmt::Debug + Send + Sync {
+    /// Called after the active log file `file_id` has been sealed and a new active log file
+    /// has been created.
+    fn on_log_sealed(&self, _path: &Path, _file_id: u32) {}
+
+    /// Called after the sealed log file `file_id` without live entries has been removed.
+    fn on_log_removed(&self, _path: &Path, _file_id: u32) {}
+
+    /// Called before compaction rewrites `keys` keys.
+    fn on_compaction_started(&self, _path: &Path, _keys: usize) {}
+
+    /// Called after compaction has succeeded.
+    fn on_compaction_finished(&self, _path: &Path) {}
+
+    /// Called on open if log file ranges have been lost, see `RecoveryMode`.
+    fn on_recovery(&self, _path: &Path, _report: &RecoveryReport) {}
+
+    /// Called when a read or `DiskStorage::sweep_expired` finds the `key` has expired. Reads
+    /// call it every time they find the key expired, until it is swept or written again.
+    fn on_key_expired(&self, _path: &Path, _key: &[u8]) {}
+
+    /// Called on open after each log file has been read into the keydir. Not called if the
+    /// keydir is loaded from a snapshot. Log files may be read on several threads.
+    fn on_open_progress(&self, _path: &Path, _progress: &OpenProgress) {}
+}
@@ -0,0 +1,91 @@
+//! OpenTelemetry export, enabled by the `opentelemetry` feature.
+//!
+//! Operation metrics, see `metrics`, are also recorded through the global `MeterProvider` as
+//! the `rumdb.operations` counter and the `rumdb.operation.duration` histogram, in seconds,
+//! with an `operation` attribute. Compactions are traced through the global `TracerProvider`
+//! as `rumdb.compact` spans, with the `rumdb.path` and `rumdb.keys` attributes and an error
+//! status if compaction fails.
+//!
+//! rumdb only depends on the OpenTelemetry API. The application installs the SDK and an
+//! exporter, e.g. OTLP, before opening storages, as instruments are created on open.
+
+use std::{path::Path, time::Duration};
+
+use opentelemetry::{
+    global::{self, BoxedSpan},
+    metrics::{Counter, Histogram},
+    trace::{Span, Status, Tracer},
+    KeyValue,
+};
+
+use crate::metrics::Operation;
+
+/// Name of the instrumentation scope of rumdb meters and tracers.
+const SCOPE: &str = "rumdb";
+
+/// Operation instruments of a storage.
+#[derive(Debug)]
+pub(crate) struct Instruments {
+    operations: Counter<u64>,
+    durations: Histogram<f64>,
+}
+
+impl Default for Instruments {
+    fn default() -> Self {
+        let meter = global::meter(SCOPE);
+
+        Self {
+            operations: meter
+                .u64_counter("rumdb.operations")
+                .with_description("Completed storage operations")
+                .build(),
+            durations: meter
+                .f64_histogram("rumdb.operation.duration")
+                .with_description("Duration of storage operations")
+                .with_unit("s")
+                .build(),
+        }
+    }
+}
+
+impl Instruments {
+    pub fn record(&self, operation: Operation, elapsed: Duration) {
+        let attributes = [KeyValue::new("operation", operation.name())];
+
+        self.operations.add(1, &attributes);
+        self.durations.record(elapsed.as_secs_f64(), &attributes);
+    }
+}
+
+/// Span of a compaction, ended when dropped. Fails unless marked succeeded.
+pub(crate) struct CompactionSpan {
+    span: BoxedSpan,
+    succeeded: bool,
+}
+
+impl CompactionSpan {
+    pub fn start(path: &Path, keys: usize) -> Self {
+        let mut span = global::tracer(SCOPE).start("rumdb.compact");
+        span.set_attribute(KeyValue::new("rumdb.path", path.display().to_string()));
+        span.set_attribute(KeyValue::new("rumdb.keys", keys as i64));
+
+        Self {
+            span,
+            succeeded: false,
+        }
+    }
+
+    pub fn succeeded(mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for CompactionSpan {
+    fn drop(&mut self) {
+        if !self.succeeded {
+            self.span.set_status(Status::error("compaction failed"));
+        }
+
+        self.span.end();
+    }
+}
@@ -0,0 +1,533 @@
+//! Asynchronous leader/follower replication.
+//!
+//! The leader streams entries appended to its log files to followers over TCP. A follower
+//! applies them to its own storage, keeping their timestamps, and persists the leader log
+//! position it has reached, so it resumes from there after reconnecting. An empty follower
+//! first receives a snapshot of all live pairs, as does a follower whose position lies in a
+//! log file the leader has since removed by a compaction or GC. Such a follower removes its
+//! keys first, so it serves partial reads until the snapshot has been applied. Entries
+//! rewritten by compactions are not shipped, followers have applied the originals already.
+//! Keyspaces are not replicated.
+//!
+//! Entries reach followers once flushed to the leader log files, which `Database` does every
+//! second. Merge operands applied right before a follower crash may be applied twice.
+//!
+//! Leader messages are tagged records. Integers are little-endian.
+
+use std::{
+    fmt,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+
+use crate::{errors::StorageError, log_reader::EntryKind, Database};
+
+const HANDSHAKE_MAGIC: &[u8; 8] = b"RUMDBREP";
+const PROTOCOL_VERSION: u8 = 4;
+
+const TAG_ENTRY: u8 = 1;
+const TAG_POSITION: u8 = 2;
+const TAG_ERROR: u8 = 3;
+const TAG_SNAPSHOT: u8 = 4;
+
+/// How often the leader checks for new entries.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of idle polls after which the leader sends its position anyway, detecting
+/// disconnected followers.
+const HEARTBEAT_POLLS: u32 = 10;
+
+/// Maximum number of entry bytes read from the log files at once.
+const MAX_BATCH_SIZE: u64 = 1024 * 1024;
+
+/// Entry read from the log files of the leader.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ReplicatedEntry {
+    pub kind: EntryKind,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub timestamp: u64,
+    /// Time a put value expires at, 0 if it never expires.
+    pub expires_at: u64,
+}
+
+/// Position in the log files of a storage. Offset 0 is the start of the log file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogPosition {
+    pub file_id: u32,
+    pub offset: u64,
+}
+
+impl fmt::Display for LogPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.rumdb.log:{}", self.file_id, self.offset)
+    }
+}
+
+/// Accepts followers on the `listener`, serving each on its own thread. Blocks forever.
+pub fn serve(db: &Database, listener: TcpListener) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let db = db.clone();
+
+        thread::spawn(move || {
+            if let Err(e) = serve_follower(&db, stream) {
+                log::warn!("⚠️  Replication to a follower stopped: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Streams entries to the follower connected through the `stream` until it disconnects.
+pub fn serve_follower(db: &Database, stream: TcpStream) -> Result<(), StorageError> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let mut handshake = [0; 22];
+    reader.read_exact(&mut handshake)?;
+
+    if &handshake[..8] != HANDSHAKE_MAGIC || handshake[8] != PROTOCOL_VERSION {
+        return Err(StorageError::Replication(
+            "unsupported follower".to_string(),
+        ));
+    }
+
+    let mut position = match handshake[9] {
+        0 => {
+            log::info!("📸 Sending a snapshot to a new follower");
+            send_snapshot(db, &mut writer)?
+        }
+        _ => LogPosition {
+            file_id: u32::from_le_bytes(handshake[10..14].try_into().unwrap()),
+            offset: u64::from_le_bytes(handshake[14..22].try_into().unwrap()),
+        },
+    };
+
+    log::info!("🔁 Replicating to a follower from {position}");
+
+    let mut idle_polls = 0;
+
+    loop {
+        let (entries, next_position) = match db
+            .read()
+            .and_then(|storage| storage.read_log_entries(position, MAX_BATCH_SIZE))
+        {
+            Ok(batch) => batch,
+            Err(StorageError::UnknownLogFile(file_id)) => {
+                log::info!(
+                    "📸 Log file {file_id} is gone, sending a snapshot to a follower at {position}"
+                );
+                position = send_snapshot(db, &mut writer)?;
+                writer.flush()?;
+                continue;
+            }
+            Err(e) => {
+                send_error(&mut writer, &e.to_string())?;
+                return Err(e);
+            }
+        };
+
+        if entries.is_empty() && idle_polls < HEARTBEAT_POLLS {
+            idle_polls += 1;
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        for entry in entries {
+            write_entry(&mut writer, &entry)?;
+        }
+
+        position = next_position;
+        write_position(&mut writer, position)?;
+        writer.flush()?;
+
+        idle_polls = 0;
+    }
+}
+
+/// Sends all live pairs as put entries, replacing all pairs of the follower, followed by
+/// the position they reflect. Pairs are read from a snapshot, locking the storage for one
+/// pair at a time, so a slow follower doesn't block writers.
+fn send_snapshot(db: &Database, writer: &mut impl Write) -> Result<LogPosition, StorageError> {
+    let (mut snapshot, position) = {
+        let storage = db.read()?;
+        (storage.snapshot_prefix(&[])?, storage.log_end())
+    };
+
+    writer.write_all(&[TAG_SNAPSHOT])?;
+
+    loop {
+        let Some(pair) = snapshot.next_entry(&*db.read()?) else {
+            break;
+        };
+        let (key, value, timestamp, expires_at) = pair?;
+
+        write_entry(
+            writer,
+            &ReplicatedEntry {
+                kind: EntryKind::Put,
+                key,
+                value,
+                timestamp,
+                expires_at,
+            },
+        )?;
+    }
+
+    write_position(writer, position)?;
+
+    Ok(position)
+}
+
+fn write_entry(writer: &mut impl Write, entry: &ReplicatedEntry) -> io::Result<()> {
+    let kind = match entry.kind {
+        EntryKind::Put => 0,
+        EntryKind::Tombstone => 1,
+        EntryKind::MergeOperand => 2,
+    };
+
+    writer.write_all(&[TAG_ENTRY, kind])?;
+    writer.write_all(&entry.timestamp.to_le_bytes())?;
+    writer.write_all(&entry.expires_at.to_le_bytes())?;
+    writer.write_all(&(entry.key.len() as u32).to_le_bytes())?;
+    writer.write_all(&entry.key)?;
+    writer.write_all(&(entry.value.len() as u64).to_le_bytes())?;
+    writer.write_all(&entry.value)
+}
+
+fn write_position(writer: &mut impl Write, position: LogPosition) -> io::Result<()> {
+    writer.write_all(&[TAG_POSITION])?;
+    writer.write_all(&position.file_id.to_le_bytes())?;
+    writer.write_all(&position.offset.to_le_bytes())
+}
+
+fn send_error(writer: &mut impl Write, message: &str) -> io::Result<()> {
+    writer.write_all(&[TAG_ERROR])?;
+    writer.write_all(&(message.len() as u32).to_le_bytes())?;
+    writer.write_all(message.as_bytes())?;
+    writer.flush()
+}
+
+/// Connects to the leader and applies its entries to the `db` until the leader disconnects.
+///
+/// The `db` must either be empty or have been following the same leader before.
+pub fn follow(db: &Database, leader: impl ToSocketAddrs) -> Result<(), StorageError> {
+    let mut position = db.read()?.replication_position()?;
+
+    if position.is_none() && db.read()?.storage_stats().keys > 0 {
+        return Err(StorageError::Replication(
+            "follower is not empty and has no replication position".to_string(),
+        ));
+    }
+
+    let stream = TcpStream::connect(leader)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let mut handshake = Vec::with_capacity(22);
+    handshake.extend_from_slice(HANDSHAKE_MAGIC);
+    handshake.push(PROTOCOL_VERSION);
+    handshake.push(position.is_some().into());
+
+    let start = position.unwrap_or_default();
+    handshake.extend_from_slice(&start.file_id.to_le_bytes());
+    handshake.extend_from_slice(&start.offset.to_le_bytes());
+    writer.write_all(&handshake)?;
+
+    loop {
+        let mut tag = [0];
+
+        match reader.read_exact(&mut tag) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+
+        match tag[0] {
+            TAG_ENTRY => {
+                let kind = match read_array::<1>(&mut reader)?[0] {
+                    0 => EntryKind::Put,
+                    1 => EntryKind::Tombstone,
+                    2 => EntryKind::MergeOperand,
+                    kind => {
+                        return Err(StorageError::Replication(format!(
+                            "unknown entry kind: {kind}"
+                        )))
+                    }
+                };
+
+                let timestamp = u64::from_le_bytes(read_array(&mut reader)?);
+                let expires_at = u64::from_le_bytes(read_array(&mut reader)?);
+                let key_size = u32::from_le_bytes(read_array(&mut reader)?);
+                let key = read_vec(&mut reader, key_size.into())?;
+                let value_size = u64::from_le_bytes(read_array(&mut reader)?);
+                let value = read_vec(&mut reader, value_size)?;
+
+                db.write()?.apply_entry(ReplicatedEntry {
+                    kind,
+                    key,
+                    value,
+                    timestamp,
+                    expires_at,
+                })?;
+            }
+            TAG_POSITION => {
+                let leader_position = LogPosition {
+                    file_id: u32::from_le_bytes(read_array(&mut reader)?),
+                    offset: u64::from_le_bytes(read_array(&mut reader)?),
+                };
+
+                if position != Some(leader_position) {
+                    db.write()?.set_replication_position(leader_position)?;
+                    position = Some(leader_position);
+                }
+            }
+            TAG_SNAPSHOT => {
+                // Pairs missing from the snapshot have been removed on the leader. The old
+                // position stays persisted until the snapshot is complete.
+                db.delete_prefix(&[])?;
+            }
+            TAG_ERROR => {
+                let len = u32::from_le_bytes(read_array(&mut reader)?);
+                let message = read_vec(&mut reader, len.into())?;
+
+                return Err(StorageError::Replication(format!(
+                    "leader: {}",
+                    String::from_utf8_lossy(&message)
+                )));
+            }
+            tag => return Err(StorageError::Replication(format!("unknown message: {tag}"))),
+        }
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+fn read_vec(reader: &mut impl Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+
+    if buf.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Shutdown, sync::mpsc, time::Instant};
+
+    use crate::DbOptions;
+
+    use super::*;
+
+    fn open(dir: &tempdir::TempDir) -> Database {
+        let opts = DbOptions::default().merge_operator(|_, existing, operand| {
+            Some([existing.unwrap_or_default(), operand].concat())
+        });
+
+        Database::open_with(dir.path(), opts).unwrap()
+    }
+
+    /// Replicates the `leader` to the `follower` until `done` returns true.
+    fn replicate(leader: &Database, follower: &Database, done: impl Fn(&Database) -> bool) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        leader.flush().unwrap();
+
+        let follower_thread = {
+            let follower = follower.clone();
+            thread::spawn(move || follow(&follower, addr))
+        };
+
+        let (stream, _) = listener.accept().unwrap();
+        let connection = stream.try_clone().unwrap();
+
+        let leader_thread = {
+            let leader = leader.clone();
+            thread::spawn(move || serve_follower(&leader, stream))
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        let end = Some(leader.read().unwrap().log_end());
+
+        while !done(follower) || follower.read().unwrap().replication_position().unwrap() != end {
+            assert!(Instant::now() < deadline, "follower hasn't caught up");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        connection.shutdown(Shutdown::Both).unwrap();
+        follower_thread.join().unwrap().unwrap();
+        assert!(leader_thread.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn replication_should_ship_log_entries() {
+        let leader_dir = tempdir::TempDir::new("replication-test.db").unwrap();
+        let follower_dir = tempdir::TempDir::new("replication-test.db").unwrap();
+        let leader = open(&leader_dir);
+        let follower = open(&follower_dir);
+
+        leader.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        leader.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        leader.merge(b"c".to_vec(), b"x".to_vec()).unwrap();
+
+        replicate(&leader, &follower, |follower| {
+            follower.get(b"c").unwrap().is_some()
+        });
+        assert_eq!(follower.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(follower.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        leader.remove(b"a").unwrap();
+        leader.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        leader.merge(b"c".to_vec(), b"y".to_vec()).unwrap();
+
+        replicate(&leader, &follower, |follower| {
+            follower.get(b"c").unwrap() == Some(b"xy".to_vec())
+        });
+        assert_eq!(follower.get(b"a").unwrap(), None);
+        assert_eq!(follower.get(b"d").unwrap(), Some(b"4".to_vec()));
+
+        // Resumes from the persisted position, without applying operands twice.
+        drop(follower);
+        let follower = open(&follower_dir);
+        leader.merge(b"c".to_vec(), b"z".to_vec()).unwrap();
+
+        replicate(&leader, &follower, |follower| {
+            follower.get(b"c").unwrap() == Some(b"xyz".to_vec())
+        });
+        assert_eq!(follower.storage_stats().unwrap().keys, 3);
+    }
+
+    #[test]
+    fn replication_should_resync_follower_behind_compaction() {
+        let leader_dir = tempdir::TempDir::new("replication-test.db").unwrap();
+        let follower_dir = tempdir::TempDir::new("replication-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(256);
+        let leader = Database::open_with(leader_dir.path(), opts).unwrap();
+        let follower = open(&follower_dir);
+
+        for i in 0..20u8 {
+            leader.put(vec![i], vec![i; 20]).unwrap();
+        }
+        replicate(&leader, &follower, |follower| {
+            follower.get(&[19]).unwrap().is_some()
+        });
+
+        // Entries rewritten by a compaction aren't shipped again.
+        let end = leader.read().unwrap().log_end();
+        leader.compact().unwrap();
+        let (entries, _) = leader
+            .read()
+            .unwrap()
+            .read_log_entries(end, u64::MAX)
+            .unwrap();
+        assert!(entries.is_empty());
+
+        replicate(&leader, &follower, |_| true);
+        let position = follower.read().unwrap().replication_position().unwrap();
+
+        // The log file of the follower position is compacted away meanwhile.
+        for i in 0..10u8 {
+            leader.remove(&[i]).unwrap();
+        }
+        for i in 10..20u8 {
+            leader.put(vec![i], vec![0; 20]).unwrap();
+        }
+        leader.put(vec![100], vec![100]).unwrap();
+        leader.compact_all().unwrap();
+
+        assert!(matches!(
+            leader
+                .read()
+                .unwrap()
+                .read_log_entries(position.unwrap(), u64::MAX),
+            Err(StorageError::UnknownLogFile(_))
+        ));
+
+        replicate(&leader, &follower, |follower| {
+            follower.get(&[100]).unwrap().is_some()
+        });
+
+        let scan = |db: &Database| {
+            let mut pairs = db.scan(&[]).unwrap();
+            pairs.sort();
+            pairs
+        };
+        assert_eq!(follower.storage_stats().unwrap().keys, 11);
+        assert_eq!(scan(&follower), scan(&leader));
+    }
+
+    #[test]
+    fn replication_should_reject_non_empty_follower_without_position() {
+        let dir = tempdir::TempDir::new("replication-test.db").unwrap();
+        let follower = open(&dir);
+        follower.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+        assert!(matches!(
+            follow(&follower, "127.0.0.1:1"),
+            Err(StorageError::Replication(_))
+        ));
+    }
+
+    /// Puts a pair from another thread on the first write, failing if the put is blocked.
+    struct WritingSink {
+        db: Database,
+        written: bool,
+    }
+
+    impl Write for WritingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.written {
+                self.written = true;
+
+                let (sender, receiver) = mpsc::channel();
+                let db = self.db.clone();
+                thread::spawn(move || sender.send(db.put(b"z".to_vec(), b"26".to_vec())));
+
+                receiver
+                    .recv_timeout(Duration::from_secs(5))
+                    .expect("put blocked by the snapshot")
+                    .unwrap();
+            }
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replication_should_not_block_writers_while_sending_snapshot() {
+        let dir = tempdir::TempDir::new("replication-test.db").unwrap();
+        let db = open(&dir);
+
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        let end = db.read().unwrap().log_end();
+
+        let mut sink = WritingSink {
+            db: db.clone(),
+            written: false,
+        };
+        let position = send_snapshot(&db, &mut sink).unwrap();
+
+        assert!(sink.written);
+        assert_eq!(position, end);
+        assert_eq!(db.get(b"z").unwrap(), Some(b"26".to_vec()));
+    }
+}
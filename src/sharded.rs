@@ -0,0 +1,237 @@
+//! Database partitioned into shards.
+
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    errors::{FormatError, StorageError},
+    storage::{KeyValue, Storage},
+    vfs::{OpenMode, TempFile},
+    Database, DbOptions,
+};
+
+/// File holding the number of shards, in the directory of a sharded database.
+const SHARDS_FILE: &str = "SHARDS";
+
+/// Database partitioned into shards by a hash of the keys.
+///
+/// Each shard is an independent `Database` in its own subdirectory, with its own lock, log
+/// files, maintenance and compaction, so writes to keys of different shards don't contend.
+/// The number of shards is set when the database is created and can't change. Handles are
+/// cheap to clone and share the same shards.
+#[derive(Debug, Clone)]
+pub struct ShardedDb {
+    shards: Vec<Database>,
+}
+
+impl ShardedDb {
+    /// Opens or creates a database with `shards` shards at the `path` directory, every shard
+    /// opened with the `opts`. Fails if an existing database has a different number of
+    /// shards.
+    pub fn open(
+        path: impl AsRef<Path>,
+        shards: NonZeroUsize,
+        opts: DbOptions,
+    ) -> Result<Self, StorageError> {
+        let path = path.as_ref();
+        let count = Self::shard_count_of(path, &opts)?;
+
+        match count {
+            Some(count) if count != shards.get() => {
+                return Err(StorageError::ShardCountMismatch {
+                    expected: shards.get(),
+                    found: count,
+                })
+            }
+            Some(_) => (),
+            None => Self::create(path, shards, &opts)?,
+        }
+
+        let shards = (0..shards.get())
+            .map(|shard| Database::open_with(Self::shard_path(path, shard), opts.clone()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { shards })
+    }
+
+    /// Number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Shards, ordered by index.
+    pub fn shards(&self) -> &[Database] {
+        &self.shards
+    }
+
+    /// Shard the key belongs to.
+    pub fn shard(&self, k: &[u8]) -> &Database {
+        &self.shards[crc32fast::hash(k) as usize % self.shards.len()]
+    }
+
+    /// Get a value from the database.
+    pub fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.shard(k).get(k)
+    }
+
+    /// Put a value into the database.
+    pub fn put(&self, k: Vec<u8>, v: Vec<u8>) -> Result<(), StorageError> {
+        self.shard(&k).put(k, v)
+    }
+
+    /// Remove a value from the database.
+    pub fn remove(&self, k: &[u8]) -> Result<(), StorageError> {
+        self.shard(k).remove(k)
+    }
+
+    /// Returns all key-value pairs whose key starts with `prefix`, shard by shard.
+    pub fn scan(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, StorageError> {
+        let mut pairs = Vec::new();
+
+        for shard in &self.shards {
+            pairs.extend(shard.scan(prefix)?);
+        }
+
+        Ok(pairs)
+    }
+
+    /// Compacts the shards one after another. Writes to other shards proceed meanwhile.
+    pub fn compact(&self) -> Result<(), StorageError> {
+        self.shards.iter().try_for_each(Database::compact)
+    }
+
+    /// Writes buffered entries of all shards to their log files.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.shards.iter().try_for_each(Database::flush)
+    }
+
+    fn shard_path(path: &Path, shard: usize) -> PathBuf {
+        path.join(format!("shard-{shard}"))
+    }
+
+    /// Number of shards of the database at `path`, if it exists.
+    fn shard_count_of(path: &Path, opts: &DbOptions) -> Result<Option<usize>, StorageError> {
+        let file_path = path.join(SHARDS_FILE);
+
+        if !opts.vfs.exists(&file_path) {
+            return Ok(None);
+        }
+
+        let mut buf = [0; 12];
+        opts.vfs
+            .open(&file_path, OpenMode::ReadOnly)?
+            .read_exact_at(&mut buf, 0)?;
+
+        if crc32fast::hash(&buf[..8]).to_le_bytes() != buf[8..] {
+            return Err(FormatError::ChecksumMismatch.into());
+        }
+
+        Ok(Some(
+            u64::from_le_bytes(buf[..8].try_into().unwrap()) as usize
+        ))
+    }
+
+    /// Creates an empty sharded database at `path`, persisting the number of shards.
+    fn create(path: &Path, shards: NonZeroUsize, opts: &DbOptions) -> Result<(), StorageError> {
+        let vfs = &*opts.vfs;
+        vfs.create_dir_all(path)?;
+
+        if !vfs.list(path)?.is_empty() {
+            return Err(StorageError::DirectoryNotEmpty(path.to_path_buf()));
+        }
+
+        let mut buf = (shards.get() as u64).to_le_bytes().to_vec();
+        buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+
+        let temp = TempFile::create(vfs, &path.join(SHARDS_FILE))?;
+        temp.file().append_all(&buf)?;
+        temp.persist(vfs)?;
+
+        Ok(())
+    }
+}
+
+impl Storage for ShardedDb {
+    fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        ShardedDb::get(self, k)
+    }
+
+    fn put(&mut self, k: Vec<u8>, v: Vec<u8>) -> Result<(), StorageError> {
+        ShardedDb::put(self, k, v)
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Result<(), StorageError> {
+        ShardedDb::remove(self, k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn sharded_db_should_spread_keys_across_shards() {
+        let dir = tempdir::TempDir::new("sharded-db-test").unwrap();
+        let shards = NonZeroUsize::new(4).unwrap();
+        let db = ShardedDb::open(dir.path(), shards, DbOptions::default()).unwrap();
+
+        let writers: Vec<_> = (0..4u8)
+            .map(|t| {
+                let db = db.clone();
+
+                thread::spawn(move || {
+                    for i in 0..50u8 {
+                        db.put(vec![t, i], vec![i]).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut storage: Box<dyn Storage> = Box::new(db.clone());
+        storage.remove(&[0, 0]).unwrap();
+        assert_eq!(storage.get(&[0, 0]).unwrap(), None);
+        assert_eq!(storage.get(&[3, 49]).unwrap(), Some(vec![49]));
+
+        assert_eq!(db.scan(&[1]).unwrap().len(), 50);
+        assert!(db
+            .shards()
+            .iter()
+            .all(|shard| shard.storage_stats().unwrap().keys > 0));
+
+        db.compact().unwrap();
+        drop((db, storage));
+
+        // The number of shards is fixed once created.
+        let db = ShardedDb::open(dir.path(), shards, DbOptions::default()).unwrap();
+        assert_eq!(db.shard_count(), 4);
+        assert_eq!(db.scan(&[]).unwrap().len(), 199);
+        drop(db);
+
+        assert!(matches!(
+            ShardedDb::open(
+                dir.path(),
+                NonZeroUsize::new(2).unwrap(),
+                DbOptions::default()
+            ),
+            Err(StorageError::ShardCountMismatch {
+                expected: 2,
+                found: 4
+            })
+        ));
+
+        let plain = tempdir::TempDir::new("sharded-db-test").unwrap();
+        drop(Database::open(plain.path()).unwrap());
+        assert!(matches!(
+            ShardedDb::open(plain.path(), shards, DbOptions::default()),
+            Err(StorageError::DirectoryNotEmpty(_))
+        ));
+    }
+}
@@ -0,0 +1,385 @@
+//! Keydir snapshots.
+//!
+//! A snapshot is written on close and lets the next open skip scanning log files. It starts
+//! with magic bytes, a version, the id and size of every log file it was taken against, a
+//! crc32 of the tail of the active log file and the entry header layouts of the log files,
+//! followed by tagged keydir entry and merge chain records. A crc32 of everything before it closes the file.
+
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    errors::FormatError,
+    format::{FormatVersion, KeydirEntry, Layout},
+    vfs::{OpenMode, TempFile, Vfs, VfsAppender, VfsReader},
+};
+
+/// Snapshot file name, relative to the storage directory.
+pub(crate) const SNAPSHOT_FILE: &str = "KEYDIR.snapshot";
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"RUMDBKDS";
+const SNAPSHOT_VERSION: u8 = 5;
+
+/// Bytes at the end of the active log file covered by the checksum in the snapshot.
+pub(crate) const SNAPSHOT_TAIL_SIZE: u64 = 4096;
+
+const TAG_END: u8 = 0;
+const TAG_ENTRY: u8 = 1;
+const TAG_MERGE_CHAIN: u8 = 2;
+
+/// Id and size of a log file.
+pub(crate) type LogFileInfo = (u32, u64);
+
+/// Id and entry header layout of a log file.
+pub(crate) type LogLayout = (u32, Layout);
+
+/// Snapshot record.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Record {
+    /// Keydir entry.
+    Entry(Vec<u8>, KeydirEntry),
+    /// Merge chain of a key.
+    MergeChain {
+        key: Vec<u8>,
+        base: Option<KeydirEntry>,
+        operands: Vec<KeydirEntry>,
+    },
+}
+
+/// Writes a snapshot to a temporary file, renaming it to `path` once complete.
+pub(crate) struct SnapshotWriter {
+    inner: BufWriter<VfsAppender>,
+    temp: TempFile,
+    hasher: crc32fast::Hasher,
+}
+
+impl SnapshotWriter {
+    /// Creates a snapshot taken against the `log_files`, the last of them being the active
+    /// log file with the `tail_crc`, whose entry headers have the `layouts`.
+    pub fn create(
+        vfs: &dyn Vfs,
+        path: &Path,
+        log_files: &[LogFileInfo],
+        tail_crc: u32,
+        layouts: &[LogLayout],
+    ) -> Result<Self, io::Error> {
+        let temp = TempFile::create(vfs, path)?;
+
+        let mut writer = Self {
+            inner: BufWriter::new(VfsAppender(temp.file().clone())),
+            temp,
+            hasher: crc32fast::Hasher::new(),
+        };
+
+        writer.write(SNAPSHOT_MAGIC)?;
+        writer.write(&[SNAPSHOT_VERSION])?;
+        writer.write(&(log_files.len() as u32).to_le_bytes())?;
+
+        for (file_id, size) in log_files {
+            writer.write(&file_id.to_le_bytes())?;
+            writer.write(&size.to_le_bytes())?;
+        }
+
+        writer.write(&tail_crc.to_le_bytes())?;
+        writer.write(&(layouts.len() as u32).to_le_bytes())?;
+
+        for (file_id, layout) in layouts {
+            writer.write(&file_id.to_le_bytes())?;
+            writer.write(&[layout.version as u8])?;
+            writer.write(&layout.base.to_le_bytes())?;
+        }
+
+        Ok(writer)
+    }
+
+    /// Appends a keydir entry.
+    pub fn entry(&mut self, key: &[u8], entry: &KeydirEntry) -> Result<(), io::Error> {
+        self.write(&[TAG_ENTRY])?;
+        self.key(key)?;
+        self.keydir_entry(entry)
+    }
+
+    /// Appends the merge chain of the `key`.
+    pub fn merge_chain(
+        &mut self,
+        key: &[u8],
+        base: Option<&KeydirEntry>,
+        operands: &[KeydirEntry],
+    ) -> Result<(), io::Error> {
+        self.write(&[TAG_MERGE_CHAIN])?;
+        self.key(key)?;
+
+        match base {
+            Some(base) => {
+                self.write(&[1])?;
+                self.keydir_entry(base)?;
+            }
+            None => self.write(&[0])?,
+        }
+
+        self.write(&(operands.len() as u32).to_le_bytes())?;
+
+        for operand in operands {
+            self.keydir_entry(operand)?;
+        }
+
+        Ok(())
+    }
+
+    /// Completes the snapshot and atomically moves it into `path`.
+    pub fn finish(mut self, vfs: &dyn Vfs) -> Result<(), io::Error> {
+        self.write(&[TAG_END])?;
+
+        let crc = self.hasher.finalize();
+        self.inner.write_all(&crc.to_le_bytes())?;
+        self.inner.flush()?;
+
+        self.temp.persist(vfs)
+    }
+
+    fn key(&mut self, key: &[u8]) -> Result<(), io::Error> {
+        self.write(&(key.len() as u32).to_le_bytes())?;
+        self.write(key)
+    }
+
+    fn keydir_entry(&mut self, entry: &KeydirEntry) -> Result<(), io::Error> {
+        self.write(&entry.file_id.to_le_bytes())?;
+        self.write(&entry.value_size.to_le_bytes())?;
+        self.write(&entry.value_pos.to_le_bytes())?;
+        self.write(&entry.timestamp.to_le_bytes())?;
+        self.write(&entry.expires_at.to_le_bytes())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.hasher.update(buf);
+        self.inner.write_all(buf)
+    }
+}
+
+/// Reads snapshot records. The checksum is verified once the last record has been read.
+pub(crate) struct SnapshotReader {
+    inner: BufReader<VfsReader>,
+    hasher: crc32fast::Hasher,
+    log_files: Vec<LogFileInfo>,
+    tail_crc: u32,
+    layouts: Vec<LogLayout>,
+}
+
+impl SnapshotReader {
+    /// Opens the snapshot at `path` and reads its log file table.
+    pub fn open(vfs: &dyn Vfs, path: &Path) -> Result<Self, FormatError> {
+        let file = vfs
+            .open(path, OpenMode::ReadOnly)
+            .or(Err(FormatError::DeserializeError))?;
+
+        let mut reader = Self {
+            inner: BufReader::new(VfsReader::new(file, 0)),
+            hasher: crc32fast::Hasher::new(),
+            log_files: Vec::new(),
+            tail_crc: 0,
+            layouts: Vec::new(),
+        };
+
+        if &reader.read_array::<8>()? != SNAPSHOT_MAGIC {
+            return Err(FormatError::DeserializeError);
+        }
+
+        match reader.read_array::<1>()?[0] {
+            SNAPSHOT_VERSION => (),
+            version => return Err(FormatError::UnsupportedVersion(version)),
+        }
+
+        let log_files = reader.read_u32()?;
+
+        for _ in 0..log_files {
+            let file_id = reader.read_u32()?;
+            let size = reader.read_u64()?;
+
+            reader.log_files.push((file_id, size));
+        }
+
+        reader.tail_crc = reader.read_u32()?;
+
+        let layouts = reader.read_u32()?;
+
+        for _ in 0..layouts {
+            let file_id = reader.read_u32()?;
+            let version = FormatVersion::from_u8(reader.read_array::<1>()?[0])?;
+            let base = reader.read_u64()?;
+
+            reader.layouts.push((file_id, Layout::new(version, base)));
+        }
+
+        Ok(reader)
+    }
+
+    /// Log files the snapshot was taken against, ordered by id.
+    pub fn log_files(&self) -> &[LogFileInfo] {
+        &self.log_files
+    }
+
+    /// Entry header layouts of the log files, ordered by id.
+    pub fn layouts(&self) -> &[LogLayout] {
+        &self.layouts
+    }
+
+    /// Checksum of the tail of the active log file.
+    pub fn tail_crc(&self) -> u32 {
+        self.tail_crc
+    }
+
+    /// Reads the next record. Returns `None` after the last record if the checksum matches.
+    pub fn next_record(&mut self) -> Result<Option<Record>, FormatError> {
+        match self.read_array::<1>()?[0] {
+            TAG_ENTRY => {
+                let key = self.read_key()?;
+                let entry = self.read_keydir_entry()?;
+
+                Ok(Some(Record::Entry(key, entry)))
+            }
+            TAG_MERGE_CHAIN => {
+                let key = self.read_key()?;
+
+                let base = match self.read_array::<1>()?[0] {
+                    0 => None,
+                    _ => Some(self.read_keydir_entry()?),
+                };
+
+                let operands = (0..self.read_u32()?)
+                    .map(|_| self.read_keydir_entry())
+                    .collect::<Result<_, _>>()?;
+
+                Ok(Some(Record::MergeChain {
+                    key,
+                    base,
+                    operands,
+                }))
+            }
+            TAG_END => {
+                let crc = self.hasher.clone().finalize();
+                let mut expected = [0; 4];
+                self.inner
+                    .read_exact(&mut expected)
+                    .or(Err(FormatError::DeserializeError))?;
+
+                if crc != u32::from_le_bytes(expected) {
+                    return Err(FormatError::ChecksumMismatch);
+                }
+
+                Ok(None)
+            }
+            _ => Err(FormatError::DeserializeError),
+        }
+    }
+
+    fn read_key(&mut self) -> Result<Vec<u8>, FormatError> {
+        let mut key = vec![0; self.read_u32()? as usize];
+        self.read_exact(&mut key)?;
+
+        Ok(key)
+    }
+
+    fn read_keydir_entry(&mut self) -> Result<KeydirEntry, FormatError> {
+        Ok(KeydirEntry::new(
+            self.read_u32()?,
+            self.read_u64()?,
+            self.read_u64()?,
+            self.read_u64()?,
+        )
+        .expiring(self.read_u64()?))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, FormatError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], FormatError> {
+        let mut buf = [0; N];
+        self.read_exact(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), FormatError> {
+        self.inner
+            .read_exact(buf)
+            .or(Err(FormatError::DeserializeError))?;
+        self.hasher.update(buf);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::vfs::StdVfs;
+
+    use super::*;
+
+    #[test]
+    fn snapshot_should_roundtrip_records() {
+        let dir = tempdir::TempDir::new("snapshot-test").unwrap();
+        let path = dir.path().join(SNAPSHOT_FILE);
+        let entry = KeydirEntry::new(1, 2, 3, 4).expiring(5);
+
+        let layouts = [
+            (0, Layout::new(FormatVersion::V4, 0)),
+            (1, Layout::new(FormatVersion::V5, 1000)),
+        ];
+
+        let mut writer =
+            SnapshotWriter::create(&StdVfs, &path, &[(0, 100), (1, 50)], 7, &layouts).unwrap();
+        writer.entry(b"hello", &entry).unwrap();
+        writer
+            .merge_chain(b"counter", None, &[entry, entry])
+            .unwrap();
+        writer.finish(&StdVfs).unwrap();
+
+        let mut reader = SnapshotReader::open(&StdVfs, &path).unwrap();
+        assert_eq!(reader.log_files(), &[(0, 100), (1, 50)]);
+        assert_eq!(reader.tail_crc(), 7);
+        assert_eq!(reader.layouts(), &layouts);
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(Record::Entry(b"hello".to_vec(), entry))
+        );
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(Record::MergeChain {
+                key: b"counter".to_vec(),
+                base: None,
+                operands: vec![entry, entry],
+            })
+        );
+        assert_eq!(reader.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn snapshot_should_detect_corruption() {
+        let dir = tempdir::TempDir::new("snapshot-test").unwrap();
+        let path = dir.path().join(SNAPSHOT_FILE);
+
+        let mut writer = SnapshotWriter::create(&StdVfs, &path, &[(0, 100)], 7, &[]).unwrap();
+        writer
+            .entry(b"hello", &KeydirEntry::new(1, 2, 3, 4))
+            .unwrap();
+        writer.finish(&StdVfs).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        let len = data.len();
+        data[len - 10] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let mut reader = SnapshotReader::open(&StdVfs, &path).unwrap();
+        assert!(reader.next_record().is_ok());
+        assert!(reader.next_record().is_err());
+    }
+}
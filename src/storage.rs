@@ -1,20 +1,53 @@
 //! RumDB storage.
 
 use std::{
-    collections::BTreeMap,
-    fs::{self, File, OpenOptions},
-    io::{self, Read, Seek, SeekFrom, Write},
-    os::unix::prelude::FileExt,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    io::{self, BufReader, BufWriter, IoSlice, Read, Write},
+    ops::Bound,
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    errors::StorageError,
-    format::{DiskEntry, Header, KeydirEntry, HEADER_SIZE},
-    keydir::{Keydir, KeydirDefault},
-    DbOptions,
+    bitcask::{BitcaskReader, BitcaskWriter},
+    changes::{ChangeEvent, Subscribers, Watch},
+    dump::{self, DumpReader, DumpWriter},
+    encoding,
+    epoch::{EpochGuard, Epochs},
+    errors::{CompareAndSwapError, FormatError, StorageError, TxnConflict},
+    file_cache::FileCache,
+    footer::{self, IndexEntry, FOOTER_MAGIC},
+    format::{
+        DiskEntry, FormatVersion, Header, KeydirEntry, Layout, CHUNK_SIZE, MAX_SEGMENT_HEADER_SIZE,
+    },
+    keydir::{Keydir, KeydirDefault, OrderedKeydir},
+    log_reader::{EntryKind, LogReader},
+    observer::StorageObserver,
+    replication::{LogPosition, ReplicatedEntry},
+    snapshot::{
+        LogFileInfo, Record, SnapshotReader, SnapshotWriter, SNAPSHOT_FILE, SNAPSHOT_TAIL_SIZE,
+    },
+    value_cache::ValueCache,
+    vfs::{OpenMode, StdVfs, TempFile, Vfs, VfsAppender, VfsFile, VfsReader},
+    DbOptions, RecoveryMode, WriteStallMode,
 };
 
+#[cfg(feature = "metrics")]
+use crate::metrics::{Metrics, MetricsSnapshot, Operation};
+
+/// Key-value pair.
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// Key, value, timestamp and expiration time of a pair, the latter 0 if it never expires.
+pub type KeyValueEntry = (Vec<u8>, Vec<u8>, u64, u64);
+
 /// Storge trait.
 pub trait Storage {
     /// Get an entry from the storage.
@@ -35,326 +68,9845 @@ where
 {
     keydir: K,
     /// Mapping between file id and actual file.
-    log_files: BTreeMap<u32, File>,
+    log_files: BTreeMap<u32, LogFile>,
+
+    /// Writer appending entries to the last log file.
+    active: ActiveLog,
 
     _lock: Lockfile,
 
     path: PathBuf,
 
     opts: DbOptions,
-}
 
-impl<K> DiskStorage<K>
-where
-    K: Keydir + KeydirDefault,
-{
-    /// Opens or creates a new storage at the `path` directory.
-    pub fn open(path: impl AsRef<Path>, opts: DbOptions) -> Result<Self, StorageError> {
-        Self::_open(path, opts)
-    }
+    /// Named keyspaces, each with its own keydir and log files.
+    keyspaces: HashMap<String, DiskStorage<K>>,
 
-    /// Opens or creates a new storage at the `path` directory with default options.
-    pub fn open_default(path: impl AsRef<Path>) -> Result<Self, StorageError> {
-        Self::_open(path, DbOptions::default())
-    }
+    /// Keys with pending merge operands. The keydir points to the latest operand of such keys.
+    merge_chains: MergeChains,
 
-    pub fn _open(path: impl AsRef<Path>, opts: DbOptions) -> Result<Self, StorageError> {
-        let path = path.as_ref();
+    /// Live entries of each log file.
+    live_entries: BTreeMap<u32, LiveEntries>,
 
-        fs::create_dir_all(path)?;
-        let lock = Lockfile::lock(path.join("LOCK")).or(Err(StorageError::AlreadyLocked))?;
+    /// Entry header layouts of log files, which sizes of live entries depend on.
+    layouts: BTreeMap<u32, Layout>,
 
-        log::info!("🏗  Building keydir...");
+    /// Whether a log file has been rotated since the last GC.
+    gc_pending: bool,
 
-        let (keydir, log_files) = Self::build_keydir(path)?;
+    /// Id of the first log file kept by the last `clear`, until the older log files it
+    /// retired are deleted and the clear marker file is removed.
+    clear_pending: Option<u32>,
 
-        log::info!("🏗  Keydir has been built successfully");
+    /// Log file ranges lost while opening the storage.
+    recovery_report: RecoveryReport,
 
-        Ok(Self {
-            path: path.to_path_buf(),
-            keydir,
-            log_files,
-            _lock: lock,
-            opts,
-        })
-    }
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
 
-    fn build_keydir(path: &Path) -> Result<(K, BTreeMap<u32, File>), io::Error> {
-        let mut file_opts = OpenOptions::new();
-        file_opts.read(true).write(true).create(true);
+    subscribers: Subscribers,
 
-        let mut log_files = BTreeMap::new();
+    /// Previous versions of keys, see `DbOptions::history_versions`.
+    history: History,
 
-        fs::read_dir(path)?
-            .filter_map(Result::ok)
-            .filter(|f| f.path().extension().unwrap_or_default() == "log")
-            .for_each(|f| {
-                if let Some(Some(file_id)) = f.file_name().to_str().map(|f| f.split(".").next()) {
-                    if let Ok(file_id) = file_id.parse::<u32>() {
-                        let file = file_opts.open(f.path()).expect("log file");
-                        log_files.insert(file_id, file);
-                    }
-                }
-            });
+    /// Open handles of sealed log files.
+    file_cache: Arc<FileCache>,
 
-        let mut keydir = K::default();
+    /// Recently read values, see `DbOptions::value_cache_size`.
+    value_cache: ValueCache,
 
-        for (file_id, log) in log_files.iter_mut() {
-            Self::ingest_log(&mut keydir, *file_id, log)?;
-        }
+    /// Log files removed by GC, deleted once no reader may reference them.
+    epochs: Arc<Epochs>,
 
-        if log_files.is_empty() {
-            let file = file_opts
-                .open(path.join(Self::format_log_file_name(0)))
-                .expect("log file");
-            log_files.insert(0, file);
-        }
+    /// Why writes are stalled, see `DbOptions::stall_fragmentation`.
+    stall: Option<String>,
+
+    /// Bytes taken by sealed log files.
+    sealed_size: u64,
+
+    /// Bytes rewritten by merges, see `DbOptions::merge_budget`.
+    merge_budget: MergeBudget,
+
+    /// Bytes per second written by the running or last compaction.
+    compaction_throughput: u64,
+
+    /// Latest compactions, oldest first.
+    compactions: VecDeque<CompactionRecord>,
+
+    /// Log files reads have found missing, see `repair_missing_segments`.
+    missing_log_files: Mutex<BTreeSet<u32>>,
+
+    /// Number of expired keys removed by `sweep_expired`.
+    expired_swept: u64,
+}
+
+/// Expired keys of a storage and its keyspaces, found by `DiskStorage::expired_keys`.
+#[derive(Debug, Default)]
+pub(crate) struct ExpiredKeys {
+    keys: Vec<Vec<u8>>,
+    keyspaces: HashMap<String, ExpiredKeys>,
+}
 
-        Ok((keydir, log_files))
+impl ExpiredKeys {
+    /// Number of expired keys, keyspaces included.
+    pub fn len(&self) -> usize {
+        self.keys.len() + self.keyspaces.values().map(ExpiredKeys::len).sum::<usize>()
     }
 
-    fn ingest_log(keydir: &mut K, file_id: u32, log: &mut File) -> Result<(), io::Error> {
-        log::info!("💾 Ingesting: {}", Self::format_log_file_name(file_id));
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
 
-        let mut buf = [0; HEADER_SIZE];
+/// Compaction of log files run step by step, see `DiskStorage::compaction_step`.
+pub(crate) struct Compaction {
+    /// Compacted log files, ordered by id.
+    file_ids: Vec<u32>,
 
-        loop {
-            if log.read(&mut buf)? == 0 {
-                break;
-            }
+    /// Keys with live entries in the compacted log files.
+    keys: Vec<Vec<u8>>,
 
-            let header = Header::from(buf);
+    /// Index of the next key to rewrite.
+    next: usize,
 
-            let key_size = header.key_size();
-            let value_size = header.value_size();
+    /// Active log file when the compaction started.
+    first_output: u32,
 
-            let mut key = vec![0; key_size];
-            log.read_exact(&mut key)?;
+    started: Instant,
 
-            let value_pos = log.stream_position()?;
+    /// Entries rewritten so far.
+    entries: u64,
 
-            log.seek(SeekFrom::Current(value_size.try_into().unwrap()))?;
+    /// Bytes written so far.
+    bytes: u64,
 
-            let timestamp = header.timestamp();
+    #[cfg(feature = "opentelemetry")]
+    span: Option<crate::otel::CompactionSpan>,
 
-            let keydir_entry = KeydirEntry::new(file_id, value_size, value_pos, timestamp);
+    #[cfg(feature = "metrics")]
+    _timer: crate::metrics::Timer,
+}
 
-            if value_size > 0 {
-                keydir.put(key, keydir_entry);
-            } else {
-                keydir.remove(&key);
-            }
+impl Compaction {
+    /// Bytes per second written so far.
+    fn throughput(&self) -> u64 {
+        match self.started.elapsed().as_secs_f64() {
+            0.0 => 0,
+            secs => (self.bytes as f64 / secs) as u64,
         }
+    }
 
-        Ok(())
+    /// Time to wait until the bytes written so far are within the `rate_limit`.
+    fn delay(&self, rate_limit: Option<u64>) -> Duration {
+        match rate_limit {
+            Some(rate_limit) if rate_limit > 0 => {
+                Duration::from_secs_f64(self.bytes as f64 / rate_limit as f64)
+                    .saturating_sub(self.started.elapsed())
+            }
+            _ => Duration::ZERO,
+        }
     }
+}
 
-    fn rotate_log(&mut self, k_size: usize, v_size: usize) -> Result<(), io::Error> {
-        let mut active_file_entry = self.log_files.last_entry().unwrap();
-        let active_file_id = *active_file_entry.key();
-        let active_file = active_file_entry.get_mut();
+/// Bytes of live entries rewritten by merges since the start of the merge interval.
+#[derive(Debug, Default)]
+struct MergeBudget {
+    since: u64,
+    spent: u64,
+}
 
-        let estimated_entry_size = k_size + v_size + HEADER_SIZE;
+/// Value of a key built from merge operands.
+#[derive(Debug, Default)]
+struct MergeChain {
+    /// Entry of the value the operands are applied to.
+    base: Option<KeydirEntry>,
+    /// Merge operand entries, oldest first.
+    operands: Vec<KeydirEntry>,
+}
 
-        let current_file_size = active_file.stream_position()? as usize;
+type MergeChains = HashMap<Vec<u8>, MergeChain>;
 
-        if current_file_size + estimated_entry_size > self.opts.max_log_file_size {
-            active_file.flush()?;
+/// Key as a transaction read it, to detect later changes of the key on commit.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TxnRead {
+    /// Keydir entry the key was read through, `None` if it was absent.
+    keydir_entry: Option<KeydirEntry>,
+    /// Checksum of the value read, telling relocated entries from overwrites written in
+    /// the same second with values of the same size.
+    checksum: Option<u32>,
+}
 
-            let mut file_opts = OpenOptions::new();
-            file_opts.read(true).write(true).create(true);
+/// Version of the value of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// Timestamp of the entry written by the put or removal.
+    pub timestamp: u64,
+    /// Value, `None` if the key has been removed.
+    pub value: Option<Vec<u8>>,
+}
 
-            let new_active_file_id = active_file_id + 1;
-            let new_active_file = file_opts.open(
-                self.path
-                    .join(Self::format_log_file_name(new_active_file_id)),
-            )?;
+/// Value of a key with the metadata of the entry it was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueEntry {
+    /// Value of the key.
+    pub value: Vec<u8>,
+    /// Timestamp of the latest entry of the key.
+    pub timestamp: u64,
+    /// Log file containing the latest entry of the key.
+    pub file_id: u32,
+    /// Size of the value stored in the latest entry, the merge operand for merged values.
+    pub value_size: u64,
+}
+
+/// Per-call options of `put_opt`, overriding the policies set in `DbOptions`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PutOptions {
+    /// Whether the entry is synced to disk before returning, `DbOptions::sync_writes`
+    /// by default.
+    pub sync: Option<bool>,
+    /// Time after which the key expires, rounded up to whole seconds. Never by default.
+    pub ttl: Option<Duration>,
+    /// Timestamp of the entry, the current time by default.
+    pub timestamp_override: Option<u64>,
+}
+
+/// Per-call options of `get_opt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOptions {
+    /// Whether the checksum of the entry is verified. Entries of legacy log files have none.
+    pub verify_checksum: bool,
+    /// Whether the value is added to the value cache, see `DbOptions::value_cache_size`.
+    pub fill_cache: bool,
+}
 
-            self.log_files.insert(new_active_file_id, new_active_file);
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            verify_checksum: false,
+            fill_cache: true,
         }
+    }
+}
 
-        Ok(())
+/// Puts and removals written at once by `DiskStorage::write_batch`. Later operations on
+/// a key override earlier ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    /// Keys with their values, `None` for removals.
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    /// Adds a put of the value.
+    pub fn put(&mut self, k: Vec<u8>, v: Vec<u8>) {
+        self.ops.push((k, Some(v)));
     }
 
-    fn format_log_file_name(file_id: u32) -> String {
-        format!("{}.rumdb.log", file_id)
+    /// Adds a removal of the key.
+    pub fn remove(&mut self, k: &[u8]) {
+        self.ops.push((k.to_vec(), None));
     }
-}
 
-impl<K> Storage for DiskStorage<K>
-where
-    K: Keydir + KeydirDefault,
-{
-    fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
-        let res = match self.keydir.get(k) {
-            Some(keydir_entry) => {
-                let file_id = keydir_entry.file_id;
-                let mut buf = vec![0; keydir_entry.value_size as usize];
+    /// Number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
 
-                let file = self
-                    .log_files
-                    .get(&file_id)
-                    .ok_or(StorageError::UnknownLogFile(file_id))?;
+    /// Whether the batch has no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
 
-                file.read_exact_at(&mut buf, keydir_entry.value_pos)?;
+/// Previous version of a key: a put entry or a removal.
+#[derive(Debug, Clone, Copy)]
+struct PastVersion {
+    timestamp: u64,
+    entry: Option<KeydirEntry>,
+}
 
-                Some(buf)
-            }
-            None => None,
-        };
+/// Previous versions of keys, oldest first.
+#[derive(Debug, Default)]
+struct History {
+    versions: HashMap<Vec<u8>, VecDeque<PastVersion>>,
+    limit: usize,
+}
 
-        Ok(res)
+impl History {
+    fn new(limit: usize) -> Self {
+        Self {
+            versions: HashMap::new(),
+            limit,
+        }
     }
 
-    fn put(&mut self, k: Vec<u8>, v: Vec<u8>) -> Result<(), StorageError> {
-        self.rotate_log(k.len(), v.len())?;
+    /// Records that the key changes from its `current` entry at the `timestamp`, having been
+    /// `removed`. Returns whether the `current` entry has been retained and the entries of
+    /// versions no longer retained.
+    fn record(
+        &mut self,
+        k: &[u8],
+        current: Option<KeydirEntry>,
+        timestamp: u64,
+        removed: bool,
+    ) -> (bool, Vec<KeydirEntry>) {
+        if self.limit == 0 {
+            return (false, Vec::new());
+        }
 
-        let disk_entry = DiskEntry::new(&k, v);
+        let versions = self.versions.entry(k.to_vec()).or_default();
 
-        let mut active_file_entry = self.log_files.last_entry().unwrap();
+        // A version is replaced by the one written within the same second.
+        if current.is_none()
+            && versions
+                .back()
+                .is_some_and(|last| last.entry.is_none() && last.timestamp == timestamp)
+        {
+            versions.pop_back();
+        }
 
-        let active_file_id = *active_file_entry.key();
-        let active_file = active_file_entry.get_mut();
+        let mut retained = false;
 
-        active_file.write(disk_entry.header.as_slice())?;
-        active_file.write(disk_entry.key.as_slice())?;
-        active_file.write(disk_entry.value.as_slice())?;
+        if let Some(current) = current.filter(|current| current.timestamp != timestamp) {
+            versions.push_back(PastVersion {
+                timestamp: current.timestamp,
+                entry: Some(current),
+            });
+            retained = true;
+        }
 
-        let pos = active_file.stream_position()?;
-        let value_size = disk_entry.header.value_size();
-        let value_pos = pos - value_size as u64;
+        if removed && !versions.is_empty() {
+            versions.push_back(PastVersion {
+                timestamp,
+                entry: None,
+            });
+        }
 
-        let timestamp = disk_entry.header.timestamp();
+        let mut dropped = Vec::new();
 
-        let keydir_entry = KeydirEntry::new(active_file_id, value_size, value_pos, timestamp);
+        while versions.len() > self.limit {
+            dropped.extend(versions.pop_front().and_then(|version| version.entry));
+        }
 
-        self.keydir.put(k, keydir_entry);
+        if versions.is_empty() {
+            self.versions.remove(k);
+        }
 
-        Ok(())
+        (retained, dropped)
     }
 
-    fn remove(&mut self, k: &[u8]) -> Result<(), StorageError> {
-        if self.keydir.get(k).is_some() {
-            self.put(k.to_vec(), Vec::new())?;
-        }
+    /// Drops versions of the key, returning their entries.
+    fn remove(&mut self, k: &[u8]) -> Vec<KeydirEntry> {
+        self.versions
+            .remove(k)
+            .into_iter()
+            .flatten()
+            .filter_map(|version| version.entry)
+            .collect()
+    }
 
-        self.keydir.remove(k);
+    fn get(&self, k: &[u8]) -> impl DoubleEndedIterator<Item = &PastVersion> {
+        self.versions.get(k).into_iter().flatten()
+    }
 
-        Ok(())
+    fn entries(&self) -> impl Iterator<Item = (&Vec<u8>, &KeydirEntry)> {
+        self.versions.iter().flat_map(|(k, versions)| {
+            versions
+                .iter()
+                .filter_map(move |version| Some((k, version.entry.as_ref()?)))
+        })
     }
 }
 
-/// A simple lockfile for `DiskStorage`.
-#[derive(Debug)]
-struct Lockfile {
-    handle: Option<File>,
-    path: PathBuf,
-}
+type LogFile = Arc<dyn VfsFile>;
 
-impl Lockfile {
-    /// Creates a lock at the provided `path`. Fails if lock is already exists.
-    fn lock(path: impl AsRef<Path>) -> Result<Self, io::Error> {
-        let path = path.as_ref();
+/// Live entries of a log file.
+#[derive(Debug, Clone, Copy, Default)]
+struct LiveEntries {
+    count: u64,
+    bytes: u64,
+    /// Timestamp the last live entry has been released at.
+    dead_since: u64,
+}
 
-        let dir_path = path.parent().expect("lock file must have a parent");
-        fs::create_dir_all(dir_path)?;
+impl LiveEntries {
+    fn add(&mut self, k: &[u8], keydir_entry: &KeydirEntry, layout: Option<Layout>) {
+        self.count += 1;
+        self.bytes += entry_size(k, keydir_entry, layout);
+    }
 
-        let mut lockfile_opts = OpenOptions::new();
-        lockfile_opts.read(true).write(true).create_new(true);
+    fn release(&mut self, k: &[u8], keydir_entry: &KeydirEntry, layout: Option<Layout>) {
+        self.count = self.count.saturating_sub(1);
+        self.bytes = self
+            .bytes
+            .saturating_sub(entry_size(k, keydir_entry, layout));
 
-        let lockfile = lockfile_opts.open(path)?;
+        if self.count == 0 {
+            self.dead_since = DiskEntry::now();
+        }
+    }
+}
 
-        Ok(Self {
-            handle: Some(lockfile),
-            path: path.to_path_buf(),
-        })
+/// Size of the entry of the key in a log file with the `layout`. Entries of log files
+/// whose layout is unknown are assumed to have a header of the current format version.
+fn entry_size(k: &[u8], keydir_entry: &KeydirEntry, layout: Option<Layout>) -> u64 {
+    match layout {
+        // Sizes of compact headers follow from the fields stored in the keydir entry.
+        Some(layout) => Header::new(
+            keydir_entry.timestamp,
+            k.len() as u32,
+            keydir_entry.value_size,
+        )
+        .expiring(keydir_entry.expires_at)
+        .entry_size(layout),
+        None => entry_size_of(k.len(), keydir_entry.value_size),
     }
 }
 
-impl Drop for Lockfile {
-    fn drop(&mut self) {
-        self.handle.take();
-        fs::remove_file(&self.path).expect("lock already dropped.");
+fn entry_size_of(key_size: usize, value_size: u64) -> u64 {
+    (FormatVersion::CURRENT.header_size() + key_size) as u64 + value_size
+}
+
+/// Net effect of a log file on a key.
+#[derive(Debug, Default)]
+struct KeyUpdate {
+    /// Last put (`Some`) or removal (`None`) of the key in the log file, if any.
+    reset: Option<Option<KeydirEntry>>,
+    /// Merge operands written after the reset, oldest first.
+    operands: Vec<KeydirEntry>,
+}
+
+/// Disk storage statistics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiskStorageStats {
+    /// Number of live keys.
+    pub keys: usize,
+    /// Approximate number of bytes the keydir takes in memory.
+    pub keydir_memory: usize,
+    /// Number of log files.
+    pub log_files: usize,
+    /// Bytes taken by log files, entries buffered by the active log writer included.
+    pub disk_usage: u64,
+    /// Bytes of live entries.
+    pub live_bytes: u64,
+    /// Bytes of superseded entries, tombstones and segment headers.
+    pub dead_bytes: u64,
+    /// Statistics of each log file, ordered by id.
+    pub segments: Vec<SegmentStats>,
+    /// Number of `get` calls served from the value cache.
+    pub value_cache_hits: u64,
+    /// Number of `get` calls that missed the value cache and read log files.
+    pub value_cache_misses: u64,
+    /// Maximum compaction throughput in bytes per second, see
+    /// `DbOptions::compaction_rate_limit`.
+    pub compaction_rate_limit: Option<u64>,
+    /// Bytes per second written by the running or last compaction.
+    pub compaction_throughput: u64,
+    /// Latest compactions, oldest first, see `DiskStorage::compaction_history`.
+    pub compactions: Vec<CompactionRecord>,
+    /// Number of expired keys removed by `DiskStorage::sweep_expired` since open.
+    pub expired_swept: u64,
+}
+
+impl DiskStorageStats {
+    /// Share of the disk usage taken by dead bytes, from 0 to 1.
+    pub fn fragmentation(&self) -> f64 {
+        fragmentation(self.dead_bytes, self.disk_usage)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::keydir::HashmapKeydir;
+impl fmt::Display for DiskStorageStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "keys: {}, keydir memory: {} bytes, log files: {}, disk usage: {} bytes, \
+             dead: {} bytes ({:.1}%)",
+            self.keys,
+            self.keydir_memory,
+            self.log_files,
+            self.disk_usage,
+            self.dead_bytes,
+            self.fragmentation() * 100.0
+        )?;
 
-    use super::*;
+        write!(f, ", compaction: {} bytes/s", self.compaction_throughput)?;
 
-    #[test]
-    fn disk_storage_should_get_put() {
-        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
-        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+        if let Some(limit) = self.compaction_rate_limit {
+            write!(f, " (limit: {limit} bytes/s)")?;
+        }
 
-        let res = db.get(b"hello").unwrap();
-        assert_eq!(res, None);
+        if let Some(record) = self.compactions.last() {
+            write!(f, ", last compaction: {record}")?;
+        }
 
-        db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+        match self.expired_swept {
+            0 => Ok(()),
+            swept => write!(f, ", expired swept: {swept}"),
+        }
+    }
+}
 
-        let res = db.get(b"hello").unwrap();
-        assert_eq!(res, Some(b"world".to_vec()));
+/// Finished compaction or merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompactionRecord {
+    /// Timestamp the compaction finished at.
+    pub finished_at: u64,
+    /// Compacted log files.
+    pub inputs: Vec<u32>,
+    /// Log files live entries were rewritten into.
+    pub outputs: Vec<u32>,
+    /// Number of rewritten live entries.
+    pub entries_rewritten: u64,
+    /// Number of entries of the removed log files, less the rewritten ones.
+    pub entries_dropped: u64,
+    /// Bytes of the removed log files, less the rewritten bytes.
+    pub bytes_reclaimed: u64,
+    pub duration: Duration,
+}
 
-        db.put(b"hello".to_vec(), b"underworld".to_vec()).unwrap();
+impl fmt::Display for CompactionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} into {:?}, {} entries rewritten, {} dropped, {} bytes reclaimed in {:?}",
+            self.inputs,
+            self.outputs,
+            self.entries_rewritten,
+            self.entries_dropped,
+            self.bytes_reclaimed,
+            self.duration
+        )
+    }
+}
 
-        let res = db.get(b"hello").unwrap();
-        assert_eq!(res, Some(b"underworld".to_vec()));
+/// Log file statistics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SegmentStats {
+    pub file_id: u32,
+    /// Size of the log file in bytes.
+    pub total_bytes: u64,
+    /// Number of live entries.
+    pub live_entries: u64,
+    /// Bytes of live entries.
+    pub live_bytes: u64,
+    /// Bytes of superseded entries, tombstones and the segment header.
+    pub dead_bytes: u64,
+}
 
-        db.remove(b"hello").unwrap();
+impl SegmentStats {
+    /// Share of the log file taken by dead bytes, from 0 to 1.
+    pub fn fragmentation(&self) -> f64 {
+        fragmentation(self.dead_bytes, self.total_bytes)
+    }
+}
 
-        let res = db.get(b"hello").unwrap();
-        assert_eq!(res, None);
+fn fragmentation(dead_bytes: u64, total_bytes: u64) -> f64 {
+    match total_bytes {
+        0 => 0.0,
+        total_bytes => dead_bytes as f64 / total_bytes as f64,
     }
+}
 
-    #[test]
-    fn disk_storage_should_persist() {
-        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+/// Log file range lost on open: truncated or skipped as corrupted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostRange {
+    pub file_id: u32,
+    pub start: u64,
+    pub end: u64,
+}
 
-        {
-            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
-            db.put(b"persistence".to_vec(), b"check".to_vec()).unwrap();
-            db.put(b"removed".to_vec(), b"entry".to_vec()).unwrap();
-            db.remove(b"removed").unwrap();
-        }
+/// Log file ranges lost while opening a storage.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Lost ranges, ordered by file id and offset.
+    pub lost: Vec<LostRange>,
+}
 
-        {
-            let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+impl RecoveryReport {
+    /// Whether nothing was lost.
+    pub fn is_clean(&self) -> bool {
+        self.lost.is_empty()
+    }
 
-            let res = db.get(b"persistence").unwrap();
-            assert_eq!(res, Some(b"check".to_vec()));
+    /// Total number of lost bytes.
+    pub fn lost_bytes(&self) -> u64 {
+        self.lost.iter().map(|range| range.end - range.start).sum()
+    }
+}
 
-            let res = db.get(b"removed").unwrap();
-            assert_eq!(res, None);
+/// Progress of reading log files while opening a storage, see
+/// `StorageObserver::on_open_progress`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenProgress {
+    /// Number of log files read so far.
+    pub log_files_done: usize,
+    /// Number of log files to read.
+    pub log_files_total: usize,
+    /// Bytes of log files read so far.
+    pub bytes_done: u64,
+    /// Bytes of log files to read.
+    pub bytes_total: u64,
+}
+
+/// Recovery mode of an open along with the log file ranges lost so far.
+#[derive(Debug)]
+struct Recovery {
+    mode: RecoveryMode,
+    /// Entries written after this timestamp are ignored, see `DiskStorage::open_at`.
+    until: Option<u64>,
+    lost: Mutex<Vec<LostRange>>,
+    /// Observer notified of the progress, along with the storage directory.
+    observer: Option<(Arc<dyn StorageObserver>, PathBuf)>,
+    progress: Mutex<OpenProgress>,
+    /// Entry header layouts of the log files read so far.
+    layouts: Mutex<BTreeMap<u32, Layout>>,
+}
+
+impl Recovery {
+    fn new(opts: &DbOptions, path: &Path) -> Self {
+        Self {
+            mode: opts.recovery_mode,
+            until: opts.open_at,
+            lost: Mutex::new(Vec::new()),
+            observer: opts
+                .observer
+                .clone()
+                .map(|observer| (observer, path.to_path_buf())),
+            progress: Mutex::default(),
+            layouts: Mutex::default(),
         }
     }
 
-    #[test]
-    fn disk_storage_should_rotate_logs() {
-        const VERSION: u8 = 3;
-        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+    /// Sets the log files whose reading progress is reported.
+    fn start_progress(&self, logs: &BTreeMap<u32, LogFile>) {
+        if self.observer.is_none() {
+            return;
+        }
 
-        {
-            let mut db: DiskStorage<HashmapKeydir> =
-                DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(50)).unwrap();
+        let mut progress = self.progress.lock().unwrap_or_else(PoisonError::into_inner);
+        progress.log_files_total = logs.len();
+        progress.bytes_total = logs.values().map(|log| log.len().unwrap_or(0)).sum();
+    }
 
-            for i in 0..=VERSION {
-                db.put(b"version".to_vec(), vec![i]).unwrap();
+    /// Reports the `log` file as read.
+    fn log_read(&self, log: &LogFile) {
+        let Some((observer, path)) = &self.observer else {
+            return;
+        };
+
+        // Notified holding the lock, so the progress never goes backwards.
+        let mut progress = self.progress.lock().unwrap_or_else(PoisonError::into_inner);
+        progress.log_files_done += 1;
+        progress.bytes_done += log.len().unwrap_or(0);
+
+        observer.on_open_progress(path, &progress);
+    }
+
+    /// Records the entry header `layout` of the log file.
+    fn layout_read(&self, file_id: u32, layout: Layout) {
+        self.layouts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(file_id, layout);
+    }
+
+    /// Takes the entry header layouts of the log files read so far.
+    fn take_layouts(&self) -> BTreeMap<u32, Layout> {
+        std::mem::take(&mut *self.layouts.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn lose(&self, file_id: u32, start: u64, end: u64) {
+        self.lost
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(LostRange {
+                file_id,
+                start,
+                end,
+            });
+    }
+
+    fn into_report(self) -> RecoveryReport {
+        let mut lost = self
+            .lost
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        lost.sort_by_key(|range| (range.file_id, range.start));
+
+        RecoveryReport { lost }
+    }
+}
+
+/// Inconsistency found by `DiskStorage::verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// Log file entry whose checksum doesn't match.
+    ChecksumMismatch { file_id: u32, offset: u64 },
+    /// Log file which can't be read to the end.
+    UnreadableLogFile { file_id: u32, error: String },
+    /// Key pointing to a log file which doesn't exist.
+    MissingLogFile { key: Vec<u8>, file_id: u32 },
+    /// Key pointing to a log file entry whose checksum doesn't match.
+    CorruptValue {
+        key: Vec<u8>,
+        file_id: u32,
+        value_pos: u64,
+    },
+    /// Key pointing to a position of a log file with no value of the key.
+    DanglingKey {
+        key: Vec<u8>,
+        file_id: u32,
+        value_pos: u64,
+    },
+    /// Log file whose footer index doesn't match its entries.
+    FooterMismatch { file_id: u32 },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChecksumMismatch { file_id, offset } => {
+                write!(f, "checksum mismatch in {file_id}.rumdb.log at {offset}")
+            }
+            Self::UnreadableLogFile { file_id, error } => {
+                write!(f, "unreadable {file_id}.rumdb.log: {error}")
+            }
+            Self::MissingLogFile { key, file_id } => write!(
+                f,
+                "key {} points to missing {file_id}.rumdb.log",
+                key.escape_ascii()
+            ),
+            Self::CorruptValue {
+                key,
+                file_id,
+                value_pos,
+            } => write!(
+                f,
+                "key {} points to corrupt value in {file_id}.rumdb.log at {value_pos}",
+                key.escape_ascii()
+            ),
+            Self::DanglingKey {
+                key,
+                file_id,
+                value_pos,
+            } => write!(
+                f,
+                "key {} points to no value in {file_id}.rumdb.log at {value_pos}",
+                key.escape_ascii()
+            ),
+            Self::FooterMismatch { file_id } => {
+                write!(f, "footer of {file_id}.rumdb.log doesn't match its entries")
             }
         }
+    }
+}
 
-        assert!(
-            dir.path().join("1.rumdb.log").exists(),
-            "log file has not been rotated"
-        );
+/// Result of `DiskStorage::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of log files scanned.
+    pub log_files: usize,
+    /// Number of log file entries read, superseded ones included.
+    pub entries: u64,
+    /// Number of live keys checked.
+    pub keys: usize,
+    pub inconsistencies: Vec<Inconsistency>,
+}
 
-        {
-            let db: DiskStorage<HashmapKeydir> =
-                DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(50)).unwrap();
+impl VerifyReport {
+    /// Whether no inconsistencies were found.
+    pub fn is_ok(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
 
-            let res = db.get(b"version").unwrap();
-            assert_eq!(res, Some(vec![VERSION]));
-        }
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "log files: {}, entries: {}, keys: {}, inconsistencies: {}",
+            self.log_files,
+            self.entries,
+            self.keys,
+            self.inconsistencies.len()
+        )
+    }
+}
+
+/// Maximum gap between two values read by `get_many` with a single read.
+const MAX_COALESCE_GAP: u64 = 4 * 1024;
+
+/// Bytes `for_each_in_disk_order` reads at once, unless a single value is larger.
+const DISK_ORDER_READ_SIZE: u64 = 1024 * 1024;
+
+/// Number of keys `compact` reads the values of at once.
+const COMPACTION_BATCH_SIZE: usize = 64;
+
+/// Number of the latest compactions `DiskStorage::compaction_history` keeps.
+const COMPACTION_HISTORY_SIZE: usize = 32;
+
+/// Size of the write buffer of a `BulkLoader`.
+const BULK_LOAD_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Lock file held while the storage is open, relative to the storage directory.
+const LOCK_FILE: &str = "LOCK";
+
+/// Directory log files removed by GC are moved to, relative to the storage directory.
+/// See `DbOptions::trash_retention`.
+const TRASH_DIR: &str = "trash";
+
+/// Directory keyspaces are stored in, relative to the storage directory.
+const KEYSPACES_DIR: &str = "keyspaces";
+
+/// Leader log position reached by a replication follower.
+const REPLICATION_FILE: &str = "REPLICATION";
+
+/// Id of the first log file kept by a `clear`, present until older log files are removed.
+const CLEAR_FILE: &str = "CLEARED";
+
+impl<K> DiskStorage<K>
+where
+    K: Keydir + KeydirDefault,
+{
+    /// Opens or creates a new storage at the `path` directory.
+    pub fn open(path: impl AsRef<Path>, opts: DbOptions) -> Result<Self, StorageError> {
+        Self::_open(path, opts)
+    }
+
+    /// Opens or creates a new storage at the `path` directory with default options.
+    pub fn open_default(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::_open(path, DbOptions::default())
+    }
+
+    pub fn _open(path: impl AsRef<Path>, opts: DbOptions) -> Result<Self, StorageError> {
+        let path = path.as_ref();
+
+        // Storages opened at a point in time leave the directory as is.
+        if opts.open_at.is_none() {
+            create_dir_synced(&*opts.vfs, path)?;
+        } else if !exists_with_vfs(&*opts.vfs, path) {
+            return Err(StorageError::NotADatabase(path.to_path_buf()));
+        }
+
+        let lock = Lockfile::lock(&*opts.vfs, &path.join(LOCK_FILE))?;
+
+        if opts.open_at.is_none() {
+            Self::remove_temp_files(path, &opts)?;
+            Self::purge_trash(path, &opts)?;
+        }
+
+        log::info!("🏗  Building keydir...");
+
+        let recovery = Recovery::new(&opts, path);
+        let file_cache = FileCache::new(opts.vfs.clone(), opts.max_open_files, opts.direct_io);
+        let value_cache = ValueCache::new(opts.value_cache_size);
+        let (keydir, log_files, merge_chains, history) =
+            Self::build_keydir(path, &opts, &recovery, &file_cache)?;
+
+        log::info!("🏗  Keydir has been built successfully");
+
+        let keyspaces = Self::open_keyspaces(path, &opts)?;
+
+        let Some((active_file_id, active_file)) = log_files.last_key_value() else {
+            return Err(StorageError::UnknownLogFile(0));
+        };
+        let active = ActiveLog::new(*active_file_id, active_file, opts.write_buffer_size)?;
+
+        let mut layouts = recovery.take_layouts();
+        layouts.insert(active.file_id, active.layout);
+
+        let mut live_entries = Self::count_live_entries(&keydir, &merge_chains, &history, &layouts);
+
+        // It is unknown when log files without live entries died, so their history is
+        // retained as if they died now.
+        for file_id in log_files.keys() {
+            live_entries.entry(*file_id).or_insert_with(|| LiveEntries {
+                dead_since: DiskEntry::now(),
+                ..Default::default()
+            });
+        }
+
+        let recovery_report = recovery.into_report();
+
+        if let Some(observer) = opts
+            .observer
+            .as_ref()
+            .filter(|_| !recovery_report.is_clean())
+        {
+            observer.on_recovery(path, &recovery_report);
+        }
+
+        let mut db = Self {
+            path: path.to_path_buf(),
+            keydir,
+            log_files,
+            active,
+            _lock: lock,
+            opts,
+            keyspaces,
+            merge_chains,
+            live_entries,
+            layouts,
+            gc_pending: false,
+            clear_pending: None,
+            recovery_report,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
+            subscribers: Subscribers::default(),
+            history,
+            file_cache,
+            value_cache,
+            epochs: Arc::default(),
+            stall: None,
+            sealed_size: 0,
+            merge_budget: MergeBudget::default(),
+            compaction_throughput: 0,
+            compactions: VecDeque::new(),
+            missing_log_files: Mutex::default(),
+            expired_swept: 0,
+        };
+
+        db.update_sealed_size();
+        db.update_write_stall();
+
+        Ok(db)
+    }
+
+    /// Opens or creates a new storage at the `path` directory with default options and
+    /// the `mode` of handling corrupted log files. See `recovery_report` for what was lost.
+    pub fn open_with_recovery(
+        path: impl AsRef<Path>,
+        mode: RecoveryMode,
+    ) -> Result<Self, StorageError> {
+        Self::_open(path, DbOptions::default().recovery_mode(mode))
+    }
+
+    /// Opens the storage at the `path` directory as it was at the `timestamp`, ignoring
+    /// entries written after it. The storage is read-only; `export` it and `import` the dump
+    /// to restore the point in time. Log files are left as is, an incomplete entry at the end
+    /// of the active log file is ignored rather than truncated. Fails with `NotADatabase` if
+    /// the directory doesn't hold a storage.
+    ///
+    /// Overwritten values are only available until their log files are removed, so open
+    /// storages with `DbOptions::history_retention` to keep them for a while.
+    pub fn open_at(
+        path: impl AsRef<Path>,
+        mut opts: DbOptions,
+        timestamp: u64,
+    ) -> Result<Self, StorageError> {
+        opts.open_at = Some(timestamp);
+        opts.keydir_snapshot = false;
+
+        Self::_open(path, opts)
+    }
+
+    /// Fails if the storage has been opened at a point in time.
+    fn check_writable(&self) -> Result<(), StorageError> {
+        match self.opts.open_at {
+            Some(_) => Err(StorageError::ReadOnly),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if the key or the value size exceeds the limits set in `DbOptions`.
+    fn check_entry_size(&self, key_size: usize, value_size: u64) -> Result<(), StorageError> {
+        if key_size > self.opts.max_key_size {
+            return Err(StorageError::KeyTooLarge {
+                size: key_size,
+                max: self.opts.max_key_size,
+            });
+        }
+
+        if value_size > self.opts.max_value_size {
+            return Err(StorageError::ValueTooLarge {
+                size: value_size,
+                max: self.opts.max_value_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns log file ranges lost while opening the storage.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    /// Subscribes to changes of all keys. See `subscribe_prefix`.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        self.subscribe_prefix(b"")
+    }
+
+    /// Subscribes to changes of keys starting with the `prefix`. An event is sent after
+    /// every put, removal or merge of such a key, until the receiver is dropped.
+    ///
+    /// Events queue up in memory until received. Keyspaces are not included.
+    pub fn subscribe_prefix(&mut self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        self.subscribers.subscribe(prefix)
+    }
+
+    /// Watches the key for changes. Keys of keyspaces are watched on the keyspace.
+    pub fn watch(&mut self, k: &[u8]) -> Watch {
+        Watch::new(k, &mut self.subscribers)
+    }
+
+    /// Returns the current value of the key if a subscriber is interested in its changes.
+    fn value_for_subscribers(&self, k: &[u8]) -> Result<Option<Option<Vec<u8>>>, StorageError> {
+        if !self.subscribers.is_interested(k) {
+            return Ok(None);
+        }
+
+        let value = match self.keydir.get(k) {
+            Some(keydir_entry) => self.value_of(k, &keydir_entry)?,
+            None => None,
+        };
+
+        Ok(Some(value))
+    }
+
+    /// Returns a snapshot of the operation metrics. Keyspaces are not included.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    fn open_keyspaces(
+        path: &Path,
+        opts: &DbOptions,
+    ) -> Result<HashMap<String, Self>, StorageError> {
+        let mut keyspaces = HashMap::new();
+        let keyspaces_path = path.join(KEYSPACES_DIR);
+
+        if !opts.vfs.exists(&keyspaces_path) {
+            return Ok(keyspaces);
+        }
+
+        for name in opts.vfs.list(&keyspaces_path)? {
+            if Self::is_valid_keyspace_name(&name) {
+                log::info!("🗂  Opening keyspace: {name}");
+
+                let keyspace = Self::open(keyspaces_path.join(&name), opts.clone())?;
+                keyspaces.insert(name, keyspace);
+            }
+        }
+
+        Ok(keyspaces)
+    }
+
+    /// Returns the keyspace with the `name`, creating it if it doesn't exist.
+    ///
+    /// Keyspaces are isolated from each other and from the storage itself: each keeps its own
+    /// keydir and log files. Names may contain ASCII letters, digits, `-` and `_`.
+    pub fn keyspace(&mut self, name: &str) -> Result<&mut Self, StorageError> {
+        if !Self::is_valid_keyspace_name(name) {
+            return Err(StorageError::InvalidKeyspaceName(name.to_string()));
+        }
+
+        if !self.keyspaces.contains_key(name) {
+            let path = self.path.join(KEYSPACES_DIR).join(name);
+            let keyspace = Self::open(path, self.opts.clone())?;
+
+            self.keyspaces.insert(name.to_string(), keyspace);
+        }
+
+        Ok(self.keyspaces.get_mut(name).unwrap())
+    }
+
+    /// Returns the keyspace with the `name` if it exists.
+    pub fn get_keyspace(&self, name: &str) -> Option<&Self> {
+        self.keyspaces.get(name)
+    }
+
+    /// Returns the storage statistics. Keyspaces are not included.
+    pub fn storage_stats(&self) -> DiskStorageStats {
+        let segments: Vec<_> = self
+            .log_files
+            .iter()
+            .map(|(&file_id, file)| {
+                // Sealed log files never change, so their size only fails to read if
+                // the file is gone. Such a log file is counted as empty.
+                let total_bytes = if file_id == self.active.file_id {
+                    self.active.size
+                } else {
+                    file.len().unwrap_or(0)
+                };
+
+                let live = self.live_entries.get(&file_id).copied().unwrap_or_default();
+
+                SegmentStats {
+                    file_id,
+                    total_bytes,
+                    live_entries: live.count,
+                    live_bytes: live.bytes,
+                    dead_bytes: total_bytes.saturating_sub(live.bytes),
+                }
+            })
+            .collect();
+
+        DiskStorageStats {
+            keys: self.keydir.len(),
+            keydir_memory: self.keydir.approximate_memory_usage(),
+            log_files: self.log_files.len(),
+            disk_usage: segments.iter().map(|segment| segment.total_bytes).sum(),
+            live_bytes: segments.iter().map(|segment| segment.live_bytes).sum(),
+            dead_bytes: segments.iter().map(|segment| segment.dead_bytes).sum(),
+            segments,
+            value_cache_hits: self.value_cache.hits(),
+            value_cache_misses: self.value_cache.misses(),
+            compaction_rate_limit: self.opts.compaction_rate_limit,
+            compaction_throughput: self.compaction_throughput,
+            compactions: self.compactions.iter().cloned().collect(),
+            expired_swept: self.expired_swept,
+        }
+    }
+
+    /// Returns the latest compactions and merges, oldest first. Keyspaces are not included.
+    pub fn compaction_history(&self) -> impl Iterator<Item = &CompactionRecord> {
+        self.compactions.iter()
+    }
+
+    /// Scans every log file, verifying entry checksums, and checks that every key points
+    /// to a value of the key. Buffered entries are flushed first. Keyspaces are not included.
+    pub fn verify(&mut self) -> Result<VerifyReport, StorageError> {
+        self.active.flush()?;
+
+        let mut report = VerifyReport {
+            log_files: self.log_files.len(),
+            keys: self.keydir.len(),
+            ..Default::default()
+        };
+
+        // Key, value size and checksum validity of every value, by file id and position.
+        let mut values = HashMap::new();
+
+        for (&file_id, log) in self.log_files.iter() {
+            let reader = LogReader::new(log.clone()).map_err(|e| e.in_log_file(file_id))?;
+            let index = reader.read_index();
+
+            // Entries as indexed by the footer, checked against the footer index.
+            let mut indexed = Vec::new();
+            let mut readable = true;
+
+            for entry in reader {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        report
+                            .inconsistencies
+                            .push(Inconsistency::UnreadableLogFile {
+                                file_id,
+                                error: e.to_string(),
+                            });
+                        readable = false;
+                        break;
+                    }
+                };
+
+                report.entries += 1;
+
+                if let Ok(Some(_)) = index {
+                    indexed.push(IndexEntry::from(entry.clone()));
+                }
+
+                if entry.checksum_valid == Some(false) {
+                    report
+                        .inconsistencies
+                        .push(Inconsistency::ChecksumMismatch {
+                            file_id,
+                            offset: entry.offset,
+                        });
+                }
+
+                values.insert(
+                    (file_id, entry.value_pos),
+                    (entry.key, entry.value_size, entry.checksum_valid),
+                );
+            }
+
+            let footer_mismatch = match index {
+                Ok(Some(index)) => readable && index != indexed,
+                Ok(None) => false,
+                Err(_) => true,
+            };
+
+            if footer_mismatch {
+                report
+                    .inconsistencies
+                    .push(Inconsistency::FooterMismatch { file_id });
+            }
+        }
+
+        let chain_entries = self.merge_chains.iter().flat_map(|(k, chain)| {
+            chain
+                .base
+                .iter()
+                .chain(&chain.operands)
+                .map(|keydir_entry| (k.clone(), *keydir_entry))
+        });
+
+        let keydir_entries = self
+            .keydir
+            .iter()
+            .filter(|(k, _)| !self.merge_chains.contains_key(k));
+
+        for (key, keydir_entry) in keydir_entries.chain(chain_entries) {
+            let file_id = keydir_entry.file_id;
+            let value_pos = keydir_entry.value_pos;
+
+            if !self.log_files.contains_key(&file_id) {
+                report
+                    .inconsistencies
+                    .push(Inconsistency::MissingLogFile { key, file_id });
+                continue;
+            }
+
+            match values.get(&(file_id, value_pos)) {
+                Some((k, value_size, checksum_valid))
+                    if *k == key && *value_size == keydir_entry.value_size =>
+                {
+                    if *checksum_valid == Some(false) {
+                        report.inconsistencies.push(Inconsistency::CorruptValue {
+                            key,
+                            file_id,
+                            value_pos,
+                        });
+                    }
+                }
+                _ => report.inconsistencies.push(Inconsistency::DanglingKey {
+                    key,
+                    file_id,
+                    value_pos,
+                }),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Log files reads have found missing since the storage was opened or last repaired.
+    pub fn missing_log_files(&self) -> Vec<u32> {
+        let missing = self
+            .missing_log_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        missing.iter().copied().collect()
+    }
+
+    /// Drops sealed log files which have disappeared from the storage directory, e.g.
+    /// deleted by hand, so reads of their keys stop failing with
+    /// `StorageError::MissingLogFile`. Returns the lost keys, which are removed.
+    ///
+    /// Lost keys get tombstones, so they don't come back with older values from older log
+    /// files on open. Neither do keys the missing log files held tombstones of, so every key
+    /// of older log files missing from the keydir gets a tombstone too. Keyspaces are not
+    /// included.
+    pub fn repair_missing_segments(&mut self) -> Result<Vec<Vec<u8>>, StorageError> {
+        self.check_writable()?;
+
+        let missing: Vec<_> = self
+            .log_files
+            .keys()
+            .copied()
+            .filter(|&file_id| {
+                file_id != self.active.file_id
+                    && !self
+                        .opts
+                        .vfs
+                        .exists(&self.path.join(Self::format_log_file_name(file_id)))
+            })
+            .collect();
+
+        let Some(&newest_missing) = missing.last() else {
+            self.missing_log_files
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+            return Ok(Vec::new());
+        };
+
+        let lost = self.keys_in_log_files(|file_id| missing.binary_search(&file_id).is_ok());
+
+        for &file_id in &missing {
+            log::warn!(
+                "🩹 Dropping missing log file {}",
+                Self::format_log_file_name(file_id)
+            );
+
+            self.log_files.remove(&file_id);
+            self.file_cache
+                .evict(&self.path.join(Self::format_log_file_name(file_id)));
+            self.value_cache.evict_file(file_id);
+            self.live_entries.remove(&file_id);
+            self.layouts.remove(&file_id);
+        }
+
+        let mut removed = HashSet::new();
+
+        for (&file_id, log) in self.log_files.range(..newest_missing) {
+            for entry in Self::log_index(file_id, log)? {
+                if entry.kind != EntryKind::Tombstone && self.keydir.get(&entry.key).is_none() {
+                    removed.insert(entry.key);
+                }
+            }
+        }
+
+        let timestamp = self.now();
+
+        for k in lost.iter().chain(&removed) {
+            self.write_entry(&DiskEntry::tombstone(k).at(timestamp))?;
+            self.remove_keydir_entry(k);
+
+            for dropped in self.history.remove(k) {
+                self.release_entry(k, &dropped);
+            }
+        }
+
+        self.sync_active_log()?;
+        self.update_sealed_size();
+        self.update_write_stall();
+        self.missing_log_files
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+
+        log::warn!("🩹 {} keys lost with missing log files", lost.len());
+
+        Ok(lost)
+    }
+
+    /// Removes up to `limit` expired keys of the storage and its keyspaces by writing
+    /// tombstones, so their entries are reclaimed without the keys being read. Scans the
+    /// keydir. The observer is notified of every removed key. Returns the number of removed
+    /// keys.
+    pub fn sweep_expired(&mut self, limit: usize) -> Result<usize, StorageError> {
+        let expired = self.expired_keys(limit);
+        self.remove_expired(expired)
+    }
+
+    /// Finds up to `limit` expired keys of the storage and its keyspaces for
+    /// `remove_expired`. Only reads, so the keydir can be scanned under a shared lock.
+    pub(crate) fn expired_keys(&self, limit: usize) -> ExpiredKeys {
+        let keys: Vec<_> = self
+            .keydir
+            .iter()
+            .filter(|(k, keydir_entry)| self.is_expired(k, keydir_entry))
+            .map(|(k, _)| k)
+            .take(limit)
+            .collect();
+
+        let mut expired = ExpiredKeys {
+            keys,
+            keyspaces: HashMap::new(),
+        };
+
+        for (name, keyspace) in &self.keyspaces {
+            let keyspace_expired = keyspace.expired_keys(limit.saturating_sub(expired.len()));
+
+            if !keyspace_expired.is_empty() {
+                expired.keyspaces.insert(name.clone(), keyspace_expired);
+            }
+        }
+
+        expired
+    }
+
+    /// Removes the `expired` keys found by `expired_keys` which are still expired, by
+    /// writing tombstones. Returns the number of removed keys.
+    pub(crate) fn remove_expired(&mut self, expired: ExpiredKeys) -> Result<usize, StorageError> {
+        self.check_writable()?;
+
+        let timestamp = self.now();
+        let mut swept = 0;
+
+        for k in &expired.keys {
+            // The key may have been written since it was found expired.
+            if !self
+                .keydir
+                .get(k)
+                .is_some_and(|keydir_entry| self.is_expired(k, &keydir_entry))
+            {
+                continue;
+            }
+
+            self.remove_at(k, timestamp)?;
+            self.notify(|observer| observer.on_key_expired(&self.path, k));
+            swept += 1;
+        }
+
+        self.commit()?;
+        self.expired_swept += swept as u64;
+
+        if swept > 0 {
+            log::debug!("🧹 Swept {swept} expired keys");
+        }
+
+        for (name, keyspace_expired) in expired.keyspaces {
+            if let Some(keyspace) = self.keyspaces.get_mut(&name) {
+                swept += keyspace.remove_expired(keyspace_expired)?;
+            }
+        }
+
+        Ok(swept)
+    }
+
+    /// Rebuilds the keydir from the entries of the log files, the recovery tool of last
+    /// resort when index artifacts are corrupted. The keydir snapshot is removed, entry
+    /// checksums of sealed log files are verified, their footers are discarded and rewritten
+    /// from their entries. Returns the report of `verify` run on the rebuilt keydir.
+    /// Keyspaces are not included.
+    pub fn rebuild_index(&mut self) -> Result<VerifyReport, StorageError> {
+        self.check_writable()?;
+        self.active.flush()?;
+
+        log::info!("🏗  Rebuilding keydir from log files...");
+
+        let snapshot_path = self.path.join(SNAPSHOT_FILE);
+
+        if self.opts.vfs.exists(&snapshot_path) {
+            self.opts.vfs.remove(&snapshot_path)?;
+            self.opts.vfs.sync_dir(&self.path)?;
+        }
+
+        let active_file_id = self.active.file_id;
+        let sealed: Vec<u32> = self
+            .log_files
+            .keys()
+            .copied()
+            .filter(|&file_id| file_id != active_file_id)
+            .collect();
+
+        for &file_id in &sealed {
+            self.strip_footer(file_id)?;
+        }
+
+        let recovery = Recovery::new(&self.opts, &self.path);
+        let mut keydir = K::with_options(&self.opts);
+        let mut merge_chains = MergeChains::new();
+        let mut history = History::new(self.opts.history_versions);
+
+        recovery.start_progress(&self.log_files);
+
+        for (&file_id, log) in self.log_files.iter() {
+            Self::ingest_log(
+                &mut keydir,
+                &mut merge_chains,
+                &mut history,
+                file_id,
+                log,
+                file_id == active_file_id,
+                &recovery,
+            )?;
+        }
+
+        let mut layouts = recovery.take_layouts();
+        layouts.insert(self.active.file_id, self.active.layout);
+
+        let mut live_entries = Self::count_live_entries(&keydir, &merge_chains, &history, &layouts);
+
+        for file_id in self.log_files.keys() {
+            let dead_since = match self.live_entries.get(file_id) {
+                Some(live) if live.count == 0 => live.dead_since,
+                _ => DiskEntry::now(),
+            };
+
+            live_entries.entry(*file_id).or_insert(LiveEntries {
+                dead_since,
+                ..Default::default()
+            });
+        }
+
+        self.keydir = keydir;
+        self.merge_chains = merge_chains;
+        self.history = history;
+        self.live_entries = live_entries;
+        self.layouts = layouts;
+
+        for &file_id in &sealed {
+            self.rewrite_footer(file_id)?;
+        }
+
+        self.update_sealed_size();
+        self.update_write_stall();
+
+        log::info!(
+            "🏗  Keydir has been rebuilt from {} log files",
+            self.log_files.len()
+        );
+
+        self.verify()
+    }
+
+    /// Verifies entry checksums of the sealed log file and strips its footer, if any.
+    /// A footer with a corrupt trailer is recognized by its magic bytes after the last entry.
+    ///
+    /// Sealed log files are never resized, since they may be mapped or hard-linked by
+    /// checkpoints, so the entries are copied to a new log file replacing the old one.
+    fn strip_footer(&self, file_id: u32) -> Result<(), StorageError> {
+        let path = self.path.join(Self::format_log_file_name(file_id));
+        let file = self.opts.vfs.open(&path, OpenMode::ReadOnly)?;
+        let reader = LogReader::new(file.clone()).map_err(|e| e.in_log_file(file_id))?;
+
+        let mut entries_end = reader.version().segment_header_size() as u64;
+        let mut corruption = None;
+
+        for entry in reader {
+            match entry {
+                Ok(entry) if entry.checksum_valid != Some(false) => {
+                    entries_end = entry.offset + entry.size;
+                }
+                Ok(entry) => {
+                    corruption = Some(StorageError::ChecksumMismatch {
+                        file_id,
+                        offset: entry.offset,
+                    });
+                    break;
+                }
+                Err(e) => {
+                    corruption = Some(e.in_log_file(file_id));
+                    break;
+                }
+            }
+        }
+
+        if let Some(e) = corruption {
+            let mut magic = [0; FOOTER_MAGIC.len()];
+
+            if file.read_exact_at(&mut magic, entries_end).is_err() || &magic != FOOTER_MAGIC {
+                return Err(e);
+            }
+        }
+
+        if file.len()? > entries_end {
+            Self::copy_log_file(&*self.opts.vfs, &file, entries_end, &path)?;
+            self.file_cache.evict(&path);
+        }
+
+        Ok(())
+    }
+
+    /// Seals the sealed log file, whose footer has been stripped, with a footer indexing its
+    /// entries. Like `strip_footer`, writes a new log file replacing the old one. Log files
+    /// of format versions without footers are left as they are.
+    fn rewrite_footer(&self, file_id: u32) -> Result<(), StorageError> {
+        let vfs = &*self.opts.vfs;
+        let path = self.path.join(Self::format_log_file_name(file_id));
+        let file = vfs.open(&path, OpenMode::ReadOnly)?;
+        let reader = LogReader::new(file.clone()).map_err(|e| e.in_log_file(file_id))?;
+
+        if let FormatVersion::V1 | FormatVersion::V2 | FormatVersion::V3 = reader.version() {
+            return Ok(());
+        }
+
+        let footer_pos = reader.size();
+        let version = reader.version();
+        let index = reader
+            .verify_checksums(false)
+            .map(|entry| entry.map(IndexEntry::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        let live = self.live_entries.get(&file_id).map_or(0, |live| live.count);
+
+        let dst = TempFile::create(vfs, &path)?;
+        io::copy(
+            &mut VfsReader::new(&*file, 0).take(footer_pos),
+            &mut VfsAppender(dst.file().clone()),
+        )?;
+        dst.file()
+            .append_all(&footer::encode(&index, live, footer_pos, version))?;
+        dst.persist(vfs)?;
+        self.file_cache.evict(&path);
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the names of all keyspaces.
+    pub fn keyspace_names(&self) -> impl Iterator<Item = &str> {
+        self.keyspaces.keys().map(String::as_str)
+    }
+
+    fn is_valid_keyspace_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn build_keydir(
+        path: &Path,
+        opts: &DbOptions,
+        recovery: &Recovery,
+        file_cache: &Arc<FileCache>,
+    ) -> Result<(K, BTreeMap<u32, LogFile>, MergeChains, History), StorageError> {
+        let mut names: BTreeMap<u32, String> = opts
+            .vfs
+            .list(path)?
+            .into_iter()
+            .filter_map(|name| Some((name.strip_suffix(".rumdb.log")?.parse().ok()?, name)))
+            .collect();
+
+        // Log files left by an interrupted `clear` are ignored, and removed unless read-only.
+        if let Some(first_file_id) = Self::read_clear_marker(path, opts)? {
+            let kept = names.split_off(&first_file_id);
+
+            if opts.open_at.is_none() {
+                Self::remove_cleared_logs(path, opts, names.values())?;
+            }
+
+            names = kept;
+        }
+
+        let active_file_id = names.keys().last().copied();
+        let mut log_files = BTreeMap::<u32, LogFile>::new();
+
+        for (file_id, name) in names {
+            let file_path = path.join(&name);
+
+            if Some(file_id) == active_file_id {
+                log_files.insert(
+                    file_id,
+                    Self::open_log_file(opts, &file_path, OpenMode::Existing)?,
+                );
+                continue;
+            }
+
+            // Sealed log files are never written, unless corrupted entries are truncated.
+            let mode = match opts.recovery_mode {
+                RecoveryMode::Strict => Self::sealed_open_mode(opts),
+                RecoveryMode::SkipCorrupted => OpenMode::Existing,
+            };
+
+            log_files.insert(file_id, file_cache.file(file_path, mode));
+        }
+
+        let mut active_version = opts.format_version();
+
+        let mut history = History::new(opts.history_versions);
+
+        // Snapshots reflect the latest state of the storage only, without history.
+        let snapshot = match opts.open_at {
+            Some(_) => None,
+            None if opts.history_versions > 0 => None,
+            None => Self::load_snapshot(path, opts, &log_files, recovery),
+        };
+
+        let (keydir, merge_chains) = match snapshot {
+            Some(loaded) => {
+                if let Some((&file_id, log)) = log_files.last_key_value() {
+                    active_version = LogReader::new(log.clone())
+                        .map_err(|e| e.in_log_file(file_id))?
+                        .version();
+                }
+
+                loaded
+            }
+            None => {
+                let mut keydir = K::with_options(opts);
+                let mut merge_chains = MergeChains::new();
+
+                recovery.start_progress(&log_files);
+                let mut logs: Vec<_> = log_files.iter().collect();
+                let active_log = logs.pop();
+
+                // Net updates of log files ingested in parallel lose previous versions.
+                let threads = match opts.history_versions {
+                    0 => opts.keydir_build_threads,
+                    _ => 1,
+                };
+
+                Self::ingest_sealed_logs(
+                    &mut keydir,
+                    &mut merge_chains,
+                    &mut history,
+                    logs,
+                    threads,
+                    recovery,
+                )?;
+
+                if let Some((file_id, log)) = active_log {
+                    active_version = Self::ingest_log(
+                        &mut keydir,
+                        &mut merge_chains,
+                        &mut history,
+                        *file_id,
+                        log,
+                        true,
+                        recovery,
+                    )?;
+                }
+
+                (keydir, merge_chains)
+            }
+        };
+
+        let active_sealed = match log_files.last_key_value() {
+            Some((&file_id, log)) => LogReader::new(log.clone())
+                .map_err(|e| e.in_log_file(file_id))?
+                .has_footer(),
+            None => false,
+        };
+
+        match active_file_id {
+            // Storages opened at a point in time are never written.
+            _ if opts.open_at.is_some() => (),
+            // Entries are never appended to a log file of an older format version, or to
+            // a log file sealed right before a crash.
+            Some(file_id) if active_version != opts.format_version() || active_sealed => {
+                let file = Self::create_log_file(opts, path, file_id + 1)?;
+                log_files.insert(file_id + 1, file);
+            }
+            Some(_) => (),
+            None => {
+                let file = Self::create_log_file(opts, path, 0)?;
+                log_files.insert(0, file);
+            }
+        }
+
+        Ok((keydir, log_files, merge_chains, history))
+    }
+
+    /// Mode sealed log files are opened in.
+    #[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+    fn sealed_open_mode(opts: &DbOptions) -> OpenMode {
+        #[cfg(feature = "mmap")]
+        if opts.mmap_reads {
+            return OpenMode::Mapped;
+        }
+
+        OpenMode::ReadOnly
+    }
+
+    /// Counts live entries of each log file: entries the keydir points to, bases and
+    /// operands of merge chains, and retained previous versions.
+    fn count_live_entries(
+        keydir: &K,
+        merge_chains: &MergeChains,
+        history: &History,
+        layouts: &BTreeMap<u32, Layout>,
+    ) -> BTreeMap<u32, LiveEntries> {
+        let mut live_entries = BTreeMap::<u32, LiveEntries>::new();
+
+        for (k, keydir_entry) in history.entries() {
+            live_entries.entry(keydir_entry.file_id).or_default().add(
+                k,
+                keydir_entry,
+                layouts.get(&keydir_entry.file_id).copied(),
+            );
+        }
+
+        let chain_entries = merge_chains.iter().flat_map(|(k, chain)| {
+            chain
+                .base
+                .iter()
+                .chain(&chain.operands)
+                .map(move |keydir_entry| (k.clone(), *keydir_entry))
+        });
+
+        let keydir_entries = keydir.iter().filter(|(k, _)| !merge_chains.contains_key(k));
+
+        for (k, keydir_entry) in keydir_entries.chain(chain_entries) {
+            live_entries.entry(keydir_entry.file_id).or_default().add(
+                &k,
+                &keydir_entry,
+                layouts.get(&keydir_entry.file_id).copied(),
+            );
+        }
+
+        live_entries
+    }
+
+    /// Loads the keydir from the snapshot if it was taken against the current `log_files`.
+    /// The snapshot is removed either way, as the first write makes it stale.
+    fn load_snapshot(
+        path: &Path,
+        opts: &DbOptions,
+        log_files: &BTreeMap<u32, LogFile>,
+        recovery: &Recovery,
+    ) -> Option<(K, MergeChains)> {
+        let snapshot_path = path.join(SNAPSHOT_FILE);
+
+        if !opts.vfs.exists(&snapshot_path) {
+            return None;
+        }
+
+        // Corruption doesn't change log file sizes, so log files are scanned when recovering.
+        let loaded = match opts.recovery_mode {
+            RecoveryMode::Strict => Self::read_snapshot(&snapshot_path, opts, log_files, recovery),
+            RecoveryMode::SkipCorrupted => Ok(None),
+        };
+
+        if let Err(e) = opts
+            .vfs
+            .remove(&snapshot_path)
+            .and_then(|()| opts.vfs.sync_dir(path))
+        {
+            log::warn!("📸 Failed to remove keydir snapshot: {e}");
+        }
+
+        match loaded {
+            Ok(Some(loaded)) => {
+                log::info!("📸 Keydir has been loaded from the snapshot");
+                Some(loaded)
+            }
+            Ok(None) => {
+                log::info!("📸 Keydir snapshot is stale, scanning log files");
+                None
+            }
+            Err(e) => {
+                log::warn!("📸 Keydir snapshot is corrupt, scanning log files: {e}");
+                None
+            }
+        }
+    }
+
+    fn read_snapshot(
+        path: &Path,
+        opts: &DbOptions,
+        log_files: &BTreeMap<u32, LogFile>,
+        recovery: &Recovery,
+    ) -> Result<Option<(K, MergeChains)>, StorageError> {
+        let mut reader = SnapshotReader::open(&*opts.vfs, path)?;
+
+        if reader.log_files() != Self::log_file_sizes(log_files)?
+            || reader.tail_crc() != Self::active_tail_crc(log_files)?
+        {
+            return Ok(None);
+        }
+
+        let mut keydir = K::with_options(opts);
+        let mut merge_chains = MergeChains::new();
+
+        while let Some(record) = reader.next_record()? {
+            match record {
+                Record::Entry(k, entry) => keydir.put(k, entry),
+                Record::MergeChain {
+                    key,
+                    base,
+                    operands,
+                } => {
+                    merge_chains.insert(key, MergeChain { base, operands });
+                }
+            }
+        }
+
+        for &(file_id, layout) in reader.layouts() {
+            recovery.layout_read(file_id, layout);
+        }
+
+        Ok(Some((keydir, merge_chains)))
+    }
+
+    /// Ingests sealed log files on up to `threads` threads.
+    ///
+    /// Each thread reduces a log file to the net update of every key in it. Updates are
+    /// applied to the keydir in log file order, so the result is the same as of ingesting
+    /// the log files one by one. Threads read at most twice as many log files as there are
+    /// threads ahead of the one applied next, bounding the updates held in memory.
+    fn ingest_sealed_logs(
+        keydir: &mut K,
+        merge_chains: &mut MergeChains,
+        history: &mut History,
+        logs: Vec<(&u32, &LogFile)>,
+        threads: usize,
+        recovery: &Recovery,
+    ) -> Result<(), StorageError> {
+        if threads <= 1 || logs.len() <= 1 {
+            for (file_id, log) in logs {
+                Self::ingest_log(
+                    keydir,
+                    merge_chains,
+                    history,
+                    *file_id,
+                    log,
+                    false,
+                    recovery,
+                )?;
+            }
+
+            return Ok(());
+        }
+
+        let file_ids: Vec<u32> = logs.iter().map(|(file_id, _)| **file_id).collect();
+        let workers = threads.min(logs.len());
+        let read_ahead = workers * 2;
+        let queue = Mutex::new(logs.into_iter());
+        let (tx, rx) = mpsc::sync_channel(read_ahead);
+
+        // A thread takes a permit before reading a log file, and gets it back once the log
+        // file has been applied.
+        let (permit_tx, permit_rx) = mpsc::sync_channel(read_ahead);
+        let permits = Mutex::new(permit_rx);
+
+        for _ in 0..read_ahead {
+            let _ = permit_tx.send(());
+        }
+
+        let (queue, permits) = (&queue, &permits);
+
+        // The channels are moved into the scope, so threads blocked on them stop once it
+        // returns early.
+        thread::scope(move |scope| {
+            for _ in 0..workers {
+                let tx = tx.clone();
+
+                scope.spawn(move || loop {
+                    let permit = permits
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    let next = queue.lock().unwrap_or_else(PoisonError::into_inner).next();
+
+                    let (Ok(()), Some((file_id, log))) = (permit, next) else {
+                        break;
+                    };
+
+                    let updates = panic::catch_unwind(AssertUnwindSafe(|| {
+                        Self::read_key_updates(*file_id, log, recovery)
+                    }))
+                    .unwrap_or_else(|_| Err(io::Error::other("ingest thread panicked").into()));
+
+                    if tx.send((*file_id, updates)).is_err() {
+                        break;
+                    }
+                });
+            }
+
+            drop(tx);
+
+            let mut pending = BTreeMap::new();
+
+            for file_id in file_ids {
+                let updates = loop {
+                    if let Some(updates) = pending.remove(&file_id) {
+                        break updates;
+                    }
+
+                    let Ok((done_file_id, updates)) = rx.recv() else {
+                        return Err(io::Error::other("ingest threads have stopped early").into());
+                    };
+                    pending.insert(done_file_id, updates);
+                };
+
+                for (key, update) in updates? {
+                    Self::apply_key_update(keydir, merge_chains, key, update);
+                }
+
+                let _ = permit_tx.send(());
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Reads the net update of every key in the sealed log file.
+    fn read_key_updates(
+        file_id: u32,
+        log: &LogFile,
+        recovery: &Recovery,
+    ) -> Result<HashMap<Vec<u8>, KeyUpdate>, StorageError> {
+        let mut updates = HashMap::<_, KeyUpdate>::new();
+
+        Self::read_log(file_id, log, false, recovery, |key, keydir_entry, kind| {
+            let update = updates.entry(key).or_default();
+
+            match kind {
+                EntryKind::MergeOperand => update.operands.push(keydir_entry),
+                EntryKind::Put | EntryKind::Tombstone => {
+                    update.reset = Some((kind == EntryKind::Put).then_some(keydir_entry));
+                    update.operands.clear();
+                }
+            }
+        })?;
+        recovery.log_read(log);
+
+        Ok(updates)
+    }
+
+    fn apply_key_update(
+        keydir: &mut K,
+        merge_chains: &mut MergeChains,
+        key: Vec<u8>,
+        update: KeyUpdate,
+    ) {
+        if let Some(reset) = update.reset {
+            merge_chains.remove(&key);
+
+            match reset {
+                Some(keydir_entry) => keydir.put(key.clone(), keydir_entry),
+                None => {
+                    keydir.remove(&key);
+                }
+            }
+        }
+
+        for operand in update.operands {
+            Self::push_merge_operand(keydir, merge_chains, key.clone(), operand);
+        }
+    }
+
+    /// Reads all entries of the log file into the keydir. Returns the log file format version.
+    fn ingest_log(
+        keydir: &mut K,
+        merge_chains: &mut MergeChains,
+        history: &mut History,
+        file_id: u32,
+        log: &LogFile,
+        active: bool,
+        recovery: &Recovery,
+    ) -> Result<FormatVersion, StorageError> {
+        let version = Self::read_log(file_id, log, active, recovery, |key, keydir_entry, kind| {
+            if kind == EntryKind::MergeOperand {
+                history.remove(&key);
+                Self::push_merge_operand(keydir, merge_chains, key, keydir_entry);
+
+                return;
+            }
+
+            let current = match merge_chains.remove(&key) {
+                Some(_) => None,
+                None => keydir.get(&key),
+            };
+
+            let removed = kind == EntryKind::Tombstone;
+            history.record(&key, current, keydir_entry.timestamp, removed);
+
+            if removed {
+                keydir.remove(&key);
+            } else {
+                keydir.put(key, keydir_entry);
+            }
+        })?;
+        recovery.log_read(log);
+
+        Ok(version)
+    }
+
+    /// Reads all entries of the log file, passing them to `on_entry`.
+    /// Returns the log file format version.
+    ///
+    /// An incomplete trailing entry, left by a crash in the middle of a write, is truncated
+    /// if the log file is the active one. In a sealed log file it is an error, unless
+    /// corrupted entries are skipped. Entries of an atomic batch are passed on once the whole
+    /// batch is read, an incomplete batch is lost along with the corrupted entry.
+    fn read_log(
+        file_id: u32,
+        log: &LogFile,
+        active: bool,
+        recovery: &Recovery,
+        mut on_entry: impl FnMut(Vec<u8>, KeydirEntry, EntryKind),
+    ) -> Result<FormatVersion, StorageError> {
+        log::info!("💾 Ingesting: {}", Self::format_log_file_name(file_id));
+
+        let skip_corrupted = recovery.mode == RecoveryMode::SkipCorrupted;
+
+        // Only the active log file may contain a torn entry, so checksums of sealed
+        // log files are not verified to keep the startup fast.
+        let mut reader = LogReader::new(log.clone())
+            .map_err(|e| e.in_log_file(file_id))?
+            .verify_checksums(active || skip_corrupted);
+        let version = reader.version();
+        let log_size = reader.size();
+        recovery.layout_read(file_id, reader.layout());
+        let entries_pos = version.segment_header_size() as u64;
+
+        // Sealed log files are ingested from the index in their footer, if it's intact.
+        if !active && !skip_corrupted {
+            match reader.read_index() {
+                Ok(Some(index)) => {
+                    for entry in index {
+                        // Keys precede their values, which end before the footer.
+                        let in_bounds = entry.value_pos >= entries_pos + entry.key.len() as u64
+                            && entry
+                                .value_pos
+                                .checked_add(entry.value_size)
+                                .is_some_and(|end| end <= log_size);
+
+                        if !in_bounds {
+                            return Err(StorageError::Corrupted {
+                                file_id,
+                                offset: entry.value_pos,
+                            });
+                        }
+
+                        if recovery.until.is_some_and(|until| entry.timestamp > until) {
+                            continue;
+                        }
+
+                        let keydir_entry = KeydirEntry::new(
+                            file_id,
+                            entry.value_size,
+                            entry.value_pos,
+                            entry.timestamp,
+                        )
+                        .expiring(entry.expires_at);
+
+                        on_entry(entry.key, keydir_entry, entry.kind);
+                    }
+
+                    return Ok(version);
+                }
+                Ok(None) => (),
+                Err(e) => log::warn!(
+                    "⚠️  Unreadable footer of {}, scanning entries: {e}",
+                    Self::format_log_file_name(file_id)
+                ),
+            }
+        }
+
+        let mut batch = Vec::new();
+        let mut batch_start = None;
+
+        while let Some(entry) = reader.next() {
+            let corrupt_pos = match entry {
+                Ok(entry) if recovery.until.is_some_and(|until| entry.timestamp > until) => {
+                    continue;
+                }
+                Ok(entry) if entry.checksum_valid != Some(false) => {
+                    let keydir_entry = KeydirEntry::new(
+                        file_id,
+                        entry.value_size,
+                        entry.value_pos,
+                        entry.timestamp,
+                    )
+                    .expiring(entry.expires_at);
+
+                    if entry.batched {
+                        batch_start.get_or_insert(entry.offset);
+                        batch.push((entry.key, keydir_entry, entry.kind));
+                        continue;
+                    }
+
+                    for (key, keydir_entry, kind) in batch.drain(..) {
+                        on_entry(key, keydir_entry, kind);
+                    }
+
+                    batch_start = None;
+                    on_entry(entry.key, keydir_entry, entry.kind);
+                    continue;
+                }
+                Ok(entry) if !skip_corrupted && entry.offset + entry.size < log_size => {
+                    return Err(StorageError::ChecksumMismatch {
+                        file_id,
+                        offset: entry.offset,
+                    });
+                }
+                Ok(entry) => entry.offset,
+                Err(StorageError::FormatError(FormatError::TornEntry(pos))) => pos,
+                Err(e) => return Err(e),
+            };
+
+            let lost_pos = batch_start.take().unwrap_or(corrupt_pos);
+            batch.clear();
+
+            if skip_corrupted {
+                if let Some(next_pos) = reader.skip_to_valid_entry(corrupt_pos + 1)? {
+                    log::warn!(
+                        "⏭  Skipping corrupted entries in {} at {lost_pos}..{next_pos}",
+                        Self::format_log_file_name(file_id)
+                    );
+
+                    recovery.lose(file_id, lost_pos, next_pos);
+                    continue;
+                }
+            }
+
+            Self::truncate_torn_entry(
+                &**log,
+                file_id,
+                lost_pos,
+                active || skip_corrupted,
+                recovery,
+            )?;
+            recovery.lose(file_id, lost_pos, log_size);
+            break;
+        }
+
+        // A crash in the middle of a batch leaves its first entries only.
+        if let Some(batch_start) = batch_start {
+            Self::truncate_torn_entry(
+                &**log,
+                file_id,
+                batch_start,
+                active || skip_corrupted,
+                recovery,
+            )?;
+            recovery.lose(file_id, batch_start, log_size);
+        }
+
+        Ok(version)
+    }
+
+    /// Truncates the log file at `pos`, dropping the incomplete entry written there.
+    /// Storages opened at a point in time ignore the entry instead, leaving the log file as is.
+    /// Fails if `truncate` is false.
+    fn truncate_torn_entry(
+        log: &dyn VfsFile,
+        file_id: u32,
+        pos: u64,
+        truncate: bool,
+        recovery: &Recovery,
+    ) -> Result<(), StorageError> {
+        if !truncate {
+            return Err(StorageError::Corrupted {
+                file_id,
+                offset: pos,
+            });
+        }
+
+        if recovery.until.is_some() {
+            log::warn!(
+                "⏭  Ignoring incomplete entry in {} at {pos}",
+                Self::format_log_file_name(file_id)
+            );
+
+            return Ok(());
+        }
+
+        log::warn!(
+            "✂️  Truncating incomplete entry in {} at {pos}",
+            Self::format_log_file_name(file_id)
+        );
+
+        Ok(log.set_len(pos)?)
+    }
+
+    /// Seals the active log file if `size` more bytes would not fit into it.
+    fn rotate_log(&mut self, size: u64) -> Result<(), StorageError> {
+        if self.active.size + size > self.opts.max_log_file_size as u64 {
+            self.seal_active_log(self.active.file_id + 1)?;
+            self.gc_pending = true;
+            self.update_sealed_size();
+            self.update_write_stall();
+        }
+
+        Ok(())
+    }
+
+    /// Seals the active log file and creates the next one with the `new_active_file_id`.
+    fn seal_active_log(&mut self, new_active_file_id: u32) -> Result<(), StorageError> {
+        self.active.flush()?;
+        self.write_footer()?;
+
+        let sealed_file_id = self.active.file_id;
+        let new_active_file = Self::create_log_file(&self.opts, &self.path, new_active_file_id)?;
+
+        self.active = ActiveLog::new(
+            new_active_file_id,
+            &new_active_file,
+            self.opts.write_buffer_size,
+        )?;
+        self.log_files.insert(new_active_file_id, new_active_file);
+        self.layouts.insert(new_active_file_id, self.active.layout);
+
+        let sealed_path = self.path.join(Self::format_log_file_name(sealed_file_id));
+        self.log_files.insert(
+            sealed_file_id,
+            self.file_cache
+                .file(sealed_path, Self::sealed_open_mode(&self.opts)),
+        );
+
+        self.notify(|observer| observer.on_log_sealed(&self.path, sealed_file_id));
+
+        Ok(())
+    }
+
+    /// Appends a footer indexing the entries of the active log file, which is being sealed.
+    ///
+    /// The footer is synced before the next log file is created, so a crash in between
+    /// leaves an incomplete footer in the active log file only, where it is truncated.
+    fn write_footer(&mut self) -> Result<(), StorageError> {
+        let file_id = self.active.file_id;
+        let file = self.active_log_file()?.clone();
+
+        let index = LogReader::new(file.clone())
+            .map_err(|e| e.in_log_file(file_id))?
+            .verify_checksums(false)
+            .map(|entry| entry.map(IndexEntry::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        let live = self.live_entries.get(&file_id).map_or(0, |live| live.count);
+
+        let footer = footer::encode(&index, live, self.active.size, self.active.layout.version);
+        self.active
+            .write_all_vectored(&mut [IoSlice::new(&footer)])?;
+        self.active.flush()?;
+        file.sync()?;
+
+        Ok(())
+    }
+
+    /// Removes dead log files if a log file has been rotated since the last GC, in this
+    /// storage and its keyspaces. GC is kept off the write path, so writes don't pay for
+    /// it: `Database` runs it in the background, `compact` runs it too.
+    pub fn gc_rotated_logs(&mut self) -> Result<(), StorageError> {
+        if self.gc_pending {
+            self.gc()?;
+        }
+
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.gc_rotated_logs()?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the oldest sealed log files without live entries for longer than the history
+    /// retention.
+    ///
+    /// A log file may hold tombstones shadowing entries in older log files, so only log
+    /// files without any older log files left are deleted.
+    fn gc(&mut self) -> Result<(), io::Error> {
+        let active_file_id = self.active.file_id;
+        let retention = self.opts.history_retention.as_secs();
+        let now = DiskEntry::now();
+
+        while let Some((&file_id, _)) = self.log_files.first_key_value() {
+            let live = self.live_entries.get(&file_id).copied().unwrap_or_default();
+
+            if file_id == active_file_id
+                || live.count > 0
+                || (retention > 0 && live.dead_since + retention > now)
+            {
+                break;
+            }
+
+            self.retire_log(file_id);
+        }
+
+        self.update_sealed_size();
+        self.update_write_stall();
+        self.reclaim_logs()?;
+        self.gc_pending = false;
+
+        Ok(())
+    }
+
+    /// Deletes sealed log files without live entries for longer than the history retention
+    /// which `gc` keeps behind older log files, unless they hold tombstones which may be
+    /// needed.
+    ///
+    /// A tombstone may only be dropped once no older log file can contain its key, or the
+    /// key has been put again in a newer log file. Otherwise dropping it would resurrect the
+    /// removed value the next time the keydir is built. Keys of log files are read from
+    /// their footers, or by scanning them, so this runs on `compact` only.
+    fn gc_tombstones(&mut self) -> Result<(), StorageError> {
+        let active_file_id = self.active.file_id;
+        let retention = self.opts.history_retention.as_secs();
+        let now = DiskEntry::now();
+
+        let is_dead = |live: Option<&LiveEntries>| {
+            let live = live.copied().unwrap_or_default();
+            live.count == 0 && (retention == 0 || live.dead_since + retention <= now)
+        };
+
+        let Some(last_dead) = self
+            .log_files
+            .keys()
+            .rev()
+            .copied()
+            .filter(|&file_id| file_id != active_file_id)
+            .find(|file_id| is_dead(self.live_entries.get(file_id)))
+        else {
+            return Ok(());
+        };
+
+        // Keys of the log files kept so far, which are older than the current one.
+        let mut older_keys = HashSet::new();
+        let mut dropped = Vec::new();
+
+        for (&file_id, log) in self.log_files.range(..=last_dead) {
+            let index = Self::log_index(file_id, log)?;
+
+            // The active log file is older than others while bulk loaded log files are
+            // moved into place, so it is told apart by id rather than by position.
+            let droppable = file_id != active_file_id
+                && is_dead(self.live_entries.get(&file_id))
+                && index
+                    .iter()
+                    .filter(|entry| entry.kind == EntryKind::Tombstone)
+                    .all(|entry| {
+                        !older_keys.contains(&entry.key)
+                            || (!self.merge_chains.contains_key(&entry.key)
+                                && self
+                                    .keydir
+                                    .get(&entry.key)
+                                    .is_some_and(|keydir_entry| keydir_entry.file_id > file_id))
+                    });
+
+            if droppable {
+                dropped.push(file_id);
+            } else {
+                older_keys.extend(index.into_iter().map(|entry| entry.key));
+            }
+        }
+
+        for file_id in dropped {
+            log::info!(
+                "🪦 Dropping {} with tombstones shadowing no older log file",
+                Self::format_log_file_name(file_id)
+            );
+            self.retire_log(file_id);
+        }
+
+        self.update_sealed_size();
+        self.update_write_stall();
+
+        Ok(self.reclaim_logs()?)
+    }
+
+    /// Index of the entries of the log file, read from its footer or by scanning it.
+    fn log_index(file_id: u32, log: &LogFile) -> Result<Vec<IndexEntry>, StorageError> {
+        let reader = LogReader::new(log.clone()).map_err(|e| e.in_log_file(file_id))?;
+
+        if let Ok(Some(index)) = reader.read_index() {
+            return Ok(index);
+        }
+
+        reader
+            .verify_checksums(false)
+            .map(|entry| entry.map(IndexEntry::from))
+            .collect()
+    }
+
+    /// Removes the log file from the storage, to be deleted once no reader may reference it.
+    /// The active log file is never removed.
+    fn retire_log(&mut self, file_id: u32) {
+        if file_id == self.active.file_id {
+            debug_assert!(false, "active log file {file_id} retired");
+            return;
+        }
+
+        if let Some(file) = self.log_files.remove(&file_id) {
+            self.epochs.retire(file_id, file);
+        }
+
+        self.value_cache.evict_file(file_id);
+        self.live_entries.remove(&file_id);
+        self.layouts.remove(&file_id);
+    }
+
+    /// Sums sizes of sealed log files, which never change, for `DbOptions::max_db_size`.
+    fn update_sealed_size(&mut self) {
+        if self.opts.max_db_size.is_none() {
+            return;
+        }
+
+        // As in `storage_stats`, a log file whose size fails to read is counted as empty.
+        self.sealed_size = self
+            .log_files
+            .iter()
+            .filter(|(&file_id, _)| file_id != self.active.file_id)
+            .map(|(_, file)| file.len().unwrap_or(0))
+            .sum();
+    }
+
+    /// Fails if writing `size` more bytes would take the log files past
+    /// `DbOptions::max_db_size`. Compacts first if that could reclaim enough space.
+    fn check_quota(&mut self, size: u64) -> Result<(), StorageError> {
+        let Some(max) = self.opts.max_db_size else {
+            return Ok(());
+        };
+
+        let projected = |db: &Self| db.sealed_size + db.active.size + size;
+
+        if projected(self) <= max {
+            return Ok(());
+        }
+
+        // Removing dead log files is cheaper than compacting.
+        if self.gc_pending {
+            self.gc()?;
+
+            if projected(self) <= max {
+                return Ok(());
+            }
+        }
+
+        let sealed_live_bytes: u64 = self
+            .live_entries
+            .iter()
+            .filter(|(&file_id, _)| file_id != self.active.file_id)
+            .map(|(_, live)| live.bytes)
+            .sum();
+        let reclaimable = self.sealed_size.saturating_sub(sealed_live_bytes);
+
+        // Log files are kept for the history retention, so compaction wouldn't reclaim them.
+        if self.opts.history_retention.is_zero() && reclaimable >= projected(self) - max {
+            log::info!("🗜  Compacting to stay within the quota of {max} bytes");
+            self.compact()?;
+        }
+
+        match projected(self) {
+            size if size > max => Err(StorageError::QuotaExceeded { size, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Stalls or resumes writes depending on the thresholds set in `DbOptions`.
+    fn update_write_stall(&mut self) {
+        let log_files = self.log_files.len();
+        let stall = match (self.opts.stall_log_files, self.opts.stall_fragmentation) {
+            (Some(max), _) if log_files > max => {
+                Some(format!("{log_files} log files exceed the maximum of {max}"))
+            }
+            // Only sealed log files can be compacted.
+            (_, Some(max)) if log_files > 1 => {
+                let fragmentation = self.storage_stats().fragmentation();
+
+                (fragmentation > max).then(|| {
+                    format!(
+                        "fragmentation of {:.1}% exceeds the maximum of {:.1}%",
+                        fragmentation * 100.0,
+                        max * 100.0
+                    )
+                })
+            }
+            _ => None,
+        };
+
+        match (&self.stall, &stall) {
+            (None, Some(reason)) => log::warn!("🚦 Stalling writes: {reason}"),
+            (Some(_), None) => log::info!("🚦 Resuming writes"),
+            _ => (),
+        }
+
+        self.stall = stall;
+    }
+
+    /// Rejects or delays the write if writes are stalled.
+    fn check_write_stall(&self) -> Result<(), StorageError> {
+        match (&self.stall, self.opts.write_stall_mode) {
+            (None, _) => Ok(()),
+            (Some(reason), WriteStallMode::Reject) => {
+                Err(StorageError::WriteStalled(reason.clone()))
+            }
+            (Some(_), WriteStallMode::Delay(delay)) => {
+                thread::sleep(delay);
+                Ok(())
+            }
+        }
+    }
+
+    /// Deletes log files retired by GC which no reader may reference anymore.
+    pub(crate) fn reclaim_logs(&mut self) -> Result<(), io::Error> {
+        let reclaimable = self.epochs.reclaim();
+
+        for &file_id in &reclaimable {
+            let name = Self::format_log_file_name(file_id);
+            let file_path = self.path.join(&name);
+            self.file_cache.evict(&file_path);
+            Self::discard_log_file(&self.path, &self.opts, &name)?;
+
+            self.notify(|observer| observer.on_log_removed(&self.path, file_id));
+        }
+
+        if !reclaimable.is_empty() {
+            self.opts.vfs.sync_dir(&self.path)?;
+            Self::purge_trash(&self.path, &self.opts)?;
+        }
+
+        if let Some(first_file_id) = self.clear_pending {
+            if !self.epochs.retired_before(first_file_id) {
+                Self::remove_clear_marker(&self.path, &self.opts)?;
+                self.clear_pending = None;
+            }
+        }
+
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.reclaim_logs()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites live entries of sealed log files into the active log file, so the sealed
+    /// log files can be deleted. Merge operands are folded into values.
+    pub fn compact(&mut self) -> Result<(), StorageError> {
+        let compaction = self.start_compaction()?;
+        self.run_compaction(compaction)?;
+
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites live entries of the log files into the active log file, then removes them
+    /// unless GC has to keep them, e.g. after a large batch delete. The active log file is
+    /// sealed first if listed. Fails if a log file doesn't exist. Keyspaces are not included.
+    pub fn compact_segments(&mut self, file_ids: &[u32]) -> Result<(), StorageError> {
+        let compaction = self.start_segments_compaction(file_ids)?;
+        self.run_compaction(compaction)
+    }
+
+    /// Seals the active log file and compacts all log files, keyspaces included, so even
+    /// dead entries of the active log file are removed.
+    pub fn compact_all(&mut self) -> Result<(), StorageError> {
+        let compaction = self.start_full_compaction()?;
+        self.run_compaction(compaction)?;
+
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.compact_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Merges the sealed log files with a share of dead bytes of at least
+    /// `DbOptions::merge_fragmentation`, most fragmented first, rewriting their live entries
+    /// into the active log file. Log files are picked as long as their live bytes fit into
+    /// what is left of `DbOptions::merge_budget` for the current merge interval, so merges
+    /// are spread over intervals. Returns the ids of the merged log files.
+    ///
+    /// Keyspaces are merged too, each within a budget of its own. Does nothing unless
+    /// merging is enabled or on storages opened at a point in time.
+    pub fn merge_fragmented(&mut self) -> Result<Vec<u32>, StorageError> {
+        let mut file_ids = Vec::new();
+
+        if let Some(compaction) = self.start_merge()? {
+            file_ids.clone_from(&compaction.file_ids);
+            self.run_compaction(compaction)?;
+        }
+
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.merge_fragmented()?;
+        }
+
+        Ok(file_ids)
+    }
+
+    /// Starts compacting the sealed log files, see `compact`. Keyspaces are not included.
+    pub(crate) fn start_compaction(&mut self) -> Result<Compaction, StorageError> {
+        let active_file_id = self.active.file_id;
+        let file_ids = self.log_files.range(..active_file_id).map(|(&id, _)| id);
+
+        self.compaction_of(file_ids.collect())
+    }
+
+    /// Starts compacting the log files, see `compact_segments`.
+    pub(crate) fn start_segments_compaction(
+        &mut self,
+        file_ids: &[u32],
+    ) -> Result<Compaction, StorageError> {
+        self.check_writable()?;
+
+        if let Some(&file_id) = file_ids.iter().find(|id| !self.log_files.contains_key(id)) {
+            return Err(StorageError::UnknownLogFile(file_id));
+        }
+
+        if file_ids.contains(&self.active.file_id) {
+            self.seal_active_log(self.active.file_id + 1)?;
+        }
+
+        self.compaction_of(file_ids.to_vec())
+    }
+
+    /// Starts compacting all log files, see `compact_all`. Keyspaces are not included.
+    pub(crate) fn start_full_compaction(&mut self) -> Result<Compaction, StorageError> {
+        self.check_writable()?;
+        self.seal_active_log(self.active.file_id + 1)?;
+
+        self.start_compaction()
+    }
+
+    /// Starts merging the most fragmented log files within the merge budget, if any, see
+    /// `merge_fragmented`. Keyspaces are not included.
+    pub(crate) fn start_merge(&mut self) -> Result<Option<Compaction>, StorageError> {
+        let (Some(min_fragmentation), None) = (self.opts.merge_fragmentation, self.opts.open_at)
+        else {
+            return Ok(None);
+        };
+
+        let now = self.now();
+        let interval = self.opts.merge_interval.as_secs();
+
+        if now.saturating_sub(self.merge_budget.since) >= interval {
+            self.merge_budget = MergeBudget {
+                since: now,
+                spent: 0,
+            };
+        }
+
+        let mut segments: Vec<_> = self
+            .storage_stats()
+            .segments
+            .into_iter()
+            .filter(|segment| {
+                segment.file_id != self.active.file_id
+                    && segment.live_entries > 0
+                    && segment.fragmentation() >= min_fragmentation
+            })
+            .collect();
+
+        segments.sort_by(|a, b| b.fragmentation().total_cmp(&a.fragmentation()));
+
+        let mut file_ids = Vec::new();
+
+        for segment in segments {
+            let spent = self.merge_budget.spent.saturating_add(segment.live_bytes);
+
+            if spent <= self.opts.merge_budget {
+                self.merge_budget.spent = spent;
+                file_ids.push(segment.file_id);
+            }
+        }
+
+        if file_ids.is_empty() {
+            return Ok(None);
+        }
+
+        log::info!("🧩 Merging log files {file_ids:?}");
+        self.compaction_of(file_ids).map(Some)
+    }
+
+    /// Starts rewriting live entries of the log files into the active log file.
+    fn compaction_of(&mut self, mut file_ids: Vec<u32>) -> Result<Compaction, StorageError> {
+        self.check_writable()?;
+
+        file_ids.sort_unstable();
+        file_ids.dedup();
+        let keys = self.keys_in_log_files(|file_id| file_ids.binary_search(&file_id).is_ok());
+
+        log::info!("🗜  Compacting {} keys", keys.len());
+        self.notify(|observer| observer.on_compaction_started(&self.path, keys.len()));
+        self.compaction_throughput = 0;
+
+        Ok(Compaction {
+            #[cfg(feature = "opentelemetry")]
+            span: Some(crate::otel::CompactionSpan::start(&self.path, keys.len())),
+            #[cfg(feature = "metrics")]
+            _timer: self.metrics.start(Operation::Compact),
+            file_ids,
+            keys,
+            next: 0,
+            first_output: self.active.file_id,
+            started: Instant::now(),
+            entries: 0,
+            bytes: 0,
+        })
+    }
+
+    /// Runs the compaction step by step, sleeping whenever it is throttled.
+    fn run_compaction(&mut self, mut compaction: Compaction) -> Result<(), StorageError> {
+        while let Some(delay) = self.compaction_step(&mut compaction)? {
+            thread::sleep(delay);
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the next batch of keys of the compaction, or finishes it once all keys are
+    /// rewritten. Returns how long to wait before the next step to keep to
+    /// `DbOptions::compaction_rate_limit`, or `None` once the compaction has finished.
+    pub(crate) fn compaction_step(
+        &mut self,
+        compaction: &mut Compaction,
+    ) -> Result<Option<Duration>, StorageError> {
+        if compaction.next < compaction.keys.len() {
+            let end = compaction
+                .keys
+                .len()
+                .min(compaction.next + COMPACTION_BATCH_SIZE);
+
+            // Keys written in between steps may have left the compacted log files.
+            let keys: Vec<_> = compaction.keys[compaction.next..end]
+                .iter()
+                .filter(|k| {
+                    self.keydir.get(k).is_some_and(|keydir_entry| {
+                        self.has_entries_in(k, &keydir_entry, |file_id| {
+                            compaction.file_ids.binary_search(&file_id).is_ok()
+                        })
+                    })
+                })
+                .cloned()
+                .collect();
+
+            self.rewrite_keys(&keys, compaction)?;
+            compaction.next = end;
+            self.compaction_throughput = compaction.throughput();
+
+            return Ok(Some(compaction.delay(self.opts.compaction_rate_limit)));
+        }
+
+        // Sealed log files are only removed once the rewritten entries are durable, so a
+        // crash mid-compaction leaves both copies and the newer one wins on open.
+        self.sync_active_log()?;
+
+        // Sizes of the sealed log files, and entries of the compacted ones, which GC may
+        // remove.
+        let mut sealed = BTreeMap::new();
+
+        for (&file_id, log) in self.log_files.range(..self.active.file_id) {
+            let entries = match compaction.file_ids.binary_search(&file_id) {
+                Ok(_) => Self::log_index(file_id, log)?.len() as u64,
+                Err(_) => 0,
+            };
+
+            sealed.insert(file_id, (log.len().unwrap_or(0), entries));
+        }
+
+        self.gc()?;
+        self.gc_tombstones()?;
+        self.compaction_throughput = compaction.throughput();
+
+        let (removed_bytes, removed_entries) = sealed
+            .into_iter()
+            .filter(|(file_id, _)| !self.log_files.contains_key(file_id))
+            .fold((0, 0), |(bytes, entries), (_, (size, count))| {
+                (bytes + size, entries + count)
+            });
+
+        let outputs = match compaction.entries {
+            0 => Vec::new(),
+            _ => (compaction.first_output..=self.active.file_id)
+                .filter(|file_id| self.log_files.contains_key(file_id))
+                .collect(),
+        };
+
+        let record = CompactionRecord {
+            finished_at: self.now(),
+            inputs: std::mem::take(&mut compaction.file_ids),
+            outputs,
+            entries_rewritten: compaction.entries,
+            entries_dropped: removed_entries.saturating_sub(compaction.entries),
+            bytes_reclaimed: removed_bytes.saturating_sub(compaction.bytes),
+            duration: compaction.started.elapsed(),
+        };
+
+        log::info!("🗜  Compacted {record}");
+
+        if self.compactions.len() == COMPACTION_HISTORY_SIZE {
+            self.compactions.pop_front();
+        }
+        self.compactions.push_back(record);
+
+        self.notify(|observer| observer.on_compaction_finished(&self.path));
+
+        #[cfg(feature = "opentelemetry")]
+        if let Some(span) = compaction.span.take() {
+            span.succeeded();
+        }
+
+        Ok(None)
+    }
+
+    /// Keys with live entries in the log files matching the predicate, merge operands
+    /// included.
+    fn keys_in_log_files(&self, in_log_file: impl Fn(u32) -> bool) -> Vec<Vec<u8>> {
+        self.keydir
+            .iter()
+            .filter(|(k, keydir_entry)| self.has_entries_in(k, keydir_entry, &in_log_file))
+            .map(|(k, _)| k)
+            .collect()
+    }
+
+    /// Whether the key has live entries in the log files matching the predicate.
+    fn has_entries_in(
+        &self,
+        k: &[u8],
+        keydir_entry: &KeydirEntry,
+        in_log_file: impl Fn(u32) -> bool,
+    ) -> bool {
+        match self.merge_chains.get(k) {
+            Some(chain) => chain
+                .base
+                .iter()
+                .chain(&chain.operands)
+                .any(|entry| in_log_file(entry.file_id)),
+            None => in_log_file(keydir_entry.file_id),
+        }
+    }
+
+    /// Rewrites the current values of the keys of the compaction into the active log file.
+    fn rewrite_keys(
+        &mut self,
+        keys: &[Vec<u8>],
+        compaction: &mut Compaction,
+    ) -> Result<(), StorageError> {
+        // Keys are rewritten bypassing `get` and `put`, which would count as user operations.
+        // Values are read in batches by `get_many`. Rewritten entries keep their timestamps,
+        // so `open_at` still sees them, and expiration times. Expired keys are removed.
+        for keys in keys.chunks(COMPACTION_BATCH_SIZE) {
+            let values = self.get_many(&keys.iter().map(|k| &k[..]).collect::<Vec<_>>())?;
+
+            for (k, value) in keys.iter().zip(values) {
+                let Some(keydir_entry) = self.keydir.get(k) else {
+                    continue;
+                };
+                let timestamp = keydir_entry.timestamp;
+                let expires_at = self.expiration_of(k, &keydir_entry);
+
+                match value {
+                    Some(v) => {
+                        let keydir_entry = self.write_entry(
+                            &DiskEntry::new(k, &v)
+                                .at(timestamp)
+                                .expiring(expires_at)
+                                .rewritten(),
+                        )?;
+                        compaction.entries += 1;
+                        compaction.bytes += entry_size(k, &keydir_entry, Some(self.active.layout));
+                        self.put_keydir_entry(k.clone(), keydir_entry);
+                    }
+                    None => {
+                        self.write_entry(&DiskEntry::tombstone(k).at(timestamp))?;
+                        compaction.bytes += Header::new(timestamp, k.len() as u32, 0)
+                            .entry_size(self.active.layout);
+                        self.remove_keydir_entry(k);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes all keys, keyspaces included, leaving an empty storage.
+    ///
+    /// A new active log file is created and all older log files are retired, to be removed
+    /// once no reader may reference them. A marker file written in between makes the removal
+    /// complete on the next open after a crash.
+    pub fn clear(&mut self) -> Result<(), StorageError> {
+        self.check_writable()?;
+
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.clear()?;
+        }
+
+        let events: Vec<_> = self
+            .keydir
+            .iter()
+            .filter(|(k, _)| self.subscribers.is_interested(k))
+            .map(|(k, keydir_entry)| {
+                let old = self.value_of(&k, &keydir_entry)?;
+                Ok((k, old))
+            })
+            .collect::<Result<_, StorageError>>()?;
+
+        self.active.flush()?;
+
+        let new_active_file_id = self.active.file_id + 1;
+        let new_active_file = Self::create_log_file(&self.opts, &self.path, new_active_file_id)?;
+        new_active_file.sync()?;
+
+        self.write_clear_marker(new_active_file_id)?;
+
+        self.active = ActiveLog::new(
+            new_active_file_id,
+            &new_active_file,
+            self.opts.write_buffer_size,
+        )?;
+
+        let cleared = std::mem::replace(
+            &mut self.log_files,
+            BTreeMap::from([(new_active_file_id, new_active_file)]),
+        );
+
+        for (file_id, file) in cleared {
+            self.epochs.retire(file_id, file);
+        }
+
+        self.clear_pending = Some(new_active_file_id);
+        self.keydir = K::with_options(&self.opts);
+        self.merge_chains.clear();
+        self.live_entries.clear();
+        self.layouts = BTreeMap::from([(new_active_file_id, self.active.layout)]);
+        self.history = History::new(self.opts.history_versions);
+        self.value_cache = ValueCache::new(self.opts.value_cache_size);
+        self.update_sealed_size();
+        self.update_write_stall();
+        self.reclaim_logs()?;
+
+        let timestamp = self.now();
+
+        for (key, old) in events {
+            self.subscribers.publish(ChangeEvent {
+                key,
+                old,
+                new: None,
+                timestamp,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Discards the log files named `names` cleared by `clear`, then removes the clear
+    /// marker file.
+    fn remove_cleared_logs<'a>(
+        path: &Path,
+        opts: &DbOptions,
+        names: impl Iterator<Item = &'a String>,
+    ) -> Result<(), io::Error> {
+        for name in names {
+            Self::discard_log_file(path, opts, name)?;
+        }
+
+        Self::remove_clear_marker(path, opts)
+    }
+
+    /// Removes the clear marker file once removals of the cleared log files are durable.
+    fn remove_clear_marker(path: &Path, opts: &DbOptions) -> Result<(), io::Error> {
+        opts.vfs.sync_dir(path)?;
+        opts.vfs.remove(&path.join(CLEAR_FILE))?;
+        opts.vfs.sync_dir(path)
+    }
+
+    /// Removes the log file named `name`, or moves it into the trash directory if
+    /// `DbOptions::trash_retention` is set. The storage directory is not synced.
+    fn discard_log_file(path: &Path, opts: &DbOptions, name: &str) -> Result<(), io::Error> {
+        if opts.trash_retention.is_zero() {
+            log::info!("🧹 Removing log file: {name}");
+            opts.vfs.remove(&path.join(name))
+        } else {
+            log::info!("🗑  Moving log file to trash: {name}");
+            Self::move_to_trash(path, opts, name)
+        }
+    }
+
+    /// Moves the log file named `name` into the trash directory, prefixing its name with the
+    /// time it has been trashed at.
+    fn move_to_trash(path: &Path, opts: &DbOptions, name: &str) -> Result<(), io::Error> {
+        let vfs = &*opts.vfs;
+        let trash = path.join(TRASH_DIR);
+
+        create_dir_synced(vfs, &trash)?;
+        vfs.rename(
+            &path.join(name),
+            &trash.join(format!("{}-{name}", DiskEntry::now())),
+        )?;
+        vfs.sync_dir(&trash)
+    }
+
+    /// Deletes log files which have been in the trash directory for the
+    /// `DbOptions::trash_retention`.
+    fn purge_trash(path: &Path, opts: &DbOptions) -> Result<(), io::Error> {
+        let trash = path.join(TRASH_DIR);
+
+        if opts.trash_retention.is_zero() || !opts.vfs.exists(&trash) {
+            return Ok(());
+        }
+
+        let purge_before = DiskEntry::now().saturating_sub(opts.trash_retention.as_secs());
+        let mut purged = false;
+
+        for name in opts.vfs.list(&trash)? {
+            let Some(trashed_at) = trashed_at(&name) else {
+                continue;
+            };
+
+            if trashed_at <= purge_before {
+                log::info!("🗑  Purging log file from trash: {name}");
+                opts.vfs.remove(&trash.join(&name))?;
+                purged = true;
+            }
+        }
+
+        if purged {
+            opts.vfs.sync_dir(&trash)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes temporary files left behind by a crash while writing a file.
+    fn remove_temp_files(path: &Path, opts: &DbOptions) -> Result<(), StorageError> {
+        let suffix = format!(".{}", TempFile::EXTENSION);
+        let mut removed = false;
+
+        for name in opts.vfs.list(path)? {
+            if name.ends_with(&suffix) {
+                log::warn!("🧹 Removing incomplete file: {name}");
+                opts.vfs.remove(&path.join(&name))?;
+                removed = true;
+            }
+        }
+
+        if removed {
+            opts.vfs.sync_dir(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Persists the id of the first log file kept by `clear`.
+    fn write_clear_marker(&self, first_file_id: u32) -> Result<(), StorageError> {
+        let mut buf = first_file_id.to_le_bytes().to_vec();
+        buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+
+        let vfs = &*self.opts.vfs;
+        let temp = TempFile::create(vfs, &self.path.join(CLEAR_FILE))?;
+        temp.file().append_all(&buf)?;
+        temp.persist(vfs)?;
+
+        Ok(())
+    }
+
+    /// Id of the first log file kept by an interrupted `clear`, if any.
+    fn read_clear_marker(path: &Path, opts: &DbOptions) -> Result<Option<u32>, StorageError> {
+        let path = path.join(CLEAR_FILE);
+
+        if !opts.vfs.exists(&path) {
+            return Ok(None);
+        }
+
+        let mut buf = [0; 8];
+        opts.vfs
+            .open(&path, OpenMode::ReadOnly)?
+            .read_exact_at(&mut buf, 0)?;
+
+        if crc32fast::hash(&buf[..4]).to_le_bytes() != buf[4..] {
+            return Err(FormatError::ChecksumMismatch.into());
+        }
+
+        Ok(Some(u32::from_le_bytes(buf[..4].try_into().unwrap())))
+    }
+
+    /// Passes the observer set in `DbOptions`, if any, to `f`.
+    fn notify(&self, f: impl FnOnce(&dyn StorageObserver)) {
+        if let Some(observer) = &self.opts.observer {
+            f(&**observer);
+        }
+    }
+
+    /// Points the key to the `keydir_entry`, releasing its previous entries.
+    fn put_keydir_entry(&mut self, k: Vec<u8>, keydir_entry: KeydirEntry) {
+        let current = self.keydir.get(&k);
+        self.release_entries(&k, current);
+        self.add_live_entry(&k, &keydir_entry);
+
+        self.keydir.put(k, keydir_entry);
+    }
+
+    /// Removes the key from the keydir, releasing its entries.
+    fn remove_keydir_entry(&mut self, k: &[u8]) {
+        let removed = self.keydir.remove(k);
+        self.release_entries(k, removed);
+    }
+
+    /// Drops the merge chain of the key and stops counting its entries as live, or the
+    /// `current` keydir entry of the key if it has no merge chain.
+    fn release_entries(&mut self, k: &[u8], current: Option<KeydirEntry>) {
+        let released: Vec<_> = match self.merge_chains.remove(k) {
+            Some(chain) => chain.base.into_iter().chain(chain.operands).collect(),
+            None => current.into_iter().collect(),
+        };
+
+        for keydir_entry in released {
+            self.release_entry(k, &keydir_entry);
+        }
+    }
+
+    /// Counts the entry of the key as live.
+    fn add_live_entry(&mut self, k: &[u8], keydir_entry: &KeydirEntry) {
+        let layout = self.layouts.get(&keydir_entry.file_id).copied();
+
+        self.live_entries
+            .entry(keydir_entry.file_id)
+            .or_default()
+            .add(k, keydir_entry, layout);
+    }
+
+    fn release_entry(&mut self, k: &[u8], keydir_entry: &KeydirEntry) {
+        let layout = self.layouts.get(&keydir_entry.file_id).copied();
+
+        if let Some(live) = self.live_entries.get_mut(&keydir_entry.file_id) {
+            live.release(k, keydir_entry, layout);
+        }
+    }
+
+    /// Entry of the current version of the key, `None` if the key doesn't exist or is
+    /// built from merge operands.
+    fn current_version(&self, k: &[u8]) -> Option<KeydirEntry> {
+        if self.merge_chains.contains_key(k) {
+            None
+        } else {
+            self.keydir.get(k)
+        }
+    }
+
+    /// Retains the `current` version of the key, changed at the `timestamp`, counting its
+    /// entry as live again. Releases entries of versions no longer retained.
+    fn retain_version(
+        &mut self,
+        k: &[u8],
+        current: Option<KeydirEntry>,
+        timestamp: u64,
+        removed: bool,
+    ) {
+        let (retained, dropped) = self.history.record(k, current, timestamp, removed);
+
+        if let Some(current) = current.filter(|_| retained) {
+            self.add_live_entry(k, &current);
+        }
+
+        for keydir_entry in dropped {
+            self.release_entry(k, &keydir_entry);
+        }
+    }
+
+    /// Opens a log file, bypassing the page cache with `DbOptions::direct_io`.
+    fn open_log_file(opts: &DbOptions, path: &Path, mode: OpenMode) -> Result<LogFile, io::Error> {
+        if opts.direct_io {
+            opts.vfs.open_direct(path, mode)
+        } else {
+            opts.vfs.open(path, mode)
+        }
+    }
+
+    /// Creates a new log file with a segment header of the current format version.
+    fn create_log_file(opts: &DbOptions, dir: &Path, file_id: u32) -> Result<LogFile, io::Error> {
+        let path = dir.join(Self::format_log_file_name(file_id));
+        let file = Self::open_log_file(opts, &path, OpenMode::CreateNew)?;
+
+        let version = opts.format_version();
+        let created_at = opts.clock.now();
+        let segment_header = version.segment_header(created_at);
+
+        // A log file with a torn segment header would block creating it again, as would
+        // a log file which may not survive a crash.
+        if let Err(e) = file
+            .append_all(&segment_header[..version.segment_header_size()])
+            .and_then(|()| opts.vfs.sync_dir(dir))
+        {
+            opts.vfs.remove(&path)?;
+
+            return Err(e);
+        }
+
+        Ok(file)
+    }
+
+    /// Appends the entry to the active log file.
+    /// Returns the keydir entry pointing to the written value.
+    fn write_entry(&mut self, disk_entry: &DiskEntry) -> Result<KeydirEntry, StorageError> {
+        self.check_writable()?;
+        self.rotate_log(disk_entry.header.entry_size(self.active.layout))?;
+
+        let layout = self.active.layout;
+        let header = disk_entry.header.encode(layout);
+
+        self.active.write_all_vectored(&mut [
+            IoSlice::new(&header[..disk_entry.header.size(layout)]),
+            IoSlice::new(disk_entry.key),
+            IoSlice::new(disk_entry.value),
+        ])?;
+
+        let value_size = disk_entry.header.value_size();
+        let value_pos = self.active.size - value_size;
+
+        let timestamp = disk_entry.header.timestamp();
+
+        Ok(
+            KeydirEntry::new(self.active.file_id, value_size, value_pos, timestamp)
+                .expiring(disk_entry.header.expires_at()),
+        )
+    }
+
+    /// Creates a consistent copy of the database at the `path` directory without closing it.
+    ///
+    /// Sealed log files are copied as is, the active log file is synced and copied up to
+    /// the synced offset. Entries still buffered by the active log writer are not included.
+    /// The `path` directory must be empty or not exist.
+    pub fn backup_to(&self, path: impl AsRef<Path>) -> Result<(), StorageError> {
+        let path = path.as_ref();
+        let vfs = &*self.opts.vfs;
+
+        create_dir_synced(vfs, path)?;
+
+        if !vfs.list(path)?.is_empty() {
+            return Err(StorageError::DirectoryNotEmpty(path.to_path_buf()));
+        }
+
+        self.active_log_file()?.sync()?;
+        let active_file_size = self.active.flushed_size();
+
+        for (file_id, src) in self.log_files.iter() {
+            let size = if *file_id == self.active.file_id {
+                active_file_size
+            } else {
+                src.len()?
+            };
+
+            Self::copy_log_file(
+                vfs,
+                src,
+                size,
+                &path.join(Self::format_log_file_name(*file_id)),
+            )?;
+        }
+
+        for (name, keyspace) in self.keyspaces.iter() {
+            keyspace.backup_to(path.join(KEYSPACES_DIR).join(name))?;
+        }
+
+        log::info!("💾 Backup has been created at {}", path.display());
+
+        Ok(())
+    }
+
+    /// Creates a copy of the database at the `path` directory which can be opened on its
+    /// own, e.g. as a test fixture or for read-only analytics, without closing the database.
+    ///
+    /// Buffered entries are flushed first. Sealed log files, which are never modified in
+    /// place, only replaced, are hard linked, or copied if linking fails, e.g. across
+    /// filesystems. The active log file is
+    /// synced and copied. The `path` directory must be empty or not exist.
+    pub fn checkpoint(&mut self, path: impl AsRef<Path>) -> Result<(), StorageError> {
+        let path = path.as_ref();
+        let vfs = &*self.opts.vfs;
+
+        create_dir_synced(vfs, path)?;
+
+        if !vfs.list(path)?.is_empty() {
+            return Err(StorageError::DirectoryNotEmpty(path.to_path_buf()));
+        }
+
+        self.active.flush()?;
+        self.active_log_file()?.sync()?;
+
+        let mut linked = 0;
+
+        for (file_id, src) in self.log_files.iter() {
+            let name = Self::format_log_file_name(*file_id);
+
+            if *file_id != self.active.file_id {
+                match vfs.hard_link(&self.path.join(&name), &path.join(&name)) {
+                    Ok(()) => {
+                        linked += 1;
+                        continue;
+                    }
+                    Err(e) => log::debug!("🔗 Copying {name}, failed to link it: {e}"),
+                }
+            }
+
+            Self::copy_log_file(vfs, src, src.len()?, &path.join(name))?;
+        }
+
+        vfs.sync_dir(path)?;
+
+        for (name, keyspace) in self.keyspaces.iter_mut() {
+            keyspace.checkpoint(path.join(KEYSPACES_DIR).join(name))?;
+        }
+
+        log::info!(
+            "💾 Checkpoint has been created at {}, {linked} log files linked",
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Copies the first `size` bytes of the `src` log file to a new file at `path`.
+    fn copy_log_file(
+        vfs: &dyn Vfs,
+        src: &LogFile,
+        size: u64,
+        path: &Path,
+    ) -> Result<(), StorageError> {
+        let dst = TempFile::create(vfs, path)?;
+
+        io::copy(
+            &mut VfsReader::new(&**src, 0).take(size),
+            &mut VfsAppender(dst.file().clone()),
+        )?;
+        dst.persist(vfs)?;
+
+        Ok(())
+    }
+
+    /// Reads entries of the log files at or after the `from` position until about `max_bytes`
+    /// have been read, returning them with the position following the last one. Entries still
+    /// buffered by the active log writer are not included, nor are copies rewritten by
+    /// compactions, whose originals precede them.
+    ///
+    /// Fails with `StorageError::UnknownLogFile` once the log file of the position has been
+    /// removed by a compaction or GC.
+    pub(crate) fn read_log_entries(
+        &self,
+        from: LogPosition,
+        max_bytes: u64,
+    ) -> Result<(Vec<ReplicatedEntry>, LogPosition), StorageError> {
+        let mut entries = Vec::new();
+        let mut position = from;
+        let mut read = 0;
+
+        while read < max_bytes {
+            let file = self
+                .log_files
+                .get(&position.file_id)
+                .ok_or(StorageError::UnknownLogFile(position.file_id))?;
+            let active = position.file_id == self.active.file_id;
+
+            let mut reader = LogReader::new(file.clone())
+                .map_err(|e| e.in_log_file(position.file_id))?
+                .verify_checksums(false);
+            reader.seek(position.offset);
+
+            while let Some(entry) = reader.next() {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    // The active log writer may have flushed a part of an entry only.
+                    Err(StorageError::FormatError(FormatError::TornEntry(_))) if active => break,
+                    Err(e) => return Err(e),
+                };
+
+                position.offset = entry.offset + entry.size;
+                read += entry.size;
+
+                if !entry.rewritten {
+                    entries.push(ReplicatedEntry {
+                        kind: entry.kind,
+                        value: reader.read_value(&entry)?,
+                        key: entry.key,
+                        timestamp: entry.timestamp,
+                        expires_at: entry.expires_at,
+                    });
+                }
+
+                if read >= max_bytes {
+                    return Ok((entries, position));
+                }
+            }
+
+            if active {
+                break;
+            }
+
+            let next_file_id = self
+                .log_files
+                .range(position.file_id + 1..)
+                .next()
+                .ok_or(StorageError::UnknownLogFile(position.file_id + 1))?
+                .0;
+
+            position = LogPosition {
+                file_id: *next_file_id,
+                offset: 0,
+            };
+        }
+
+        Ok((entries, position))
+    }
+
+    /// Position following the last entry written to the log files.
+    pub(crate) fn log_end(&self) -> LogPosition {
+        LogPosition {
+            file_id: self.active.file_id,
+            offset: self.active.size,
+        }
+    }
+
+    /// Applies an entry replicated from another storage, keeping its timestamp.
+    pub(crate) fn apply_entry(&mut self, entry: ReplicatedEntry) -> Result<(), StorageError> {
+        match entry.kind {
+            EntryKind::Put => {
+                self.put_expiring(entry.key, entry.value, entry.timestamp, entry.expires_at)
+            }
+            EntryKind::Tombstone => self.remove_at(&entry.key, entry.timestamp),
+            EntryKind::MergeOperand => self.merge_at(entry.key, entry.value, entry.timestamp),
+        }
+    }
+
+    /// Leader log position persisted by `set_replication_position`, if any.
+    pub(crate) fn replication_position(&self) -> Result<Option<LogPosition>, StorageError> {
+        let vfs = &*self.opts.vfs;
+        let path = self.path.join(REPLICATION_FILE);
+
+        if !vfs.exists(&path) {
+            return Ok(None);
+        }
+
+        let mut buf = [0; 16];
+        vfs.open(&path, OpenMode::ReadOnly)?
+            .read_exact_at(&mut buf, 0)?;
+
+        if crc32fast::hash(&buf[..12]).to_le_bytes() != buf[12..] {
+            return Err(FormatError::ChecksumMismatch.into());
+        }
+
+        Ok(Some(LogPosition {
+            file_id: u32::from_le_bytes(buf[..4].try_into().unwrap()),
+            offset: u64::from_le_bytes(buf[4..12].try_into().unwrap()),
+        }))
+    }
+
+    /// Syncs the storage and persists the leader log position it has been replicated up to.
+    pub(crate) fn set_replication_position(
+        &mut self,
+        position: LogPosition,
+    ) -> Result<(), StorageError> {
+        self.sync()?;
+
+        let mut buf = Vec::with_capacity(16);
+        buf.extend_from_slice(&position.file_id.to_le_bytes());
+        buf.extend_from_slice(&position.offset.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+
+        let vfs = &*self.opts.vfs;
+        let temp = TempFile::create(vfs, &self.path.join(REPLICATION_FILE))?;
+        temp.file().append_all(&buf)?;
+        temp.persist(vfs)?;
+
+        Ok(())
+    }
+
+    /// Writes all live key-value pairs, keyspaces included, to the `writer` in the portable
+    /// dump format read by `import`. Returns the number of exported pairs.
+    pub fn export(&self, writer: impl Write) -> Result<u64, StorageError> {
+        let mut writer = DumpWriter::new(BufWriter::new(writer))?;
+        let mut pairs = self.export_pairs(&mut writer)?;
+
+        let mut names: Vec<_> = self.keyspace_names().collect();
+        names.sort();
+
+        for name in names {
+            writer.keyspace(name)?;
+            pairs += self.keyspaces[name].export_pairs(&mut writer)?;
+        }
+
+        writer.finish()?;
+
+        Ok(pairs)
+    }
+
+    fn export_pairs(&self, writer: &mut DumpWriter<impl Write>) -> Result<u64, StorageError> {
+        let mut pairs = 0;
+
+        for pair in self.live_pairs() {
+            let (k, v, timestamp, _) = pair?;
+            writer.pair(&k, &v, timestamp)?;
+            pairs += 1;
+        }
+
+        Ok(pairs)
+    }
+
+    /// Iterates live key-value pairs with the timestamps of their latest entries and their
+    /// expiration times.
+    pub(crate) fn live_pairs(
+        &self,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>, u64, u64), StorageError>> + '_ {
+        self.keydir
+            .iter()
+            .filter_map(|(k, keydir_entry)| match self.value_of(&k, &keydir_entry) {
+                Ok(Some(v)) => {
+                    let expires_at = self.expiration_of(&k, &keydir_entry);
+                    Some(Ok((k, v, keydir_entry.timestamp, expires_at)))
+                }
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            })
+    }
+
+    /// Creates a database at the `path` directory from a dump written by `export`, which may
+    /// come from another format version or machine. The directory must be empty or not exist.
+    pub fn import(path: impl AsRef<Path>, reader: impl Read) -> Result<Self, StorageError> {
+        let path = path.as_ref();
+        let opts = DbOptions::default();
+
+        create_dir_synced(&*opts.vfs, path)?;
+
+        if !opts.vfs.list(path)?.is_empty() {
+            return Err(StorageError::DirectoryNotEmpty(path.to_path_buf()));
+        }
+
+        let mut db = Self::open(path, opts)?;
+        db.import_from(reader)?;
+
+        Ok(db)
+    }
+
+    /// Puts the key-value pairs of a dump written by `export`, keeping their timestamps and
+    /// creating keyspaces as needed. Returns the number of imported pairs.
+    ///
+    /// The dump checksum is verified at its end, so pairs read before a corruption is
+    /// detected have already been put.
+    pub fn import_from(&mut self, reader: impl Read) -> Result<u64, StorageError> {
+        let mut reader = DumpReader::new(BufReader::new(reader))?;
+        let mut keyspace: Option<String> = None;
+        let mut pairs = 0;
+
+        while let Some(record) = reader.next_record()? {
+            match record {
+                dump::Record::Pair {
+                    key,
+                    value,
+                    timestamp,
+                } => {
+                    let storage = match &keyspace {
+                        Some(name) => self.keyspace(name)?,
+                        None => &mut *self,
+                    };
+
+                    storage.put_at(key, value, timestamp)?;
+                    pairs += 1;
+                }
+                dump::Record::Keyspace(name) => {
+                    self.keyspace(&name)?;
+                    keyspace = Some(name);
+                }
+            }
+        }
+
+        log::info!("📥 Imported {pairs} key-value pairs");
+
+        Ok(pairs)
+    }
+
+    /// Writes the live key-value pairs to bitcask data and hint files in the `dir` directory,
+    /// to be opened by Riak's bitcask. The directory must be empty or not exist. Returns the
+    /// number of exported pairs.
+    ///
+    /// Only the default keyspace is exported, and expiration times are dropped since bitcask
+    /// has no equivalent. Timestamps past `u32::MAX`, which bitcask can't store, are saturated.
+    /// Keys longer than 65535 bytes are rejected.
+    pub fn export_bitcask(&self, dir: impl AsRef<Path>) -> Result<u64, StorageError> {
+        let dir = dir.as_ref();
+        let vfs = &*self.opts.vfs;
+
+        create_dir_synced(vfs, dir)?;
+
+        if !vfs.list(dir)?.is_empty() {
+            return Err(StorageError::DirectoryNotEmpty(dir.to_path_buf()));
+        }
+
+        let mut writer = BitcaskWriter::new(vfs, dir);
+        let mut pairs = 0;
+
+        for pair in self.live_pairs() {
+            let (k, v, timestamp, _) = pair?;
+
+            if k.len() > u16::MAX as usize {
+                return Err(StorageError::KeyTooLarge {
+                    size: k.len(),
+                    max: u16::MAX as usize,
+                });
+            }
+
+            if v.len() as u64 > u32::MAX as u64 {
+                return Err(StorageError::ValueTooLarge {
+                    size: v.len() as u64,
+                    max: u32::MAX as u64,
+                });
+            }
+
+            writer.pair(&k, &v, timestamp.try_into().unwrap_or(u32::MAX))?;
+            pairs += 1;
+        }
+
+        writer.finish()?;
+        log::info!("📤 Exported {pairs} key-value pairs to bitcask files");
+
+        Ok(pairs)
+    }
+
+    /// Creates a database at the `path` directory from the bitcask directory `bitcask_dir`
+    /// written by Riak's bitcask, keeping the timestamps of the latest entries. Keys are read
+    /// from hint files where they are valid and from data files otherwise. The directory must
+    /// be empty or not exist.
+    pub fn import_bitcask(
+        path: impl AsRef<Path>,
+        bitcask_dir: impl AsRef<Path>,
+    ) -> Result<Self, StorageError> {
+        let path = path.as_ref();
+        let opts = DbOptions::default();
+
+        let reader = BitcaskReader::open(&*opts.vfs, bitcask_dir.as_ref())?;
+
+        create_dir_synced(&*opts.vfs, path)?;
+
+        if !opts.vfs.list(path)?.is_empty() {
+            return Err(StorageError::DirectoryNotEmpty(path.to_path_buf()));
+        }
+
+        let mut db = Self::open(path, opts)?;
+        let mut pairs = 0;
+
+        for pair in reader.pairs() {
+            let (k, v, timestamp) = pair?;
+            db.put_at(k, v, timestamp.into())?;
+            pairs += 1;
+        }
+
+        log::info!("📥 Imported {pairs} key-value pairs from bitcask files");
+
+        Ok(db)
+    }
+
+    /// Returns a loader putting key-value pairs into fresh log files much faster than `put`,
+    /// deferring keydir updates until `BulkLoader::finish`.
+    pub fn bulk_load(&mut self) -> Result<BulkLoader<'_, K>, StorageError> {
+        self.check_writable()?;
+
+        Ok(BulkLoader {
+            db: self,
+            sealed: Vec::new(),
+            current: None,
+            pairs: 0,
+        })
+    }
+
+    /// Returns an iterator over all key-value pairs whose key starts with `prefix`.
+    ///
+    /// Pairs are yielded in the keydir iteration order.
+    pub fn scan_prefix<'a>(
+        &'a self,
+        prefix: &'a [u8],
+    ) -> impl Iterator<Item = Result<KeyValue, StorageError>> + 'a {
+        self.keydir
+            .iter_prefix(prefix)
+            .filter_map(|(k, keydir_entry)| self.key_value(k, &keydir_entry))
+    }
+
+    /// Returns an iterator over keys starting with `prefix`, in the keydir iteration order.
+    /// Values are not read. Expired keys are skipped.
+    pub fn scan_prefix_keys<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = Vec<u8>> + 'a {
+        self.keydir
+            .iter_prefix(prefix)
+            .filter(|(k, keydir_entry)| !self.is_expired(k, keydir_entry))
+            .map(|(k, _)| k)
+    }
+
+    /// Takes a snapshot of the keys starting with `prefix` and the entries they point to,
+    /// see `PrefixSnapshot`. Values built from merge operands are folded right away.
+    pub(crate) fn snapshot_prefix(&self, prefix: &[u8]) -> Result<PrefixSnapshot, StorageError> {
+        let epoch = self.pin();
+        let mut pairs = Vec::new();
+
+        for (k, keydir_entry) in self.keydir.iter_prefix(prefix) {
+            if self.merge_chains.contains_key(&k) {
+                if let Some(value) = self.value_of(&k, &keydir_entry)? {
+                    let folded = PinnedValue::Folded {
+                        value,
+                        timestamp: keydir_entry.timestamp,
+                        expires_at: self.expiration_of(&k, &keydir_entry),
+                    };
+                    pairs.push((k, folded));
+                }
+            } else if !keydir_entry.is_expired_at(self.now()) {
+                pairs.push((k, PinnedValue::Entry(keydir_entry)));
+            }
+        }
+
+        Ok(PrefixSnapshot {
+            pairs: pairs.into_iter(),
+            _epoch: epoch,
+        })
+    }
+
+    /// Returns an iterator over key-value pairs whose key starts with `prefix` and which
+    /// the `filter` accepts, in the keydir iteration order. Values are read into a reused
+    /// buffer as the iterator advances, so rejected values aren't allocated.
+    pub fn scan_filtered<'a, F>(
+        &'a self,
+        prefix: &'a [u8],
+        filter: F,
+    ) -> impl Iterator<Item = Result<KeyValue, StorageError>> + 'a
+    where
+        F: FnMut(&[u8], &[u8]) -> bool + 'a,
+    {
+        self.scan_filtered_by_key(prefix, |_| true, filter)
+    }
+
+    /// Like `scan_filtered`, but values of keys the `key_filter` rejects aren't read at all.
+    pub fn scan_filtered_by_key<'a, P, F>(
+        &'a self,
+        prefix: &'a [u8],
+        mut key_filter: P,
+        mut filter: F,
+    ) -> impl Iterator<Item = Result<KeyValue, StorageError>> + 'a
+    where
+        P: FnMut(&[u8]) -> bool + 'a,
+        F: FnMut(&[u8], &[u8]) -> bool + 'a,
+    {
+        let mut buf = Vec::new();
+
+        self.keydir
+            .iter_prefix(prefix)
+            .filter(move |(k, _)| key_filter(k))
+            .filter_map(move |(k, keydir_entry)| {
+                match self.read_value_into(&k, &keydir_entry, &mut buf) {
+                    Ok(true) if filter(&k, &buf) => Some(Ok((k, std::mem::take(&mut buf)))),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                }
+            })
+    }
+
+    /// Reads the value of the key pointed by the `keydir_entry` into `buf`, folding merge
+    /// operands. Returns false if the key has expired or merged into a removal.
+    fn read_value_into(
+        &self,
+        k: &[u8],
+        keydir_entry: &KeydirEntry,
+        buf: &mut Vec<u8>,
+    ) -> Result<bool, StorageError> {
+        if self.merge_chains.contains_key(k) {
+            return Ok(match self.value_of(k, keydir_entry)? {
+                Some(v) => {
+                    *buf = v;
+                    true
+                }
+                None => false,
+            });
+        }
+
+        if keydir_entry.is_expired_at(self.now()) {
+            return Ok(false);
+        }
+
+        buf.resize(keydir_entry.value_size as usize, 0);
+        self.read_log_at(keydir_entry.file_id, buf, keydir_entry.value_pos)?;
+
+        Ok(true)
+    }
+
+    /// Returns an iterator over keys written at or after the `timestamp`, in the keydir
+    /// iteration order. Expired keys are skipped.
+    pub fn modified_since(&self, timestamp: u64) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.keydir
+            .iter()
+            .filter(move |(k, keydir_entry)| {
+                keydir_entry.timestamp >= timestamp && !self.is_expired(k, keydir_entry)
+            })
+            .map(|(k, _)| k)
+    }
+
+    /// Returns an iterator over key-value pairs written at or after the `timestamp`.
+    pub fn modified_pairs_since(
+        &self,
+        timestamp: u64,
+    ) -> impl Iterator<Item = Result<KeyValue, StorageError>> + '_ {
+        self.keydir
+            .iter()
+            .filter(move |(_, keydir_entry)| keydir_entry.timestamp >= timestamp)
+            .filter_map(|(k, keydir_entry)| self.key_value(k, &keydir_entry))
+    }
+
+    /// Removes all keys starting with `prefix`. Returns the number of removed keys.
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> Result<usize, StorageError> {
+        let keys: Vec<_> = self.keydir.iter_prefix(prefix).map(|(k, _)| k).collect();
+
+        self.remove_keys(&keys)
+    }
+
+    /// Removes the keys with tombstones written at the same timestamp, committed at once.
+    fn remove_keys(&mut self, keys: &[Vec<u8>]) -> Result<usize, StorageError> {
+        let timestamp = self.now();
+
+        for k in keys {
+            self.remove_at(k, timestamp)?;
+        }
+
+        self.commit()?;
+
+        Ok(keys.len())
+    }
+
+    /// Reads the value of the key into a key-value pair.
+    /// Returns `None` if merge operands of the key fold into no value.
+    fn key_value(
+        &self,
+        k: Vec<u8>,
+        keydir_entry: &KeydirEntry,
+    ) -> Option<Result<KeyValue, StorageError>> {
+        match self.value_of(&k, keydir_entry) {
+            Ok(Some(v)) => Some(Ok((k, v))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Flushes buffered entries and syncs the active log file to disk.
+    pub fn sync(&mut self) -> Result<(), StorageError> {
+        self.sync_active_log()?;
+
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes buffered entries of the storage and its keyspaces, and returns their active
+    /// log files to sync. Lets a caller sync them without holding a lock on the storage.
+    pub(crate) fn flush_for_sync(&mut self) -> Result<Vec<LogFile>, StorageError> {
+        self.active.flush()?;
+
+        let mut files = vec![self.active.writer.get_ref().0.clone()];
+
+        for keyspace in self.keyspaces.values_mut() {
+            files.extend(keyspace.flush_for_sync()?);
+        }
+
+        Ok(files)
+    }
+
+    fn sync_active_log(&mut self) -> Result<(), StorageError> {
+        self.active.flush()?;
+        self.active.writer.get_ref().0.sync()?;
+
+        Ok(())
+    }
+
+    /// Syncs the entries of a write before it returns with `DbOptions::sync_writes`.
+    fn commit(&mut self) -> Result<(), StorageError> {
+        if self.opts.sync_writes {
+            self.sync_active_log()?;
+        }
+
+        Ok(())
+    }
+
+    /// Closes the storage and its keyspaces: flushes and syncs the active log file, snapshots
+    /// the keydir, marking a clean shutdown, and releases the lock. Dropping the storage
+    /// only flushes the active log file, without reporting failures.
+    pub fn close(mut self) -> Result<(), StorageError> {
+        for (_, keyspace) in self.keyspaces.drain() {
+            keyspace.close()?;
+        }
+
+        self.snapshot_keydir()
+    }
+
+    /// Syncs the storage and its keyspaces and snapshots their keydirs, so the next open
+    /// doesn't have to scan log files. Writes made afterwards make the snapshot stale.
+    pub fn snapshot_keydir(&mut self) -> Result<(), StorageError> {
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.snapshot_keydir()?;
+        }
+
+        self.sync_active_log()?;
+
+        if self.snapshot_on_close() {
+            self.write_snapshot()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes entries buffered by the active log writer to the log file, without syncing it.
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        self.active.flush()?;
+
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Puts a value of `len` bytes read from the `reader`, without loading it into memory.
+    ///
+    /// The value is streamed into the active log file chunk by chunk. If the `reader` fails
+    /// or ends early, the partially written entry is discarded.
+    pub fn put_from_reader(
+        &mut self,
+        k: Vec<u8>,
+        mut reader: impl Read,
+        len: u64,
+    ) -> Result<(), StorageError> {
+        self.check_writable()?;
+        self.check_entry_size(k.len(), len)?;
+        self.check_write_stall()?;
+        self.check_quota(entry_size_of(k.len(), len))?;
+
+        let old = self.value_for_subscribers(&k)?;
+
+        self.rotate_log((self.active.layout.version.header_size() + k.len()) as u64 + len)?;
+
+        // The value bypasses the buffer, so entries buffered before it go first.
+        self.active.flush()?;
+
+        let active_file = &*self.active.writer.get_ref().0;
+        let layout = self.active.layout;
+        let entry_pos = self.active.size;
+        let mut header = Header::new(self.now(), k.len() as u32, len);
+
+        let res = Self::write_streamed_entry(
+            active_file,
+            layout,
+            entry_pos,
+            &mut header,
+            &k,
+            &mut reader,
+        );
+
+        if let Err(e) = res {
+            active_file.set_len(entry_pos)?;
+
+            return Err(e.into());
+        }
+
+        self.active.size += header.entry_size(layout);
+
+        let value_pos = entry_pos + (header.size(layout) + k.len()) as u64;
+        let keydir_entry =
+            KeydirEntry::new(self.active.file_id, len, value_pos, header.timestamp());
+
+        let new = old
+            .is_some()
+            .then(|| self.read_value(&keydir_entry))
+            .transpose()?;
+
+        let current = self.current_version(&k);
+        self.put_keydir_entry(k.clone(), keydir_entry);
+        self.retain_version(&k, current, keydir_entry.timestamp, false);
+
+        if let Some(old) = old {
+            self.subscribers.publish(ChangeEvent {
+                key: k,
+                old,
+                new,
+                timestamp: keydir_entry.timestamp,
+            });
+        }
+
+        self.commit()
+    }
+
+    /// Writes the entry streaming the value from the `reader`. The header checksum is
+    /// computed along the way and written last.
+    fn write_streamed_entry(
+        file: &dyn VfsFile,
+        layout: Layout,
+        header_pos: u64,
+        header: &mut Header,
+        key: &[u8],
+        reader: &mut impl Read,
+    ) -> Result<(), io::Error> {
+        file.append_all(&header.encode(layout)[..header.size(layout)])?;
+        file.append_all(key)?;
+
+        let mut hasher = header.hasher(layout.version);
+        hasher.update(key);
+
+        let mut chunk = vec![0; CHUNK_SIZE.min(header.value_size() as usize)];
+        let mut remaining = header.value_size();
+
+        while remaining > 0 {
+            let chunk_size = chunk.len().min(remaining as usize);
+
+            reader.read_exact(&mut chunk[..chunk_size])?;
+            hasher.update(&chunk[..chunk_size]);
+            file.append_all(&chunk[..chunk_size])?;
+
+            remaining -= chunk_size as u64;
+        }
+
+        header.set_crc(hasher.finalize());
+        file.write_all_at(&header.encode(layout)[..4], header_pos)?;
+
+        Ok(())
+    }
+
+    /// Puts the value, overriding the policies set in `DbOptions` with the `opts`.
+    pub fn put_opt(
+        &mut self,
+        k: Vec<u8>,
+        v: Vec<u8>,
+        opts: PutOptions,
+    ) -> Result<(), StorageError> {
+        #[cfg(feature = "metrics")]
+        let _timer = self.metrics.start(Operation::Put);
+
+        let now = self.now();
+        let timestamp = opts.timestamp_override.unwrap_or(now);
+        let expires_at = opts.ttl.map_or(0, |ttl| {
+            let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+            now.saturating_add(secs)
+        });
+
+        self.put_expiring(k, v, timestamp, expires_at)?;
+
+        match opts.sync {
+            Some(true) => self.sync_active_log(),
+            Some(false) => Ok(()),
+            None => self.commit(),
+        }
+    }
+
+    /// Gets the value of the key with the `opts`. Checksums of values built from merge
+    /// operands are not verified.
+    pub fn get_opt(&self, k: &[u8], opts: ReadOptions) -> Result<Option<Vec<u8>>, StorageError> {
+        #[cfg(feature = "metrics")]
+        let _timer = self.metrics.start(Operation::Get);
+        let _epoch = self.pin();
+
+        let res = match self.keydir.get(k) {
+            Some(keydir_entry) if self.is_expired(k, &keydir_entry) => {
+                self.notify(|observer| observer.on_key_expired(&self.path, k));
+                None
+            }
+            Some(keydir_entry) if self.merge_chains.contains_key(k) => {
+                self.value_of(k, &keydir_entry)?
+            }
+            Some(keydir_entry) if opts.verify_checksum => {
+                Some(self.read_verified_value(k, &keydir_entry)?)
+            }
+            Some(keydir_entry) => Some(self.cached_value(&keydir_entry, opts.fill_cache)?),
+            None => None,
+        };
+
+        Ok(res)
+    }
+
+    /// Get a value as `Bytes`, sharing it with the value cache or with the memory-mapped log
+    /// file it's stored in rather than copying it, see `DbOptions::mmap_reads`.
+    #[cfg(feature = "bytes")]
+    pub fn get_bytes(&self, k: &[u8]) -> Result<Option<bytes::Bytes>, StorageError> {
+        let _epoch = self.pin();
+
+        let res = match self.keydir.get(k) {
+            Some(keydir_entry) if self.is_expired(k, &keydir_entry) => {
+                self.notify(|observer| observer.on_key_expired(&self.path, k));
+                None
+            }
+            Some(keydir_entry) if self.merge_chains.contains_key(k) => {
+                self.value_of(k, &keydir_entry)?.map(bytes::Bytes::from)
+            }
+            Some(keydir_entry) => Some(self.shared_value(&keydir_entry)?),
+            None => None,
+        };
+
+        Ok(res)
+    }
+
+    /// Reads a value pointed by the `keydir_entry` like `cached_value`, without copying
+    /// cached values and values of memory-mapped log files.
+    #[cfg(feature = "bytes")]
+    fn shared_value(&self, keydir_entry: &KeydirEntry) -> Result<bytes::Bytes, StorageError> {
+        let (file_id, pos) = (keydir_entry.file_id, keydir_entry.value_pos);
+
+        if let Some(value) = self.value_cache.get_shared(file_id, pos) {
+            return Ok(bytes::Bytes::from_owner(value));
+        }
+
+        let start = pos as usize;
+        let end = start + keydir_entry.value_size as usize;
+
+        if file_id != self.active.file_id {
+            if let Some(map) = self.log_file(file_id)?.mapped() {
+                if end <= (*map).as_ref().len() {
+                    return Ok(bytes::Bytes::from_owner(MappedBytes(map)).slice(start..end));
+                }
+            }
+        }
+
+        let value = self.read_value(keydir_entry)?;
+
+        if self.opts.value_cache_size == 0 {
+            return Ok(value.into());
+        }
+
+        let value: Arc<[u8]> = value.into();
+        self.value_cache.insert_shared(file_id, pos, value.clone());
+
+        Ok(bytes::Bytes::from_owner(value))
+    }
+
+    /// Appends a merge operand for the key. Operands are folded into the value with
+    /// the merge operator set in `DbOptions` when the value is read.
+    pub fn merge(&mut self, k: Vec<u8>, operand: Vec<u8>) -> Result<(), StorageError> {
+        self.merge_at(k, operand, self.now())?;
+        self.commit()
+    }
+
+    /// Puts the value with an entry written at the `timestamp`, notifying subscribers.
+    fn put_at(&mut self, k: Vec<u8>, v: Vec<u8>, timestamp: u64) -> Result<(), StorageError> {
+        self.put_expiring(k, v, timestamp, 0)
+    }
+
+    /// Puts the value with an entry written at the `timestamp` and expiring at `expires_at`,
+    /// never if 0.
+    fn put_expiring(
+        &mut self,
+        k: Vec<u8>,
+        v: Vec<u8>,
+        timestamp: u64,
+        expires_at: u64,
+    ) -> Result<(), StorageError> {
+        self.check_entry_size(k.len(), v.len() as u64)?;
+        self.check_write_stall()?;
+        self.check_quota(entry_size_of(k.len(), v.len() as u64))?;
+
+        let old = self.value_for_subscribers(&k)?;
+        let keydir_entry =
+            self.write_entry(&DiskEntry::new(&k, &v).at(timestamp).expiring(expires_at))?;
+
+        self.apply_put(k, v, keydir_entry, old);
+
+        Ok(())
+    }
+
+    /// Points the key to the written `keydir_entry`, notifying subscribers of the change from
+    /// the `old` value.
+    fn apply_put(
+        &mut self,
+        k: Vec<u8>,
+        v: Vec<u8>,
+        keydir_entry: KeydirEntry,
+        old: Option<Option<Vec<u8>>>,
+    ) {
+        let timestamp = keydir_entry.timestamp;
+
+        let current = self.current_version(&k);
+        self.put_keydir_entry(k.clone(), keydir_entry);
+        self.retain_version(&k, current, timestamp, false);
+
+        if let Some(old) = old {
+            self.subscribers.publish(ChangeEvent {
+                key: k,
+                old,
+                new: Some(v),
+                timestamp,
+            });
+        }
+    }
+
+    /// Removes the key with a tombstone written at the `timestamp`, notifying subscribers.
+    fn remove_at(&mut self, k: &[u8], timestamp: u64) -> Result<(), StorageError> {
+        if self.keydir.get(k).is_some() {
+            let old = self.value_for_subscribers(k)?;
+            self.write_entry(&DiskEntry::tombstone(k).at(timestamp))?;
+
+            self.apply_remove(k, timestamp, old);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the key whose tombstone has been written at the `timestamp`, notifying
+    /// subscribers of the change from the `old` value.
+    fn apply_remove(&mut self, k: &[u8], timestamp: u64, old: Option<Option<Vec<u8>>>) {
+        let current = self.current_version(k);
+        self.remove_keydir_entry(k);
+        self.retain_version(k, current, timestamp, true);
+
+        if let Some(old) = old {
+            self.subscribers.publish(ChangeEvent {
+                key: k.to_vec(),
+                old,
+                new: None,
+                timestamp,
+            });
+        }
+    }
+
+    /// Writes the puts and removals of the `batch` with a single write. After a crash, either
+    /// all or none of them are found in the log files.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<(), StorageError> {
+        self.write_batch_at(batch, self.now(), true)?;
+        self.commit()
+    }
+
+    /// Writes the puts and removals of the `batch` with a single write, as independent
+    /// entries. Unlike `write_batch`, a crash may leave only some of them in the log files.
+    pub fn write_pipelined(&mut self, batch: WriteBatch) -> Result<(), StorageError> {
+        self.write_batch_at(batch, self.now(), false)?;
+        self.commit()
+    }
+
+    /// Writes the `batch` with entries written at the `timestamp`, notifying subscribers.
+    /// Entries of an `atomic` batch are recovered all or none.
+    fn write_batch_at(
+        &mut self,
+        batch: WriteBatch,
+        timestamp: u64,
+        atomic: bool,
+    ) -> Result<(), StorageError> {
+        self.check_writable()?;
+
+        let Some(last) = batch.ops.len().checked_sub(1) else {
+            return Ok(());
+        };
+
+        for (k, v) in &batch.ops {
+            self.check_entry_size(k.len(), v.as_ref().map_or(0, |v| v.len() as u64))?;
+        }
+
+        // Batches of removals only free space, so they aren't stalled.
+        if batch.ops.iter().any(|(_, v)| v.is_some()) {
+            self.check_write_stall()?;
+
+            let size = batch
+                .ops
+                .iter()
+                .map(|(k, v)| entry_size_of(k.len(), v.as_ref().map_or(0, |v| v.len() as u64)))
+                .sum();
+            self.check_quota(size)?;
+        }
+
+        let file_id;
+        let mut pos;
+
+        // Every entry but the last one is marked as followed by more entries of the batch.
+        let keydir_entries: Vec<_> = {
+            let entries: Vec<_> = batch
+                .ops
+                .iter()
+                .enumerate()
+                .map(|(i, (k, v))| {
+                    let entry = match v {
+                        Some(v) => DiskEntry::new(k, v),
+                        None => DiskEntry::tombstone(k),
+                    }
+                    .at(timestamp);
+
+                    if atomic && i < last {
+                        entry.batched()
+                    } else {
+                        entry
+                    }
+                })
+                .collect();
+
+            let size = entries
+                .iter()
+                .map(|entry| entry.header.entry_size(self.active.layout))
+                .sum();
+
+            // The whole batch goes into the same log file.
+            self.rotate_log(size)?;
+
+            let layout = self.active.layout;
+            let headers: Vec<_> = entries
+                .iter()
+                .map(|entry| entry.header.encode(layout))
+                .collect();
+
+            let mut bufs: Vec<_> = entries
+                .iter()
+                .zip(&headers)
+                .flat_map(|(entry, header)| {
+                    [
+                        IoSlice::new(&header[..entry.header.size(layout)]),
+                        IoSlice::new(entry.key),
+                        IoSlice::new(entry.value),
+                    ]
+                })
+                .collect();
+
+            file_id = self.active.file_id;
+            pos = self.active.size;
+            self.active.write_all_vectored(&mut bufs)?;
+
+            entries
+                .iter()
+                .map(|entry| {
+                    pos += entry.header.entry_size(layout);
+                    let value_size = entry.header.value_size();
+
+                    KeydirEntry::new(file_id, value_size, pos - value_size, timestamp)
+                })
+                .collect()
+        };
+
+        for ((k, v), keydir_entry) in batch.ops.into_iter().zip(keydir_entries) {
+            match v {
+                Some(v) => {
+                    let old = self.value_for_subscribers(&k)?;
+                    self.apply_put(k, v, keydir_entry, old);
+                }
+                None if self.keydir.get(&k).is_some() => {
+                    let old = self.value_for_subscribers(&k)?;
+                    self.apply_remove(&k, timestamp, old);
+                }
+                None => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends a merge operand written at the `timestamp`, notifying subscribers.
+    fn merge_at(
+        &mut self,
+        k: Vec<u8>,
+        operand: Vec<u8>,
+        timestamp: u64,
+    ) -> Result<(), StorageError> {
+        if self.opts.merge_operator.is_none() {
+            return Err(StorageError::MergeOperatorNotSet);
+        }
+
+        self.check_entry_size(k.len(), operand.len() as u64)?;
+        self.check_write_stall()?;
+        self.check_quota(entry_size_of(k.len(), operand.len() as u64))?;
+
+        let old = self.value_for_subscribers(&k)?;
+        let keydir_entry =
+            self.write_entry(&DiskEntry::merge_operand(&k, &operand).at(timestamp))?;
+
+        if Self::expired_before(&self.keydir, &self.merge_chains, &k, timestamp) {
+            self.remove_keydir_entry(&k);
+        }
+
+        self.add_live_entry(&k, &keydir_entry);
+
+        for dropped in self.history.remove(&k) {
+            self.release_entry(&k, &dropped);
+        }
+
+        Self::push_merge_operand(
+            &mut self.keydir,
+            &mut self.merge_chains,
+            k.clone(),
+            keydir_entry,
+        );
+
+        if let Some(old) = old {
+            let new = self.value_of(&k, &keydir_entry)?;
+
+            self.subscribers.publish(ChangeEvent {
+                key: k,
+                old,
+                new,
+                timestamp,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Appends the operand to the merge chain of the key. An operand written once the value
+    /// has expired starts a new value.
+    fn push_merge_operand(
+        keydir: &mut K,
+        merge_chains: &mut MergeChains,
+        k: Vec<u8>,
+        keydir_entry: KeydirEntry,
+    ) {
+        if Self::expired_before(keydir, merge_chains, &k, keydir_entry.timestamp) {
+            merge_chains.remove(&k);
+            keydir.remove(&k);
+        }
+
+        let base = keydir.get(&k);
+
+        merge_chains
+            .entry(k.clone())
+            .or_insert_with(|| MergeChain {
+                base,
+                operands: Vec::new(),
+            })
+            .operands
+            .push(keydir_entry);
+
+        keydir.put(k, keydir_entry);
+    }
+
+    /// Whether the value of the key expired at or before the `timestamp`.
+    fn expired_before(keydir: &K, merge_chains: &MergeChains, k: &[u8], timestamp: u64) -> bool {
+        let base = match merge_chains.get(k) {
+            Some(chain) => chain.base,
+            None => keydir.get(k),
+        };
+
+        base.is_some_and(|base| base.expires_at != 0 && base.expires_at <= timestamp)
+    }
+
+    /// Puts the value, returning the previous value of the key, `None` if the key didn't exist.
+    pub fn insert(&mut self, k: Vec<u8>, v: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError> {
+        let value = self.get(&k)?;
+        self.put(k, v)?;
+
+        Ok(value)
+    }
+
+    /// Puts the value only if the key doesn't exist. Returns whether the value was put.
+    pub fn put_if_absent(&mut self, k: Vec<u8>, v: Vec<u8>) -> Result<bool, StorageError> {
+        if self.get(&k)?.is_some() {
+            return Ok(false);
+        }
+
+        self.put(k, v)?;
+
+        Ok(true)
+    }
+
+    /// Adds `delta` to the counter stored under the key, 0 if the key doesn't exist.
+    /// Returns the new value. Counters are 8-byte values, see `encoding::encode_u64`.
+    pub fn increment(&mut self, k: &[u8], delta: i64) -> Result<u64, StorageError> {
+        let current = match self.get(k)? {
+            Some(v) => encoding::decode_u64(&v).ok_or(StorageError::NotACounter(v.len()))?,
+            None => 0,
+        };
+
+        let new = current
+            .checked_add_signed(delta)
+            .ok_or(StorageError::CounterOverflow)?;
+        self.put(k.to_vec(), encoding::encode_u64(new).to_vec())?;
+
+        Ok(new)
+    }
+
+    /// Removes the key, returning its value, `None` if the key doesn't exist.
+    pub fn take(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let value = self.get(k)?;
+        self.remove(k)?;
+
+        Ok(value)
+    }
+
+    /// Atomically replaces the value of the key with `new` if the current value is `expected`.
+    ///
+    /// `None` as `expected` means the key must be absent, `None` as `new` removes the key.
+    /// On mismatch nothing is written and the actual value is returned.
+    pub fn compare_and_swap(
+        &mut self,
+        k: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Result<(), CompareAndSwapError>, StorageError> {
+        let current = self.get(k)?;
+
+        if current.as_deref() != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+
+        match new {
+            Some(v) => self.put(k.to_vec(), v)?,
+            None => self.remove(k)?,
+        }
+
+        Ok(Ok(()))
+    }
+
+    /// Gets the value of the key along with the metadata of its keydir entry.
+    pub fn get_entry(&self, k: &[u8]) -> Result<Option<ValueEntry>, StorageError> {
+        let _epoch = self.pin();
+        let Some(keydir_entry) = self.keydir.get(k) else {
+            return Ok(None);
+        };
+
+        let entry = self.value_of(k, &keydir_entry)?.map(|value| ValueEntry {
+            value,
+            timestamp: keydir_entry.timestamp,
+            file_id: keydir_entry.file_id,
+            value_size: keydir_entry.value_size,
+        });
+
+        Ok(entry)
+    }
+
+    /// Gets the value of the key for a transaction, along with what the transaction read,
+    /// to detect later changes of the key.
+    pub(crate) fn get_for_txn(&self, k: &[u8]) -> Result<(TxnRead, Option<Vec<u8>>), StorageError> {
+        let keydir_entry = self.keydir.get(k);
+        let value = self.get(k)?;
+        let checksum = value.as_deref().map(crc32fast::hash);
+
+        Ok((
+            TxnRead {
+                keydir_entry,
+                checksum,
+            },
+            value,
+        ))
+    }
+
+    /// Whether the key changed since the transaction `read` it. Compactions move entries
+    /// to other log files, keeping their timestamp, size and expiration time, so moved
+    /// entries are told from overwrites by the checksum of their value.
+    fn txn_read_changed(&self, k: &[u8], read: &TxnRead) -> Result<bool, StorageError> {
+        let current = self.keydir.get(k);
+
+        match (current, read.keydir_entry) {
+            (current, read_entry) if current == read_entry => Ok(false),
+            (Some(current), Some(read_entry))
+                if current.timestamp == read_entry.timestamp
+                    && current.value_size == read_entry.value_size
+                    && current.expires_at == read_entry.expires_at =>
+            {
+                Ok(self.get(k)?.as_deref().map(crc32fast::hash) != read.checksum)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Writes the `batch` if none of the read keys changed since they were read. On
+    /// conflict the first changed key is returned.
+    pub(crate) fn commit_txn(
+        &mut self,
+        reads: &HashMap<Vec<u8>, TxnRead>,
+        batch: WriteBatch,
+    ) -> Result<Result<(), TxnConflict>, StorageError> {
+        for (key, read) in reads {
+            if self.txn_read_changed(key, read)? {
+                return Ok(Err(TxnConflict { key: key.clone() }));
+            }
+        }
+
+        self.write_batch(batch)?;
+
+        Ok(Ok(()))
+    }
+
+    /// Replaces the value of the key with the result of `f` applied to the current value.
+    ///
+    /// Returning `None` from `f` removes the key. Returns the new value.
+    pub fn update<F>(&mut self, k: &[u8], f: F) -> Result<Option<Vec<u8>>, StorageError>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let current = self.get(k)?;
+        let new = f(current.as_deref());
+
+        match &new {
+            Some(v) => self.put(k.to_vec(), v.clone())?,
+            None => self.remove(k)?,
+        }
+
+        Ok(new)
+    }
+
+    /// Gets values of all the `keys`, returned in the same order.
+    ///
+    /// Reads are grouped by log file and sorted by offset. Values lying close to each other
+    /// are fetched with a single read.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let _epoch = self.pin();
+        let mut res = vec![None; keys.len()];
+        let mut reads = Vec::with_capacity(keys.len());
+
+        for (i, k) in keys.iter().enumerate() {
+            match self.keydir.get(k) {
+                Some(keydir_entry) if self.is_expired(k, &keydir_entry) => {
+                    self.notify(|observer| observer.on_key_expired(&self.path, k));
+                }
+                Some(keydir_entry) if self.merge_chains.contains_key(*k) => {
+                    res[i] = self.value_of(k, &keydir_entry)?;
+                }
+                Some(keydir_entry) => reads.push((i, keydir_entry)),
+                None => (),
+            }
+        }
+
+        reads.sort_unstable_by_key(|(_, e)| (e.file_id, e.value_pos));
+
+        let batches: Vec<_> = reads
+            .chunk_by(|(_, a), (_, b)| {
+                a.file_id == b.file_id
+                    && a.value_pos + a.value_size + MAX_COALESCE_GAP >= b.value_pos
+            })
+            .collect();
+
+        let mut bufs: Vec<_> = batches
+            .iter()
+            .map(|batch| {
+                let start = batch[0].1.value_pos;
+                let end = batch
+                    .iter()
+                    .map(|(_, e)| e.value_pos + e.value_size)
+                    .max()
+                    .unwrap();
+
+                (batch[0].1.file_id, start, vec![0; (end - start) as usize])
+            })
+            .collect();
+
+        self.read_log_batch(
+            bufs.iter_mut()
+                .map(|(file_id, start, buf)| (*file_id, *start, &mut buf[..])),
+        )?;
+
+        for (batch, (_, start, buf)) in batches.into_iter().zip(bufs) {
+            for (i, e) in batch {
+                let offset = (e.value_pos - start) as usize;
+                res[*i] = Some(buf[offset..offset + e.value_size as usize].to_vec());
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Calls `f` with every key-value pair, in the order values are laid out in the log
+    /// files, so a full traversal reads the log files sequentially rather than at random.
+    /// Values close to each other are read together. Values built from merge operands are
+    /// folded last. Expired keys are skipped. Keyspaces are not included.
+    pub fn for_each_in_disk_order(
+        &self,
+        mut f: impl FnMut(&[u8], &[u8]),
+    ) -> Result<(), StorageError> {
+        let _epoch = self.pin();
+
+        let (merged, mut entries): (Vec<_>, Vec<_>) = self
+            .keydir
+            .iter()
+            .filter(|(k, keydir_entry)| !self.is_expired(k, keydir_entry))
+            .partition(|(k, _)| self.merge_chains.contains_key(k));
+
+        entries.sort_unstable_by_key(|(_, e)| (e.file_id, e.value_pos));
+
+        let mut buf = Vec::new();
+        let mut rest = &entries[..];
+
+        while let Some(((_, first), _)) = rest.split_first() {
+            let start = first.value_pos;
+            let mut end = start + first.value_size;
+
+            let len = 1 + rest[1..]
+                .iter()
+                .take_while(|(_, e)| {
+                    let joined = e.file_id == first.file_id
+                        && end + MAX_COALESCE_GAP >= e.value_pos
+                        && e.value_pos + e.value_size - start <= DISK_ORDER_READ_SIZE;
+
+                    if joined {
+                        end = end.max(e.value_pos + e.value_size);
+                    }
+
+                    joined
+                })
+                .count();
+
+            buf.resize((end - start) as usize, 0);
+            self.read_log_at(first.file_id, &mut buf, start)?;
+
+            let (batch, tail) = rest.split_at(len);
+
+            for (k, e) in batch {
+                let offset = (e.value_pos - start) as usize;
+                f(k, &buf[offset..offset + e.value_size as usize]);
+            }
+
+            rest = tail;
+        }
+
+        for (k, keydir_entry) in merged {
+            if let Some(v) = self.value_of(&k, &keydir_entry)? {
+                f(&k, &v);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a reader over the value of the key, without loading the value into memory.
+    ///
+    /// Values built from merge operands are folded in memory first.
+    pub fn get_reader(&self, k: &[u8]) -> Result<Option<ValueReader<'_>>, StorageError> {
+        let res = match self.keydir.get(k) {
+            Some(_) if self.merge_chains.contains_key(k) => self
+                .get(k)?
+                .map(|value| ValueReader::Memory(io::Cursor::new(value))),
+            Some(keydir_entry) if keydir_entry.is_expired_at(self.now()) => None,
+            Some(keydir_entry)
+                if keydir_entry.file_id == self.active.file_id
+                    && keydir_entry.value_pos + keydir_entry.value_size
+                        > self.active.flushed_size() =>
+            {
+                let value = self.read_value(&keydir_entry)?;
+
+                Some(ValueReader::Memory(io::Cursor::new(value)))
+            }
+            Some(keydir_entry) => {
+                let file_id = keydir_entry.file_id;
+
+                let file = self
+                    .log_files
+                    .get(&file_id)
+                    .ok_or(StorageError::UnknownLogFile(file_id))?;
+
+                Some(ValueReader::File {
+                    file: &**file,
+                    pos: keydir_entry.value_pos,
+                    remaining: keydir_entry.value_size,
+                })
+            }
+            None => None,
+        };
+
+        Ok(res)
+    }
+
+    /// Returns the value of the key pointed by the `keydir_entry`, folding merge operands.
+    fn value_of(
+        &self,
+        k: &[u8],
+        keydir_entry: &KeydirEntry,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        if self.is_expired(k, keydir_entry) {
+            return Ok(None);
+        }
+
+        let Some(chain) = self.merge_chains.get(k) else {
+            return Ok(Some(self.read_value(keydir_entry)?));
+        };
+
+        let merge_operator = self
+            .opts
+            .merge_operator
+            .ok_or(StorageError::MergeOperatorNotSet)?;
+
+        let mut value = chain.base.map(|base| self.read_value(&base)).transpose()?;
+
+        for operand in chain.operands.iter() {
+            let operand = self.read_value(operand)?;
+            value = merge_operator(k, value.as_deref(), &operand);
+        }
+
+        Ok(value)
+    }
+
+    /// Time the key pointed by the `keydir_entry` expires at, 0 if it never expires. Values
+    /// built from merge operands expire with their base value.
+    fn expiration_of(&self, k: &[u8], keydir_entry: &KeydirEntry) -> u64 {
+        match self.merge_chains.get(k) {
+            Some(chain) => chain.base.map_or(0, |base| base.expires_at),
+            None => keydir_entry.expires_at,
+        }
+    }
+
+    /// Current timestamp of the clock set in `DbOptions`.
+    fn now(&self) -> u64 {
+        self.opts.clock.now()
+    }
+
+    /// Whether the key pointed by the `keydir_entry` has expired.
+    fn is_expired(&self, k: &[u8], keydir_entry: &KeydirEntry) -> bool {
+        match self.merge_chains.get(k) {
+            Some(chain) => chain
+                .base
+                .is_some_and(|base| base.is_expired_at(self.now())),
+            None => keydir_entry.is_expired_at(self.now()),
+        }
+    }
+
+    /// Returns the current and retained previous versions of the key, newest first.
+    /// See `DbOptions::history_versions`.
+    pub fn get_versions(&self, k: &[u8]) -> Result<Vec<Version>, StorageError> {
+        let _epoch = self.pin();
+        let mut versions = Vec::new();
+
+        if let Some(keydir_entry) = self.keydir.get(k) {
+            versions.push(Version {
+                timestamp: keydir_entry.timestamp,
+                value: self.value_of(k, &keydir_entry)?,
+            });
+        }
+
+        for version in self.history.get(k).rev() {
+            versions.push(Version {
+                timestamp: version.timestamp,
+                value: version
+                    .entry
+                    .map(|entry| self.read_value(&entry))
+                    .transpose()?,
+            });
+        }
+
+        Ok(versions)
+    }
+
+    /// Returns the value the key had at the `timestamp`, as far as versions are retained.
+    /// See `DbOptions::history_versions`.
+    pub fn get_at(&self, k: &[u8], timestamp: u64) -> Result<Option<Vec<u8>>, StorageError> {
+        let _epoch = self.pin();
+        if let Some(keydir_entry) = self.keydir.get(k) {
+            if keydir_entry.timestamp <= timestamp {
+                return self.value_of(k, &keydir_entry);
+            }
+        }
+
+        match self.history.get(k).rev().find(|v| v.timestamp <= timestamp) {
+            Some(version) => version
+                .entry
+                .map(|entry| self.read_value(&entry))
+                .transpose(),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a value pointed by the `keydir_entry` from the log file.
+    fn read_value(&self, keydir_entry: &KeydirEntry) -> Result<Vec<u8>, StorageError> {
+        let mut buf = vec![0; keydir_entry.value_size as usize];
+
+        self.read_log_at(keydir_entry.file_id, &mut buf, keydir_entry.value_pos)?;
+
+        Ok(buf)
+    }
+
+    /// Reads a value pointed by the `keydir_entry` through the value cache, adding it to
+    /// the cache if `fill`.
+    fn cached_value(
+        &self,
+        keydir_entry: &KeydirEntry,
+        fill: bool,
+    ) -> Result<Vec<u8>, StorageError> {
+        let (file_id, pos) = (keydir_entry.file_id, keydir_entry.value_pos);
+
+        if let Some(value) = self.value_cache.get(file_id, pos) {
+            return Ok(value);
+        }
+
+        let value = self.read_value(keydir_entry)?;
+
+        if fill {
+            self.value_cache.insert(file_id, pos, &value);
+        }
+
+        Ok(value)
+    }
+
+    /// Reads a whole entry pointed by the `keydir_entry` and returns its value if its checksum
+    /// matches. Entries of legacy log files have no checksum.
+    fn read_verified_value(
+        &self,
+        k: &[u8],
+        keydir_entry: &KeydirEntry,
+    ) -> Result<Vec<u8>, StorageError> {
+        let file_id = keydir_entry.file_id;
+        let mut prefix = [0; MAX_SEGMENT_HEADER_SIZE];
+        self.read_log_at(file_id, &mut prefix, 0)?;
+
+        let version = FormatVersion::detect(&prefix)
+            .map_err(|e| StorageError::from(e).in_log_file(file_id))?;
+
+        if version == FormatVersion::V1 {
+            return self.read_value(keydir_entry);
+        }
+
+        // Sizes of compact headers follow from the fields stored in the keydir entry.
+        let layout = Layout::new(version, version.created_at(&prefix).unwrap_or_default());
+        let header_size = Header::new(
+            keydir_entry.timestamp,
+            k.len() as u32,
+            keydir_entry.value_size,
+        )
+        .expiring(keydir_entry.expires_at)
+        .size(layout);
+
+        let entry_pos = keydir_entry.value_pos - (header_size + k.len()) as u64;
+        let mut buf = vec![0; header_size + k.len() + keydir_entry.value_size as usize];
+        self.read_log_at(file_id, &mut buf, entry_pos)?;
+
+        let header = Header::decode(&buf[..header_size], layout)?;
+        let mut hasher = header.hasher(version);
+        hasher.update(&buf[header_size..]);
+
+        if hasher.finalize() != header.crc() {
+            return Err(StorageError::ChecksumMismatch {
+                file_id,
+                offset: entry_pos,
+            });
+        }
+
+        Ok(buf.split_off(header_size + k.len()))
+    }
+
+    /// Reads exactly `buf.len()` bytes of the log file at `pos`. Bytes not flushed to
+    /// the active log file yet are copied from the active log writer buffer.
+    fn read_log_at(&self, file_id: u32, buf: &mut [u8], pos: u64) -> Result<(), StorageError> {
+        let file = self.log_file(file_id)?;
+
+        let flushed = if file_id == self.active.file_id {
+            self.active.read_buffered(buf, pos)
+        } else {
+            buf.len()
+        };
+
+        file.read_exact_at(&mut buf[..flushed], pos)
+            .map_err(|e| self.check_missing(file_id, e.into()))
+    }
+
+    /// Reads exactly `buf.len()` bytes of every `(file_id, pos, buf)` read. Reads of each
+    /// sealed log file are submitted as one batch, see `VfsFile::read_batch_at`.
+    fn read_log_batch<'a>(
+        &self,
+        reads: impl IntoIterator<Item = (u32, u64, &'a mut [u8])>,
+    ) -> Result<(), StorageError> {
+        let mut batches: BTreeMap<u32, Vec<(u64, &mut [u8])>> = BTreeMap::new();
+
+        for (file_id, pos, buf) in reads {
+            if file_id == self.active.file_id {
+                self.read_log_at(file_id, buf, pos)?;
+            } else {
+                batches.entry(file_id).or_default().push((pos, buf));
+            }
+        }
+
+        for (file_id, mut reads) in batches {
+            self.log_file(file_id)?
+                .read_batch_at(&mut reads)
+                .map_err(|e| self.check_missing(file_id, e.into()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Turns an error reading the log file into `StorageError::MissingLogFile` if the log
+    /// file has disappeared, e.g. deleted by hand, recording it for
+    /// `repair_missing_segments`.
+    fn check_missing(&self, file_id: u32, e: StorageError) -> StorageError {
+        let not_found =
+            matches!(&e, StorageError::IoError(e) if e.kind() == io::ErrorKind::NotFound);
+        let file_path = self.path.join(Self::format_log_file_name(file_id));
+
+        // Log files removed by GC are no longer expected to exist.
+        if !not_found || !self.log_files.contains_key(&file_id) || self.opts.vfs.exists(&file_path)
+        {
+            return e;
+        }
+
+        let mut missing = self
+            .missing_log_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if missing.insert(file_id) {
+            log::error!(
+                "🚨 Log file {} is missing, keys in it can't be read until repaired",
+                file_path.display()
+            );
+        }
+
+        StorageError::MissingLogFile(file_id)
+    }
+
+    /// Handle of the log file, which may have been retired by GC but not deleted yet.
+    fn log_file(&self, file_id: u32) -> Result<LogFile, StorageError> {
+        self.log_files
+            .get(&file_id)
+            .cloned()
+            .or_else(|| self.epochs.retired(file_id))
+            .ok_or(StorageError::UnknownLogFile(file_id))
+    }
+
+    /// Pins the current epoch, so log files retired by GC while the guard is held aren't
+    /// deleted. Held by reads across looking up keydir entries and reading through them.
+    pub(crate) fn pin(&self) -> EpochGuard {
+        self.epochs.pin()
+    }
+
+    fn format_log_file_name(file_id: u32) -> String {
+        format!("{}.rumdb.log", file_id)
+    }
+}
+
+impl<K> DiskStorage<K>
+where
+    K: OrderedKeydir + KeydirDefault,
+{
+    /// Returns an iterator over key-value pairs with keys within the bounds,
+    /// in ascending key order.
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> impl Iterator<Item = Result<KeyValue, StorageError>> + 'a {
+        self.keydir
+            .range(start, end)
+            .filter_map(|(k, keydir_entry)| self.key_value(k, &keydir_entry))
+    }
+
+    /// Removes all keys within the bounds. Returns the number of removed keys.
+    pub fn delete_range(
+        &mut self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<usize, StorageError> {
+        let keys: Vec<_> = self.keydir.range(start, end).map(|(k, _)| k).collect();
+
+        self.remove_keys(&keys)
+    }
+
+    /// Returns up to `limit` key-value pairs in key order, with keys after the `start`
+    /// continuation token, or from the first key without one. The token for the next page is
+    /// returned if more pairs follow.
+    pub fn scan(
+        &self,
+        start: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<(Vec<KeyValue>, Option<Vec<u8>>), StorageError> {
+        let start = start.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut pairs = self
+            .range(start, Bound::Unbounded)
+            .take(limit.saturating_add(1))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if pairs.len() <= limit {
+            return Ok((pairs, None));
+        }
+
+        pairs.truncate(limit);
+        let token = pairs.last().map(|(k, _)| k.clone());
+
+        Ok((pairs, token))
+    }
+
+    /// Returns an unpositioned cursor over key-value pairs in key order.
+    pub fn cursor(&self) -> Cursor<'_, K> {
+        Cursor {
+            db: self,
+            current: None,
+        }
+    }
+
+    /// Reads the first key-value pair of the keydir entries.
+    fn first_key_value(
+        &self,
+        entries: impl Iterator<Item = (Vec<u8>, KeydirEntry)>,
+    ) -> Option<(Vec<u8>, Result<KeyValue, StorageError>)> {
+        entries
+            .filter_map(|(k, keydir_entry)| {
+                self.key_value(k.clone(), &keydir_entry).map(|res| (k, res))
+            })
+            .next()
+    }
+}
+
+/// Loads key-value pairs into fresh log files, created by `DiskStorage::bulk_load`.
+///
+/// Pairs are appended to temporary log files through a large write buffer, in any order.
+/// `finish` moves the log files into place and only then updates the keydir, so loaded
+/// pairs are invisible until it returns. Dropping the loader discards them.
+pub struct BulkLoader<'a, K>
+where
+    K: Keydir + KeydirDefault,
+{
+    db: &'a mut DiskStorage<K>,
+
+    /// Sealed log files along with their entry header layouts and the entries they hold.
+    sealed: Vec<(TempFile, Layout, Vec<IndexEntry>)>,
+
+    /// Log file being written.
+    current: Option<BulkLog>,
+
+    /// Number of loaded pairs.
+    pairs: u64,
+}
+
+/// Log file written by a `BulkLoader`.
+struct BulkLog {
+    temp: TempFile,
+    layout: Layout,
+    writer: BufWriter<VfsAppender>,
+    size: u64,
+    index: Vec<IndexEntry>,
+}
+
+impl<K> BulkLoader<'_, K>
+where
+    K: Keydir + KeydirDefault,
+{
+    /// Appends the pair to the loaded log files, with the current timestamp.
+    pub fn put(&mut self, k: Vec<u8>, v: Vec<u8>) -> Result<(), StorageError> {
+        self.db.check_entry_size(k.len(), v.len() as u64)?;
+
+        let disk_entry = DiskEntry::new(&k, &v).at(self.db.now());
+
+        let full = self.current.as_ref().is_some_and(|log| {
+            !log.index.is_empty()
+                && log.size + disk_entry.header.entry_size(log.layout)
+                    > self.db.opts.max_log_file_size as u64
+        });
+
+        if full {
+            self.seal()?;
+        }
+
+        let log = match &mut self.current {
+            Some(log) => log,
+            None => self.current.insert(self.create_log()?),
+        };
+
+        let header = disk_entry.header.encode(log.layout);
+        let header_size = disk_entry.header.size(log.layout);
+
+        log.writer.write_all(&header[..header_size])?;
+        log.writer.write_all(&k)?;
+        log.writer.write_all(&v)?;
+
+        let timestamp = disk_entry.header.timestamp();
+        let value_pos = log.size + (header_size + k.len()) as u64;
+        log.size = value_pos + v.len() as u64;
+        log.index.push(IndexEntry {
+            key: k,
+            kind: EntryKind::Put,
+            timestamp,
+            expires_at: 0,
+            value_pos,
+            value_size: v.len() as u64,
+        });
+
+        self.pairs += 1;
+
+        Ok(())
+    }
+
+    /// Moves the loaded log files into place after the existing ones and points the keydir
+    /// to the loaded pairs, which override existing values. Returns the number of loaded
+    /// pairs. Subscribers are not notified.
+    pub fn finish(mut self) -> Result<u64, StorageError> {
+        if self.current.is_some() {
+            self.seal()?;
+        }
+
+        if self.sealed.is_empty() {
+            return Ok(0);
+        }
+
+        let db = &mut *self.db;
+        let first_file_id = db.active.file_id + 1;
+        let sealed = std::mem::take(&mut self.sealed);
+
+        // Log files are moved into place before the active log file is sealed behind them,
+        // so a crash in between leaves the active log file intact and older.
+        db.sync_active_log()?;
+
+        let vfs = &*db.opts.vfs;
+        let mut entries = Vec::with_capacity(sealed.len());
+
+        for (file_id, (temp, layout, index)) in (first_file_id..).zip(sealed) {
+            if let Err(e) = temp.persist(vfs) {
+                // Log files already in place would take ids of the next log files.
+                for (file_id, _) in entries {
+                    let path = db
+                        .path
+                        .join(DiskStorage::<K>::format_log_file_name(file_id));
+                    vfs.remove(&path)?;
+                }
+
+                return Err(e.into());
+            }
+
+            db.layouts.insert(file_id, layout);
+            entries.push((file_id, index));
+        }
+
+        for (file_id, _) in &entries {
+            let path = db
+                .path
+                .join(DiskStorage::<K>::format_log_file_name(*file_id));
+            db.log_files.insert(
+                *file_id,
+                db.file_cache
+                    .file(path, DiskStorage::<K>::sealed_open_mode(&db.opts)),
+            );
+        }
+
+        let last_file_id = first_file_id + entries.len() as u32 - 1;
+        db.seal_active_log(last_file_id + 1)?;
+
+        for (file_id, index) in entries {
+            for entry in index {
+                let keydir_entry =
+                    KeydirEntry::new(file_id, entry.value_size, entry.value_pos, entry.timestamp);
+                let current = db.current_version(&entry.key);
+
+                db.put_keydir_entry(entry.key.clone(), keydir_entry);
+                db.retain_version(&entry.key, current, entry.timestamp, false);
+            }
+        }
+
+        db.gc()?;
+
+        log::info!("📥 Bulk loaded {} key-value pairs", self.pairs);
+
+        Ok(self.pairs)
+    }
+
+    /// Creates the next log file to load pairs into.
+    fn create_log(&self) -> Result<BulkLog, StorageError> {
+        let file_id = self.db.active.file_id + 1 + self.sealed.len() as u32;
+        let path = self
+            .db
+            .path
+            .join(DiskStorage::<K>::format_log_file_name(file_id));
+        let temp = TempFile::create(&*self.db.opts.vfs, &path)?;
+
+        let version = self.db.opts.format_version();
+        let created_at = self.db.opts.clock.now();
+        let segment_header = version.segment_header(created_at);
+        let size = version.segment_header_size();
+
+        let mut writer =
+            BufWriter::with_capacity(BULK_LOAD_BUFFER_SIZE, VfsAppender(temp.file().clone()));
+        writer.write_all(&segment_header[..size])?;
+
+        Ok(BulkLog {
+            temp,
+            layout: Layout::new(version, created_at),
+            writer,
+            size: size as u64,
+            index: Vec::new(),
+        })
+    }
+
+    /// Appends a footer to the log file being written and syncs it.
+    fn seal(&mut self) -> Result<(), StorageError> {
+        let Some(mut log) = self.current.take() else {
+            return Ok(());
+        };
+
+        let footer = footer::encode(
+            &log.index,
+            log.index.len() as u64,
+            log.size,
+            log.layout.version,
+        );
+        log.writer.write_all(&footer)?;
+        log.writer.flush()?;
+        log.temp.file().sync()?;
+
+        self.sealed.push((log.temp, log.layout, log.index));
+
+        Ok(())
+    }
+}
+
+impl<K> Drop for BulkLoader<'_, K>
+where
+    K: Keydir + KeydirDefault,
+{
+    fn drop(&mut self) {
+        let vfs = &*self.db.opts.vfs;
+        let temps = self
+            .sealed
+            .drain(..)
+            .map(|(temp, _, _)| temp)
+            .chain(self.current.take().map(|log| log.temp));
+
+        for temp in temps {
+            if let Err(e) = temp.discard(vfs) {
+                log::warn!("⚠️  Failed to remove a bulk loaded log file: {e}");
+            }
+        }
+    }
+}
+
+impl<K> fmt::Debug for BulkLoader<'_, K>
+where
+    K: Keydir + KeydirDefault,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BulkLoader")
+            .field("sealed", &self.sealed.len())
+            .field("pairs", &self.pairs)
+            .finish()
+    }
+}
+
+/// Cursor over key-value pairs of a storage with an ordered keydir.
+///
+/// The cursor is positioned with `seek` or `seek_for_prev` and moved with `next` and `prev`.
+/// An unpositioned cursor moves to the first or the last key. Moving past either end
+/// returns `None` and keeps the cursor at the last returned key.
+pub struct Cursor<'a, K>
+where
+    K: OrderedKeydir + KeydirDefault,
+{
+    db: &'a DiskStorage<K>,
+
+    /// Key the cursor is positioned at.
+    current: Option<Vec<u8>>,
+}
+
+impl<K> Cursor<'_, K>
+where
+    K: OrderedKeydir + KeydirDefault,
+{
+    /// Key the cursor is positioned at.
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current.as_deref()
+    }
+
+    /// Moves to the first key greater than or equal to `k`.
+    pub fn seek(&mut self, k: &[u8]) -> Option<Result<KeyValue, StorageError>> {
+        let entries = self.db.keydir.range(Bound::Included(k), Bound::Unbounded);
+        self.move_to(entries)
+    }
+
+    /// Moves to the last key less than or equal to `k`.
+    pub fn seek_for_prev(&mut self, k: &[u8]) -> Option<Result<KeyValue, StorageError>> {
+        let entries = self
+            .db
+            .keydir
+            .range_rev(Bound::Unbounded, Bound::Included(k));
+        self.move_to(entries)
+    }
+
+    /// Moves to the previous key.
+    pub fn prev(&mut self) -> Option<Result<KeyValue, StorageError>> {
+        let current = self.current.clone();
+        let end = current.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let entries = self.db.keydir.range_rev(Bound::Unbounded, end);
+
+        self.move_to(entries)
+    }
+
+    fn move_to(
+        &mut self,
+        entries: impl Iterator<Item = (Vec<u8>, KeydirEntry)>,
+    ) -> Option<Result<KeyValue, StorageError>> {
+        let (k, res) = self.db.first_key_value(entries)?;
+        self.current = Some(k);
+
+        Some(res)
+    }
+}
+
+impl<K> Iterator for Cursor<'_, K>
+where
+    K: OrderedKeydir + KeydirDefault,
+{
+    type Item = Result<KeyValue, StorageError>;
+
+    /// Moves to the next key.
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.current.clone();
+        let start = current.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let entries = self.db.keydir.range(start, Bound::Unbounded);
+
+        self.move_to(entries)
+    }
+}
+
+impl<K> fmt::Debug for Cursor<'_, K>
+where
+    K: OrderedKeydir + KeydirDefault,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cursor")
+            .field("current", &self.current)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K> Storage for DiskStorage<K>
+where
+    K: Keydir + KeydirDefault,
+{
+    fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.get_opt(k, ReadOptions::default())
+    }
+
+    fn put(&mut self, k: Vec<u8>, v: Vec<u8>) -> Result<(), StorageError> {
+        self.put_opt(k, v, PutOptions::default())
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Result<(), StorageError> {
+        #[cfg(feature = "metrics")]
+        let _timer = self.metrics.start(Operation::Remove);
+
+        self.remove_at(k, self.now())?;
+        self.commit()
+    }
+}
+
+/// Keys as of the time the snapshot was taken, with the entries they pointed to. The epoch
+/// stays pinned, so the entries stay readable whatever is written or compacted meanwhile.
+#[derive(Debug)]
+pub(crate) struct PrefixSnapshot {
+    pairs: std::vec::IntoIter<(Vec<u8>, PinnedValue)>,
+    _epoch: EpochGuard,
+}
+
+#[derive(Debug)]
+enum PinnedValue {
+    Entry(KeydirEntry),
+    /// Value folded from merge operands, with the timestamp and expiration time of the key.
+    Folded {
+        value: Vec<u8>,
+        timestamp: u64,
+        expires_at: u64,
+    },
+}
+
+impl PrefixSnapshot {
+    /// Reads the next key-value pair of the snapshot from the `storage` it was taken of.
+    pub fn next_pair<K: Keydir + KeydirDefault>(
+        &mut self,
+        storage: &DiskStorage<K>,
+    ) -> Option<Result<KeyValue, StorageError>> {
+        Some(self.next_entry(storage)?.map(|(k, v, _, _)| (k, v)))
+    }
+
+    /// Like `next_pair`, along with the timestamp and expiration time of the key.
+    pub fn next_entry<K: Keydir + KeydirDefault>(
+        &mut self,
+        storage: &DiskStorage<K>,
+    ) -> Option<Result<KeyValueEntry, StorageError>> {
+        let (k, value) = self.pairs.next()?;
+
+        match value {
+            PinnedValue::Entry(keydir_entry) => Some(
+                storage
+                    .read_value(&keydir_entry)
+                    .map(|v| (k, v, keydir_entry.timestamp, keydir_entry.expires_at)),
+            ),
+            PinnedValue::Folded {
+                value,
+                timestamp,
+                expires_at,
+            } => Some(Ok((k, value, timestamp, expires_at))),
+        }
+    }
+
+    /// Number of pairs left.
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    /// Orders the pairs left by key. Only keys are sorted, values are still read as the
+    /// snapshot advances.
+    pub fn sort(&mut self) {
+        let mut pairs: Vec<_> = self.pairs.by_ref().collect();
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        self.pairs = pairs.into_iter();
+    }
+}
+
+/// Memory-mapped log file owning the values `get_bytes` slices out of it.
+#[cfg(feature = "bytes")]
+struct MappedBytes(crate::vfs::FileMap);
+
+#[cfg(feature = "bytes")]
+impl AsRef<[u8]> for MappedBytes {
+    fn as_ref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+/// Reader over a value stored in a log file.
+#[derive(Debug)]
+pub enum ValueReader<'a> {
+    /// Value read directly from the log file.
+    File {
+        file: &'a dyn VfsFile,
+        pos: u64,
+        remaining: u64,
+    },
+    /// Value folded from merge operands.
+    Memory(io::Cursor<Vec<u8>>),
+}
+
+impl ValueReader<'_> {
+    /// Number of value bytes left to read.
+    pub fn remaining(&self) -> u64 {
+        match self {
+            Self::File { remaining, .. } => *remaining,
+            Self::Memory(cursor) => cursor.get_ref().len() as u64 - cursor.position(),
+        }
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (file, pos, remaining) = match self {
+            Self::File {
+                file,
+                pos,
+                remaining,
+            } => (file, pos, remaining),
+            Self::Memory(cursor) => return cursor.read(buf),
+        };
+
+        let len = buf.len().min((*remaining).try_into().unwrap_or(usize::MAX));
+
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let read = file.read_at(&mut buf[..len], *pos)?;
+
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        *pos += read as u64;
+        *remaining -= read as u64;
+
+        Ok(read)
+    }
+}
+
+impl<K> DiskStorage<K>
+where
+    K: Keydir + Default,
+{
+    /// Whether to snapshot the keydir on close. Snapshots are not loaded when retaining
+    /// history.
+    fn snapshot_on_close(&self) -> bool {
+        self.opts.keydir_snapshot && self.opts.history_versions == 0
+    }
+
+    /// Handle of the active log file.
+    fn active_log_file(&self) -> Result<&LogFile, StorageError> {
+        self.log_files
+            .get(&self.active.file_id)
+            .ok_or(StorageError::UnknownLogFile(self.active.file_id))
+    }
+
+    /// Snapshots the keydir, so the next open doesn't have to scan log files.
+    fn write_snapshot(&self) -> Result<(), StorageError> {
+        self.active_log_file()?.sync()?;
+
+        let vfs = &*self.opts.vfs;
+        let path = self.path.join(SNAPSHOT_FILE);
+        let mut writer = SnapshotWriter::create(
+            vfs,
+            &path,
+            &Self::log_file_sizes(&self.log_files)?,
+            Self::active_tail_crc(&self.log_files)?,
+            &self
+                .layouts
+                .iter()
+                .map(|(&file_id, &layout)| (file_id, layout))
+                .collect::<Vec<_>>(),
+        )?;
+
+        for (k, entry) in self.keydir.iter() {
+            writer.entry(&k, &entry)?;
+        }
+
+        for (k, chain) in &self.merge_chains {
+            writer.merge_chain(k, chain.base.as_ref(), &chain.operands)?;
+        }
+
+        writer.finish(vfs)?;
+
+        Ok(())
+    }
+
+    fn log_file_sizes(log_files: &BTreeMap<u32, LogFile>) -> Result<Vec<LogFileInfo>, io::Error> {
+        log_files
+            .iter()
+            .map(|(file_id, file)| Ok((*file_id, file.len()?)))
+            .collect()
+    }
+
+    /// Checksum of the last `SNAPSHOT_TAIL_SIZE` bytes of the active log file, the last of
+    /// the `log_files`. Unlike its size, it catches the tail being modified in place, by a
+    /// torn write for example. Sealed log files aren't read, as they may be remote.
+    fn active_tail_crc(log_files: &BTreeMap<u32, LogFile>) -> Result<u32, io::Error> {
+        let Some((_, file)) = log_files.last_key_value() else {
+            return Ok(0);
+        };
+
+        let size = file.len()?;
+        let tail_size = size.min(SNAPSHOT_TAIL_SIZE);
+        let mut tail = vec![0; tail_size as usize];
+        file.read_exact_at(&mut tail, size - tail_size)?;
+
+        Ok(crc32fast::hash(&tail))
+    }
+}
+
+impl<K> Drop for DiskStorage<K>
+where
+    K: Keydir + Default,
+{
+    fn drop(&mut self) {
+        if let Err(e) = self.active.flush() {
+            log::warn!("⚠️  Failed to flush the active log file: {e}");
+        }
+    }
+}
+
+/// Writer of the active log file.
+///
+/// Entries are appended through a buffer. The logical size of the log file, buffered
+/// entries included, is tracked in memory.
+#[derive(Debug)]
+struct ActiveLog {
+    file_id: u32,
+    layout: Layout,
+    writer: BufWriter<VfsAppender>,
+    size: u64,
+}
+
+impl ActiveLog {
+    /// Creates a writer appending to the end of the `file`.
+    fn new(file_id: u32, file: &LogFile, buffer_size: usize) -> Result<Self, StorageError> {
+        let reader = LogReader::new(file.clone()).map_err(|e| e.in_log_file(file_id))?;
+        let size = reader.size();
+
+        Ok(Self {
+            file_id,
+            layout: reader.layout(),
+            writer: BufWriter::with_capacity(buffer_size, VfsAppender(file.clone())),
+            size,
+        })
+    }
+
+    /// Appends all the `bufs`, with a single write call when possible.
+    ///
+    /// A failed write may leave a part of the bufs in the file, which is truncated, so
+    /// the next write starts at the tracked size.
+    fn write_all_vectored(&mut self, bufs: &mut [IoSlice<'_>]) -> Result<(), io::Error> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+
+        if let Err(e) = self.write_through_buffer(bufs, len) {
+            // Bufs that didn't fit into the buffer are written directly to the file.
+            if self.writer.buffer().is_empty() {
+                self.writer.get_ref().0.set_len(self.size)?;
+            }
+
+            return Err(e);
+        }
+
+        self.size += len as u64;
+
+        Ok(())
+    }
+
+    /// Buffers the `bufs` of `len` bytes, or writes them directly to the file if they
+    /// don't fit into the buffer. `BufWriter` can't tell the file supports vectored
+    /// writes, so it would write the bufs one by one.
+    fn write_through_buffer(&mut self, bufs: &mut [IoSlice<'_>], len: usize) -> io::Result<()> {
+        if self.writer.buffer().len() + len > self.writer.capacity() {
+            self.writer.flush()?;
+        }
+
+        if len >= self.writer.capacity() {
+            Self::write_all_vectored_to(self.writer.get_mut(), bufs)
+        } else {
+            Self::write_all_vectored_to(&mut self.writer, bufs)
+        }
+    }
+
+    fn write_all_vectored_to(
+        writer: &mut impl Write,
+        mut bufs: &mut [IoSlice<'_>],
+    ) -> io::Result<()> {
+        IoSlice::advance_slices(&mut bufs, 0);
+
+        while !bufs.is_empty() {
+            match writer.write_vectored(bufs) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
+
+    /// Size of the log file part already written to the file.
+    fn flushed_size(&self) -> u64 {
+        self.size - self.writer.buffer().len() as u64
+    }
+
+    /// Copies the buffered part of `buf.len()` bytes at `pos` into `buf`.
+    /// Returns the length of the leading part which has to be read from the file.
+    fn read_buffered(&self, buf: &mut [u8], pos: u64) -> usize {
+        let flushed_size = self.flushed_size();
+
+        if pos + buf.len() as u64 <= flushed_size {
+            return buf.len();
+        }
+
+        let file_part = flushed_size.saturating_sub(pos) as usize;
+        let buffer_start = (pos + file_part as u64 - flushed_size) as usize;
+        let buffer_end = buffer_start + buf.len() - file_part;
+
+        buf[file_part..].copy_from_slice(&self.writer.buffer()[buffer_start..buffer_end]);
+
+        file_part
+    }
+}
+
+/// Creates the `path` directory along with its missing parents, syncing the parents so
+/// the created directories survive a crash.
+fn create_dir_synced(vfs: &dyn Vfs, path: &Path) -> Result<(), io::Error> {
+    let missing: Vec<_> = path
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !vfs.exists(dir))
+        .collect();
+
+    vfs.create_dir_all(path)?;
+
+    for dir in missing {
+        match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => vfs.sync_dir(parent)?,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the `path` directory holds a database, i.e. at least one log file.
+pub fn exists(path: impl AsRef<Path>) -> bool {
+    exists_with_vfs(&StdVfs, path)
+}
+
+/// Whether the `path` directory of the `vfs` holds a database. See `exists`.
+pub fn exists_with_vfs(vfs: &dyn Vfs, path: impl AsRef<Path>) -> bool {
+    vfs.list(path.as_ref())
+        .is_ok_and(|names| names.iter().any(|name| is_log_file_name(name)))
+}
+
+/// Removes the database at the `path` directory: its log files, lock, keydir snapshot and
+/// other files it owns, along with its keyspaces. Files the database doesn't own are left
+/// in place, and so is the directory if any remain.
+///
+/// Fails with `NotADatabase` if the directory doesn't hold a database, and with
+/// `AlreadyLocked` if the database is open.
+pub fn destroy(path: impl AsRef<Path>) -> Result<(), StorageError> {
+    destroy_with_vfs(&StdVfs, path)
+}
+
+/// Removes the database at the `path` directory of the `vfs`. See `destroy`.
+pub fn destroy_with_vfs(vfs: &dyn Vfs, path: impl AsRef<Path>) -> Result<(), StorageError> {
+    let path = path.as_ref();
+
+    if !exists_with_vfs(vfs, path) {
+        return Err(StorageError::NotADatabase(path.to_path_buf()));
+    }
+
+    let lock = Lockfile::lock(vfs, &path.join(LOCK_FILE))?;
+    let keyspaces_dir = path.join(KEYSPACES_DIR);
+
+    if vfs.exists(&keyspaces_dir) {
+        for name in vfs.list(&keyspaces_dir)? {
+            let keyspace_path = keyspaces_dir.join(name);
+
+            if exists_with_vfs(vfs, &keyspace_path) {
+                destroy_with_vfs(vfs, &keyspace_path)?;
+            }
+        }
+
+        remove_dir_if_empty(vfs, &keyspaces_dir)?;
+    }
+
+    let trash = path.join(TRASH_DIR);
+
+    if vfs.exists(&trash) {
+        for name in vfs.list(&trash)? {
+            if trashed_at(&name).is_some() {
+                vfs.remove(&trash.join(name))?;
+            }
+        }
+
+        vfs.sync_dir(&trash)?;
+        remove_dir_if_empty(vfs, &trash)?;
+    }
+
+    for name in vfs.list(path)? {
+        if name != LOCK_FILE && is_owned_file_name(&name) {
+            vfs.remove(&path.join(name))?;
+        }
+    }
+
+    drop(lock);
+    vfs.remove(&path.join(LOCK_FILE))?;
+    vfs.sync_dir(path)?;
+
+    log::info!("🗑  Destroyed database at {}", path.display());
+
+    remove_dir_if_empty(vfs, path)
+}
+
+/// Time a log file in the trash directory has been trashed at, `None` if the file isn't a
+/// trashed log file.
+fn trashed_at(name: &str) -> Option<u64> {
+    let (trashed_at, name) = name.split_once('-')?;
+
+    is_log_file_name(name).then_some(trashed_at.parse().ok()?)
+}
+
+fn is_log_file_name(name: &str) -> bool {
+    name.strip_suffix(".rumdb.log")
+        .is_some_and(|file_id| file_id.parse::<u32>().is_ok())
+}
+
+/// Whether a file of a storage directory is owned by the storage, temporary files included.
+fn is_owned_file_name(name: &str) -> bool {
+    let name = name
+        .strip_suffix(TempFile::EXTENSION)
+        .and_then(|name| name.strip_suffix('.'))
+        .unwrap_or(name);
+
+    is_log_file_name(name)
+        || [LOCK_FILE, SNAPSHOT_FILE, REPLICATION_FILE, CLEAR_FILE].contains(&name)
+}
+
+/// Removes the `dir` directory unless other files remain in it.
+fn remove_dir_if_empty(vfs: &dyn Vfs, dir: &Path) -> Result<(), StorageError> {
+    let remaining = vfs.list(dir)?;
+
+    if !remaining.is_empty() {
+        log::warn!(
+            "🗑  Keeping {} with {} files not owned by the database",
+            dir.display(),
+            remaining.len()
+        );
+
+        return Ok(());
+    }
+
+    vfs.remove_dir(dir)?;
+
+    match dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => Ok(vfs.sync_dir(parent)?),
+        _ => Ok(()),
+    }
+}
+
+/// Exclusive advisory lock on the `LOCK` file of a storage directory. The lock is released
+/// once the process exits, so a `LOCK` file left behind by a crash doesn't block opening.
+#[derive(Debug)]
+struct Lockfile {
+    handle: Arc<dyn VfsFile>,
+}
+
+impl Lockfile {
+    /// Locks the file at `path`, creating it if needed. Fails if it is already locked.
+    fn lock(vfs: &dyn Vfs, path: &Path) -> Result<Self, StorageError> {
+        let handle = match vfs.open(path, OpenMode::CreateNew) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                vfs.open(path, OpenMode::Existing)?
+            }
+            handle => handle?,
+        };
+
+        if !handle.try_lock()? {
+            return Err(StorageError::AlreadyLocked);
+        }
+
+        // The process id tells which process holds the lock.
+        handle.set_len(0)?;
+        handle.append_all(format!("{}\n", std::process::id()).as_bytes())?;
+
+        Ok(Self { handle })
+    }
+}
+
+impl Drop for Lockfile {
+    fn drop(&mut self) {
+        if let Err(e) = self.handle.unlock() {
+            log::warn!("🔒 Failed to release the lock: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File, OpenOptions},
+        os::unix::prelude::FileExt,
+        time::Duration,
+    };
+
+    use crate::{
+        clock::{FixedClock, LogicalClock},
+        footer::FOOTER_MAGIC,
+        format::MAGIC,
+        keydir::{HashmapKeydir, RadixKeydir, ShardedKeydir},
+        log_reader::SegmentStats,
+        vfs::{Fault, FaultInjectingVfs, FaultPoint, MemoryVfs},
+    };
+
+    use super::*;
+
+    #[test]
+    fn disk_storage_should_get_put() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        let res = db.get(b"hello").unwrap();
+        assert_eq!(res, None);
+
+        db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+
+        let res = db.get(b"hello").unwrap();
+        assert_eq!(res, Some(b"world".to_vec()));
+
+        db.put(b"hello".to_vec(), b"underworld".to_vec()).unwrap();
+
+        let res = db.get(b"hello").unwrap();
+        assert_eq!(res, Some(b"underworld".to_vec()));
+
+        db.remove(b"hello").unwrap();
+
+        let res = db.get(b"hello").unwrap();
+        assert_eq!(res, None);
+    }
+
+    #[test]
+    fn disk_storage_should_use_sharded_keydir() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().keydir_shards(4);
+
+        {
+            let mut db: DiskStorage<ShardedKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+            db.put(b"removed".to_vec(), b"entry".to_vec()).unwrap();
+            db.remove(b"removed").unwrap();
+        }
+
+        let db: DiskStorage<ShardedKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.keydir.shards(), 4);
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert_eq!(db.get(b"removed").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_lock_directory() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let lock_path = dir.path().join("LOCK");
+
+        // Left behind by a crashed process.
+        fs::write(&lock_path, "4242\n").unwrap();
+
+        let db = DiskStorage::<HashmapKeydir>::open_default(dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&lock_path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open_default(dir.path()),
+            Err(StorageError::AlreadyLocked)
+        ));
+
+        fs::remove_file(&lock_path).unwrap();
+        drop(db);
+
+        DiskStorage::<HashmapKeydir>::open_default(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn disk_storage_should_close() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        let mut db = DiskStorage::<HashmapKeydir>::open(
+            dir.path(),
+            DbOptions::default().write_buffer_size(4096),
+        )
+        .unwrap();
+        db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+        db.keyspace("users")
+            .unwrap()
+            .put(b"alice".to_vec(), b"admin".to_vec())
+            .unwrap();
+        db.close().unwrap();
+
+        assert!(dir.path().join(SNAPSHOT_FILE).exists());
+        assert!(dir
+            .path()
+            .join("keyspaces/users")
+            .join(SNAPSHOT_FILE)
+            .exists());
+
+        let mut db = DiskStorage::<HashmapKeydir>::open_default(dir.path()).unwrap();
+        assert!(!dir.path().join(SNAPSHOT_FILE).exists());
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert_eq!(
+            db.keyspace("users").unwrap().get(b"alice").unwrap(),
+            Some(b"admin".to_vec())
+        );
+    }
+
+    #[test]
+    fn disk_storage_should_append_to_reopened_active_log() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(100);
+
+        for i in 0..3u8 {
+            let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()).unwrap();
+
+            for j in 0..5u8 {
+                db.put(vec![i, j], vec![i; 10]).unwrap();
+            }
+
+            // Sealed log files are opened read-only.
+            if i > 0 {
+                assert!(db.log_files[&0].append_all(b"garbage").is_err());
+            }
+        }
+
+        let db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts).unwrap();
+
+        for i in 0..3u8 {
+            for j in 0..5u8 {
+                assert_eq!(db.get(&[i, j]).unwrap(), Some(vec![i; 10]));
+            }
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_limit_open_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .max_open_files(2);
+
+        {
+            let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..20u8 {
+                db.put(vec![i], vec![i; 50]).unwrap();
+            }
+        }
+
+        let db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts).unwrap();
+        assert!(db.log_files.len() > 10);
+
+        for i in 0..20u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 50]));
+            assert!(db.file_cache.len() <= 2);
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_use_direct_io() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(10_000)
+            .direct_io(true);
+
+        {
+            let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..100u8 {
+                db.put(vec![i], vec![i; 300]).unwrap();
+            }
+            db.remove(&[0]).unwrap();
+            db.compact().unwrap();
+        }
+
+        let db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(&[0]).unwrap(), None);
+
+        for i in 1..100u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 300]));
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_cache_values() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(
+            dir.path(),
+            DbOptions::default()
+                .max_log_file_size(100)
+                .value_cache_size(100),
+        )
+        .unwrap();
+
+        for i in 0..4u8 {
+            db.put(vec![i], vec![i; 40]).unwrap();
+        }
+
+        for _ in 0..2 {
+            for i in 0..2u8 {
+                assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 40]));
+            }
+        }
+
+        let stats = db.storage_stats();
+        assert_eq!((stats.value_cache_hits, stats.value_cache_misses), (2, 2));
+
+        // Overwritten values are cached by their new position.
+        db.put(vec![0], vec![9; 40]).unwrap();
+        assert_eq!(db.get(&[0]).unwrap(), Some(vec![9; 40]));
+        assert_eq!(db.storage_stats().value_cache_misses, 3);
+
+        // Values of removed log files are dropped from the cache, those of the active log
+        // file stay.
+        db.compact().unwrap();
+        assert_eq!(db.value_cache.size(), 40);
+
+        assert_eq!(db.get(&[0]).unwrap(), Some(vec![9; 40]));
+        assert_eq!(db.get(&[1]).unwrap(), Some(vec![1; 40]));
+
+        let stats = db.storage_stats();
+        assert_eq!((stats.value_cache_hits, stats.value_cache_misses), (3, 4));
+    }
+
+    #[test]
+    fn disk_storage_should_put_and_get_with_options() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .value_cache_size(100)
+            .merge_operator(add_u64);
+        let expiring = |secs| PutOptions {
+            ttl: Some(Duration::from_secs(secs)),
+            ..Default::default()
+        };
+
+        {
+            let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()).unwrap();
+
+            db.put_opt(b"gone".to_vec(), b"value".to_vec(), expiring(0))
+                .unwrap();
+            db.put_opt(b"counter".to_vec(), b"value".to_vec(), expiring(0))
+                .unwrap();
+            db.put_opt(b"expiring".to_vec(), b"value".to_vec(), expiring(3600))
+                .unwrap();
+            db.put_opt(
+                b"old".to_vec(),
+                b"value".to_vec(),
+                PutOptions {
+                    sync: Some(true),
+                    timestamp_override: Some(42),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            assert_eq!(db.get(b"gone").unwrap(), None);
+            assert!(db.get_reader(b"gone").unwrap().is_none());
+            assert_eq!(
+                db.get_many(&[b"gone", b"expiring"]).unwrap(),
+                [None, Some(b"value".to_vec())]
+            );
+            assert_eq!(db.get_versions(b"old").unwrap()[0].timestamp, 42);
+
+            // Operands merged into an expired value start a new one.
+            db.merge(b"counter".to_vec(), 1u64.to_le_bytes().to_vec())
+                .unwrap();
+            assert_eq!(
+                db.get(b"counter").unwrap(),
+                Some(1u64.to_le_bytes().to_vec())
+            );
+
+            let no_fill = ReadOptions {
+                fill_cache: false,
+                ..Default::default()
+            };
+            assert_eq!(
+                db.get_opt(b"expiring", no_fill).unwrap(),
+                Some(b"value".to_vec())
+            );
+            assert_eq!(db.value_cache.size(), 0);
+            db.get(b"expiring").unwrap();
+            assert_eq!(db.value_cache.size(), 5);
+
+            db.close().unwrap();
+        }
+
+        // Expiration times are restored from the keydir snapshot and from the log files.
+        for _ in 0..2 {
+            let db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()).unwrap();
+            assert_eq!(db.get(b"gone").unwrap(), None);
+            assert_eq!(db.get(b"expiring").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(
+                db.get(b"counter").unwrap(),
+                Some(1u64.to_le_bytes().to_vec())
+            );
+            db.close().unwrap();
+
+            fs::remove_file(dir.path().join(SNAPSHOT_FILE)).unwrap();
+        }
+
+        // Expired keys are removed by compaction.
+        let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts).unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.storage_stats().keys, 3);
+        assert!(db.keydir.get(b"expiring").unwrap().expires_at > DiskEntry::now());
+
+        let verify = ReadOptions {
+            verify_checksum: true,
+            ..Default::default()
+        };
+        assert_eq!(db.get_opt(b"old", verify).unwrap(), Some(b"value".to_vec()));
+
+        db.sync().unwrap();
+        let keydir_entry = db.keydir.get(b"old").unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(
+                dir.path()
+                    .join(format!("{}.rumdb.log", keydir_entry.file_id)),
+            )
+            .unwrap()
+            .write_at(b"X", keydir_entry.value_pos)
+            .unwrap();
+
+        assert!(matches!(
+            db.get_opt(b"old", verify),
+            Err(StorageError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(db.get(b"old").unwrap(), Some(b"Xalue".to_vec()));
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn disk_storage_should_read_mapped_log_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(100).mmap_reads(true);
+
+        let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 50]).unwrap();
+        }
+
+        // Sealed on rotation.
+        assert_eq!(db.get(&[0]).unwrap(), Some(vec![0; 50]));
+        drop(db);
+
+        let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts).unwrap();
+        assert!(db.log_files[&0].append_all(b"garbage").is_err());
+
+        db.put(vec![10], vec![10; 50]).unwrap();
+
+        for i in 0..=10u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 50]));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "bytes")]
+    fn disk_storage_should_get_bytes_shared_with_value_cache() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .value_cache_size(1000)
+            .merge_operator(add_u64);
+        let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts).unwrap();
+
+        db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+        db.put_expiring(b"expired".to_vec(), b"value".to_vec(), 100, 1)
+            .unwrap();
+        db.merge(b"counter".to_vec(), 1u64.to_le_bytes().to_vec())
+            .unwrap();
+
+        let first = db.get_bytes(b"hello").unwrap().unwrap();
+        let second = db.get_bytes(b"hello").unwrap().unwrap();
+        assert_eq!(first, &b"world"[..]);
+        assert_eq!(first.as_ptr(), second.as_ptr());
+
+        assert_eq!(db.get_bytes(b"expired").unwrap(), None);
+        assert_eq!(db.get_bytes(b"missing").unwrap(), None);
+        assert_eq!(
+            db.get_bytes(b"counter").unwrap().unwrap(),
+            &1u64.to_le_bytes()[..]
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "bytes", feature = "mmap"))]
+    fn disk_storage_should_get_bytes_from_mapped_log_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(100).mmap_reads(true);
+        let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts).unwrap();
+
+        for i in 0..3u8 {
+            db.put(vec![i], vec![i; 50]).unwrap();
+        }
+
+        // Values of sealed log files point into the map, the active one's are read.
+        let first = db.get_bytes(&[0]).unwrap().unwrap();
+        let second = db.get_bytes(&[0]).unwrap().unwrap();
+        assert_eq!(first, vec![0; 50]);
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        assert_eq!(db.get_bytes(&[2]).unwrap().unwrap(), vec![2; 50]);
+
+        // Values outlive the log files they have been read from.
+        db.compact().unwrap();
+        assert!(!db.log_files.contains_key(&0));
+        assert_eq!(first, vec![0; 50]);
+    }
+
+    #[test]
+    fn disk_storage_should_persist() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            db.put(b"persistence".to_vec(), b"check".to_vec()).unwrap();
+            db.put(b"removed".to_vec(), b"entry".to_vec()).unwrap();
+            db.remove(b"removed").unwrap();
+        }
+
+        {
+            let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+            let res = db.get(b"persistence").unwrap();
+            assert_eq!(res, Some(b"check".to_vec()));
+
+            let res = db.get(b"removed").unwrap();
+            assert_eq!(res, None);
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_rotate_logs() {
+        const VERSION: u8 = 3;
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(50)).unwrap();
+
+            for i in 0..=VERSION {
+                db.put(b"version".to_vec(), vec![i]).unwrap();
+            }
+        }
+
+        assert!(
+            dir.path().join("1.rumdb.log").exists(),
+            "log file has not been rotated"
+        );
+
+        {
+            let db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(50)).unwrap();
+
+            let res = db.get(b"version").unwrap();
+            assert_eq!(res, Some(vec![VERSION]));
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_build_keydir_in_parallel() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .merge_operator(add_u64)
+            .keydir_snapshot(false);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..50u8 {
+                db.put(vec![i % 10], vec![i]).unwrap();
+                db.merge(b"counter".to_vec(), 1u64.to_le_bytes().to_vec())
+                    .unwrap();
+
+                if i % 7 == 0 {
+                    db.remove(&[i % 10]).unwrap();
+                }
+                if i == 25 {
+                    db.put(b"counter".to_vec(), 100u64.to_le_bytes().to_vec())
+                        .unwrap();
+                }
+            }
+        }
+
+        let open = |threads| -> Vec<_> {
+            let db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone().keydir_build_threads(threads)).unwrap();
+            assert!(db.log_files.len() > 10);
+
+            let mut keys: Vec<_> = (0..10u8).map(|i| vec![i]).collect();
+            keys.push(b"counter".to_vec());
+            keys.iter().map(|k| db.get(k).unwrap()).collect()
+        };
+
+        let sequential = open(1);
+        assert_eq!(sequential, open(4));
+        assert_eq!(sequential[0], Some(vec![40]));
+        assert_eq!(sequential[9], None);
+        assert_eq!(sequential[10], Some(124u64.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_remove_dead_log_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let first_log_path = dir.path().join("0.rumdb.log");
+        let opts = DbOptions::default().max_log_file_size(100);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            db.put(b"keep".to_vec(), b"old".to_vec()).unwrap();
+            db.put(b"removed".to_vec(), b"value".to_vec()).unwrap();
+            db.remove(b"removed").unwrap();
+
+            for i in 0..20 {
+                db.put(b"hot".to_vec(), vec![i]).unwrap();
+            }
+            db.gc_rotated_logs().unwrap();
+            assert!(first_log_path.exists());
+
+            db.put(b"keep".to_vec(), b"new".to_vec()).unwrap();
+
+            for i in 0..20 {
+                db.put(b"hot".to_vec(), vec![i]).unwrap();
+            }
+            // Rotation only schedules GC.
+            assert!(first_log_path.exists());
+
+            db.gc_rotated_logs().unwrap();
+            assert!(!first_log_path.exists());
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"keep").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"removed").unwrap(), None);
+        assert_eq!(db.get(b"hot").unwrap(), Some(vec![19]));
+    }
+
+    #[test]
+    fn disk_storage_should_move_dead_log_files_to_trash() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let trash = dir.path().join(TRASH_DIR);
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .trash_retention(Duration::from_secs(3600));
+
+        let trashed = |trash: &Path| -> Vec<String> {
+            fs::read_dir(trash)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..20 {
+                db.put(b"hot".to_vec(), vec![i]).unwrap();
+            }
+            db.gc_rotated_logs().unwrap();
+
+            assert!(!dir.path().join("0.rumdb.log").exists());
+            assert!(trashed(&trash)
+                .iter()
+                .any(|name| name.ends_with("-0.rumdb.log")));
+        }
+
+        // Trashed log files are purged once the retention has passed.
+        fs::write(trash.join("1000-100.rumdb.log"), b"expired").unwrap();
+        fs::write(trash.join("notes.txt"), b"not a log file").unwrap();
+        let count = trashed(&trash).len();
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"hot").unwrap(), Some(vec![19]));
+        assert_eq!(trashed(&trash).len(), count - 1);
+        assert!(!trash.join("1000-100.rumdb.log").exists());
+        drop(db);
+
+        destroy(dir.path()).unwrap();
+        assert_eq!(trashed(&trash), vec!["notes.txt"]);
+    }
+
+    #[test]
+    fn disk_storage_should_drop_tombstones_shadowing_no_older_log_file() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+
+            db.put(b"keep".to_vec(), b"1".to_vec()).unwrap();
+            db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+            db.seal_active_log(db.active.file_id + 1).unwrap();
+
+            db.remove(b"b").unwrap();
+            db.seal_active_log(db.active.file_id + 1).unwrap();
+
+            db.put(b"x".to_vec(), b"3".to_vec()).unwrap();
+            db.remove(b"x").unwrap();
+            db.seal_active_log(db.active.file_id + 1).unwrap();
+
+            db.gc().unwrap();
+            db.gc_tombstones().unwrap();
+
+            // Dropping the tombstone of `b` would resurrect it from the older log file.
+            assert!(db.log_files.contains_key(&0));
+            assert!(db.log_files.contains_key(&1));
+            assert!(!db.log_files.contains_key(&2));
+
+            // Once `b` is put again, the newer value shadows the older log file instead.
+            db.put(b"b".to_vec(), b"4".to_vec()).unwrap();
+            db.remove(b"b").unwrap();
+            db.put(b"b".to_vec(), b"5".to_vec()).unwrap();
+            db.seal_active_log(db.active.file_id + 1).unwrap();
+            db.gc_tombstones().unwrap();
+
+            assert!(!db.log_files.contains_key(&1));
+        }
+
+        assert!(!dir.path().join("1.rumdb.log").exists());
+        assert!(!dir.path().join("2.rumdb.log").exists());
+
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+        assert_eq!(db.get(b"keep").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"5".to_vec()));
+        assert_eq!(db.get(b"x").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_keep_dead_log_files_while_pinned() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let first_log_path = dir.path().join("0.rumdb.log");
+        let opts = DbOptions::default().max_log_file_size(100);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        db.put(b"keep".to_vec(), b"old".to_vec()).unwrap();
+
+        // A reader looked the key up before it was overwritten.
+        let epoch = db.pin();
+        let stale = db.keydir.get(b"keep").unwrap();
+
+        db.put(b"keep".to_vec(), b"new".to_vec()).unwrap();
+        for i in 0..20 {
+            db.put(b"hot".to_vec(), vec![i]).unwrap();
+        }
+        db.compact().unwrap();
+
+        assert!(!db.log_files.contains_key(&0));
+        assert!(first_log_path.exists());
+        assert_eq!(db.read_value(&stale).unwrap(), b"old");
+
+        drop(epoch);
+        db.reclaim_logs().unwrap();
+        assert!(!first_log_path.exists());
+        assert!(matches!(
+            db.read_value(&stale),
+            Err(StorageError::UnknownLogFile(0))
+        ));
+        assert_eq!(db.get(b"keep").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_compact() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts =
+            DbOptions::default()
+                .max_log_file_size(100)
+                .merge_operator(|_, existing, operand| {
+                    let mut value = existing.unwrap_or_default().to_vec();
+                    value.extend_from_slice(operand);
+                    Some(value)
+                });
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..10u8 {
+                db.put(vec![i], vec![i; 10]).unwrap();
+                db.merge(b"list".to_vec(), vec![i]).unwrap();
+            }
+            db.remove(&[0]).unwrap();
+
+            db.keyspace("users")
+                .unwrap()
+                .put(b"hello".to_vec(), vec![0; 60])
+                .unwrap();
+            db.keyspace("users")
+                .unwrap()
+                .put(b"hello".to_vec(), b"world".to_vec())
+                .unwrap();
+
+            let log_files = db.storage_stats().log_files;
+            db.compact().unwrap();
+            assert!(db.storage_stats().log_files < log_files);
+            assert!(!dir.path().join("0.rumdb.log").exists());
+            assert!(!dir.path().join("keyspaces/users/0.rumdb.log").exists());
+
+            assert_eq!(db.get(b"list").unwrap(), Some((0..10).collect()));
+        }
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(&[0]).unwrap(), None);
+        assert_eq!(db.get(&[9]).unwrap(), Some(vec![9; 10]));
+        assert_eq!(db.get(b"list").unwrap(), Some((0..10).collect()));
+        assert_eq!(
+            db.keyspace("users").unwrap().get(b"hello").unwrap(),
+            Some(b"world".to_vec())
+        );
+    }
+
+    #[test]
+    fn disk_storage_should_clear() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(100);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..10u8 {
+                db.put(vec![i], vec![i; 10]).unwrap();
+            }
+            db.keyspace("users")
+                .unwrap()
+                .put(b"hello".to_vec(), b"world".to_vec())
+                .unwrap();
+
+            let changes = db.subscribe_prefix(&[0]);
+            db.clear().unwrap();
+
+            let event = changes.try_recv().unwrap();
+            assert_eq!(
+                (event.key, event.old, event.new),
+                (vec![0], Some(vec![0; 10]), None)
+            );
+
+            let stats = db.storage_stats();
+            assert_eq!((stats.keys, stats.log_files), (0, 1));
+            assert_eq!(db.get(&[0]).unwrap(), None);
+            assert_eq!(db.keyspace("users").unwrap().get(b"hello").unwrap(), None);
+
+            db.put(vec![42], vec![42]).unwrap();
+        }
+
+        {
+            let db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            assert_eq!(db.storage_stats().keys, 1);
+            assert_eq!(db.get(&[42]).unwrap(), Some(vec![42]));
+
+            // A crash right after the clear marker is written leaves the old log files,
+            // which are removed on the next open.
+            let first_file_id = db.active.file_id + 1;
+            DiskStorage::<HashmapKeydir>::create_log_file(&opts, dir.path(), first_file_id)
+                .unwrap();
+            db.write_clear_marker(first_file_id).unwrap();
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(&[42]).unwrap(), None);
+        assert_eq!(db.storage_stats().log_files, 1);
+        assert!(!dir.path().join(CLEAR_FILE).exists());
+    }
+
+    #[test]
+    fn disk_storage_should_move_cleared_log_files_to_trash() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let trash = dir.path().join(TRASH_DIR);
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .trash_retention(Duration::from_secs(3600));
+
+        let trashed = |trash: &Path| -> Vec<String> {
+            let mut names: Vec<_> = fs::read_dir(trash)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .map(|name| name.split_once('-').unwrap().1.to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            db.put(b"a".to_vec(), vec![1; 100]).unwrap();
+            db.put(b"b".to_vec(), vec![2; 10]).unwrap();
+            db.clear().unwrap();
+
+            assert_eq!(
+                trashed(&trash),
+                vec!["0.rumdb.log", "1.rumdb.log", "2.rumdb.log"]
+            );
+            assert!(!dir.path().join("0.rumdb.log").exists());
+
+            // Log files left by a crash right after the clear marker is written are
+            // trashed on the next open.
+            let first_file_id = db.active.file_id + 1;
+            DiskStorage::<HashmapKeydir>::create_log_file(&opts, dir.path(), first_file_id)
+                .unwrap();
+            db.write_clear_marker(first_file_id).unwrap();
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.storage_stats().log_files, 1);
+        assert_eq!(
+            trashed(&trash),
+            vec!["0.rumdb.log", "1.rumdb.log", "2.rumdb.log", "3.rumdb.log"]
+        );
+        assert!(!dir.path().join(CLEAR_FILE).exists());
+    }
+
+    #[test]
+    fn disk_storage_should_write_entries_with_one_vectored_write() {
+        for write_buffer_size in [0, 64] {
+            let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+            let opts = DbOptions::default().write_buffer_size(write_buffer_size);
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+            // Values both fitting into the write buffer and written directly to the file.
+            for (i, size) in [0, 1, 10, 100, 1000].into_iter().enumerate() {
+                let key = vec![i as u8; i + 1];
+                let log_size = db.active.size;
+
+                db.put(key.clone(), vec![i as u8; size]).unwrap();
+
+                let entry = db.keydir.get(&key).unwrap();
+                let header_size = FormatVersion::CURRENT.header_size() as u64;
+                assert_eq!(entry.value_size, size as u64);
+                assert_eq!(entry.value_pos, log_size + header_size + key.len() as u64);
+                assert_eq!(entry.value_pos + entry.value_size, db.active.size);
+            }
+
+            db.flush().unwrap();
+
+            let log = fs::read(dir.path().join("0.rumdb.log")).unwrap();
+            assert_eq!(log.len() as u64, db.active.size);
+
+            for (i, size) in [0, 1, 10, 100, 1000].into_iter().enumerate() {
+                let key = vec![i as u8; i + 1];
+                let entry = db.keydir.get(&key).unwrap();
+                let pos = entry.value_pos as usize;
+
+                assert_eq!(&log[pos - key.len()..pos], key.as_slice());
+                assert_eq!(&log[pos..pos + size], vec![i as u8; size].as_slice());
+                assert_eq!(db.get(&key).unwrap(), Some(vec![i as u8; size]));
+            }
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_buffer_writes() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+        let opts = DbOptions::default().write_buffer_size(128);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..10u8 {
+                db.put(vec![i], vec![i; 20]).unwrap();
+            }
+
+            let flushed_size = fs::metadata(&log_path).unwrap().len();
+            assert!(flushed_size < db.active.size);
+
+            assert_eq!(db.get(&[9]).unwrap(), Some(vec![9; 20]));
+
+            let keys: Vec<_> = (0..10u8).map(|i| vec![i]).collect();
+            let keys: Vec<_> = keys.iter().map(Vec::as_slice).collect();
+            let values = db.get_many(&keys).unwrap();
+            assert!(values.iter().zip(0..).all(|(v, i)| *v == Some(vec![i; 20])));
+
+            let mut value = Vec::new();
+            db.get_reader(&[9])
+                .unwrap()
+                .unwrap()
+                .read_to_end(&mut value)
+                .unwrap();
+            assert_eq!(value, vec![9; 20]);
+
+            db.flush().unwrap();
+            assert_eq!(fs::metadata(&log_path).unwrap().len(), db.active.size);
+
+            db.put(b"unflushed".to_vec(), b"value".to_vec()).unwrap();
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(&[0]).unwrap(), Some(vec![0; 20]));
+        assert_eq!(db.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_track_active_log_size() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(200)).unwrap();
+
+        let active_log_size = |db: &DiskStorage<HashmapKeydir>| {
+            let log_path = dir.path().join(format!("{}.rumdb.log", db.active.file_id));
+            fs::metadata(log_path).unwrap().len()
+        };
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 30]).unwrap();
+            assert_eq!(db.active.size, active_log_size(&db));
+        }
+
+        db.put_from_reader(b"large".to_vec(), &[1; 50][..], 50)
+            .unwrap();
+        assert_eq!(db.active.size, active_log_size(&db));
+
+        assert!(db
+            .put_from_reader(b"short".to_vec(), &[1; 10][..], 50)
+            .is_err());
+        assert_eq!(db.active.size, active_log_size(&db));
+
+        db.remove(&[0]).unwrap();
+        assert_eq!(db.active.size, active_log_size(&db));
+    }
+
+    #[test]
+    fn disk_storage_should_report_stats() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(50)).unwrap();
+
+        assert_eq!(db.storage_stats().keys, 0);
+        assert_eq!(db.storage_stats().keydir_memory, 0);
+
+        for i in 0..4u8 {
+            db.put(vec![i; 10], b"value".to_vec()).unwrap();
+        }
+        db.remove(&[0; 10]).unwrap();
+
+        let stats = db.storage_stats();
+        assert_eq!(stats.keys, 3);
+        assert!(stats.keydir_memory >= 30);
+        assert!(stats.log_files > 1);
+        assert!(stats.to_string().starts_with("keys: 3, "));
+
+        let entry_size = (FormatVersion::CURRENT.header_size() + 10 + 5) as u64;
+        assert_eq!(stats.segments.len(), stats.log_files);
+        assert_eq!(stats.live_bytes, 3 * entry_size);
+        assert_eq!(stats.disk_usage, stats.live_bytes + stats.dead_bytes);
+        assert_eq!(
+            stats.disk_usage,
+            dir.path()
+                .read_dir()
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.to_str().unwrap().ends_with(".rumdb.log"))
+                .map(|path| fs::metadata(path).unwrap().len())
+                .sum::<u64>()
+        );
+
+        // The first log file holds only the removed entry.
+        let first = stats.segments[0];
+        assert_eq!(first.live_entries, 0);
+        assert_eq!(first.dead_bytes, first.total_bytes);
+        assert_eq!(first.fragmentation(), 1.0);
+        assert!(stats.fragmentation() > 0.0 && stats.fragmentation() < 1.0);
+    }
+
+    #[test]
+    fn disk_storage_should_account_removals_in_segment_stats() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+
+        db.put(b"a".to_vec(), b"value".to_vec()).unwrap();
+        db.put(b"b".to_vec(), b"value".to_vec()).unwrap();
+        db.seal_active_log(db.active.file_id + 1).unwrap();
+        db.remove(b"a").unwrap();
+        db.remove(b"missing").unwrap();
+        db.flush().unwrap();
+
+        // The removed entry is dead, as is its tombstone. Missing keys get no tombstone.
+        let stats = db.storage_stats();
+        let [sealed, active] = stats.segments[..] else {
+            panic!("unexpected segments: {:?}", stats.segments);
+        };
+        let version = FormatVersion::CURRENT;
+
+        assert_eq!(sealed.live_entries, 1);
+        assert_eq!(sealed.live_bytes, entry_size_of(1, 5));
+        assert_eq!(active.live_entries, 0);
+        assert_eq!(
+            active.dead_bytes,
+            (version.segment_header_size() + version.header_size() + 1) as u64
+        );
+        assert_eq!(active.fragmentation(), 1.0);
+    }
+
+    #[test]
+    fn disk_storage_should_account_compact_headers_in_segment_stats() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().compact_headers(true);
+        let segment_header_size = FormatVersion::V7.segment_header_size() as u64;
+
+        let assert_stats = |db: &DiskStorage<HashmapKeydir>| {
+            let stats = db.storage_stats();
+            let [active] = stats.segments[..] else {
+                panic!("unexpected segments: {:?}", stats.segments);
+            };
+
+            assert_eq!(active.live_entries, 2);
+            assert_eq!(active.live_bytes, active.total_bytes - segment_header_size);
+            assert_eq!(active.dead_bytes, segment_header_size);
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"a".to_vec(), b"value".to_vec()).unwrap();
+            db.put_expiring(b"b".to_vec(), vec![1; 300], 100, u64::MAX)
+                .unwrap();
+            db.flush().unwrap();
+
+            assert_stats(&db);
+            db.close().unwrap();
+        }
+
+        // Once from the snapshot, once from the log file.
+        for _ in 0..2 {
+            let db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            assert_stats(&db);
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_use_vfs() {
+        let vfs = MemoryVfs::default();
+        let path = Path::new("/db");
+        let opts = DbOptions::default()
+            .max_log_file_size(50)
+            .vfs(Arc::new(vfs.clone()));
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(path, opts.clone()).unwrap();
+
+            for i in 0..4u8 {
+                db.put(vec![i; 10], vec![i; 10]).unwrap();
+            }
+            db.remove(&[0; 10]).unwrap();
+
+            db.keyspace("users")
+                .unwrap()
+                .put(b"hello".to_vec(), b"world".to_vec())
+                .unwrap();
+            db.close().unwrap();
+        }
+
+        assert!(vfs.exists(&path.join(SNAPSHOT_FILE)));
+        assert!(vfs.list(path).unwrap().len() > 3);
+
+        for keydir_snapshot in [true, false] {
+            if !keydir_snapshot {
+                vfs.remove(&path.join(SNAPSHOT_FILE)).ok();
+            }
+
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(path, opts.clone()).unwrap();
+
+            assert_eq!(db.get(&[0; 10]).unwrap(), None);
+            assert_eq!(db.get(&[3; 10]).unwrap(), Some(vec![3; 10]));
+            assert_eq!(
+                db.keyspace("users").unwrap().get(b"hello").unwrap(),
+                Some(b"world".to_vec())
+            );
+        }
+
+        assert!(!Path::new("/db").exists());
+    }
+
+    #[test]
+    fn disk_storage_should_persist_directory_changes_on_crash() {
+        let memory_vfs = MemoryVfs::default();
+        let vfs = FaultInjectingVfs::new(Arc::new(memory_vfs.clone()));
+        let path = Path::new("/db");
+        // Each log file holds a single entry.
+        let opts = DbOptions::default().max_log_file_size(50);
+        let mut db = open_faulty(&vfs, opts.clone());
+
+        for i in 0..4u8 {
+            db.put(vec![i; 10], vec![i; 10]).unwrap();
+        }
+
+        // A new log file which can't be made durable is removed.
+        vfs.inject(FaultPoint::SyncDir, 0, Fault::Error);
+        assert!(db.put(vec![4; 10], vec![4; 10]).is_err());
+        db.put(vec![4; 10], vec![4; 10]).unwrap();
+
+        db.keyspace("users")
+            .unwrap()
+            .put(b"hello".to_vec(), b"world".to_vec())
+            .unwrap();
+        db.put(vec![0; 10], vec![10; 10]).unwrap();
+        db.compact().unwrap();
+
+        let files = vfs.list(path).unwrap();
+        crash(db, &vfs);
+        memory_vfs.crash();
+
+        // Neither created log files are lost nor removed ones come back.
+        assert_eq!(vfs.list(path).unwrap(), files);
+
+        let mut db = open_faulty(&vfs, opts);
+        assert_eq!(db.get(&[0; 10]).unwrap(), Some(vec![10; 10]));
+
+        for i in 1..5u8 {
+            assert_eq!(db.get(&[i; 10]).unwrap(), Some(vec![i; 10]));
+        }
+
+        assert_eq!(
+            db.keyspace("users").unwrap().get(b"hello").unwrap(),
+            Some(b"world".to_vec())
+        );
+    }
+
+    #[test]
+    fn disk_storage_should_write_files_atomically() {
+        let memory_vfs = MemoryVfs::default();
+        let vfs = FaultInjectingVfs::new(Arc::new(memory_vfs.clone()));
+        let path = Path::new("/db");
+        let opts = DbOptions::default()
+            .max_log_file_size(200)
+            .write_buffer_size(4096);
+        let mut db = open_faulty(&vfs, opts.clone());
+
+        for i in 0..10u8 {
+            db.put(vec![i; 10], vec![i; 10]).unwrap();
+        }
+
+        for i in 0..5u8 {
+            db.put(vec![i; 10], vec![i + 10; 10]).unwrap();
+        }
+
+        // Inputs are kept while the compaction output isn't durable.
+        let files = vfs.list(path).unwrap();
+        vfs.inject(FaultPoint::Sync, 0, Fault::Error);
+        assert!(db.compact().is_err());
+        assert!(files.iter().all(|name| vfs.exists(&path.join(name))));
+
+        db.compact().unwrap();
+
+        // A file rewrite interrupted before its rename leaves only a temporary file behind.
+        vfs.inject(FaultPoint::Rename, 0, Fault::Error);
+        assert!(db.backup_to("/backup").is_err());
+        assert!(vfs
+            .list(Path::new("/backup"))
+            .unwrap()
+            .iter()
+            .all(|name| name.ends_with(".tmp")));
+
+        vfs.open(&path.join("KEYDIR.snapshot.tmp"), OpenMode::CreateNew)
+            .unwrap()
+            .append_all(b"partial")
+            .unwrap();
+
+        crash(db, &vfs);
+        memory_vfs.crash();
+
+        let db = open_faulty(&vfs, opts);
+        assert!(!vfs.exists(&path.join("KEYDIR.snapshot.tmp")));
+
+        for i in 0..10u8 {
+            let expected = if i < 5 { i + 10 } else { i };
+            assert_eq!(db.get(&[i; 10]).unwrap(), Some(vec![expected; 10]));
+        }
+    }
+
+    fn open_faulty(vfs: &FaultInjectingVfs, opts: DbOptions) -> DiskStorage<HashmapKeydir> {
+        DiskStorage::open("/db", opts.vfs(Arc::new(vfs.clone()))).unwrap()
+    }
+
+    /// Simulates a crash: nothing is flushed or snapshotted and the lock is left behind.
+    fn crash(db: DiskStorage<HashmapKeydir>, vfs: &FaultInjectingVfs) {
+        std::mem::forget(db);
+        // The process exit would release the lock, a new file is unlocked.
+        vfs.remove(Path::new("/db/LOCK")).unwrap();
+    }
+
+    #[test]
+    fn disk_storage_should_recover_from_torn_writes() {
+        let header_size = FormatVersion::CURRENT.header_size();
+
+        // Entries torn within the header, key and value.
+        for torn in [0, header_size / 2, header_size + 5, header_size + 15] {
+            let vfs = FaultInjectingVfs::new(Arc::new(MemoryVfs::default()));
+            let mut db = open_faulty(&vfs, DbOptions::default());
+
+            db.put(vec![0; 10], vec![0; 10]).unwrap();
+
+            vfs.inject(FaultPoint::Append, 0, Fault::Torn(torn));
+            assert!(db.put(vec![1; 10], vec![1; 10]).is_err());
+
+            db.put(vec![2; 10], vec![2; 10]).unwrap();
+            assert_eq!(db.get(&[1; 10]).unwrap(), None);
+            assert_eq!(db.get(&[2; 10]).unwrap(), Some(vec![2; 10]));
+
+            // The torn entry can't be discarded, so it's left for recovery.
+            vfs.inject(FaultPoint::Append, 0, Fault::Torn(torn));
+            vfs.inject(FaultPoint::SetLen, 0, Fault::Error);
+            assert!(db.put(vec![3; 10], vec![3; 10]).is_err());
+
+            crash(db, &vfs);
+
+            let mut db = open_faulty(&vfs, DbOptions::default());
+            assert_eq!(db.get(&[0; 10]).unwrap(), Some(vec![0; 10]));
+            assert_eq!(db.get(&[1; 10]).unwrap(), None);
+            assert_eq!(db.get(&[2; 10]).unwrap(), Some(vec![2; 10]));
+            assert_eq!(db.get(&[3; 10]).unwrap(), None);
+
+            db.put(vec![4; 10], vec![4; 10]).unwrap();
+            drop(db);
+
+            let db = open_faulty(&vfs, DbOptions::default().keydir_snapshot(false));
+            assert_eq!(db.get(&[2; 10]).unwrap(), Some(vec![2; 10]));
+            assert_eq!(db.get(&[4; 10]).unwrap(), Some(vec![4; 10]));
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_not_lose_committed_writes_on_faults() {
+        let vfs = FaultInjectingVfs::new(Arc::new(MemoryVfs::default()));
+        // Each log file holds a single entry.
+        let opts = DbOptions::default().max_log_file_size(50);
+        let mut db = open_faulty(&vfs, opts.clone());
+
+        db.put(vec![0; 10], vec![0; 10]).unwrap();
+
+        vfs.inject(FaultPoint::Create, 0, Fault::Error);
+        assert!(db.put(vec![1; 10], vec![1; 10]).is_err());
+        db.put(vec![1; 10], vec![1; 10]).unwrap();
+
+        // Torn segment header of a new log file.
+        vfs.inject(FaultPoint::Append, 0, Fault::Torn(3));
+        assert!(db.put(vec![2; 10], vec![2; 10]).is_err());
+        db.put(vec![2; 10], vec![2; 10]).unwrap();
+
+        db.put(vec![0; 10], vec![10; 10]).unwrap();
+
+        db.put(vec![1; 10], vec![11; 10]).unwrap();
+
+        vfs.inject(FaultPoint::Remove, 0, Fault::Error);
+        assert!(db.gc_rotated_logs().is_err());
+        db.gc_rotated_logs().unwrap();
+
+        vfs.inject(FaultPoint::Sync, 0, Fault::Error);
+        assert!(db.sync().is_err());
+        db.sync().unwrap();
+
+        let assert_committed = |db: &DiskStorage<HashmapKeydir>| {
+            assert_eq!(db.get(&[0; 10]).unwrap(), Some(vec![10; 10]));
+            assert_eq!(db.get(&[1; 10]).unwrap(), Some(vec![11; 10]));
+            assert_eq!(db.get(&[2; 10]).unwrap(), Some(vec![2; 10]));
+        };
+
+        assert_committed(&db);
+
+        vfs.inject(FaultPoint::Rename, 0, Fault::Error);
+        drop(db);
+        assert!(!vfs.exists(&Path::new("/db").join(SNAPSHOT_FILE)));
+
+        let db = open_faulty(&vfs, opts.clone());
+        assert_committed(&db);
+        crash(db, &vfs);
+
+        let db = open_faulty(&vfs, opts);
+        assert_committed(&db);
+    }
+
+    #[test]
+    fn disk_storage_should_verify() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .write_buffer_size(1024)
+            .merge_operator(|_, _, operand| Some(operand.to_vec()));
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 10]).unwrap();
+        }
+        db.merge(vec![0], vec![10]).unwrap();
+        db.remove(&[1]).unwrap();
+
+        let report = db.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.inconsistencies);
+        assert_eq!(report.keys, 9);
+        assert_eq!(report.entries, 12);
+        assert_eq!(report.log_files, db.log_files.len());
+
+        // Corrupt value of key 2.
+        let corrupt = db.keydir.get(&[2]).unwrap();
+        let log = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join(format!("{}.rumdb.log", corrupt.file_id)))
+            .unwrap();
+        log.write_at(&[0xff], corrupt.value_pos).unwrap();
+
+        // Key 3 pointing to a wrong position and key 4 to a missing log file.
+        let mut keydir_entry = db.keydir.get(&[3]).unwrap();
+        keydir_entry.value_pos += 1;
+        db.keydir.put(vec![3], keydir_entry);
+
+        let mut keydir_entry = db.keydir.get(&[4]).unwrap();
+        keydir_entry.file_id = 1000;
+        db.keydir.put(vec![4], keydir_entry);
+
+        let report = db.verify().unwrap();
+        assert_eq!(report.inconsistencies.len(), 4);
+        assert!(report
+            .inconsistencies
+            .contains(&Inconsistency::CorruptValue {
+                key: vec![2],
+                file_id: corrupt.file_id,
+                value_pos: corrupt.value_pos,
+            }));
+        assert!(report
+            .inconsistencies
+            .contains(&Inconsistency::MissingLogFile {
+                key: vec![4],
+                file_id: 1000,
+            }));
+        assert!(report
+            .inconsistencies
+            .iter()
+            .any(|i| matches!(i, Inconsistency::ChecksumMismatch { .. })));
+        assert!(report
+            .inconsistencies
+            .iter()
+            .any(|i| matches!(i, Inconsistency::DanglingKey { key, .. } if key == &[3])));
+        assert!(report.to_string().ends_with("inconsistencies: 4"));
+    }
+
+    #[test]
+    fn disk_storage_should_be_destroyed() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let path = dir.path().join("db");
+        assert!(!exists(&path));
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(&path).unwrap();
+        db.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        db.keyspace("users")
+            .unwrap()
+            .put(b"alice".to_vec(), b"admin".to_vec())
+            .unwrap();
+        fs::write(path.join("notes.txt"), b"not a database file").unwrap();
+
+        assert!(exists(&path));
+        assert!(matches!(destroy(&path), Err(StorageError::AlreadyLocked)));
+        drop(db);
+
+        destroy(&path).unwrap();
+        assert!(!exists(&path));
+        assert_eq!(
+            fs::read_dir(&path)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>(),
+            vec!["notes.txt"]
+        );
+        assert!(matches!(destroy(&path), Err(StorageError::NotADatabase(_))));
+
+        // The directory is removed along with the database if nothing else remains.
+        let vfs = MemoryVfs::default();
+        let path = Path::new("/db");
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(path, DbOptions::default().vfs(Arc::new(vfs.clone()))).unwrap();
+        drop(db);
+
+        destroy_with_vfs(&vfs, path).unwrap();
+        assert!(!vfs.exists(path));
+    }
+
+    #[test]
+    fn disk_storage_should_rebuild_index() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(100);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..10u8 {
+                db.put(vec![i], vec![i; 10]).unwrap();
+            }
+            db.remove(&[1]).unwrap();
+        }
+
+        let open_log = |file_id: u32| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(dir.path().join(format!("{file_id}.rumdb.log")))
+                .unwrap()
+        };
+
+        // Corrupt the footer index of log file 0, which is scanned on open instead.
+        let log = open_log(0);
+        let footer_pos = LogReader::open(dir.path().join("0.rumdb.log"))
+            .unwrap()
+            .size();
+        log.write_at(&[0xff], footer_pos + FOOTER_MAGIC.len() as u64)
+            .unwrap();
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), opts.clone()).unwrap();
+        assert!(!dir.path().join(SNAPSHOT_FILE).exists());
+
+        // Corrupt the footer trailer of log file 1.
+        let log = open_log(1);
+        log.write_at(&[0xff], log.metadata().unwrap().len() - 20)
+            .unwrap();
+
+        let report = db.verify().unwrap();
+        assert!(!report.is_ok());
+
+        // Sealed log files are replaced rather than resized, open handles are left intact.
+        let sealed_len = log.metadata().unwrap().len();
+
+        let report = db.rebuild_index().unwrap();
+        assert!(report.is_ok(), "{:?}", report.inconsistencies);
+        assert_eq!(report.keys, 9);
+        assert_eq!(log.metadata().unwrap().len(), sealed_len);
+        assert_eq!(db.get(&[1]).unwrap(), None);
+        assert_eq!(db.get(&[9]).unwrap(), Some(vec![9; 10]));
+
+        for file_id in [0, 1] {
+            let reader = LogReader::open(dir.path().join(format!("{file_id}.rumdb.log"))).unwrap();
+            assert!(reader.read_index().unwrap().is_some());
+        }
+
+        db.put(vec![10], vec![10; 10]).unwrap();
+        drop(db);
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.keydir.len(), 10);
+        drop(db);
+
+        // Corrupted entries can't be recovered.
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        let corrupt = db.keydir.get(&[2]).unwrap();
+        open_log(corrupt.file_id)
+            .write_at(&[0xff], corrupt.value_pos)
+            .unwrap();
+
+        assert!(matches!(
+            db.rebuild_index(),
+            Err(StorageError::ChecksumMismatch { file_id, .. }) if file_id == corrupt.file_id
+        ));
+    }
+
+    #[test]
+    fn disk_storage_should_skip_corrupted_entries() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        // Each sealed log file holds two entries.
+        let opts = DbOptions::default()
+            .max_log_file_size(110)
+            .keydir_snapshot(false);
+
+        let positions: Vec<_> = {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            (0..7u8)
+                .map(|i| {
+                    db.put(vec![i], vec![i; 10]).unwrap();
+                    db.keydir.get(&[i]).unwrap()
+                })
+                .collect()
+        };
+
+        let open_log = |file_id: u32| {
+            OpenOptions::new()
+                .write(true)
+                .open(dir.path().join(format!("{file_id}.rumdb.log")))
+                .unwrap()
+        };
+
+        // Corrupted value of key 0, key size of key 2 and torn entry of key 5.
+        open_log(positions[0].file_id)
+            .write_at(&[0xff], positions[0].value_pos)
+            .unwrap();
+        open_log(positions[2].file_id)
+            .write_at(&[0xff; 4], positions[2].value_pos - 1 - 20)
+            .unwrap();
+        open_log(positions[5].file_id)
+            .set_len(positions[5].value_pos + 5)
+            .unwrap();
+
+        assert!(DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()).is_err());
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_recovery(dir.path(), RecoveryMode::SkipCorrupted).unwrap();
+
+        let report = db.recovery_report().clone();
+        assert_eq!(report.lost.len(), 3);
+        assert_eq!(
+            report.lost[0],
+            LostRange {
+                file_id: positions[0].file_id,
+                start: FormatVersion::CURRENT.segment_header_size() as u64,
+                end: positions[1].value_pos - 1 - FormatVersion::CURRENT.header_size() as u64,
+            }
+        );
+        assert_eq!(report.lost[2].end, report.lost[2].start + 39);
+        assert_eq!(report.lost_bytes(), 44 + 44 + 39);
+
+        for i in [0, 2, 5] {
+            assert_eq!(db.get(&[i]).unwrap(), None);
+        }
+
+        for i in [1, 3, 4, 6] {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+
+        db.put(vec![7], vec![7; 10]).unwrap();
+        drop(db);
+
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_recovery(dir.path(), RecoveryMode::SkipCorrupted).unwrap();
+        assert_eq!(db.recovery_report().lost.len(), 2);
+        assert_eq!(db.get(&[7]).unwrap(), Some(vec![7; 10]));
+    }
+
+    #[test]
+    fn disk_storage_should_export_and_import() {
+        let dir = tempdir::TempDir::new("disk-storage-test").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .merge_operator(add_u64);
+
+        let mut dump = Vec::new();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path().join("src"), opts.clone()).unwrap();
+
+            for i in 0..10u8 {
+                db.put(vec![i], vec![i; 20]).unwrap();
+            }
+
+            db.remove(&[3]).unwrap();
+            db.merge(b"counter".to_vec(), 2u64.to_le_bytes().to_vec())
+                .unwrap();
+            db.merge(b"counter".to_vec(), 3u64.to_le_bytes().to_vec())
+                .unwrap();
+            db.keyspace("users")
+                .unwrap()
+                .put(b"alice".to_vec(), b"admin".to_vec())
+                .unwrap();
+
+            assert_eq!(db.export(&mut dump).unwrap(), 11);
+        }
+
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::import(dir.path().join("dst"), dump.as_slice()).unwrap();
+
+        for i in (0..10u8).filter(|&i| i != 3) {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 20]));
+        }
+
+        assert_eq!(db.get(&[3]).unwrap(), None);
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(5u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            db.get_keyspace("users").unwrap().get(b"alice").unwrap(),
+            Some(b"admin".to_vec())
+        );
+
+        let src: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path().join("src"), opts).unwrap();
+        assert_eq!(
+            db.keydir.get(&[0]).unwrap().timestamp,
+            src.keydir.get(&[0]).unwrap().timestamp
+        );
+
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::import(dir.path().join("dst"), dump.as_slice()),
+            Err(StorageError::DirectoryNotEmpty(_))
+        ));
+
+        let mut corrupted = dump.clone();
+        corrupted[20] ^= 0xff;
+        assert!(
+            DiskStorage::<HashmapKeydir>::import(dir.path().join("bad"), corrupted.as_slice())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn disk_storage_should_export_and_import_bitcask() {
+        let dir = tempdir::TempDir::new("disk-storage-test").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path().join("src"), DbOptions::default()).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+        }
+
+        db.remove(&[3]).unwrap();
+        db.keyspace("users")
+            .unwrap()
+            .put(b"alice".to_vec(), b"admin".to_vec())
+            .unwrap();
+
+        let bitcask = dir.path().join("bitcask");
+        assert_eq!(db.export_bitcask(&bitcask).unwrap(), 9);
+        assert!(matches!(
+            db.export_bitcask(&bitcask),
+            Err(StorageError::DirectoryNotEmpty(_))
+        ));
+
+        let imported: DiskStorage<HashmapKeydir> =
+            DiskStorage::import_bitcask(dir.path().join("dst"), &bitcask).unwrap();
+
+        for i in (0..10u8).filter(|&i| i != 3) {
+            assert_eq!(imported.get(&[i]).unwrap(), Some(vec![i; 20]));
+        }
+
+        assert_eq!(imported.get(&[3]).unwrap(), None);
+        assert!(imported.get_keyspace("users").is_none());
+        assert_eq!(
+            imported.keydir.get(&[0]).unwrap().timestamp,
+            db.keydir.get(&[0]).unwrap().timestamp
+        );
+
+        db.put(vec![0; u16::MAX as usize + 1], vec![0]).unwrap();
+        assert!(matches!(
+            db.export_bitcask(dir.path().join("too-large")),
+            Err(StorageError::KeyTooLarge { max: 65535, .. })
+        ));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn disk_storage_should_record_metrics() {
+        let dir = tempdir::TempDir::new("disk-storage-test").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(100)).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+        }
+
+        db.get(&[0]).unwrap();
+        db.get(b"missing").unwrap();
+        db.remove(&[1]).unwrap();
+        db.compact().unwrap();
+
+        let metrics = db.metrics();
+        assert_eq!(metrics.put.count, 10);
+        assert_eq!(metrics.get.count, 2);
+        assert_eq!(metrics.remove.count, 1);
+        assert_eq!(metrics.compact.count, 1);
+        assert!(metrics.put.max_time >= metrics.put.mean_time());
+        assert!(metrics.put.total_time >= metrics.put.max_time);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn disk_storage_should_export_opentelemetry() {
+        use opentelemetry::global;
+        use opentelemetry_sdk::{
+            metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider},
+            trace::{InMemorySpanExporter, SdkTracerProvider},
+        };
+
+        let metric_exporter = InMemoryMetricExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metric_exporter.clone()).build())
+            .build();
+        global::set_meter_provider(meter_provider.clone());
+
+        let span_exporter = InMemorySpanExporter::default();
+        global::set_tracer_provider(
+            SdkTracerProvider::builder()
+                .with_simple_exporter(span_exporter.clone())
+                .build(),
+        );
+
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(100)).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+        }
+
+        db.compact().unwrap();
+        meter_provider.force_flush().unwrap();
+
+        let metrics: Vec<_> = metric_exporter
+            .get_finished_metrics()
+            .unwrap()
+            .iter()
+            .flat_map(|metrics| metrics.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .map(|metric| metric.name().to_string())
+            .collect();
+        assert!(metrics.contains(&"rumdb.operations".to_string()));
+        assert!(metrics.contains(&"rumdb.operation.duration".to_string()));
+
+        // Other tests may compact while the providers are installed.
+        let path = dir.path().display().to_string();
+        let span = span_exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .find(|span| {
+                span.attributes
+                    .iter()
+                    .any(|kv| kv.key.as_str() == "rumdb.path" && kv.value.as_str() == path)
+            })
+            .unwrap();
+        assert_eq!(span.name, "rumdb.compact");
+        assert_eq!(span.status, opentelemetry::trace::Status::Unset);
+        assert!(span
+            .attributes
+            .iter()
+            .any(|kv| kv.key.as_str() == "rumdb.keys"
+                && kv.value.as_str().parse::<usize>().unwrap() > 0));
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl RecordingObserver {
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.events.lock().unwrap())
+        }
+    }
+
+    impl StorageObserver for RecordingObserver {
+        fn on_log_sealed(&self, _path: &Path, file_id: u32) {
+            self.record(format!("sealed {file_id}"));
+        }
+
+        fn on_log_removed(&self, _path: &Path, file_id: u32) {
+            self.record(format!("removed {file_id}"));
+        }
+
+        fn on_compaction_started(&self, _path: &Path, keys: usize) {
+            self.record(format!("compaction started {keys}"));
+        }
+
+        fn on_compaction_finished(&self, _path: &Path) {
+            self.record("compaction finished".to_string());
+        }
+
+        fn on_recovery(&self, _path: &Path, report: &RecoveryReport) {
+            self.record(format!("recovery {}", report.lost_bytes()));
+        }
+
+        fn on_key_expired(&self, _path: &Path, key: &[u8]) {
+            self.record(format!("expired {}", key.escape_ascii()));
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_notify_observer() {
+        let dir = tempdir::TempDir::new("disk-storage-test").unwrap();
+        let observer = Arc::new(RecordingObserver::default());
+        let opts = DbOptions::default()
+            .max_log_file_size(70)
+            .observer(observer.clone());
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            db.put(vec![0; 10], vec![0; 10]).unwrap();
+            db.put(vec![1; 10], vec![1; 10]).unwrap();
+            assert_eq!(observer.take(), ["sealed 0"]);
+
+            // Log files are collected on rotation, so the first log file, left without
+            // live entries, is removed once the active log file is sealed again.
+            db.put(vec![0; 10], vec![2; 10]).unwrap();
+            assert_eq!(observer.take(), ["sealed 1"]);
+
+            db.compact().unwrap();
+            assert_eq!(
+                observer.take(),
+                [
+                    "compaction started 1",
+                    "sealed 2",
+                    "removed 0",
+                    "removed 1",
+                    "compaction finished"
+                ]
+            );
+        }
+
+        OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("3.rumdb.log"))
+            .unwrap()
+            .write_all(&[1, 2, 3])
+            .unwrap();
+
+        let _db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(observer.take(), ["recovery 3"]);
+    }
+
+    #[test]
+    fn disk_storage_should_notify_observer_of_expired_keys() {
+        let dir = tempdir::TempDir::new("disk-storage-test").unwrap();
+        let observer = Arc::new(RecordingObserver::default());
+        let clock = Arc::new(FixedClock::new(1000));
+        let opts = DbOptions::default()
+            .clock(clock.clone())
+            .observer(observer.clone());
+        let ttl = PutOptions {
+            ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        db.put_opt(b"session".to_vec(), b"value".to_vec(), ttl)
+            .unwrap();
+        db.put_opt(b"token".to_vec(), b"value".to_vec(), ttl)
+            .unwrap();
+        db.keyspace("users")
+            .unwrap()
+            .put_opt(b"alice".to_vec(), b"value".to_vec(), ttl)
+            .unwrap();
+
+        assert_eq!(db.get(b"session").unwrap(), Some(b"value".to_vec()));
+        assert!(observer.take().is_empty());
+
+        // Reads notify every time until the key is swept.
+        clock.advance(10);
+        assert_eq!(db.get(b"session").unwrap(), None);
+        assert_eq!(
+            db.get_many(&[b"session", b"missing"]).unwrap(),
+            [None, None]
+        );
+        assert_eq!(observer.take(), ["expired session", "expired session"]);
+
+        let mut swept = Vec::new();
+        assert_eq!(db.sweep_expired(10).unwrap(), 3);
+        swept.extend(observer.take());
+        swept.sort();
+        assert_eq!(swept, ["expired alice", "expired session", "expired token"]);
+
+        assert_eq!(db.get(b"session").unwrap(), None);
+        assert!(observer.take().is_empty());
+    }
+
+    #[derive(Debug, Default)]
+    struct ProgressObserver {
+        progress: Mutex<Vec<OpenProgress>>,
+    }
+
+    impl StorageObserver for ProgressObserver {
+        fn on_open_progress(&self, _path: &Path, progress: &OpenProgress) {
+            self.progress.lock().unwrap().push(*progress);
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_report_open_progress() {
+        let dir = tempdir::TempDir::new("disk-storage-test").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .keydir_snapshot(false);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..20u8 {
+                db.put(vec![i; 10], vec![i; 10]).unwrap();
+            }
+        }
+
+        let log_sizes: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".rumdb.log"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .collect();
+
+        for threads in [1, 4] {
+            let observer = Arc::new(ProgressObserver::default());
+            let opts = opts
+                .clone()
+                .keydir_build_threads(threads)
+                .observer(observer.clone());
+            let _db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+            let progress = observer.progress.lock().unwrap();
+            assert_eq!(progress.len(), log_sizes.len());
+
+            for (i, progress) in progress.iter().enumerate() {
+                assert_eq!(progress.log_files_done, i + 1);
+                assert_eq!(progress.log_files_total, log_sizes.len());
+                assert_eq!(progress.bytes_total, log_sizes.iter().sum::<u64>());
+            }
+
+            let last = progress.last().unwrap();
+            assert_eq!(last.bytes_done, last.bytes_total);
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_publish_changes() {
+        let dir = tempdir::TempDir::new("disk-storage-test").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default().merge_operator(add_u64)).unwrap();
+
+        let all = db.subscribe();
+        let users = db.subscribe_prefix(b"user:");
+
+        db.put(b"user:1".to_vec(), b"alice".to_vec()).unwrap();
+        db.put(b"user:1".to_vec(), b"bob".to_vec()).unwrap();
+        db.remove(b"user:1").unwrap();
+        db.remove(b"user:1").unwrap();
+        db.merge(b"counter".to_vec(), 2u64.to_le_bytes().to_vec())
+            .unwrap();
+        db.put_from_reader(b"user:2".to_vec(), &b"carol"[..], 5)
+            .unwrap();
+
+        let changes: Vec<_> = users
+            .try_iter()
+            .map(|event| (event.key, event.old, event.new))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (b"user:1".to_vec(), None, Some(b"alice".to_vec())),
+                (
+                    b"user:1".to_vec(),
+                    Some(b"alice".to_vec()),
+                    Some(b"bob".to_vec())
+                ),
+                (b"user:1".to_vec(), Some(b"bob".to_vec()), None),
+                (b"user:2".to_vec(), None, Some(b"carol".to_vec())),
+            ]
+        );
+
+        let changes: Vec<_> = all.try_iter().collect();
+        assert_eq!(changes.len(), 5);
+        assert_eq!(changes[3].key, b"counter");
+        assert_eq!(changes[3].new, Some(2u64.to_le_bytes().to_vec()));
+        assert!(changes[3].timestamp > 0);
+
+        let watch = db.watch(b"user:1");
+        db.put(b"user:10".to_vec(), b"eve".to_vec()).unwrap();
+        assert!(watch.wait_timeout(Duration::from_millis(10)).is_err());
+        db.put(b"user:1".to_vec(), b"frank".to_vec()).unwrap();
+        assert_eq!(watch.wait().unwrap().new, Some(b"frank".to_vec()));
+        drop(watch);
+
+        // Dropped subscribers are forgotten.
+        drop(all);
+        drop(users);
+        db.put(b"user:3".to_vec(), b"dave".to_vec()).unwrap();
+        assert!(!db.subscribers.is_interested(b"user:3"));
+    }
+
+    #[test]
+    fn disk_storage_should_stream_values() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        let value: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+
+        db.put(b"small".to_vec(), b"value".to_vec()).unwrap();
+        db.put(b"large".to_vec(), value.clone()).unwrap();
+
+        assert!(db.get_reader(b"missing").unwrap().is_none());
+
+        let mut reader = db.get_reader(b"large").unwrap().unwrap();
+        assert_eq!(reader.remaining(), value.len() as u64);
+
+        let mut res = Vec::new();
+        let mut chunk = [0; 1000];
+        loop {
+            let read = reader.read(&mut chunk).unwrap();
+            if read == 0 {
+                break;
+            }
+            res.extend_from_slice(&chunk[..read]);
+        }
+        assert_eq!(res, value);
+
+        let mut res = Vec::new();
+        db.get_reader(b"small")
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut res)
+            .unwrap();
+        assert_eq!(res, b"value");
+    }
+
+    #[test]
+    fn disk_storage_should_put_from_reader() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let value: Vec<u8> = (0..3 * CHUNK_SIZE + 1).map(|i| i as u8).collect();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+            db.put_from_reader(b"large".to_vec(), value.as_slice(), value.len() as u64)
+                .unwrap();
+            assert_eq!(db.get(b"large").unwrap(), Some(value.clone()));
+
+            let res = db.put_from_reader(b"short".to_vec(), &b"value"[..], 10);
+            assert!(res.is_err());
+            assert_eq!(db.get(b"short").unwrap(), None);
+
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+        assert_eq!(db.get(b"large").unwrap(), Some(value));
+        assert_eq!(db.get(b"short").unwrap(), None);
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    fn add_u64(_key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        let existing = existing.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+        let operand = u64::from_le_bytes(operand.try_into().unwrap());
+
+        Some((existing + operand).to_le_bytes().to_vec())
+    }
+
+    #[test]
+    fn disk_storage_should_merge() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().merge_operator(add_u64);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            assert!(matches!(
+                db.merge(b"counter".to_vec(), 1u64.to_le_bytes().to_vec()),
+                Err(StorageError::MergeOperatorNotSet)
+            ));
+        }
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for _ in 0..3 {
+                db.merge(b"counter".to_vec(), 1u64.to_le_bytes().to_vec())
+                    .unwrap();
+            }
+            assert_eq!(
+                db.get(b"counter").unwrap(),
+                Some(3u64.to_le_bytes().to_vec())
+            );
+
+            db.put(b"base".to_vec(), 10u64.to_le_bytes().to_vec())
+                .unwrap();
+            db.merge(b"base".to_vec(), 5u64.to_le_bytes().to_vec())
+                .unwrap();
+
+            db.merge(b"removed".to_vec(), 5u64.to_le_bytes().to_vec())
+                .unwrap();
+            db.remove(b"removed").unwrap();
+
+            db.merge(b"reset".to_vec(), 5u64.to_le_bytes().to_vec())
+                .unwrap();
+            db.put(b"reset".to_vec(), 1u64.to_le_bytes().to_vec())
+                .unwrap();
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(3u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(db.get(b"base").unwrap(), Some(15u64.to_le_bytes().to_vec()));
+        assert_eq!(db.get(b"removed").unwrap(), None);
+        assert_eq!(db.get(b"reset").unwrap(), Some(1u64.to_le_bytes().to_vec()));
+
+        let mut res = Vec::new();
+        db.get_reader(b"base")
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut res)
+            .unwrap();
+        assert_eq!(res, 15u64.to_le_bytes());
+
+        assert_eq!(db.scan_prefix(b"").count(), 3);
+    }
+
+    #[test]
+    fn disk_storage_should_compare_and_swap() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        let res = db.compare_and_swap(b"key", Some(b"v1"), Some(b"v2".to_vec()));
+        assert_eq!(res.unwrap(), Err(CompareAndSwapError { current: None }));
+        assert_eq!(db.get(b"key").unwrap(), None);
+
+        let res = db.compare_and_swap(b"key", None, Some(b"v1".to_vec()));
+        assert_eq!(res.unwrap(), Ok(()));
+        assert_eq!(db.get(b"key").unwrap(), Some(b"v1".to_vec()));
+
+        let res = db.compare_and_swap(b"key", None, Some(b"v2".to_vec()));
+        assert_eq!(
+            res.unwrap(),
+            Err(CompareAndSwapError {
+                current: Some(b"v1".to_vec())
+            })
+        );
+
+        let res = db.compare_and_swap(b"key", Some(b"v1"), Some(b"v2".to_vec()));
+        assert_eq!(res.unwrap(), Ok(()));
+        assert_eq!(db.get(b"key").unwrap(), Some(b"v2".to_vec()));
+
+        let res = db.compare_and_swap(b"key", Some(b"v2"), None);
+        assert_eq!(res.unwrap(), Ok(()));
+        assert_eq!(db.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_insert() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        assert_eq!(db.insert(b"key".to_vec(), b"v1".to_vec()).unwrap(), None);
+        assert_eq!(
+            db.insert(b"key".to_vec(), b"v2".to_vec()).unwrap(),
+            Some(b"v1".to_vec())
+        );
+        assert_eq!(db.get(b"key").unwrap(), Some(b"v2".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_put_if_absent() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        assert!(db.put_if_absent(b"key".to_vec(), b"v1".to_vec()).unwrap());
+        assert!(!db.put_if_absent(b"key".to_vec(), b"v2".to_vec()).unwrap());
+        assert_eq!(db.get(b"key").unwrap(), Some(b"v1".to_vec()));
+
+        db.remove(b"key").unwrap();
+        assert!(db.put_if_absent(b"key".to_vec(), b"v3".to_vec()).unwrap());
+        assert_eq!(db.get(b"key").unwrap(), Some(b"v3".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_increment() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        assert_eq!(db.increment(b"counter", 5).unwrap(), 5);
+        assert_eq!(db.increment(b"counter", -2).unwrap(), 3);
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(encoding::encode_u64(3).to_vec())
+        );
+
+        assert!(matches!(
+            db.increment(b"counter", -4),
+            Err(StorageError::CounterOverflow)
+        ));
+
+        db.put(b"name".to_vec(), b"alice".to_vec()).unwrap();
+        assert!(matches!(
+            db.increment(b"name", 1),
+            Err(StorageError::NotACounter(5))
+        ));
+    }
+
+    #[test]
+    fn disk_storage_should_take() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        assert_eq!(db.take(b"key").unwrap(), None);
+
+        db.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(db.take(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"key").unwrap(), None);
+        assert_eq!(db.take(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_update() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        let append = |old: Option<&[u8]>| {
+            let mut v = old.unwrap_or_default().to_vec();
+            v.push(b'a');
+            Some(v)
+        };
+
+        assert_eq!(db.update(b"key", append).unwrap(), Some(b"a".to_vec()));
+        assert_eq!(db.update(b"key", append).unwrap(), Some(b"aa".to_vec()));
+        assert_eq!(db.get(b"key").unwrap(), Some(b"aa".to_vec()));
+
+        assert_eq!(db.update(b"key", |_| None).unwrap(), None);
+        assert_eq!(db.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_get_many() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(100)).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; i as usize]).unwrap();
+        }
+        db.remove(&[3]).unwrap();
+        db.put(vec![5], b"overwritten".to_vec()).unwrap();
+
+        let res = db
+            .get_many(&[&[9], &[3], &[0], &[5], &[42], &[1], &[9]])
+            .unwrap();
+
+        assert_eq!(
+            res,
+            vec![
+                Some(vec![9; 9]),
+                None,
+                Some(vec![]),
+                Some(b"overwritten".to_vec()),
+                None,
+                Some(vec![1]),
+                Some(vec![9; 9]),
+            ]
+        );
+    }
+
+    #[test]
+    fn disk_storage_should_scan_prefix() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        db.put(b"user:1".to_vec(), b"alice".to_vec()).unwrap();
+        db.put(b"user:2".to_vec(), b"bob".to_vec()).unwrap();
+        db.put(b"group:1".to_vec(), b"admins".to_vec()).unwrap();
+        db.remove(b"user:2").unwrap();
+
+        let res: Vec<_> = db.scan_prefix(b"user:").map(Result::unwrap).collect();
+        assert_eq!(res, vec![(b"user:1".to_vec(), b"alice".to_vec())]);
+
+        assert_eq!(db.scan_prefix(b"").count(), 2);
+        assert_eq!(
+            db.scan_prefix_keys(b"user:").collect::<Vec<_>>(),
+            vec![b"user:1".to_vec()]
+        );
+    }
+
+    #[test]
+    fn disk_storage_should_scan_filtered() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().merge_operator(add_u64);
+        let mut db: DiskStorage<RadixKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        db.put(b"user:1".to_vec(), b"alice".to_vec()).unwrap();
+        db.put(b"user:2".to_vec(), b"bob".to_vec()).unwrap();
+        db.put(b"user:3".to_vec(), b"anna".to_vec()).unwrap();
+        db.put_expiring(b"user:4".to_vec(), b"ada".to_vec(), 100, 1)
+            .unwrap();
+        db.merge(b"user:5".to_vec(), 7u64.to_le_bytes().to_vec())
+            .unwrap();
+        db.put(b"group:1".to_vec(), b"admins".to_vec()).unwrap();
+
+        let res: Vec<_> = db
+            .scan_filtered(b"user:", |_, v| v.starts_with(b"a"))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            res,
+            vec![
+                (b"user:1".to_vec(), b"alice".to_vec()),
+                (b"user:3".to_vec(), b"anna".to_vec()),
+            ]
+        );
+
+        let res: Vec<_> = db
+            .scan_filtered(b"user:", |_, v| v.len() == 8)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(res, vec![(b"user:5".to_vec(), 7u64.to_le_bytes().to_vec())]);
+
+        // Values of keys rejected by the key filter are not read.
+        let mut filtered = Vec::new();
+        let res: Vec<_> = db
+            .scan_filtered_by_key(
+                b"user:",
+                |k| k < b"user:3".as_slice(),
+                |k, _| {
+                    filtered.push(k.to_vec());
+                    true
+                },
+            )
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(res.len(), 2);
+        assert_eq!(filtered, vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+    }
+
+    #[test]
+    fn disk_storage_should_traverse_in_disk_order() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(4096)
+            .merge_operator(add_u64);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        for i in (0..200u8).rev() {
+            db.put(vec![i], vec![i; i as usize * 10]).unwrap();
+        }
+        for i in (0..200u8).step_by(3) {
+            db.put(vec![i], vec![i; 5]).unwrap();
+        }
+        db.remove(&[7]).unwrap();
+        db.put_expiring(vec![8], vec![8], 100, 1).unwrap();
+        db.merge(vec![250], 1u64.to_le_bytes().to_vec()).unwrap();
+        assert!(db.log_files.len() > 2);
+
+        let mut pairs = Vec::new();
+        db.for_each_in_disk_order(|k, v| pairs.push((k.to_vec(), v.to_vec())))
+            .unwrap();
+
+        // Values are visited by position in the log files, merged values last.
+        let (merged, pairs) = pairs.split_last().unwrap();
+        assert_eq!(merged.0, vec![250]);
+        let positions: Vec<_> = pairs
+            .iter()
+            .map(|(k, _)| {
+                let e = db.keydir.get(k).unwrap();
+                (e.file_id, e.value_pos)
+            })
+            .collect();
+        assert!(positions.is_sorted());
+
+        let mut pairs = pairs.to_vec();
+        pairs.push(merged.clone());
+        pairs.sort();
+        let mut expected: Vec<_> = db.scan_prefix(b"").map(Result::unwrap).collect();
+        expected.sort();
+        assert_eq!(pairs.len(), 199);
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn disk_storage_should_delete_prefix_and_range() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<RadixKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        for k in [&b"a"[..], b"ab", b"abc", b"b", b"c", b"d"] {
+            db.put(k.to_vec(), k.to_vec()).unwrap();
+        }
+
+        assert_eq!(db.delete_prefix(b"ab").unwrap(), 2);
+        assert_eq!(db.delete_prefix(b"ab").unwrap(), 0);
+        assert_eq!(
+            db.delete_range(Bound::Excluded(b"a"), Bound::Excluded(b"d"))
+                .unwrap(),
+            2
+        );
+
+        drop(db);
+
+        let db: DiskStorage<RadixKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+        let keys: Vec<_> = db.scan_prefix(b"").map(|res| res.unwrap().0).collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"d".to_vec()]);
+    }
+
+    #[test]
+    fn disk_storage_should_limit_key_and_value_size() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().max_key_size(4).max_value_size(8);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        db.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+
+        assert!(matches!(
+            db.put(b"long key".to_vec(), b"value".to_vec()),
+            Err(StorageError::KeyTooLarge { size: 8, max: 4 })
+        ));
+        assert!(matches!(
+            db.put(b"key".to_vec(), b"long value".to_vec()),
+            Err(StorageError::ValueTooLarge { size: 10, max: 8 })
+        ));
+        assert!(matches!(
+            db.put_from_reader(b"key".to_vec(), &b"long value"[..], 10),
+            Err(StorageError::ValueTooLarge { .. })
+        ));
+
+        let mut batch = WriteBatch::default();
+        batch.put(b"new".to_vec(), b"value".to_vec());
+        batch.put(b"long key".to_vec(), b"value".to_vec());
+        assert!(matches!(
+            db.write_batch(batch),
+            Err(StorageError::KeyTooLarge { .. })
+        ));
+
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"new").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_stall_writes() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .stall_log_files(3);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        let mut written = 0u8;
+        let err = loop {
+            match db.put(vec![written], vec![written; 20]) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(err, StorageError::WriteStalled(_)));
+        assert_eq!(db.storage_stats().log_files, 4);
+
+        let mut batch = WriteBatch::default();
+        batch.put(b"new".to_vec(), b"value".to_vec());
+        assert!(matches!(
+            db.write_batch(batch),
+            Err(StorageError::WriteStalled(_))
+        ));
+        // Removals free space, so they proceed and compaction lifts the stall.
+        for i in 0..written {
+            db.remove(&[i]).unwrap();
+        }
+        db.compact().unwrap();
+        db.put(b"new".to_vec(), b"value".to_vec()).unwrap();
+
+        // Past the fragmentation threshold, writes are delayed instead.
+        let delay = Duration::from_millis(50);
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(200)
+            .stall_fragmentation(0.5)
+            .write_stall_mode(WriteStallMode::Delay(delay));
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        db.put(b"keep".to_vec(), b"value".to_vec()).unwrap();
+        while db.storage_stats().log_files == 1 {
+            db.put(b"hot".to_vec(), vec![0; 20]).unwrap();
+        }
+        assert!(db.storage_stats().fragmentation() > 0.5);
+
+        let start = std::time::Instant::now();
+        db.put(b"hot".to_vec(), vec![1; 20]).unwrap();
+        assert!(start.elapsed() >= delay);
+        assert_eq!(db.get(b"hot").unwrap(), Some(vec![1; 20]));
+    }
+
+    #[test]
+    fn disk_storage_should_enforce_quota() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(1000)
+            .max_db_size(5000);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        // The live entry in the first log file keeps GC from removing later dead log files,
+        // so only compaction keeps the storage within the quota.
+        db.put(b"keep".to_vec(), vec![0; 100]).unwrap();
+        for i in 0..200u8 {
+            db.put(b"hot".to_vec(), vec![i; 100]).unwrap();
+            assert!(db.storage_stats().disk_usage <= 5000);
+        }
+
+        assert!(matches!(
+            db.put(b"large".to_vec(), vec![0; 5000]),
+            Err(StorageError::QuotaExceeded { max: 5000, .. })
+        ));
+        assert!(matches!(
+            db.put_from_reader(b"large".to_vec(), &[0; 5000][..], 5000),
+            Err(StorageError::QuotaExceeded { .. })
+        ));
+
+        assert_eq!(db.get(b"keep").unwrap(), Some(vec![0; 100]));
+        assert_eq!(db.get(b"hot").unwrap(), Some(vec![199; 100]));
+        assert_eq!(db.get(b"large").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_bulk_load() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(200);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"existing".to_vec(), b"old".to_vec()).unwrap();
+            db.put(b"kept".to_vec(), b"value".to_vec()).unwrap();
+
+            // Pairs of a dropped loader are discarded.
+            let mut loader = db.bulk_load().unwrap();
+            loader.put(b"dropped".to_vec(), b"value".to_vec()).unwrap();
+            drop(loader);
+            assert_eq!(db.get(b"dropped").unwrap(), None);
+            assert!(fs::read_dir(dir.path()).unwrap().all(|entry| !entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .ends_with(".tmp")));
+
+            let mut loader = db.bulk_load().unwrap();
+            for i in (0..50u8).rev() {
+                loader.put(vec![i], vec![i; 20]).unwrap();
+            }
+            loader.put(b"existing".to_vec(), b"new".to_vec()).unwrap();
+            assert_eq!(loader.finish().unwrap(), 51);
+
+            assert_eq!(db.get(b"existing").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(db.get(b"kept").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(db.get(&[7]).unwrap(), Some(vec![7; 20]));
+            assert_eq!(db.storage_stats().keys, 52);
+            assert!(db.storage_stats().log_files > 3);
+
+            db.put(b"after".to_vec(), b"load".to_vec()).unwrap();
+            assert!(db.verify().unwrap().is_ok());
+            assert_eq!(db.bulk_load().unwrap().finish().unwrap(), 0);
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"existing").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"after").unwrap(), Some(b"load".to_vec()));
+
+        for i in 0..50u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 20]));
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_get_entry() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().max_log_file_size(120);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        db.put_at(b"hello".to_vec(), b"world".to_vec(), 42).unwrap();
+        db.put_at(b"fill".to_vec(), vec![0; 20], 43).unwrap();
+        db.put_at(b"next".to_vec(), b"file".to_vec(), 44).unwrap();
+
+        assert_eq!(
+            db.get_entry(b"hello").unwrap(),
+            Some(ValueEntry {
+                value: b"world".to_vec(),
+                timestamp: 42,
+                file_id: 0,
+                value_size: 5,
+            })
+        );
+        assert_eq!(db.get_entry(b"next").unwrap().unwrap().file_id, 1);
+        assert_eq!(db.get_entry(b"missing").unwrap(), None);
+
+        db.remove(b"hello").unwrap();
+        assert_eq!(db.get_entry(b"hello").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_write_compact_headers() {
+        let write = |path: &Path, opts: DbOptions| {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(path, opts).unwrap();
+
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+            db.put_expiring(b"ttl".to_vec(), b"value".to_vec(), 100, u64::MAX)
+                .unwrap();
+            db.put_at(b"old".to_vec(), vec![1; 300], 1).unwrap();
+            let mut batch = WriteBatch::default();
+            batch.put(b"a".to_vec(), b"1".to_vec());
+            batch.remove(b"hello");
+            db.write_batch(batch).unwrap();
+            db.put_from_reader(b"stream".to_vec(), &b"streamed"[..], 8)
+                .unwrap();
+            db.sync().unwrap();
+        };
+
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let compact_dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        write(dir.path(), DbOptions::default());
+        write(
+            compact_dir.path(),
+            DbOptions::default().compact_headers(true),
+        );
+
+        let log_size = |dir: &tempdir::TempDir| {
+            std::fs::metadata(dir.path().join("0.rumdb.log"))
+                .unwrap()
+                .len()
+        };
+        assert!(log_size(&compact_dir) < log_size(&dir));
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_default(compact_dir.path()).unwrap();
+        let verify = ReadOptions {
+            verify_checksum: true,
+            ..Default::default()
+        };
+
+        assert_eq!(db.get(b"hello").unwrap(), None);
+        assert_eq!(db.get_opt(b"ttl", verify).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get_opt(b"old", verify).unwrap(), Some(vec![1; 300]));
+        assert_eq!(db.get_opt(b"a", verify).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(
+            db.get_opt(b"stream", verify).unwrap(),
+            Some(b"streamed".to_vec())
+        );
+        assert!(db.verify().unwrap().is_ok());
+
+        // Without the option, writes go to a new log file in the current format.
+        db.put(b"new".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(db.get_entry(b"new").unwrap().unwrap().file_id, 1);
+        assert_eq!(db.get_entry(b"old").unwrap().unwrap().file_id, 0);
+    }
+
+    #[test]
+    fn disk_storage_should_iterate_modified_since() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        db.put_at(b"old".to_vec(), b"1".to_vec(), 100).unwrap();
+        db.put_at(b"new".to_vec(), b"2".to_vec(), 200).unwrap();
+        db.put_at(b"newer".to_vec(), b"3".to_vec(), 300).unwrap();
+        db.put_expiring(b"expired".to_vec(), b"4".to_vec(), 300, 1)
+            .unwrap();
+
+        let mut keys: Vec<_> = db.modified_since(200).collect();
+        keys.sort();
+        assert_eq!(keys, vec![b"new".to_vec(), b"newer".to_vec()]);
+
+        let pairs: Vec<_> = db.modified_pairs_since(300).map(Result::unwrap).collect();
+        assert_eq!(pairs, vec![(b"newer".to_vec(), b"3".to_vec())]);
+        assert_eq!(db.modified_since(301).count(), 0);
+    }
+
+    #[test]
+    fn disk_storage_should_scan_pages() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<RadixKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        for i in 0..5u8 {
+            db.put(vec![i], vec![i]).unwrap();
+        }
+
+        let (pairs, token) = db.scan(None, 2).unwrap();
+        assert_eq!(pairs, vec![(vec![0], vec![0]), (vec![1], vec![1])]);
+        assert_eq!(token, Some(vec![1]));
+
+        let (pairs, token) = db.scan(token.as_deref(), 2).unwrap();
+        assert_eq!(pairs, vec![(vec![2], vec![2]), (vec![3], vec![3])]);
+
+        let (pairs, token) = db.scan(token.as_deref(), 2).unwrap();
+        assert_eq!(pairs, vec![(vec![4], vec![4])]);
+        assert_eq!(token, None);
+
+        let (pairs, token) = db.scan(Some(&[1]), 3).unwrap();
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(token, None);
+        assert_eq!(db.scan(None, 0).unwrap(), (vec![], None));
+    }
+
+    #[test]
+    fn disk_storage_should_move_cursor() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<RadixKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        for k in [&b"b"[..], b"d", b"f"] {
+            db.put(k.to_vec(), k.to_vec()).unwrap();
+        }
+
+        let key = |res: Option<Result<KeyValue, StorageError>>| res.map(|res| res.unwrap().0);
+
+        let mut cursor = db.cursor();
+        assert_eq!(cursor.key(), None);
+        assert_eq!(key(cursor.seek(b"c")), Some(b"d".to_vec()));
+        assert_eq!(key(cursor.next()), Some(b"f".to_vec()));
+        assert_eq!(key(cursor.next()), None);
+        assert_eq!(cursor.key(), Some(&b"f"[..]));
+        assert_eq!(key(cursor.prev()), Some(b"d".to_vec()));
+
+        assert_eq!(key(cursor.seek_for_prev(b"c")), Some(b"b".to_vec()));
+        assert_eq!(key(cursor.prev()), None);
+        assert_eq!(key(cursor.seek(b"d")), Some(b"d".to_vec()));
+        assert_eq!(key(cursor.seek(b"g")), None);
+        assert_eq!(key(cursor.seek_for_prev(b"a")), None);
+
+        let keys: Vec<_> = db.cursor().map(|res| res.unwrap().0).collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"d".to_vec(), b"f".to_vec()]);
+        assert_eq!(key(db.cursor().prev()), Some(b"f".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_isolate_keyspaces() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let backup_dir = tempdir::TempDir::new("disk-storage-backup.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+            db.put(b"1".to_vec(), b"default".to_vec()).unwrap();
+            db.keyspace("users")
+                .unwrap()
+                .put(b"1".to_vec(), b"alice".to_vec())
+                .unwrap();
+            db.keyspace("groups")
+                .unwrap()
+                .put(b"1".to_vec(), b"admins".to_vec())
+                .unwrap();
+            db.keyspace("groups").unwrap().remove(b"1").unwrap();
+
+            assert!(matches!(
+                db.keyspace("../escape"),
+                Err(StorageError::InvalidKeyspaceName(_))
+            ));
+            assert!(db.get_keyspace("missing").is_none());
+
+            db.backup_to(backup_dir.path()).unwrap();
+        }
+
+        for path in [dir.path(), backup_dir.path()] {
+            let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(path).unwrap();
+
+            let mut names: Vec<_> = db.keyspace_names().collect();
+            names.sort();
+            assert_eq!(names, vec!["groups", "users"]);
+
+            assert_eq!(db.get(b"1").unwrap(), Some(b"default".to_vec()));
+
+            let users = db.get_keyspace("users").unwrap();
+            assert_eq!(users.get(b"1").unwrap(), Some(b"alice".to_vec()));
+
+            let groups = db.get_keyspace("groups").unwrap();
+            assert_eq!(groups.get(b"1").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_scan_range_in_order() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<RadixKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+
+        for k in [&b"b"[..], b"ab", b"a", b"c", b"abc"] {
+            db.put(k.to_vec(), k.to_vec()).unwrap();
+        }
+        db.remove(b"c").unwrap();
+
+        let keys: Vec<_> = db
+            .range(Bound::Included(b"ab"), Bound::Unbounded)
+            .map(|res| res.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"ab".to_vec(), b"abc".to_vec(), b"b".to_vec()]);
+
+        let keys: Vec<_> = db.scan_prefix(b"a").map(|res| res.unwrap().0).collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"ab".to_vec(), b"abc".to_vec()]);
+    }
+
+    #[test]
+    fn disk_storage_should_truncate_torn_entry() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+        }
+
+        let log_size = fs::metadata(&log_path).unwrap().len();
+
+        {
+            let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+            let header = Header::new(0, 4, 100).encode(FormatVersion::CURRENT.into());
+            log.write_all(&header[..FormatVersion::CURRENT.header_size()])
+                .unwrap();
+            log.write_all(b"torn").unwrap();
+            log.write_all(b"incomplete value").unwrap();
+        }
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            assert_eq!(fs::metadata(&log_path).unwrap().len(), log_size);
+            assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+            assert_eq!(db.get(b"torn").unwrap(), None);
+            assert_eq!(db.recovery_report().lost_bytes(), 53);
+
+            db.put(b"after".to_vec(), b"crash".to_vec()).unwrap();
+        }
+
+        {
+            let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+            assert_eq!(db.get(b"after").unwrap(), Some(b"crash".to_vec()));
+            assert!(db.recovery_report().is_clean());
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_fail_to_open_bad_directory() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        let log_dir = dir.path().join("log");
+        fs::create_dir_all(log_dir.join("0.rumdb.log")).unwrap();
+        assert!(DiskStorage::<HashmapKeydir>::open_default(&log_dir).is_err());
+
+        let lock_dir = dir.path().join("lock");
+        fs::create_dir_all(lock_dir.join("LOCK")).unwrap();
+        assert!(DiskStorage::<HashmapKeydir>::open_default(&lock_dir).is_err());
+
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(DiskStorage::<HashmapKeydir>::open_default(&file).is_err());
+    }
+
+    #[test]
+    fn disk_storage_should_report_corruption_context() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+        let opts = DbOptions::default()
+            .max_log_file_size(110)
+            .keydir_snapshot(false);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"hello".to_vec(), vec![0; 50]).unwrap();
+            db.put(b"next".to_vec(), vec![0; 50]).unwrap();
+        }
+
+        // Entries of the sealed log file end where its footer starts.
+        let log_size = LogReader::open(&log_path).unwrap().size();
+        let log = OpenOptions::new().write(true).open(&log_path).unwrap();
+
+        log.set_len(log_size - 1).unwrap();
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()),
+            Err(StorageError::Corrupted {
+                file_id: 0,
+                offset: 14
+            })
+        ));
+
+        log.write_at(&[42], MAGIC.len() as u64).unwrap();
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open(dir.path(), opts),
+            Err(StorageError::UnsupportedVersion {
+                file_id: 0,
+                version: 42
+            })
+        ));
+    }
+
+    #[test]
+    fn disk_storage_should_reject_entries_beyond_log_file() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+        let opts = DbOptions::default()
+            .max_log_file_size(110)
+            .keydir_snapshot(false);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"hello".to_vec(), vec![0; 50]).unwrap();
+            db.put(b"next".to_vec(), vec![0; 50]).unwrap();
+        }
+
+        let reader = LogReader::open(&log_path).unwrap();
+        let footer_pos = reader.size();
+        let version = reader.version();
+        let index = reader.read_index().unwrap().unwrap();
+        let log = OpenOptions::new().write(true).open(&log_path).unwrap();
+
+        // Rewrites the footer with an intact checksum, the value of "hello" resized.
+        let rewrite_footer = |value_size: u64| {
+            let mut index = index.clone();
+            index[0].value_size = value_size;
+
+            log.set_len(footer_pos).unwrap();
+            log.write_at(&footer::encode(&index, 1, footer_pos, version), footer_pos)
+                .unwrap();
+        };
+
+        for value_size in [51, u64::MAX] {
+            rewrite_footer(value_size);
+            assert!(matches!(
+                DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()),
+                Err(StorageError::Corrupted { file_id: 0, offset })
+                    if offset == index[0].value_pos
+            ));
+        }
+
+        rewrite_footer(50);
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"hello").unwrap(), Some(vec![0; 50]));
+    }
+
+    #[test]
+    fn disk_storage_should_merge_most_fragmented_log_files_within_budget() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let clock = Arc::new(FixedClock::new(1000));
+        let opts = DbOptions::default()
+            .clock(clock.clone())
+            .merge_fragmentation(0.4)
+            .merge_budget(150)
+            .merge_interval(Duration::from_secs(60));
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        for k in [&b"a"[..], b"b"] {
+            db.put(k.to_vec(), vec![0; 100]).unwrap();
+        }
+        db.seal_active_log(db.active.file_id + 1).unwrap();
+
+        for k in [&b"c"[..], b"d", b"e", b"f"] {
+            db.put(k.to_vec(), vec![1; 100]).unwrap();
+        }
+        db.seal_active_log(db.active.file_id + 1).unwrap();
+
+        for k in [&b"a"[..], b"c", b"d", b"e"] {
+            db.put(k.to_vec(), vec![2; 100]).unwrap();
+        }
+
+        // The budget fits the live entries of a single log file, the most fragmented one.
+        assert_eq!(db.merge_fragmented().unwrap(), vec![1]);
+        assert!(db.log_files.contains_key(&0));
+        assert!(!db.log_files.contains_key(&1));
+        assert!(db.merge_fragmented().unwrap().is_empty());
+
+        clock.advance(60);
+        assert_eq!(db.merge_fragmented().unwrap(), vec![0]);
+        assert!(!db.log_files.contains_key(&0));
+
+        assert_eq!(db.get(b"b").unwrap(), Some(vec![0; 100]));
+        assert_eq!(db.get(b"f").unwrap(), Some(vec![1; 100]));
+        assert_eq!(db.get(b"a").unwrap(), Some(vec![2; 100]));
+
+        // Merging is disabled by default.
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.put(b"b".to_vec(), b"1".to_vec()).unwrap();
+        db.seal_active_log(db.active.file_id + 1).unwrap();
+        db.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        assert!(db.merge_fragmented().unwrap().is_empty());
+        assert!(db.log_files.contains_key(&0));
+    }
+
+    #[test]
+    fn disk_storage_should_sweep_expired_keys() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let clock = Arc::new(FixedClock::new(1000));
+        let opts = DbOptions::default()
+            .clock(clock.clone())
+            .keydir_snapshot(false);
+        let ttl = |secs| PutOptions {
+            ttl: Some(Duration::from_secs(secs)),
+            ..Default::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..3u8 {
+                db.put_opt(vec![i], vec![i; 10], ttl(10)).unwrap();
+            }
+            db.put_opt(b"later".to_vec(), b"value".to_vec(), ttl(100))
+                .unwrap();
+            db.put(b"forever".to_vec(), b"value".to_vec()).unwrap();
+            db.keyspace("users")
+                .unwrap()
+                .put_opt(b"alice".to_vec(), b"value".to_vec(), ttl(10))
+                .unwrap();
+
+            assert_eq!(db.sweep_expired(10).unwrap(), 0);
+
+            clock.advance(10);
+            assert_eq!(db.storage_stats().keys, 5);
+            assert_eq!(db.sweep_expired(2).unwrap(), 2);
+            assert_eq!(db.sweep_expired(10).unwrap(), 2);
+            assert_eq!(db.sweep_expired(10).unwrap(), 0);
+
+            let stats = db.storage_stats();
+            assert_eq!(stats.keys, 2);
+            assert_eq!(stats.expired_swept, 3);
+            assert!(stats.to_string().ends_with(", expired swept: 3"));
+            assert_eq!(db.keyspace("users").unwrap().storage_stats().keys, 0);
+
+            // Keys written again since they were found expired are kept.
+            db.put_opt(b"renewed".to_vec(), b"old".to_vec(), ttl(10))
+                .unwrap();
+            clock.advance(10);
+            let expired = db.expired_keys(10);
+            assert_eq!(expired.len(), 1);
+            db.put(b"renewed".to_vec(), b"new".to_vec()).unwrap();
+            assert_eq!(db.remove_expired(expired).unwrap(), 0);
+            assert_eq!(db.get(b"renewed").unwrap(), Some(b"new".to_vec()));
+        }
+
+        // Swept keys stay removed, whatever the clock says.
+        clock.set(1000);
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(&[0]).unwrap(), None);
+        assert_eq!(db.get(b"later").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"forever").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_repair_missing_log_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+
+            db.put(b"x".to_vec(), b"old".to_vec()).unwrap();
+            db.put(b"k".to_vec(), b"1".to_vec()).unwrap();
+            db.seal_active_log(db.active.file_id + 1).unwrap();
+            db.remove(b"x").unwrap();
+            db.put(b"y".to_vec(), b"2".to_vec()).unwrap();
+            db.seal_active_log(db.active.file_id + 1).unwrap();
+            db.put(b"z".to_vec(), b"3".to_vec()).unwrap();
+
+            let path = dir.path().join("1.rumdb.log");
+            fs::remove_file(&path).unwrap();
+            db.file_cache.evict(&path);
+
+            assert!(matches!(db.get(b"y"), Err(StorageError::MissingLogFile(1))));
+            assert_eq!(db.missing_log_files(), vec![1]);
+            assert_eq!(db.get(b"k").unwrap(), Some(b"1".to_vec()));
+
+            assert_eq!(db.repair_missing_segments().unwrap(), vec![b"y".to_vec()]);
+            assert!(db.missing_log_files().is_empty());
+            assert!(!db.log_files.contains_key(&1));
+            assert_eq!(db.get(b"y").unwrap(), None);
+            assert!(db.repair_missing_segments().unwrap().is_empty());
+        }
+
+        // Neither the lost key nor the key removed in the missing log file come back.
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+        assert_eq!(db.get(b"x").unwrap(), None);
+        assert_eq!(db.get(b"y").unwrap(), None);
+        assert_eq!(db.get(b"k").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"z").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_never_remove_active_log_file() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().merge_fragmentation(0.0);
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.remove(b"a").unwrap();
+
+        // A freshly rotated active log file holds no live entries, only a segment header.
+        db.seal_active_log(db.active.file_id + 1).unwrap();
+        let active_file_id = db.active.file_id;
+
+        db.gc().unwrap();
+        db.gc_tombstones().unwrap();
+        assert!(db.merge_fragmented().unwrap().is_empty());
+        db.compact().unwrap();
+        assert_eq!(db.log_files.keys().copied().collect::<Vec<_>>(), vec![1]);
+
+        // A dead log file newer than the active one, as bulk loaded log files are while
+        // moved into place.
+        let newer = DiskStorage::<HashmapKeydir>::create_log_file(&opts, dir.path(), 5).unwrap();
+        db.log_files.insert(5, newer);
+        db.gc_tombstones().unwrap();
+
+        assert_eq!(db.active.file_id, active_file_id);
+        assert_eq!(db.log_files.keys().copied().collect::<Vec<_>>(), vec![1]);
+
+        db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_compact_segments() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+
+            db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+            db.seal_active_log(db.active.file_id + 1).unwrap();
+            db.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+            db.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+            db.seal_active_log(db.active.file_id + 1).unwrap();
+            db.remove(b"c").unwrap();
+
+            db.compact_segments(&[1]).unwrap();
+            assert!(db.log_files.contains_key(&0));
+            assert!(!db.log_files.contains_key(&1));
+            assert_eq!(db.get(b"d").unwrap(), Some(b"4".to_vec()));
+
+            assert!(matches!(
+                db.compact_segments(&[7]),
+                Err(StorageError::UnknownLogFile(7))
+            ));
+
+            // Dead entries of the active log file go too.
+            db.remove(b"b").unwrap();
+            db.remove(b"d").unwrap();
+            db.compact_all().unwrap();
+
+            assert_eq!(db.log_files.len(), 1);
+            assert_eq!(db.storage_stats().segments[0].live_entries, 1);
+        }
+
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.scan_prefix(b"").count(), 1);
+    }
+
+    #[test]
+    fn disk_storage_should_record_compaction_history() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default()).unwrap();
+
+        for k in [&b"a"[..], b"b", b"c", b"a"] {
+            db.put(k.to_vec(), b"value".to_vec()).unwrap();
+        }
+        db.remove(b"b").unwrap();
+        db.seal_active_log(db.active.file_id + 1).unwrap();
+
+        let size = db.log_files[&0].len().unwrap();
+        db.compact().unwrap();
+
+        let record = db.compaction_history().next().unwrap().clone();
+        assert_eq!(record.inputs, vec![0]);
+        assert_eq!(record.outputs, vec![1]);
+        assert_eq!(record.entries_rewritten, 2);
+        assert_eq!(record.entries_dropped, 3);
+        assert_eq!(
+            record.bytes_reclaimed,
+            size - 2 * entry_size_of(1, b"value".len() as u64)
+        );
+        assert!(db
+            .storage_stats()
+            .to_string()
+            .contains(", last compaction: [0] into [1], 2 entries rewritten, 3 dropped, "));
+
+        // Only the latest compactions are kept.
+        for _ in 0..COMPACTION_HISTORY_SIZE {
+            db.compact().unwrap();
+        }
+
+        let history: Vec<_> = db.compaction_history().collect();
+        assert_eq!(history.len(), COMPACTION_HISTORY_SIZE);
+        assert!(history.iter().all(|record| record.entries_rewritten == 0));
+    }
+
+    #[test]
+    fn disk_storage_should_throttle_compaction() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().compaction_rate_limit(100_000);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        for i in 0..20u8 {
+            db.put(vec![i], vec![i; 1000]).unwrap();
+        }
+        db.seal_active_log(db.active.file_id + 1).unwrap();
+
+        let start = std::time::Instant::now();
+        db.compact().unwrap();
+
+        // About 20 KB are rewritten at 100 KB/s.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let stats = db.storage_stats();
+        assert!(stats.compaction_throughput > 0 && stats.compaction_throughput <= 100_000);
+        assert_eq!(db.get(&[3]).unwrap(), Some(vec![3; 1000]));
+    }
+
+    #[test]
+    fn disk_storage_should_use_clock() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let clock = Arc::new(FixedClock::new(1000));
+        let opts = DbOptions::default().clock(clock.clone());
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+        db.put_opt(
+            b"ttl".to_vec(),
+            b"value".to_vec(),
+            PutOptions {
+                ttl: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(db.get_entry(b"hello").unwrap().unwrap().timestamp, 1000);
+        assert_eq!(db.get(b"ttl").unwrap(), Some(b"value".to_vec()));
+
+        clock.advance(10);
+        db.remove(b"hello").unwrap();
+        assert_eq!(db.get(b"ttl").unwrap(), None);
+        assert_eq!(db.modified_since(1010).count(), 0);
+
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default().clock(Arc::new(LogicalClock::starting_at(1)));
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+
+        // Creating the active log file takes the first timestamp.
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(db.get_entry(b"a").unwrap().unwrap().timestamp, 2);
+        assert_eq!(db.get_entry(b"b").unwrap().unwrap().timestamp, 3);
+    }
+
+    #[test]
+    fn disk_storage_should_seal_log_files_with_footer() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = |file_id: u32| dir.path().join(format!("{file_id}.rumdb.log"));
+        let opts = DbOptions::default()
+            .max_log_file_size(110)
+            .keydir_snapshot(false);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put_at(b"hello".to_vec(), vec![1; 50], 42).unwrap();
+            db.put_at(b"next".to_vec(), vec![2; 50], 43).unwrap();
+        }
+
+        assert_eq!(
+            LogReader::open(log_path(0)).unwrap().stats(),
+            Some(SegmentStats {
+                entries: 1,
+                live_entries: 1,
+                min_timestamp: 42,
+                max_timestamp: 42,
+            })
+        );
+        assert_eq!(LogReader::open(log_path(1)).unwrap().stats(), None);
+
+        // A crash right after sealing the active log file.
+        let reader = LogReader::open(log_path(1)).unwrap();
+        let size = reader.size();
+        let reader_version = reader.version();
+        let index: Vec<_> = reader.map(|entry| entry.unwrap().into()).collect();
+        let log = OpenOptions::new().append(true).open(log_path(1)).unwrap();
+        let footer = footer::encode(&index, 1, size, reader_version);
+        std::io::Write::write_all(&mut &log, &footer).unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            assert_eq!(db.get(b"hello").unwrap(), Some(vec![1; 50]));
+            assert_eq!(db.get(b"next").unwrap(), Some(vec![2; 50]));
+            assert!(db.verify().unwrap().is_ok());
+
+            db.put(b"last".to_vec(), b"value".to_vec()).unwrap();
+            assert_eq!(db.get_entry(b"last").unwrap().unwrap().file_id, 2);
+        }
+
+        // A crash in the middle of sealing the active log file.
+        let log = OpenOptions::new().append(true).open(log_path(2)).unwrap();
+        std::io::Write::write_all(&mut &log, FOOTER_MAGIC).unwrap();
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.get(b"last").unwrap(), Some(b"value".to_vec()));
+        assert!(db.verify().unwrap().is_ok());
+        drop(db);
+
+        // Log files with a corrupt footer are scanned.
+        let footer_pos = LogReader::open(log_path(0)).unwrap().size();
+        let log = OpenOptions::new().write(true).open(log_path(0)).unwrap();
+        log.write_at(b"J", footer_pos + FOOTER_MAGIC.len() as u64 + 29)
+            .unwrap();
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"hello").unwrap(), Some(vec![1; 50]));
+        assert_eq!(
+            db.verify().unwrap().inconsistencies,
+            vec![Inconsistency::FooterMismatch { file_id: 0 }]
+        );
+    }
+
+    #[test]
+    fn disk_storage_should_write_batch_atomically() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+        let opts = DbOptions::default().keydir_snapshot(false);
+
+        let mut batch = WriteBatch::default();
+        batch.put(b"a".to_vec(), b"1".to_vec());
+        batch.put(b"b".to_vec(), b"2".to_vec());
+        batch.remove(b"hello");
+        assert_eq!(batch.len(), 3);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+            db.write_batch(batch.clone()).unwrap();
+
+            assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(db.get(b"hello").unwrap(), None);
+        }
+
+        {
+            let db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get(b"hello").unwrap(), None);
+        }
+
+        // Cut off the tombstone ending the batch.
+        let log_size = fs::metadata(&log_path).unwrap().len();
+        let log = OpenOptions::new().write(true).open(&log_path).unwrap();
+        log.set_len(log_size - 38).unwrap();
+
+        {
+            let db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            assert_eq!(db.get(b"a").unwrap(), None);
+            assert_eq!(db.get(b"b").unwrap(), None);
+            assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+            assert_eq!(db.recovery_report().lost_bytes(), 35 + 35);
+        }
+
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), log_size - 38 - 70);
+    }
+
+    #[test]
+    fn disk_storage_should_write_pipelined_entries_independently() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+        let opts = DbOptions::default().keydir_snapshot(false);
+
+        let mut batch = WriteBatch::default();
+        batch.put(b"a".to_vec(), b"1".to_vec());
+        batch.put(b"b".to_vec(), b"2".to_vec());
+        batch.remove(b"hello");
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+            db.write_pipelined(batch).unwrap();
+            db.write_pipelined(WriteBatch::default()).unwrap();
+
+            assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get(b"hello").unwrap(), None);
+        }
+
+        // Tear the tombstone, the entries written before it survive.
+        let log_size = fs::metadata(&log_path).unwrap().len();
+        let log = OpenOptions::new().write(true).open(&log_path).unwrap();
+        log.set_len(log_size - 10).unwrap();
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert_eq!(db.recovery_report().lost_bytes(), 28);
+    }
+
+    #[test]
+    fn disk_storage_should_truncate_tail_entry_with_bad_checksum() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+            db.put(b"torn".to_vec(), b"value".to_vec()).unwrap();
+        }
+
+        {
+            let log = OpenOptions::new().write(true).open(&log_path).unwrap();
+            let log_size = log.metadata().unwrap().len();
+            log.write_all_at(b"\0\0\0", log_size - 3).unwrap();
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert_eq!(db.get(b"torn").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_load_keydir_snapshot() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let log_path = dir.path().join("0.rumdb.log");
+        let snapshot_path = dir.path().join(SNAPSHOT_FILE);
+        let opts = DbOptions::default().merge_operator(add_u64);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"hello".to_vec(), b"world".to_vec()).unwrap();
+            db.merge(b"counter".to_vec(), 2u64.to_le_bytes().to_vec())
+                .unwrap();
+            db.put(b"padding".to_vec(), vec![0; SNAPSHOT_TAIL_SIZE as usize])
+                .unwrap();
+        }
+
+        // Dropping the storage doesn't snapshot the keydir, closing it does.
+        assert!(!snapshot_path.exists());
+        DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone())
+            .unwrap()
+            .close()
+            .unwrap();
+        assert!(snapshot_path.exists());
+
+        // The keydir is not rebuilt from the logs, so a corrupted key before the tail of the
+        // active log file goes unnoticed.
+        let log = fs::read(&log_path).unwrap();
+        let key_pos = log.windows(5).position(|w| w == b"hello").unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .write_all_at(b"j", key_pos as u64)
+            .unwrap();
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert!(!snapshot_path.exists());
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(2u64.to_le_bytes().to_vec())
+        );
+
+        db.put(b"after".to_vec(), b"snapshot".to_vec()).unwrap();
+        assert_eq!(db.get(b"after").unwrap(), Some(b"snapshot".to_vec()));
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn disk_storage_should_ignore_stale_or_corrupt_snapshot() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let snapshot_path = dir.path().join(SNAPSHOT_FILE);
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            db.put(b"version".to_vec(), vec![1]).unwrap();
+            db.close().unwrap();
+        }
+
+        let stale_snapshot = fs::read(&snapshot_path).unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            db.put(b"version".to_vec(), vec![2]).unwrap();
+            db.close().unwrap();
+        }
+
+        fs::write(&snapshot_path, stale_snapshot).unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            assert_eq!(db.get(b"version").unwrap(), Some(vec![2]));
+
+            db.put(b"version".to_vec(), vec![3]).unwrap();
+            db.close().unwrap();
+        }
+
+        // The active log file modified in place keeps its size, but not its tail checksum.
+        let log_path = dir.path().join("0.rumdb.log");
+        let log = OpenOptions::new().write(true).open(&log_path).unwrap();
+        log.write_all_at(&[0xff], log.metadata().unwrap().len() - 1)
+            .unwrap();
+
+        {
+            let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            assert_eq!(db.get(b"version").unwrap(), Some(vec![2]));
+            db.close().unwrap();
+        }
+
+        let mut snapshot = fs::read(&snapshot_path).unwrap();
+        let len = snapshot.len();
+        snapshot[len - 6] ^= 0xff;
+        fs::write(&snapshot_path, snapshot).unwrap();
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+        assert_eq!(db.get(b"version").unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn disk_storage_should_read_legacy_log_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut log = File::create(dir.path().join("0.rumdb.log")).unwrap();
+
+            for (k, v) in [
+                (&b"hello"[..], &b"world"[..]),
+                (b"removed", b"entry"),
+                (b"removed", b""),
+            ] {
+                let header = Header::new(0, k.len() as u32, v.len() as u64);
+                log.write_all(
+                    &header.encode(FormatVersion::V1.into())[..FormatVersion::V1.header_size()],
+                )
+                .unwrap();
+                log.write_all(k).unwrap();
+                log.write_all(v).unwrap();
+            }
+        }
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+            assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+            assert_eq!(db.get(b"removed").unwrap(), None);
+
+            db.put(b"empty".to_vec(), Vec::new()).unwrap();
+        }
+
+        assert!(
+            dir.path().join("1.rumdb.log").exists(),
+            "legacy log file must not be appended to"
+        );
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open_default(dir.path()).unwrap();
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert_eq!(db.get(b"empty").unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn disk_storage_should_roundtrip_u64_timestamps() {
+        for compact_headers in [false, true] {
+            let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+            let now = u64::from(u32::MAX) + 100;
+            let clock = Arc::new(FixedClock::new(now));
+            // Each new log file holds one entry, so footer indexes are read too.
+            let opts = DbOptions::default()
+                .clock(clock.clone())
+                .compact_headers(compact_headers)
+                .max_log_file_size(if compact_headers { 40 } else { 80 });
+            let ttl = PutOptions {
+                ttl: Some(Duration::from_secs(10)),
+                ..Default::default()
+            };
+
+            // A log file of format version 4, storing timestamps as `u32`.
+            {
+                let mut log = File::create(dir.path().join("0.rumdb.log")).unwrap();
+                let segment_header = FormatVersion::V4.segment_header(42);
+                log.write_all(&segment_header[..FormatVersion::V4.segment_header_size()])
+                    .unwrap();
+
+                let entry = DiskEntry::new(b"old", b"value").at(42);
+                let header = entry.header.encode(FormatVersion::V4.into());
+                log.write_all(&header[..FormatVersion::V4.header_size()])
+                    .unwrap();
+                log.write_all(b"old").unwrap();
+                log.write_all(b"value").unwrap();
+            }
+
+            {
+                let mut db: DiskStorage<HashmapKeydir> =
+                    DiskStorage::open(dir.path(), opts.clone()).unwrap();
+                db.put(b"new".to_vec(), b"value".to_vec()).unwrap();
+                db.put_opt(b"expiring".to_vec(), b"value".to_vec(), ttl)
+                    .unwrap();
+                db.put(b"last".to_vec(), b"value".to_vec()).unwrap();
+            }
+
+            for opts in [opts.clone(), opts.clone().keydir_snapshot(false)] {
+                let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+                assert_eq!(db.get_entry(b"old").unwrap().unwrap().timestamp, 42);
+                assert_eq!(db.get_entry(b"new").unwrap().unwrap().timestamp, now);
+                assert_eq!(db.get(b"expiring").unwrap(), Some(b"value".to_vec()));
+                assert_eq!(db.modified_since(now).count(), 3);
+            }
+
+            for file_id in [1, 2] {
+                let reader =
+                    LogReader::open(dir.path().join(format!("{file_id}.rumdb.log"))).unwrap();
+                assert_eq!(reader.created_at(), Some(now));
+
+                let stats = reader.stats().unwrap();
+                assert_eq!((stats.min_timestamp, stats.max_timestamp), (now, now));
+            }
+
+            clock.set(now + 10);
+            let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+            assert_eq!(db.get(b"expiring").unwrap(), None);
+            assert_eq!(db.get(b"old").unwrap(), Some(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_backup() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let backup_dir = tempdir::TempDir::new("disk-storage-backup.db").unwrap();
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path(), DbOptions::default().max_log_file_size(50)).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 10]).unwrap();
+        }
+        db.remove(&[0]).unwrap();
+
+        db.backup_to(backup_dir.path()).unwrap();
+
+        db.put(vec![1], b"after backup".to_vec()).unwrap();
+
+        assert!(matches!(
+            db.backup_to(backup_dir.path()),
+            Err(StorageError::DirectoryNotEmpty(_))
+        ));
+
+        let backup: DiskStorage<HashmapKeydir> = DiskStorage::open(
+            backup_dir.path(),
+            DbOptions::default().max_log_file_size(50),
+        )
+        .unwrap();
+
+        assert_eq!(backup.get(&[0]).unwrap(), None);
+        for i in 1..10u8 {
+            assert_eq!(backup.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_checkpoint() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let checkpoint_dir = dir.path().join("checkpoint");
+        let opts = DbOptions::default()
+            .max_log_file_size(50)
+            .write_buffer_size(1024);
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path().join("db"), opts.clone()).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 10]).unwrap();
+        }
+        db.remove(&[0]).unwrap();
+        db.keyspace("users")
+            .unwrap()
+            .put(b"alice".to_vec(), b"admin".to_vec())
+            .unwrap();
+
+        db.checkpoint(&checkpoint_dir).unwrap();
+
+        // Sealed log files are shared, the active one is copied.
+        let nlink = |file_id: u32| {
+            fs::metadata(checkpoint_dir.join(format!("{file_id}.rumdb.log")))
+                .unwrap()
+                .nlink()
+        };
+        assert_eq!(nlink(db.keydir.get(&[5]).unwrap().file_id), 2);
+        assert_eq!(nlink(db.active.file_id), 1);
+
+        db.put(vec![1], b"after checkpoint".to_vec()).unwrap();
+
+        assert!(matches!(
+            db.checkpoint(&checkpoint_dir),
+            Err(StorageError::DirectoryNotEmpty(_))
+        ));
+
+        let checkpoint: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(&checkpoint_dir, opts).unwrap();
+
+        assert_eq!(checkpoint.get(&[0]).unwrap(), None);
+        for i in 1..10u8 {
+            assert_eq!(checkpoint.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+        assert_eq!(
+            checkpoint
+                .get_keyspace("users")
+                .unwrap()
+                .get(b"alice")
+                .unwrap(),
+            Some(b"admin".to_vec())
+        );
+    }
+
+    #[test]
+    fn disk_storage_should_keep_checkpoint_intact_on_rebuild_index() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let checkpoint_dir = dir.path().join("checkpoint");
+        let opts = DbOptions::default().max_log_file_size(50);
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(dir.path().join("db"), opts.clone()).unwrap();
+
+        for i in 0..5u8 {
+            db.put(vec![i], vec![i; 10]).unwrap();
+        }
+
+        db.checkpoint(&checkpoint_dir).unwrap();
+
+        let read_checkpoint = || -> BTreeMap<_, _> {
+            fs::read_dir(&checkpoint_dir)
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    (
+                        path.file_name().unwrap().to_owned(),
+                        fs::read(&path).unwrap(),
+                    )
+                })
+                .collect()
+        };
+        let files = read_checkpoint();
+
+        // Footers rewritten with the new live entry counts replace the linked log files.
+        for i in 0..5u8 {
+            db.remove(&[i]).unwrap();
+        }
+        assert!(db.rebuild_index().unwrap().is_ok());
+
+        assert_eq!(read_checkpoint(), files);
+
+        let checkpoint: DiskStorage<HashmapKeydir> =
+            DiskStorage::open(&checkpoint_dir, opts).unwrap();
+        for i in 0..5u8 {
+            assert_eq!(checkpoint.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+    }
+
+    #[test]
+    fn disk_storage_should_open_at_timestamp() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .history_retention(Duration::from_secs(3600));
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            db.put_at(b"a".to_vec(), b"v1".to_vec(), 100).unwrap();
+            db.put_at(b"b".to_vec(), b"v1".to_vec(), 100).unwrap();
+            db.put_at(b"a".to_vec(), b"v2".to_vec(), 200).unwrap();
+            db.remove_at(b"b", 200).unwrap();
+            db.put_at(b"a".to_vec(), b"v3".to_vec(), 300).unwrap();
+
+            for i in 0..20 {
+                db.put_at(b"hot".to_vec(), vec![i], 300).unwrap();
+            }
+
+            db.compact().unwrap();
+
+            // Dead log files are retained.
+            assert!(dir.path().join("0.rumdb.log").exists());
+        }
+
+        let at = |timestamp| -> DiskStorage<HashmapKeydir> {
+            DiskStorage::open_at(dir.path(), opts.clone(), timestamp).unwrap()
+        };
+
+        let db = at(50);
+        assert_eq!(db.get(b"a").unwrap(), None);
+        drop(db);
+
+        let db = at(150);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"v1".to_vec()));
+        drop(db);
+
+        let mut db = at(250);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert!(matches!(
+            db.put(b"a".to_vec(), b"v4".to_vec()),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(db.compact(), Err(StorageError::ReadOnly)));
+        drop(db);
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"v3".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn disk_storage_should_leave_directory_unchanged_when_opened_at_timestamp() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let opts = DbOptions::default().compact_headers(true);
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+            db.put(b"a".to_vec(), b"value".to_vec()).unwrap();
+        }
+
+        // A torn entry and a temp file left by a crash.
+        OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("0.rumdb.log"))
+            .unwrap()
+            .write_all(&[1, 2, 3])
+            .unwrap();
+        fs::write(
+            dir.path()
+                .join(format!("1.rumdb.log.{}", TempFile::EXTENSION)),
+            b"partial",
+        )
+        .unwrap();
+
+        let read_dir = || -> BTreeMap<_, _> {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    (
+                        path.file_name().unwrap().to_owned(),
+                        fs::read(&path).unwrap(),
+                    )
+                })
+                .collect()
+        };
+        let files = read_dir();
+
+        // The log file is of an older format version than the default one.
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_at(dir.path(), DbOptions::default(), u64::MAX).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.recovery_report().lost_bytes(), 3);
+        drop(db);
+
+        assert_eq!(read_dir(), files);
+
+        let missing = dir.path().join("missing");
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open_at(&missing, DbOptions::default(), u64::MAX),
+            Err(StorageError::NotADatabase(_))
+        ));
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn disk_storage_should_retain_versions() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .history_versions(2);
+
+        let version = |timestamp, value: Option<&[u8]>| Version {
+            timestamp,
+            value: value.map(<[u8]>::to_vec),
+        };
+
+        let expected = [version(500, None), version(400, Some(b"v4"))];
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open(dir.path(), opts.clone()).unwrap();
+
+            for (i, timestamp) in [100, 200, 300, 400].into_iter().enumerate() {
+                db.put_at(b"a".to_vec(), format!("v{}", i + 1).into_bytes(), timestamp)
+                    .unwrap();
+            }
+
+            assert_eq!(db.get_at(b"a", 250).unwrap(), Some(b"v2".to_vec()));
+            assert_eq!(db.get_at(b"a", 150).unwrap(), None);
+            assert_eq!(db.get_versions(b"a").unwrap().len(), 3);
+
+            db.remove_at(b"a", 500).unwrap();
+            assert_eq!(db.get_versions(b"a").unwrap(), expected);
+
+            // Versions written within the same second replace each other.
+            db.put_at(b"b".to_vec(), b"x".to_vec(), 100).unwrap();
+            db.put_at(b"b".to_vec(), b"y".to_vec(), 100).unwrap();
+            assert_eq!(db.get_versions(b"b").unwrap(), [version(100, Some(b"y"))]);
+
+            db.compact().unwrap();
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get_versions(b"a").unwrap(), expected);
+        assert_eq!(db.get_at(b"a", 450).unwrap(), Some(b"v4".to_vec()));
+        assert_eq!(db.get_at(b"a", 600).unwrap(), None);
+        assert_eq!(db.get_versions(b"b").unwrap(), [version(100, Some(b"y"))]);
     }
 }
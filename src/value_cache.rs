@@ -0,0 +1,198 @@
+//! Cache of recently read values.
+//!
+//! Values are keyed by the log file and the position they are stored at. Entries of log files
+//! never change once written, so cached values only go away when evicted or when their log
+//! file is removed.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+/// Position of a value: log file id and offset.
+type ValuePos = (u32, u64);
+
+/// Least recently used cache of values, bounded by their total size in bytes.
+#[derive(Debug, Default)]
+pub(crate) struct ValueCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Cached values along with the tick they were last used at.
+    values: HashMap<ValuePos, (Arc<[u8]>, u64)>,
+    /// Positions of cached values by the tick they were last used at.
+    lru: BTreeMap<u64, ValuePos>,
+    size: usize,
+    tick: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, pos: ValuePos, used: u64) -> u64 {
+        self.tick += 1;
+        self.lru.remove(&used);
+        self.lru.insert(self.tick, pos);
+        self.tick
+    }
+}
+
+impl ValueCache {
+    /// Creates a cache holding at most `capacity` bytes of values. A zero capacity
+    /// disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Total size of cached values in bytes.
+    #[cfg(test)]
+    pub fn size(&self) -> usize {
+        self.state().size
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns a copy of the value stored in the log file at `pos`, if cached.
+    pub fn get(&self, file_id: u32, pos: u64) -> Option<Vec<u8>> {
+        self.get_shared(file_id, pos).map(|value| value.to_vec())
+    }
+
+    /// Returns the value stored in the log file at `pos`, if cached, without copying it.
+    pub fn get_shared(&self, file_id: u32, pos: u64) -> Option<Arc<[u8]>> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let mut state = self.state();
+
+        let Some(&(_, used)) = state.values.get(&(file_id, pos)) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        let tick = state.touch((file_id, pos), used);
+        let (value, used) = state.values.get_mut(&(file_id, pos)).unwrap();
+        *used = tick;
+
+        Some(value.clone())
+    }
+
+    /// Caches the value stored in the log file at `pos`, evicting the least recently used
+    /// values to make room. Values larger than the capacity are not cached.
+    pub fn insert(&self, file_id: u32, pos: u64, value: &[u8]) {
+        if self.capacity == 0 || value.len() > self.capacity {
+            return;
+        }
+
+        self.insert_shared(file_id, pos, value.into());
+    }
+
+    /// Like `insert`, sharing the `value` rather than copying it.
+    pub fn insert_shared(&self, file_id: u32, pos: u64, value: Arc<[u8]>) {
+        if self.capacity == 0 || value.len() > self.capacity {
+            return;
+        }
+
+        let mut state = self.state();
+
+        if state.values.contains_key(&(file_id, pos)) {
+            return;
+        }
+
+        while state.size + value.len() > self.capacity {
+            let Some((_, lru)) = state.lru.pop_first() else {
+                break;
+            };
+
+            if let Some((evicted, _)) = state.values.remove(&lru) {
+                state.size -= evicted.len();
+            }
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+
+        state.lru.insert(tick, (file_id, pos));
+        state.size += value.len();
+        state.values.insert((file_id, pos), (value, tick));
+    }
+
+    /// Drops cached values of the log file, e.g. before removing the file.
+    pub fn evict_file(&self, file_id: u32) {
+        let mut state = self.state();
+        let CacheState {
+            values, lru, size, ..
+        } = &mut *state;
+
+        values.retain(|&(id, _), (value, used)| {
+            if id != file_id {
+                return true;
+            }
+
+            lru.remove(used);
+            *size -= value.len();
+            false
+        });
+    }
+
+    /// Number of reads served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of reads not found in the cache.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_cache_should_evict_least_recently_used_values() {
+        let cache = ValueCache::new(10);
+
+        cache.insert(0, 0, b"aaaa");
+        cache.insert(0, 4, b"bbbb");
+        assert_eq!(cache.get(0, 0), Some(b"aaaa".to_vec()));
+
+        // The second value is the least recently used one.
+        cache.insert(1, 0, b"cccc");
+        assert_eq!(cache.get(0, 4), None);
+        assert_eq!(cache.get(0, 0), Some(b"aaaa".to_vec()));
+        assert_eq!(cache.get(1, 0), Some(b"cccc".to_vec()));
+        assert_eq!((cache.hits(), cache.misses()), (3, 1));
+
+        cache.insert(2, 0, b"too large value");
+        assert_eq!(cache.get(2, 0), None);
+
+        cache.evict_file(0);
+        assert_eq!(cache.get(0, 0), None);
+        assert_eq!(cache.get(1, 0), Some(b"cccc".to_vec()));
+        assert_eq!(cache.size(), 4);
+    }
+
+    #[test]
+    fn value_cache_should_be_disabled_with_zero_capacity() {
+        let cache = ValueCache::new(0);
+
+        cache.insert(0, 0, b"");
+        assert_eq!(cache.get(0, 0), None);
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+    }
+}
@@ -0,0 +1,1373 @@
+//! Filesystem abstraction.
+//!
+//! `DiskStorage` performs all file operations through a `Vfs`, which is `StdVfs`, the real
+//! filesystem, by default. Another implementation can be set with `DbOptions::vfs`, e.g.
+//! `MemoryVfs` keeping files in memory, `FaultInjectingVfs` failing operations on demand, or
+//! `UringVfs` submitting batched I/O to an io_uring with the `io-uring` feature on Linux.
+//! `TieredVfs` offloads sealed log files to object storage.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, IoSlice, Read, Seek, SeekFrom, Write},
+    ops::Deref,
+    os::unix::prelude::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+#[cfg(feature = "s3")]
+mod s3;
+mod tiered;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+
+#[cfg(feature = "s3")]
+pub use self::s3::S3RemoteStore;
+pub use tiered::{MemoryRemoteStore, RemoteStore, TieredVfs};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use uring::UringVfs;
+
+/// Contents of a memory-mapped file, see `VfsFile::mapped`.
+pub type FileMap = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// Filesystem operations used by the storage.
+pub trait Vfs: fmt::Debug + Send + Sync {
+    /// Opens the file at `path` for reading and, unless `OpenMode::ReadOnly`, appending.
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>>;
+
+    /// Opens the file like `open`, bypassing the page cache where supported, e.g. by `StdVfs`
+    /// on Linux. Other implementations open the file with `open`.
+    fn open_direct(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>> {
+        self.open(path, mode)
+    }
+
+    /// Renames a file, replacing the `to` file if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a hard link at `to` to the `from` file. Fails if `to` exists, or e.g. if the
+    /// paths are on different filesystems.
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Returns names of the entries of the `dir` directory.
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+
+    /// Creates a directory along with all its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes an empty directory.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Whether a file or directory exists at `path`.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Syncs the `dir` directory, making files created, removed or renamed in it durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+/// How a file is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Opens an existing file.
+    Existing,
+    /// Opens an existing file for reading only. Writes fail.
+    ReadOnly,
+    /// Opens an existing file for reading only, memory-mapped by `StdVfs` with the `mmap`
+    /// feature. The file must not be resized while open.
+    Mapped,
+    /// Creates a file, truncating an existing one.
+    Truncate,
+    /// Creates a file, failing if it already exists.
+    CreateNew,
+}
+
+/// File opened through a `Vfs`.
+///
+/// Appends go to the end of the file, reads and writes at an offset don't move it.
+pub trait VfsFile: fmt::Debug + Send + Sync {
+    /// Reads bytes at `pos`, returning how many were read.
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize>;
+
+    /// Overwrites bytes at `pos`, which must not be past the end of the file.
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()>;
+
+    /// Appends bytes to the end of the file, returning how many were written.
+    fn append(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Appends bytes of several buffers to the end of the file, returning how many were
+    /// written.
+    fn append_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let buf = bufs
+            .iter()
+            .find(|buf| !buf.is_empty())
+            .map_or(&[][..], |buf| buf);
+
+        self.append(buf)
+    }
+
+    /// Truncates or extends the file to `len` bytes.
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Size of the file in bytes.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Whether the file is empty.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Syncs the file content to the storage device.
+    fn sync(&self) -> io::Result<()>;
+
+    /// Takes an exclusive advisory lock on the file without blocking. Returns `false` if
+    /// the file is locked through another handle. Locks are released on `unlock` or once
+    /// the process exits.
+    fn try_lock(&self) -> io::Result<bool>;
+
+    /// Releases the lock taken by `try_lock`.
+    fn unlock(&self) -> io::Result<()>;
+
+    /// Contents of the file if it's memory-mapped, to be read without copying.
+    fn mapped(&self) -> Option<FileMap> {
+        None
+    }
+
+    /// Reads exactly `buf.len()` bytes at `pos`.
+    fn read_exact_at(&self, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, pos) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    pos += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads exactly `buf.len()` bytes at `pos` of every `(pos, buf)` read, which
+    /// implementations may submit to the device at once.
+    fn read_batch_at(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        for (pos, buf) in reads.iter_mut() {
+            self.read_exact_at(buf, *pos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends all the bytes of `buf`.
+    fn append_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.append(buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => buf = &buf[n..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Sequential reader over a `VfsFile`, starting at an offset.
+#[derive(Debug)]
+pub(crate) struct VfsReader<F = Arc<dyn VfsFile>> {
+    file: F,
+    pos: u64,
+}
+
+impl<F> VfsReader<F> {
+    pub fn new(file: F, pos: u64) -> Self {
+        Self { file, pos }
+    }
+}
+
+impl<F, T> Read for VfsReader<F>
+where
+    F: Deref<Target = T>,
+    T: VfsFile + ?Sized,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+
+        Ok(read)
+    }
+}
+
+/// Writer appending to a `VfsFile`.
+#[derive(Debug, Clone)]
+pub(crate) struct VfsAppender(pub Arc<dyn VfsFile>);
+
+impl Write for VfsAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.0.append_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// File written under a temporary `*.tmp` name and atomically renamed to its path once
+/// complete, so a crash never leaves the path holding a partially written file.
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    file: Arc<dyn VfsFile>,
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Extension of temporary files. Leftovers of a crash are removed on open.
+    pub const EXTENSION: &'static str = "tmp";
+
+    /// Creates the temporary file of the `path`, truncating a leftover one.
+    pub fn create(vfs: &dyn Vfs, path: &Path) -> io::Result<Self> {
+        let file = vfs.open(&Self::temp_path(path), OpenMode::Truncate)?;
+
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn file(&self) -> &Arc<dyn VfsFile> {
+        &self.file
+    }
+
+    /// Syncs the file, renames it to its path and syncs the directory.
+    pub fn persist(self, vfs: &dyn Vfs) -> io::Result<()> {
+        self.file.sync()?;
+        vfs.rename(&Self::temp_path(&self.path), &self.path)?;
+
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => vfs.sync_dir(dir),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the file without moving it into place.
+    pub fn discard(self, vfs: &dyn Vfs) -> io::Result<()> {
+        vfs.remove(&Self::temp_path(&self.path))
+    }
+
+    fn temp_path(path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(Self::EXTENSION);
+
+        path.with_file_name(name)
+    }
+}
+
+/// The real filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdVfs;
+
+impl StdVfs {
+    fn open_options(mode: OpenMode) -> OpenOptions {
+        let read_only = matches!(mode, OpenMode::ReadOnly | OpenMode::Mapped);
+
+        let mut opts = OpenOptions::new();
+        opts.read(true).write(!read_only);
+
+        match mode {
+            OpenMode::Existing | OpenMode::ReadOnly | OpenMode::Mapped => opts.create(false),
+            OpenMode::Truncate => opts.create(true).truncate(true),
+            OpenMode::CreateNew => opts.create_new(true),
+        };
+
+        opts
+    }
+
+    /// Opens the file at `path` with the cursor at its end.
+    fn open_file(path: &Path, mode: OpenMode) -> io::Result<File> {
+        // Appends are written at the cursor, so it starts at the end of the file.
+        let mut file = Self::open_options(mode).open(path)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(file)
+    }
+}
+
+impl Vfs for StdVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>> {
+        let file = Self::open_file(path, mode)?;
+
+        #[cfg(feature = "mmap")]
+        if mode == OpenMode::Mapped {
+            // SAFETY: log files are not resized once sealed, as required by `OpenMode::Mapped`.
+            let map = unsafe { memmap2::Mmap::map(&file)? };
+
+            return Ok(Arc::new(MappedFile {
+                map: Arc::new(map),
+                file,
+            }));
+        }
+
+        Ok(Arc::new(StdFile(file)))
+    }
+
+    /// Opens the file with `O_DIRECT`, memory-mapped files excepted.
+    #[cfg(target_os = "linux")]
+    fn open_direct(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>> {
+        if mode == OpenMode::Mapped {
+            return self.open(path, mode);
+        }
+
+        Ok(Arc::new(DirectFile::open(path, mode)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+}
+
+/// File of the real filesystem. Appends are written at the file cursor, which is kept at
+/// the end of the file.
+#[derive(Debug)]
+struct StdFile(File);
+
+impl StdFile {
+    fn try_lock_file(file: &File) -> io::Result<bool> {
+        match file.try_lock() {
+            Ok(()) => Ok(true),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+impl VfsFile for StdFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.0.read_at(buf, pos)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        self.0.write_all_at(buf, pos)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        (&self.0).write(buf)
+    }
+
+    fn append_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        (&self.0).write_vectored(bufs)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)?;
+        (&self.0).seek(SeekFrom::Start(len))?;
+
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        Self::try_lock_file(&self.0)
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.0.unlock()
+    }
+}
+
+/// Alignment of offsets, lengths and buffers of direct I/O, a multiple of the logical block
+/// size of common devices.
+#[cfg(target_os = "linux")]
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// File of the real filesystem opened with `O_DIRECT`, bypassing the page cache.
+///
+/// Direct I/O transfers whole aligned blocks, so reads go through an aligned buffer and
+/// writes read, patch and rewrite the blocks they touch. Blocks written past the end of the
+/// file are truncated back.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct DirectFile {
+    file: File,
+    /// Serializes writes, which rewrite whole blocks.
+    write_lock: Mutex<()>,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectFile {
+    fn open(path: &Path, mode: OpenMode) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = StdVfs::open_options(mode)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+
+        Ok(Self {
+            file,
+            write_lock: Mutex::default(),
+        })
+    }
+
+    /// Returns an aligned buffer of `len` bytes borrowed from the `storage`.
+    fn aligned(storage: &mut Vec<u8>, len: usize) -> &mut [u8] {
+        *storage = vec![0; len + DIRECT_IO_ALIGN];
+        let offset = storage.as_ptr().align_offset(DIRECT_IO_ALIGN);
+
+        &mut storage[offset..offset + len]
+    }
+
+    /// Aligned block range covering `len` bytes at `pos`.
+    fn block_range(pos: u64, len: usize) -> (u64, usize) {
+        let align = DIRECT_IO_ALIGN as u64;
+        let start = pos / align * align;
+        let end = (pos + len as u64).div_ceil(align) * align;
+
+        (start, (end - start) as usize)
+    }
+
+    /// Writes `buf` at `pos` by rewriting the blocks it touches. Callers hold the write lock.
+    fn write_blocks(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let file_len = self.len()?;
+
+        let (start, len) = Self::block_range(pos, buf.len());
+        let mut storage = Vec::new();
+        let blocks = Self::aligned(&mut storage, len);
+
+        // Only the first and the last blocks may be partially overwritten.
+        self.read_blocks(&mut blocks[..DIRECT_IO_ALIGN], start)?;
+
+        if len > DIRECT_IO_ALIGN {
+            let last = len - DIRECT_IO_ALIGN;
+            self.read_blocks(&mut blocks[last..], start + last as u64)?;
+        }
+
+        let offset = (pos - start) as usize;
+        blocks[offset..offset + buf.len()].copy_from_slice(buf);
+        self.file.write_all_at(blocks, start)?;
+
+        self.file.set_len(file_len.max(pos + buf.len() as u64))
+    }
+
+    /// Reads whole blocks at the aligned `pos`, returning how many bytes were read before
+    /// the end of the file.
+    fn read_blocks(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        let mut read = 0;
+
+        while read < buf.len() {
+            match self.file.read_at(&mut buf[read..], pos + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl VfsFile for DirectFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        let (start, len) = Self::block_range(pos, buf.len());
+        let mut storage = Vec::new();
+        let blocks = Self::aligned(&mut storage, len);
+
+        let read = self.read_blocks(blocks, start)?;
+        let offset = (pos - start) as usize;
+        let n = read.saturating_sub(offset).min(buf.len());
+
+        buf[..n].copy_from_slice(&blocks[offset..offset + n]);
+
+        Ok(n)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        let _guard = self
+            .write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        self.write_blocks(buf, pos)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        let _guard = self
+            .write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        self.write_blocks(buf, self.len()?)?;
+
+        Ok(buf.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        StdFile::try_lock_file(&self.file)
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.file.unlock()
+    }
+}
+
+/// Memory-mapped file of the real filesystem. Reads copy from the map, writes fail.
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+struct MappedFile {
+    map: Arc<memmap2::Mmap>,
+    file: File,
+}
+
+#[cfg(feature = "mmap")]
+impl VfsFile for MappedFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        let start = (pos as usize).min(self.map.len());
+        let len = buf.len().min(self.map.len() - start);
+
+        buf[..len].copy_from_slice(&self.map[start..start + len]);
+
+        Ok(len)
+    }
+
+    fn write_all_at(&self, _buf: &[u8], _pos: u64) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn append(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(read_only())
+    }
+
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.map.len() as u64)
+    }
+
+    fn mapped(&self) -> Option<FileMap> {
+        Some(self.map.clone())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        StdFile::try_lock_file(&self.file)
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.file.unlock()
+    }
+}
+
+/// Filesystem keeping files in memory. Clones share the same files.
+///
+/// File contents are always durable, while files created, removed or renamed since their
+/// directory was last synced are reverted by `crash`.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryVfs {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    files: BTreeMap<PathBuf, Arc<MemoryFile>>,
+    dirs: BTreeSet<PathBuf>,
+    /// Files as of the last sync of their directories.
+    synced_files: BTreeMap<PathBuf, Arc<MemoryFile>>,
+}
+
+impl MemoryVfs {
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Simulates a crash, reverting directory changes not synced with `sync_dir` and
+    /// releasing all file locks.
+    pub fn crash(&self) {
+        let mut state = self.state();
+        state.files = state.synced_files.clone();
+
+        for file in state.files.values() {
+            file.locked.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+impl Vfs for MemoryVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>> {
+        let mut state = self.state();
+
+        let file = match (mode, state.files.get(path)) {
+            (OpenMode::Existing, Some(file)) => file.clone(),
+            (OpenMode::ReadOnly | OpenMode::Mapped, Some(file)) => {
+                return Ok(Arc::new(ReadOnlyFile(file.clone())))
+            }
+            (OpenMode::Existing | OpenMode::ReadOnly | OpenMode::Mapped, None) => {
+                return Err(not_found(path))
+            }
+            (OpenMode::CreateNew, Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                ))
+            }
+            (OpenMode::Truncate, Some(file)) => {
+                file.set_len(0)?;
+                file.clone()
+            }
+            (_, None) => {
+                let file = Arc::new(MemoryFile::default());
+                state.files.insert(path.to_path_buf(), file.clone());
+                file
+            }
+        };
+
+        Ok(file)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        let file = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_path_buf(), file);
+
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.state()
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        let file = state
+            .files
+            .get(from)
+            .ok_or_else(|| not_found(from))?
+            .clone();
+
+        if state.files.contains_key(to) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", to.display()),
+            ));
+        }
+
+        state.files.insert(to.to_path_buf(), file);
+
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let state = self.state();
+
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+
+        let names: BTreeSet<_> = state
+            .files
+            .keys()
+            .chain(&state.dirs)
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+
+        Ok(names.into_iter().collect())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_path_buf());
+        }
+
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+
+        if state
+            .files
+            .keys()
+            .chain(&state.dirs)
+            .any(|child| child.parent() == Some(path))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("{} is not empty", path.display()),
+            ));
+        }
+
+        state.dirs.remove(path);
+
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state();
+
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state();
+
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+
+        let files: Vec<_> = state
+            .files
+            .iter()
+            .filter(|(path, _)| path.parent() == Some(dir))
+            .map(|(path, file)| (path.clone(), file.clone()))
+            .collect();
+
+        state
+            .synced_files
+            .retain(|path, _| path.parent() != Some(dir));
+        state.synced_files.extend(files);
+
+        Ok(())
+    }
+}
+
+/// In-memory file. All handles share the lock, as they share the file.
+#[derive(Debug, Default)]
+struct MemoryFile {
+    data: Mutex<Vec<u8>>,
+    locked: AtomicBool,
+}
+
+impl MemoryFile {
+    fn data(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl VfsFile for MemoryFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        let data = self.data();
+        let start = (pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+
+        buf[..len].copy_from_slice(&data[start..start + len]);
+
+        Ok(len)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        let mut data = self.data();
+        let pos = pos as usize;
+
+        if pos > data.len() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        let overlap = buf.len().min(data.len() - pos);
+        data[pos..pos + overlap].copy_from_slice(&buf[..overlap]);
+        data.extend_from_slice(&buf[overlap..]);
+
+        Ok(())
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        self.data().extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn append_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut data = self.data();
+
+        for buf in bufs {
+            data.extend_from_slice(buf);
+        }
+
+        Ok(bufs.iter().map(|buf| buf.len()).sum())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data().resize(len as usize, 0);
+
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data().len() as u64)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        Ok(!self.locked.swap(true, Ordering::SeqCst))
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.locked.store(false, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
+
+/// Handle of an in-memory file opened read-only.
+#[derive(Debug)]
+struct ReadOnlyFile(Arc<MemoryFile>);
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "file is opened read-only")
+}
+
+impl VfsFile for ReadOnlyFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.0.read_at(buf, pos)
+    }
+
+    fn write_all_at(&self, _buf: &[u8], _pos: u64) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn append(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(read_only())
+    }
+
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.0.len()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        self.0.try_lock()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.0.unlock()
+    }
+}
+
+/// Operation of a `FaultInjectingVfs` a fault can be injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// Appending to a file. An entry header, key and value are appended with a single
+    /// write unless buffered.
+    Append,
+    /// Overwriting bytes of a file, e.g. the checksum of a streamed entry.
+    WriteAt,
+    /// Truncating a file, e.g. discarding a torn entry.
+    SetLen,
+    /// Syncing a file.
+    Sync,
+    /// Creating a file, e.g. a log file on rotation.
+    Create,
+    /// Removing a file or directory, e.g. a log file on GC.
+    Remove,
+    /// Renaming a file, e.g. the keydir snapshot.
+    Rename,
+    /// Syncing a directory, e.g. after a log file has been created.
+    SyncDir,
+}
+
+/// Fault injected into an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails without any effect.
+    Error,
+    /// An append writes only its first `n` bytes and fails. Other operations fail without
+    /// any effect.
+    Torn(usize),
+}
+
+/// Filesystem failing operations on demand, for testing recovery from I/O errors.
+///
+/// Operations are passed to the inner `Vfs` unless a fault is injected into them.
+/// Clones share the same faults.
+#[derive(Debug, Clone)]
+pub struct FaultInjectingVfs {
+    inner: Arc<dyn Vfs>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+    /// Faults to inject, along with the number of operations to skip first.
+    injected: HashMap<FaultPoint, (u64, Fault)>,
+    /// Number of operations performed at each point.
+    operations: HashMap<FaultPoint, u64>,
+}
+
+impl Faults {
+    /// Counts an operation at the `point`, returning the fault to inject into it, if any.
+    fn hit(&mut self, point: FaultPoint) -> Option<Fault> {
+        *self.operations.entry(point).or_default() += 1;
+
+        let (skip, fault) = self.injected.get_mut(&point)?;
+
+        if *skip > 0 {
+            *skip -= 1;
+            return None;
+        }
+
+        let fault = *fault;
+        self.injected.remove(&point);
+
+        Some(fault)
+    }
+}
+
+impl FaultInjectingVfs {
+    pub fn new(inner: Arc<dyn Vfs>) -> Self {
+        Self {
+            inner,
+            faults: Default::default(),
+        }
+    }
+
+    /// Injects the `fault` into the operation at the `point` following the next `skip`
+    /// ones. A fault is injected once, replacing a fault not injected yet at the `point`.
+    pub fn inject(&self, point: FaultPoint, skip: u64, fault: Fault) {
+        self.faults().injected.insert(point, (skip, fault));
+    }
+
+    /// Removes faults not injected yet.
+    pub fn clear(&self) {
+        self.faults().injected.clear();
+    }
+
+    /// Number of operations performed at the `point`, failed ones included.
+    pub fn operations(&self, point: FaultPoint) -> u64 {
+        self.faults().operations.get(&point).copied().unwrap_or(0)
+    }
+
+    fn faults(&self) -> MutexGuard<'_, Faults> {
+        self.faults.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Counts an operation at the `point`, failing it if a fault is injected into it.
+fn check(faults: &Mutex<Faults>, point: FaultPoint) -> io::Result<()> {
+    let fault = faults
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .hit(point);
+
+    match fault {
+        Some(_) => Err(injected_fault(point)),
+        None => Ok(()),
+    }
+}
+
+fn injected_fault(point: FaultPoint) -> io::Error {
+    io::Error::other(format!("injected fault: {point:?}"))
+}
+
+impl Vfs for FaultInjectingVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>> {
+        if matches!(mode, OpenMode::Truncate | OpenMode::CreateNew) {
+            check(&self.faults, FaultPoint::Create)?;
+        }
+
+        Ok(Arc::new(FaultInjectingFile {
+            inner: self.inner.open(path, mode)?,
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        check(&self.faults, FaultPoint::Rename)?;
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        check(&self.faults, FaultPoint::Remove)?;
+        self.inner.remove(path)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        check(&self.faults, FaultPoint::Create)?;
+        self.inner.hard_link(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        self.inner.list(dir)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        check(&self.faults, FaultPoint::Remove)?;
+        self.inner.remove_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        check(&self.faults, FaultPoint::SyncDir)?;
+        self.inner.sync_dir(dir)
+    }
+}
+
+/// File of a `FaultInjectingVfs`.
+#[derive(Debug)]
+struct FaultInjectingFile {
+    inner: Arc<dyn VfsFile>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultInjectingFile {
+    /// Appends the first `n` bytes of the `bufs` and fails.
+    fn tear(&self, bufs: &[IoSlice<'_>], mut n: usize) -> io::Result<usize> {
+        for buf in bufs {
+            let len = buf.len().min(n);
+            self.inner.append_all(&buf[..len])?;
+            n -= len;
+        }
+
+        Err(injected_fault(FaultPoint::Append))
+    }
+}
+
+impl VfsFile for FaultInjectingFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.inner.read_at(buf, pos)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        check(&self.faults, FaultPoint::WriteAt)?;
+        self.inner.write_all_at(buf, pos)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        self.append_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn append_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let fault = self
+            .faults
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .hit(FaultPoint::Append);
+
+        match fault {
+            Some(Fault::Torn(n)) => self.tear(bufs, n),
+            Some(Fault::Error) => Err(injected_fault(FaultPoint::Append)),
+            None => self.inner.append_vectored(bufs),
+        }
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        check(&self.faults, FaultPoint::SetLen)?;
+        self.inner.set_len(len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        check(&self.faults, FaultPoint::Sync)?;
+        self.inner.sync()
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        self.inner.try_lock()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.inner.unlock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_vfs(vfs: impl Vfs, dir: &Path) {
+        vfs.create_dir_all(dir).unwrap();
+
+        let path = dir.join("file");
+        assert!(!vfs.exists(&path));
+        assert!(vfs.open(&path, OpenMode::Existing).is_err());
+
+        let file = vfs.open(&path, OpenMode::CreateNew).unwrap();
+        assert!(vfs.open(&path, OpenMode::CreateNew).is_err());
+
+        file.append_all(b"hello").unwrap();
+        file.append_vectored(&[IoSlice::new(b" "), IoSlice::new(b"world")])
+            .unwrap();
+        file.write_all_at(b"j", 0).unwrap();
+
+        let mut buf = [0; 11];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"jello world");
+
+        file.set_len(5).unwrap();
+        file.append_all(b"!").unwrap();
+        assert_eq!(file.len().unwrap(), 6);
+
+        let renamed = dir.join("renamed");
+        vfs.rename(&path, &renamed).unwrap();
+        assert!(!vfs.exists(&path));
+        assert_eq!(vfs.list(dir).unwrap(), vec!["renamed".to_string()]);
+
+        let file = vfs.open(&renamed, OpenMode::Existing).unwrap();
+        let mut buf = [0; 6];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"jello!");
+
+        let file = vfs.open(&renamed, OpenMode::Truncate).unwrap();
+        assert!(file.is_empty().unwrap());
+
+        file.append_all(b"data").unwrap();
+
+        let read_only = vfs.open(&renamed, OpenMode::ReadOnly).unwrap();
+        let mut buf = [0; 4];
+        read_only.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"data");
+        assert!(read_only.append_all(b"!").is_err());
+        assert!(read_only.set_len(0).is_err());
+
+        let other = vfs.open(&renamed, OpenMode::Existing).unwrap();
+        assert!(file.try_lock().unwrap());
+        assert!(!other.try_lock().unwrap());
+        file.unlock().unwrap();
+        assert!(other.try_lock().unwrap());
+        other.unlock().unwrap();
+
+        vfs.remove(&renamed).unwrap();
+        assert!(vfs.list(dir).unwrap().is_empty());
+
+        vfs.sync_dir(dir).unwrap();
+        assert!(vfs.sync_dir(&dir.join("missing")).is_err());
+
+        let file = vfs.open(&path, OpenMode::CreateNew).unwrap();
+        let linked = dir.join("linked");
+        vfs.hard_link(&path, &linked).unwrap();
+        assert!(vfs.hard_link(&path, &linked).is_err());
+        file.append_all(b"shared").unwrap();
+        assert_eq!(
+            vfs.open(&linked, OpenMode::ReadOnly)
+                .unwrap()
+                .len()
+                .unwrap(),
+            6
+        );
+        vfs.remove(&path).unwrap();
+        vfs.remove(&linked).unwrap();
+
+        let nested = dir.join("nested");
+        vfs.create_dir_all(&nested).unwrap();
+        assert!(vfs.remove_dir(dir).is_err());
+        vfs.remove_dir(&nested).unwrap();
+        assert!(!vfs.exists(&nested));
+        assert!(vfs.remove_dir(&nested).is_err());
+    }
+
+    #[test]
+    fn std_vfs_should_implement_vfs() {
+        let dir = tempdir::TempDir::new("vfs-test").unwrap();
+
+        test_vfs(StdVfs, &dir.path().join("nested"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn std_vfs_should_open_files_directly() {
+        let dir = tempdir::TempDir::new("vfs-test").unwrap();
+        let path = dir.path().join("file");
+
+        let file = StdVfs.open_direct(&path, OpenMode::CreateNew).unwrap();
+        let data: Vec<_> = (0..10_000u32).map(|i| i as u8).collect();
+
+        // Writes span block boundaries and leave partial blocks.
+        for chunk in data.chunks(3_000) {
+            file.append_all(chunk).unwrap();
+        }
+        file.write_all_at(b"patch", 4_094).unwrap();
+        assert_eq!(file.len().unwrap(), 10_000);
+
+        let mut expected = data.clone();
+        expected[4_094..4_099].copy_from_slice(b"patch");
+
+        let file = StdVfs.open_direct(&path, OpenMode::ReadOnly).unwrap();
+        let mut buf = vec![0; 10_000];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+
+        let mut buf = [0; 7];
+        file.read_exact_at(&mut buf, 4_093).unwrap();
+        assert_eq!(&buf, &expected[4_093..4_100]);
+        assert!(file.read_exact_at(&mut buf, 9_995).is_err());
+    }
+
+    #[test]
+    fn memory_vfs_should_implement_vfs() {
+        test_vfs(MemoryVfs::default(), Path::new("/db/nested"));
+    }
+
+    #[test]
+    fn memory_vfs_should_revert_unsynced_dir_changes_on_crash() {
+        let vfs = MemoryVfs::default();
+        let dir = Path::new("/db");
+        vfs.create_dir_all(dir).unwrap();
+
+        vfs.open(&dir.join("synced"), OpenMode::CreateNew)
+            .unwrap()
+            .append_all(b"data")
+            .unwrap();
+        vfs.open(&dir.join("removed"), OpenMode::CreateNew).unwrap();
+        vfs.sync_dir(dir).unwrap();
+
+        let locked = vfs.open(&dir.join("synced"), OpenMode::Existing).unwrap();
+        assert!(locked.try_lock().unwrap());
+
+        vfs.remove(&dir.join("removed")).unwrap();
+        vfs.rename(&dir.join("synced"), &dir.join("renamed"))
+            .unwrap();
+        vfs.open(&dir.join("unsynced"), OpenMode::CreateNew)
+            .unwrap();
+
+        vfs.crash();
+        assert_eq!(vfs.list(dir).unwrap(), vec!["removed", "synced"]);
+
+        let file = vfs.open(&dir.join("synced"), OpenMode::Existing).unwrap();
+        assert_eq!(file.len().unwrap(), 4);
+        assert!(file.try_lock().unwrap());
+
+        vfs.open(&dir.join("unsynced"), OpenMode::CreateNew)
+            .unwrap();
+        vfs.remove(&dir.join("removed")).unwrap();
+        vfs.sync_dir(dir).unwrap();
+        vfs.crash();
+        assert_eq!(vfs.list(dir).unwrap(), vec!["synced", "unsynced"]);
+    }
+
+    #[test]
+    fn fault_injecting_vfs_should_inject_faults() {
+        let vfs = FaultInjectingVfs::new(Arc::new(MemoryVfs::default()));
+        test_vfs(vfs.clone(), Path::new("/db/nested"));
+
+        let path = Path::new("/db/file");
+        let file = vfs.open(path, OpenMode::CreateNew).unwrap();
+
+        vfs.inject(FaultPoint::Append, 1, Fault::Torn(3));
+        file.append_all(b"hello").unwrap();
+        assert!(file
+            .append_vectored(&[IoSlice::new(b" "), IoSlice::new(b"world")])
+            .is_err());
+        file.append_all(b"!").unwrap();
+
+        let mut buf = [0; 9];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello wo!");
+
+        vfs.inject(FaultPoint::Sync, 0, Fault::Error);
+        assert!(file.sync().is_err());
+        assert!(file.sync().is_ok());
+        assert_eq!(vfs.operations(FaultPoint::Sync), 2);
+
+        vfs.inject(FaultPoint::Remove, 0, Fault::Error);
+        vfs.clear();
+        vfs.remove(path).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+//! Object storage in S3 or an S3-compatible service, enabled by the `s3` feature.
+
+use std::{fmt, io};
+
+use ::s3::{error::S3Error, Bucket};
+
+use super::RemoteStore;
+
+/// Remote store keeping objects in an S3 bucket, under a key prefix.
+pub struct S3RemoteStore {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3RemoteStore {
+    /// Creates a store keeping objects in the `bucket`, their keys prefixed with `prefix`,
+    /// e.g. `"rumdb/orders/"`.
+    pub fn new(bucket: Box<Bucket>, prefix: impl Into<String>) -> Self {
+        Self {
+            bucket,
+            prefix: prefix.into(),
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+}
+
+impl fmt::Debug for S3RemoteStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3RemoteStore")
+            .field("bucket", &self.bucket.name())
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+fn s3_error(e: S3Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Fails unless the response `status` of the `operation` on the `key` is successful.
+fn check_status(operation: &str, key: &str, status: u16) -> io::Result<()> {
+    match status {
+        200..=299 => Ok(()),
+        404 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("object {key} not found"),
+        )),
+        status => Err(io::Error::other(format!(
+            "S3 {operation} of {key} failed with status {status}"
+        ))),
+    }
+}
+
+impl RemoteStore for S3RemoteStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let response = self
+            .bucket
+            .put_object(self.path(key), data)
+            .map_err(s3_error)?;
+
+        check_status("put", key, response.status_code())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let response = self.bucket.get_object(self.path(key)).map_err(s3_error)?;
+        check_status("get", key, response.status_code())?;
+
+        Ok(response.to_vec())
+    }
+
+    fn size(&self, key: &str) -> io::Result<u64> {
+        let (head, status) = self.bucket.head_object(self.path(key)).map_err(s3_error)?;
+        check_status("head", key, status)?;
+
+        head.content_length
+            .map(|len| len.max(0) as u64)
+            .ok_or_else(|| io::Error::other(format!("S3 head of {key} returned no length")))
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        let response = self
+            .bucket
+            .delete_object(self.path(key))
+            .map_err(s3_error)?;
+
+        match response.status_code() {
+            404 => Ok(()),
+            status => check_status("delete", key, status),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let results = self
+            .bucket
+            .list(self.path(prefix), None)
+            .map_err(s3_error)?;
+
+        Ok(results
+            .into_iter()
+            .flat_map(|result| result.contents)
+            .filter_map(|object| Some(object.key.strip_prefix(&self.prefix)?.to_string()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s3_remote_store_should_map_response_status() {
+        assert!(check_status("get", "0.rumdb.log", 200).is_ok());
+        assert_eq!(
+            check_status("get", "0.rumdb.log", 404).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            check_status("put", "0.rumdb.log", 403)
+                .unwrap_err()
+                .to_string(),
+            "S3 put of 0.rumdb.log failed with status 403"
+        );
+    }
+}
@@ -0,0 +1,681 @@
+//! Tiering of sealed log files to object storage.
+//!
+//! `TieredVfs` keeps files on a local `Vfs` and offloads sealed log files which haven't been
+//! read for a while to a `RemoteStore`, see `TieredVfs::offload_idle`. Offloaded log files
+//! are listed and opened like local ones and fetched back into the local filesystem on first
+//! read, so the storage holds datasets larger than the local disk. Removing a log file
+//! removes its remote copy as well.
+//!
+//! Opening a storage fetches offloaded log files, as it reads them all, unless the keydir is
+//! loaded from a snapshot, see `DbOptions::keydir_snapshot`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    io::{self, IoSlice},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use super::{not_found, OpenMode, TempFile, Vfs, VfsFile};
+
+/// Suffix of log file names. Only log files are offloaded.
+const LOG_SUFFIX: &str = ".rumdb.log";
+
+/// Object storage sealed log files are offloaded to, e.g. `S3RemoteStore` with the `s3`
+/// feature. Objects are keyed by the path of their file relative to the root of the
+/// `TieredVfs`, with `/` separators.
+pub trait RemoteStore: fmt::Debug + Send + Sync {
+    /// Uploads an object, replacing an existing one.
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Downloads an object. Fails with `io::ErrorKind::NotFound` if it doesn't exist.
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Size of an object in bytes. Fails with `io::ErrorKind::NotFound` if it doesn't exist.
+    fn size(&self, key: &str) -> io::Result<u64>;
+
+    /// Removes an object. Removing a missing object succeeds.
+    fn remove(&self, key: &str) -> io::Result<()>;
+
+    /// Returns keys of the objects whose key starts with `prefix`.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+}
+
+/// Object storage keeping objects in memory. Clones share the same objects.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryRemoteStore {
+    objects: Arc<Mutex<BTreeMap<String, Arc<Vec<u8>>>>>,
+}
+
+impl MemoryRemoteStore {
+    fn objects(&self) -> MutexGuard<'_, BTreeMap<String, Arc<Vec<u8>>>> {
+        self.objects.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn missing_object(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("object {key} not found"))
+}
+
+impl RemoteStore for MemoryRemoteStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.objects()
+            .insert(key.to_string(), Arc::new(data.to_vec()));
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.objects()
+            .get(key)
+            .map(|data| data.to_vec())
+            .ok_or_else(|| missing_object(key))
+    }
+
+    fn size(&self, key: &str) -> io::Result<u64> {
+        self.objects()
+            .get(key)
+            .map(|data| data.len() as u64)
+            .ok_or_else(|| missing_object(key))
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        self.objects().remove(key);
+
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .objects()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Filesystem offloading sealed log files under its root to a `RemoteStore`.
+///
+/// Files outside of the root and files other than log files are kept on the local `Vfs`
+/// only. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct TieredVfs {
+    local: Arc<dyn Vfs>,
+    remote: Arc<dyn RemoteStore>,
+    root: PathBuf,
+    segments: Arc<Mutex<HashMap<PathBuf, Arc<Segment>>>>,
+}
+
+/// Log file which may be offloaded.
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    key: String,
+    state: Mutex<SegmentState>,
+}
+
+#[derive(Debug)]
+struct SegmentState {
+    /// Size of the log file while it is known to be offloaded only.
+    offloaded: Option<u64>,
+    last_read: Instant,
+    /// Number of open handles writing to the log file, which keep it local.
+    writers: usize,
+    /// Incremented when the local copy is removed, invalidating open local handles.
+    generation: u64,
+}
+
+impl Segment {
+    fn state(&self) -> MutexGuard<'_, SegmentState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl TieredVfs {
+    /// Creates a filesystem keeping files on the `local` one and offloading log files under
+    /// the `root` directory, e.g. the directory of the database, to the `remote` store.
+    pub fn new(
+        local: Arc<dyn Vfs>,
+        remote: Arc<dyn RemoteStore>,
+        root: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            local,
+            remote,
+            root: root.into(),
+            segments: Arc::default(),
+        }
+    }
+
+    /// Offloads the sealed log files in the `dir` directory which haven't been read for at
+    /// least `min_idle`, returning how many have been offloaded. Keyspaces keep their log
+    /// files in their own directories.
+    ///
+    /// Log files are uploaded, then their local copies are removed. A log file is sealed if
+    /// a log file with a greater id exists, as the active log file has the greatest id.
+    pub fn offload_idle(&self, dir: &Path, min_idle: Duration) -> io::Result<usize> {
+        let log_ids = |names: Vec<String>| -> BTreeSet<u32> {
+            names
+                .iter()
+                .filter_map(|name| name.strip_suffix(LOG_SUFFIX)?.parse().ok())
+                .collect()
+        };
+
+        let Some(&active_file_id) = log_ids(self.list(dir)?).last() else {
+            return Ok(0);
+        };
+        let mut offloaded = 0;
+
+        for file_id in log_ids(self.local.list(dir)?).range(..active_file_id) {
+            let Some(segment) = self.segment(&dir.join(format!("{file_id}{LOG_SUFFIX}"))) else {
+                continue;
+            };
+            let mut state = segment.state();
+
+            if state.writers > 0
+                || state.offloaded.is_some()
+                || state.last_read.elapsed() < min_idle
+                || !self.local.exists(&segment.path)
+            {
+                continue;
+            }
+
+            let file = self.local.open(&segment.path, OpenMode::ReadOnly)?;
+            let mut data = vec![0; file.len()? as usize];
+            file.read_exact_at(&mut data, 0)?;
+            self.remote.put(&segment.key, &data)?;
+            self.local.remove(&segment.path)?;
+
+            log::info!("☁️  Offloaded log file: {}", segment.key);
+
+            state.offloaded = Some(data.len() as u64);
+            state.generation += 1;
+            offloaded += 1;
+        }
+
+        if offloaded > 0 {
+            self.local.sync_dir(dir)?;
+        }
+
+        Ok(offloaded)
+    }
+
+    /// Key of the object of the log file at `path`, if it is a log file under the root.
+    fn key(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+
+        if !relative.file_name()?.to_str()?.ends_with(LOG_SUFFIX) {
+            return None;
+        }
+
+        let components: Option<Vec<_>> = relative.iter().map(|name| name.to_str()).collect();
+
+        Some(components?.join("/"))
+    }
+
+    /// Segment of the log file at `path`, if it is a log file under the root.
+    fn segment(&self, path: &Path) -> Option<Arc<Segment>> {
+        let key = self.key(path)?;
+        let mut segments = self.segments.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let segment = segments.entry(path.to_path_buf()).or_insert_with(|| {
+            Arc::new(Segment {
+                path: path.to_path_buf(),
+                key,
+                state: Mutex::new(SegmentState {
+                    offloaded: None,
+                    last_read: Instant::now(),
+                    writers: 0,
+                    generation: 0,
+                }),
+            })
+        });
+
+        Some(segment.clone())
+    }
+
+    /// Forgets the segment of the log file at `path`, e.g. once it has been removed.
+    fn forget(&self, path: &Path) {
+        self.segments
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(path);
+    }
+
+    /// Fetches the log file of the `segment` unless it is local, with its state locked.
+    fn fetch(&self, segment: &Segment, state: &mut SegmentState) -> io::Result<()> {
+        if state.offloaded.is_none() && self.local.exists(&segment.path) {
+            return Ok(());
+        }
+
+        let data = self.remote.get(&segment.key)?;
+        let temp = TempFile::create(&*self.local, &segment.path)?;
+        temp.file().append_all(&data)?;
+        temp.persist(&*self.local)?;
+
+        log::info!("☁️  Fetched log file: {}", segment.key);
+
+        state.offloaded = None;
+
+        Ok(())
+    }
+}
+
+impl Vfs for TieredVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>> {
+        let Some(segment) = self.segment(path) else {
+            return self.local.open(path, mode);
+        };
+
+        match mode {
+            OpenMode::ReadOnly | OpenMode::Mapped => {
+                if !self.exists(path) {
+                    return Err(not_found(path));
+                }
+
+                Ok(Arc::new(TieredFile {
+                    vfs: self.clone(),
+                    segment,
+                    mode,
+                    local: Mutex::default(),
+                }))
+            }
+            OpenMode::Existing => {
+                let mut state = segment.state();
+                self.fetch(&segment, &mut state)?;
+                let inner = self.local.open(path, mode)?;
+                state.writers += 1;
+                drop(state);
+
+                Ok(Arc::new(WritableFile { inner, segment }))
+            }
+            OpenMode::Truncate | OpenMode::CreateNew => {
+                let mut state = segment.state();
+
+                if mode == OpenMode::CreateNew && self.exists(path) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} already exists", path.display()),
+                    ));
+                }
+
+                self.remote.remove(&segment.key)?;
+                let inner = self.local.open(path, mode)?;
+                state.offloaded = None;
+                state.writers += 1;
+                drop(state);
+
+                Ok(Arc::new(WritableFile { inner, segment }))
+            }
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // Only files kept local, e.g. temporary files, are renamed. A replaced log file is
+        // removed remotely as well.
+        if let Some(segment) = self.segment(to) {
+            let mut state = segment.state();
+            self.remote.remove(&segment.key)?;
+            self.local.rename(from, to)?;
+            state.offloaded = None;
+            state.generation += 1;
+
+            return Ok(());
+        }
+
+        self.local.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let Some(segment) = self.segment(path) else {
+            return self.local.remove(path);
+        };
+
+        let mut state = segment.state();
+        let local = self.local.exists(path);
+
+        if !local {
+            self.remote.size(&segment.key)?;
+        }
+
+        // The remote copy is removed first, so a crash never leaves it behind once the log
+        // file has been removed.
+        self.remote.remove(&segment.key)?;
+
+        if local {
+            self.local.remove(path)?;
+        }
+
+        state.offloaded = None;
+        state.generation += 1;
+        drop(state);
+        self.forget(path);
+
+        Ok(())
+    }
+
+    /// Links local files only, offloaded log files are not found.
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.local.hard_link(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let mut names: BTreeSet<_> = self.local.list(dir)?.into_iter().collect();
+
+        if let Ok(relative) = dir.strip_prefix(&self.root) {
+            let components: Option<Vec<_>> = relative.iter().map(|name| name.to_str()).collect();
+            let prefix = match components {
+                Some(components) if components.is_empty() => String::new(),
+                Some(components) => format!("{}/", components.join("/")),
+                None => return Ok(names.into_iter().collect()),
+            };
+
+            for key in self.remote.list(&prefix)? {
+                let name = &key[prefix.len()..];
+
+                if !name.contains('/') && name.ends_with(LOG_SUFFIX) {
+                    names.insert(name.to_string());
+                }
+            }
+        }
+
+        Ok(names.into_iter().collect())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.local.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.local.remove_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.local.exists(path)
+            || self
+                .key(path)
+                .is_some_and(|key| self.remote.size(&key).is_ok())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.local.sync_dir(dir)
+    }
+}
+
+/// Log file opened for reading through a `TieredVfs`. The local copy is opened on demand,
+/// fetching the log file if it has been offloaded.
+struct TieredFile {
+    vfs: TieredVfs,
+    segment: Arc<Segment>,
+    mode: OpenMode,
+    /// Handle of the local copy, along with the generation of the segment it was opened at.
+    local: Mutex<Option<(u64, Arc<dyn VfsFile>)>>,
+}
+
+impl TieredFile {
+    fn handle(&self) -> io::Result<Arc<dyn VfsFile>> {
+        let mut state = self.segment.state();
+        state.last_read = Instant::now();
+
+        let mut local = self.local.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match &*local {
+            Some((generation, handle)) if *generation == state.generation => {
+                return Ok(handle.clone())
+            }
+            _ => (),
+        }
+
+        self.vfs.fetch(&self.segment, &mut state)?;
+        let handle = self.vfs.local.open(&self.segment.path, self.mode)?;
+        *local = Some((state.generation, handle.clone()));
+
+        Ok(handle)
+    }
+}
+
+impl fmt::Debug for TieredFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TieredFile")
+            .field("key", &self.segment.key)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl VfsFile for TieredFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.handle()?.read_at(buf, pos)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        self.handle()?.write_all_at(buf, pos)
+    }
+
+    fn read_batch_at(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        self.handle()?.read_batch_at(reads)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        self.handle()?.append(buf)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.handle()?.set_len(len)
+    }
+
+    /// Offloaded log files aren't fetched to get their size.
+    fn len(&self) -> io::Result<u64> {
+        {
+            let mut state = self.segment.state();
+
+            if let Some(size) = state.offloaded {
+                return Ok(size);
+            }
+
+            if !self.vfs.local.exists(&self.segment.path) {
+                let size = self.vfs.remote.size(&self.segment.key)?;
+                state.offloaded = Some(size);
+
+                return Ok(size);
+            }
+        }
+
+        self.handle()?.len()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.handle()?.sync()
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        self.handle()?.try_lock()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.handle()?.unlock()
+    }
+}
+
+/// Log file opened for writing through a `TieredVfs`, kept local while open.
+#[derive(Debug)]
+struct WritableFile {
+    inner: Arc<dyn VfsFile>,
+    segment: Arc<Segment>,
+}
+
+impl Drop for WritableFile {
+    fn drop(&mut self) {
+        self.segment.state().writers -= 1;
+    }
+}
+
+impl VfsFile for WritableFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.inner.read_at(buf, pos)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        self.inner.write_all_at(buf, pos)
+    }
+
+    fn read_batch_at(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        self.inner.read_batch_at(reads)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.append(buf)
+    }
+
+    fn append_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.inner.append_vectored(bufs)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        self.inner.try_lock()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.inner.unlock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{storage::Storage, vfs::MemoryVfs, DbOptions, RumDb};
+
+    use super::*;
+
+    #[test]
+    fn tiered_vfs_should_offload_and_fetch_sealed_log_files() {
+        let local = MemoryVfs::default();
+        let remote = MemoryRemoteStore::default();
+        let path = Path::new("/db");
+        let vfs = TieredVfs::new(Arc::new(local.clone()), Arc::new(remote.clone()), path);
+        let opts = DbOptions::default()
+            .vfs(Arc::new(vfs.clone()))
+            .max_log_file_size(100);
+
+        let mut db = RumDb::open(path, opts.clone()).unwrap();
+
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+        }
+
+        let local_logs = || {
+            local
+                .list(path)
+                .unwrap()
+                .into_iter()
+                .filter(|name| name.ends_with(LOG_SUFFIX))
+                .count()
+        };
+        let logs = local_logs();
+        assert!(logs > 2);
+
+        // Log files read recently stay local.
+        assert_eq!(vfs.offload_idle(path, Duration::from_secs(60)).unwrap(), 0);
+
+        // All sealed log files are offloaded, the active one stays local.
+        assert_eq!(vfs.offload_idle(path, Duration::ZERO).unwrap(), logs - 1);
+        assert_eq!(local_logs(), 1);
+        assert_eq!(remote.list("").unwrap().len(), logs - 1);
+        assert_eq!(
+            vfs.list(path).unwrap().len(),
+            local.list(path).unwrap().len() + logs - 1
+        );
+
+        // Reads fetch the log files they hit.
+        assert_eq!(db.get(&[0]).unwrap(), Some(vec![0; 20]));
+        assert_eq!(local_logs(), 2);
+        assert_eq!(db.get(&[9]).unwrap(), Some(vec![9; 20]));
+        assert_eq!(db.storage_stats().keys, 10);
+        db.close().unwrap();
+
+        // Offloaded log files are found on open, and removed once dead. The keydir is loaded
+        // from the snapshot without fetching log files.
+        let mut db = RumDb::open(path, opts).unwrap();
+        assert_eq!(local_logs(), 2);
+
+        for i in 0..10u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 20]));
+        }
+
+        vfs.offload_idle(path, Duration::ZERO).unwrap();
+        db.compact().unwrap();
+        assert!(remote.list("").unwrap().is_empty());
+
+        for i in 0..10u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 20]));
+        }
+    }
+
+    #[test]
+    fn tiered_vfs_should_key_objects_by_relative_path() {
+        let remote = MemoryRemoteStore::default();
+        let vfs = TieredVfs::new(
+            Arc::new(MemoryVfs::default()),
+            Arc::new(remote.clone()),
+            "/db",
+        );
+        let keyspace = Path::new("/db/keyspaces/users");
+        vfs.create_dir_all(keyspace).unwrap();
+
+        for name in ["0.rumdb.log", "1.rumdb.log", "hint"] {
+            vfs.open(&keyspace.join(name), OpenMode::CreateNew)
+                .unwrap()
+                .append_all(name.as_bytes())
+                .unwrap();
+        }
+
+        // Log files open for writing stay local.
+        let file = vfs
+            .open(&keyspace.join("0.rumdb.log"), OpenMode::Existing)
+            .unwrap();
+        assert_eq!(vfs.offload_idle(keyspace, Duration::ZERO).unwrap(), 0);
+        drop(file);
+
+        assert_eq!(vfs.offload_idle(keyspace, Duration::ZERO).unwrap(), 1);
+        assert_eq!(
+            remote.list("").unwrap(),
+            vec!["keyspaces/users/0.rumdb.log".to_string()]
+        );
+        assert_eq!(
+            vfs.list(keyspace).unwrap(),
+            vec!["0.rumdb.log", "1.rumdb.log", "hint"]
+        );
+        assert!(vfs.exists(&keyspace.join("0.rumdb.log")));
+
+        let file = vfs
+            .open(&keyspace.join("0.rumdb.log"), OpenMode::ReadOnly)
+            .unwrap();
+        assert_eq!(file.len().unwrap(), 11);
+        assert!(!vfs.local.exists(&keyspace.join("0.rumdb.log")));
+
+        let mut buf = [0; 11];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"0.rumdb.log");
+        assert!(vfs.local.exists(&keyspace.join("0.rumdb.log")));
+
+        vfs.offload_idle(keyspace, Duration::ZERO).unwrap();
+        vfs.remove(&keyspace.join("0.rumdb.log")).unwrap();
+        assert!(remote.list("").unwrap().is_empty());
+        assert!(!vfs.exists(&keyspace.join("0.rumdb.log")));
+        assert!(vfs.remove(&keyspace.join("0.rumdb.log")).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+//! io_uring file I/O, enabled by the `io-uring` feature on Linux.
+//!
+//! `UringVfs` opens files of the real filesystem like `StdVfs`, but submits batched reads and
+//! appends to an io_uring shared by all its files, one system call per batch. Single reads and
+//! the remaining operations are plain system calls.
+
+use std::{
+    fmt,
+    io::{self, IoSlice},
+    os::{fd::AsRawFd, unix::prelude::FileExt},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use io_uring::{opcode, squeue, types::Fd, IoUring};
+
+use super::{OpenMode, StdFile, StdVfs, Vfs, VfsFile};
+
+/// Offset making reads and writes use and advance the file cursor.
+const CURSOR: u64 = u64::MAX;
+
+/// The real filesystem, accessed through io_uring.
+#[derive(Debug, Clone)]
+pub struct UringVfs {
+    ring: Arc<Ring>,
+}
+
+impl UringVfs {
+    /// Sets up an io_uring with room for `entries` requests at once, e.g. 256. Fails if
+    /// io_uring is not supported or disabled, e.g. by a seccomp policy.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        Ok(Self {
+            ring: Arc::new(Ring(Mutex::new(IoUring::new(entries)?))),
+        })
+    }
+}
+
+impl Vfs for UringVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn VfsFile>> {
+        Ok(Arc::new(UringFile {
+            file: StdFile(StdVfs::open_file(path, mode)?),
+            ring: self.ring.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        StdVfs.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        StdVfs.remove(path)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        StdVfs.hard_link(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        StdVfs.list(dir)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        StdVfs.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        StdVfs.remove_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        StdVfs.exists(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        StdVfs.sync_dir(dir)
+    }
+}
+
+/// io_uring shared by the files of a `UringVfs`. Batches are submitted one at a time.
+struct Ring(Mutex<IoUring>);
+
+impl Ring {
+    fn lock(&self) -> MutexGuard<'_, IoUring> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Number of entries submitted at once.
+    fn capacity(&self) -> usize {
+        self.lock().params().sq_entries() as usize
+    }
+
+    /// Submits the `entries`, waits for all of them to complete and returns their results in
+    /// the submission order.
+    ///
+    /// # Safety
+    ///
+    /// Buffers the entries point to must be valid for the whole call.
+    unsafe fn run(&self, entries: &mut [squeue::Entry]) -> io::Result<Vec<i32>> {
+        let mut ring = self.lock();
+        let capacity = ring.params().sq_entries() as usize;
+        let mut results = vec![0; entries.len()];
+
+        for (chunk_idx, chunk) in entries.chunks_mut(capacity).enumerate() {
+            let offset = chunk_idx * capacity;
+
+            for (i, entry) in chunk.iter_mut().enumerate() {
+                *entry = entry.clone().user_data((offset + i) as u64);
+            }
+
+            // SAFETY: the buffers are valid as required from the caller. The queue is empty
+            // between batches and the chunk fits into it.
+            unsafe { ring.submission().push_multiple(chunk) }.map_err(io::Error::other)?;
+
+            let mut completed = 0;
+
+            while completed < chunk.len() {
+                match ring.submit_and_wait(chunk.len() - completed) {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+
+                for cqe in ring.completion() {
+                    results[cqe.user_data() as usize] = cqe.result();
+                    completed += 1;
+                }
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+impl fmt::Debug for Ring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ring").finish_non_exhaustive()
+    }
+}
+
+/// File of the real filesystem with batched reads and appends submitted to an io_uring.
+#[derive(Debug)]
+struct UringFile {
+    file: StdFile,
+    ring: Arc<Ring>,
+}
+
+impl UringFile {
+    fn fd(&self) -> Fd {
+        Fd(self.file.0.as_raw_fd())
+    }
+}
+
+/// Converts a result of a completed request to the number of bytes transferred.
+fn transferred(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+impl VfsFile for UringFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.file.0.read_at(buf, pos)
+    }
+
+    fn read_batch_at(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        let mut entries: Vec<_> = reads
+            .iter_mut()
+            .map(|(pos, buf)| {
+                let len = buf.len().min(u32::MAX as usize) as u32;
+                opcode::Read::new(self.fd(), buf.as_mut_ptr(), len)
+                    .offset(*pos)
+                    .build()
+            })
+            .collect();
+
+        // SAFETY: the buffers are borrowed from `reads` until the call returns.
+        let results = unsafe { self.ring.run(&mut entries)? };
+
+        // Short reads are finished one by one, hitting the end of the file fails.
+        for ((pos, buf), result) in reads.iter_mut().zip(results) {
+            let n = transferred(result)?;
+            self.file.read_exact_at(&mut buf[n..], *pos + n as u64)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, pos)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        self.append_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn append_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        // Linked writes run in order, a failed or short one cancels the following ones. Links
+        // don't span submissions, so only as many buffers as fit at once are written.
+        let bufs: Vec<_> = bufs
+            .iter()
+            .filter(|buf| !buf.is_empty())
+            .take(self.ring.capacity())
+            .collect();
+
+        let mut entries: Vec<_> = bufs
+            .iter()
+            .enumerate()
+            .map(|(i, buf)| {
+                let len = buf.len().min(u32::MAX as usize) as u32;
+                let entry = opcode::Write::new(self.fd(), buf.as_ptr(), len)
+                    .offset(CURSOR)
+                    .build();
+
+                if i + 1 < bufs.len() {
+                    entry.flags(squeue::Flags::IO_LINK)
+                } else {
+                    entry
+                }
+            })
+            .collect();
+
+        // SAFETY: the buffers are borrowed from `bufs` until the call returns.
+        let results = unsafe { self.ring.run(&mut entries)? };
+
+        let mut written = 0;
+
+        for (buf, result) in bufs.iter().zip(results) {
+            match transferred(result) {
+                Ok(n) => {
+                    written += n;
+
+                    if n < buf.len() {
+                        break;
+                    }
+                }
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.file.len()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        self.file.try_lock()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.file.unlock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        keydir::HashmapKeydir,
+        storage::{DiskStorage, Storage},
+        DbOptions,
+    };
+
+    use super::*;
+
+    #[test]
+    fn uring_vfs_should_batch_reads_and_appends() {
+        let dir = tempdir::TempDir::new("uring-vfs-test").unwrap();
+        let vfs = UringVfs::new(4).unwrap();
+
+        let file = vfs
+            .open(&dir.path().join("file"), OpenMode::CreateNew)
+            .unwrap();
+        let written = file
+            .append_vectored(&[
+                IoSlice::new(b"hello"),
+                IoSlice::new(b""),
+                IoSlice::new(b" world"),
+            ])
+            .unwrap();
+        assert_eq!(written, 11);
+        file.append_all(b"!").unwrap();
+        assert_eq!(file.len().unwrap(), 12);
+
+        // More reads than the ring fits at once.
+        let mut bufs = [[0; 2]; 6];
+        let mut reads: Vec<_> = bufs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, buf)| ((i * 2) as u64, &mut buf[..]))
+            .collect();
+        file.read_batch_at(&mut reads).unwrap();
+        assert_eq!(bufs.concat(), b"hello world!");
+
+        let mut buf = [0; 2];
+        assert!(file.read_batch_at(&mut [(11, &mut buf[..])]).is_err());
+    }
+
+    #[test]
+    fn disk_storage_should_work_over_uring_vfs() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = DbOptions::default()
+            .max_log_file_size(100)
+            .vfs(Arc::new(UringVfs::new(64).unwrap()));
+
+        {
+            let mut db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts.clone()).unwrap();
+
+            for i in 0..20u8 {
+                db.put(vec![i], vec![i; 30]).unwrap();
+            }
+            db.compact().unwrap();
+        }
+
+        let db = DiskStorage::<HashmapKeydir>::open(dir.path(), opts).unwrap();
+        let keys: Vec<_> = (0..20u8).map(|i| [i]).collect();
+        let keys: Vec<_> = keys.iter().map(|k| &k[..]).collect();
+
+        let values = db.get_many(&keys).unwrap();
+        assert!(values
+            .into_iter()
+            .zip(0..20u8)
+            .all(|(value, i)| value == Some(vec![i; 30])));
+    }
+}